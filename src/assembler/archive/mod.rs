@@ -0,0 +1,245 @@
+use super::image::Error;
+use super::object::Object;
+use crate::binary::Reader;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"PLAR";
+pub const VERSION: u8 = 1;
+
+/**
+ * Objects bundled into one file, with an index of
+ * the public symbols each of them defines
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+    pub members: Vec<Object>,
+    /* public symbol to index into `members` */
+    pub index: HashMap<String, usize>,
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive {
+            members: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /**
+     * Adds a member, its public symbols must not be defined by another
+     */
+    pub fn add(&mut self, object: Object) -> Result<(), String> {
+        let member = self.members.len();
+
+        for symbol in object.symbols.iter().filter(|s| s.public) {
+            if let Some(other) = self.index.get(&symbol.name) {
+                return Err(format!(
+                    "`{}` is defined in {} and {}",
+                    symbol.name, self.members[*other].name, object.name
+                ));
+            }
+        }
+        for symbol in object.symbols.iter().filter(|s| s.public) {
+            self.index.insert(symbol.name.clone(), member);
+        }
+
+        self.members.push(object);
+        Ok(())
+    }
+
+    /**
+     * Archive file, big endian:
+     * magic, version, index (u16 count) as name, member (u16),
+     * members (u16 count) as length (u32) and an object file.
+     * Strings are a u16 length and UTF-8. The index comes first
+     * so tools can list symbols without reading the members.
+     */
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+
+        let mut index: Vec<(&String, &usize)> = self.index.iter().collect();
+        index.sort();
+
+        buf.extend_from_slice(&(index.len() as u16).to_be_bytes());
+        for (name, member) in index {
+            buf.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&(*member as u16).to_be_bytes());
+        }
+
+        buf.extend_from_slice(&(self.members.len() as u16).to_be_bytes());
+        for member in &self.members {
+            let mut object = Vec::new();
+            member.write(&mut object)?;
+
+            buf.extend_from_slice(&(object.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&object);
+        }
+
+        out.write_all(&buf)
+    }
+
+    pub fn read(input: &mut dyn Read) -> Result<Archive, String> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+        let mut reader = Reader::new(&data, "library");
+
+        if reader.take(4)? != MAGIC {
+            return Err("not a library".to_string());
+        }
+
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported library version {}", version));
+        }
+
+        let mut res = Archive::new();
+
+        for _ in 0..reader.u16()? {
+            let name = reader.string()?;
+            res.index.insert(name, reader.u16()? as usize);
+        }
+
+        for _ in 0..reader.u16()? {
+            let len = reader.u32()? as usize;
+            let mut object = reader.take(len)?;
+            res.members.push(Object::read(&mut object)?);
+        }
+
+        if let Some((name, _)) = res
+            .index
+            .iter()
+            .find(|(_, member)| **member >= res.members.len())
+        {
+            return Err(format!("index entry `{}` names a missing member", name));
+        }
+
+        Ok(res)
+    }
+
+    pub fn load(path: &str) -> Result<Archive, Error> {
+        let err = |e: String| Error::Io(format!("{}: {}", path, e));
+        let mut file = fs::File::open(path).map_err(|e| err(e.to_string()))?;
+
+        Archive::read(&mut file).map_err(err)
+    }
+}
+
+/**
+ * `objects` plus the library members needed to resolve their
+ * EXTRN symbols, and those of the members pulled in, and so on
+ * Libraries are searched in order, the first one defining a
+ * symbol wins. Symbols no library has are left for the linker
+ * to report.
+ */
+pub fn resolve(objects: Vec<Object>, archives: &[Archive]) -> Vec<Object> {
+    let mut res = objects;
+    let mut pulled: Vec<(usize, usize)> = Vec::new();
+    let mut next = 0;
+
+    while next < res.len() {
+        let defined = |res: &[Object], name: &str| {
+            res.iter()
+                .any(|o| o.symbols.iter().any(|s| s.public && s.name == name))
+        };
+
+        for name in res[next].externs.clone() {
+            if defined(&res, &name) {
+                continue;
+            }
+
+            let found = archives
+                .iter()
+                .enumerate()
+                .find_map(|(a, archive)| archive.index.get(&name).map(|m| (a, *m)));
+
+            if let Some((a, m)) = found {
+                if !pulled.contains(&(a, m)) {
+                    pulled.push((a, m));
+                    res.push(archives[a].members[m].clone());
+                }
+            }
+        }
+
+        next += 1;
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::image::Options;
+    use crate::assembler::object::assemble_object;
+
+    fn object(name: &str, src: &str) -> Object {
+        let mut res = assemble_object(src, &Options::new()).unwrap();
+        res.name = name.to_string();
+        res
+    }
+
+    fn names(objects: &[Object]) -> Vec<&str> {
+        objects.iter().map(|o| o.name.as_str()).collect()
+    }
+
+    #[test]
+    fn round_trips_with_its_index() {
+        let mut archive = Archive::new();
+        archive
+            .add(object("a", "PUBLIC a, b\na:\nb:\nret\n"))
+            .unwrap();
+        archive.add(object("c", "PUBLIC c\nc:\nret\n")).unwrap();
+
+        let mut buf = Vec::new();
+        archive.write(&mut buf).unwrap();
+        let back = Archive::read(&mut &buf[..]).unwrap();
+
+        assert_eq!(back.index, archive.index);
+        assert_eq!(back.index["b"], 0);
+        assert_eq!(back.index["c"], 1);
+        assert_eq!(names(&back.members), ["a", "c"]);
+        assert_eq!(
+            Archive::read(&mut &buf[..buf.len() - 1]).unwrap_err(),
+            "library is truncated"
+        );
+    }
+
+    #[test]
+    fn members_define_their_symbols_once() {
+        let mut archive = Archive::new();
+        archive.add(object("a", "PUBLIC a\na:\nret\n")).unwrap();
+
+        assert_eq!(
+            archive.add(object("b", "PUBLIC a\na:\nret\n")),
+            Err("`a` is defined in a and b".to_string())
+        );
+        assert_eq!(archive.members.len(), 1);
+    }
+
+    #[test]
+    fn only_needed_members_are_pulled_in() {
+        let mut first = Archive::new();
+        first
+            .add(object("a", "PUBLIC a\nEXTRN b\na:\nlcall b\nret\n"))
+            .unwrap();
+        first.add(object("unused", "PUBLIC c\nc:\nret\n")).unwrap();
+
+        let mut second = Archive::new();
+        second.add(object("b", "PUBLIC b\nb:\nret\n")).unwrap();
+        second
+            .add(object("other a", "PUBLIC a\na:\nret\n"))
+            .unwrap();
+
+        let main = object("main", "EXTRN a, missing\nlcall a\nlcall missing\nend\n");
+        let linked = resolve(vec![main], &[first, second]);
+
+        /* b only through a, and the first library's a wins */
+        assert_eq!(names(&linked), ["main", "a", "b"]);
+    }
+}
@@ -3,93 +3,71 @@
  * MOV Rn, #data
  */
 pub fn mov_rn_data(rn: u8, data: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x74 + rn);
-    bytes.push(data);
-    bytes
+    vec![0x74 + rn, data]
 }
 
 /**
- * MOV Rn, Rn
+ * MOV Rn, Rm
+ * Both registers share the operand byte, `n` in the high nibble
  */
 pub fn mov_rn_rn(rn1: u8, rn2: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x7c + rn1 * 8 + rn2);
-    bytes
+    vec![0x73, rn1 << 4 | rn2]
 }
 
 /**
  * MOV A, #data
  */
 pub fn mov_a_data(data: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x8c);
-    bytes.push(data);
-    bytes
+    vec![0x8c, data]
 }
 
 /**
  * MOV A, Rn
  */
 pub fn mov_a_rn(rn: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x84 + rn);
-    bytes
+    vec![0x84 + rn]
 }
 
 /**
  * MOV Rn, A
  */
 pub fn mov_rn_a(rn: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x8d + rn);
-    bytes
+    vec![0x8d + rn]
 }
 
 /**
  * MOV B, Rn
  */
 pub fn mov_b_rn(rn: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x95 + rn);
-    bytes
+    vec![0x95 + rn]
 }
 
 /**
  * MOV B, #data
  */
 pub fn mov_b_data(data: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x9D);
-    bytes.push(data);
-    bytes
+    vec![0x9D, data]
 }
 
 /**
  * MOV Rn, B
  */
 pub fn mov_rn_b(rn: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x9E + rn);
-    bytes
+    vec![0x9E + rn]
 }
 
 /**
  * MOV A, B
  */
 pub fn mov_a_b() -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0xA6);
-    bytes
+    vec![0xA6]
 }
 
 /**
  * MOV B, A
  */
 pub fn mov_b_a() -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0xA7);
-    bytes
+    vec![0xA7]
 }
 
 /* add instructions */
@@ -98,37 +76,184 @@ pub fn mov_b_a() -> Vec<u8> {
  * ADD A, #data
  */
 pub fn add_a_data(data: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x50);
-    bytes.push(data);
-    bytes
+    vec![0x50, data]
 }
 
 /**
  * ADD A, B
  */
 pub fn add_a_b() -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x51);
-    bytes
+    vec![0x51]
 }
 
 /**
  * ADD A, Rn
  */
 pub fn add_a_rn(rn: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x52 + rn);
-    bytes
+    vec![0x52 + rn]
 }
 
 /**
  * ADD A, A
  */
 pub fn add_a_a() -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x5A);
-    bytes
+    vec![0x5A]
+}
+
+/**
+ * ADDC A, #data
+ */
+pub fn addc_a_data(data: u8) -> Vec<u8> {
+    vec![0x5B, data]
+}
+
+/**
+ * ADDC A, Rn
+ */
+pub fn addc_a_rn(rn: u8) -> Vec<u8> {
+    vec![0x5C + rn]
+}
+
+/**
+ * SUBB A, #data
+ */
+pub fn subb_a_data(data: u8) -> Vec<u8> {
+    vec![0x64, data]
+}
+
+/**
+ * SUBB A, Rn
+ */
+pub fn subb_a_rn(rn: u8) -> Vec<u8> {
+    vec![0x65 + rn]
+}
+
+/* inc and dec */
+
+/**
+ * INC A
+ */
+pub fn inc_a() -> Vec<u8> {
+    vec![0x6D]
+}
+
+/**
+ * DEC A
+ */
+pub fn dec_a() -> Vec<u8> {
+    vec![0x6E]
+}
+
+/**
+ * INC DPTR
+ */
+pub fn inc_dptr() -> Vec<u8> {
+    vec![0x6F]
+}
+
+/**
+ * INC Rn
+ */
+pub fn inc_rn(rn: u8) -> Vec<u8> {
+    vec![0xA8 + rn]
+}
+
+/**
+ * DEC Rn
+ */
+pub fn dec_rn(rn: u8) -> Vec<u8> {
+    vec![0xB0 + rn]
+}
+
+/* multiply and divide */
+
+/**
+ * MUL AB
+ */
+pub fn mul_ab() -> Vec<u8> {
+    vec![0x70]
+}
+
+/**
+ * DIV AB
+ */
+pub fn div_ab() -> Vec<u8> {
+    vec![0x71]
+}
+
+/* logic */
+
+/**
+ * ANL A, #data
+ * ORL and XRL follow, `op` is 0, 1 or 2
+ */
+pub fn logic_a_data(op: u8, data: u8) -> Vec<u8> {
+    vec![0xEA + op, data]
+}
+
+/**
+ * ANL A, direct
+ */
+pub fn logic_a_direct(op: u8, direct: u8) -> Vec<u8> {
+    vec![0xED + op, direct]
+}
+
+/**
+ * SWAP A
+ */
+pub fn swap_a() -> Vec<u8> {
+    vec![0x72]
+}
+
+/**
+ * RLC A
+ */
+pub fn rlc_a() -> Vec<u8> {
+    vec![0xF0]
+}
+
+/**
+ * RRC A
+ */
+pub fn rrc_a() -> Vec<u8> {
+    vec![0xF1]
+}
+
+/**
+ * CPL A
+ */
+pub fn cpl_a() -> Vec<u8> {
+    vec![0xF2]
+}
+
+/**
+ * CLR A
+ */
+pub fn clr_a() -> Vec<u8> {
+    vec![0xF3]
+}
+
+/**
+ * NOP
+ */
+pub fn nop() -> Vec<u8> {
+    vec![0xF4]
+}
+
+/* indirect addressing */
+
+/**
+ * MOV A, @Ri
+ */
+pub fn mov_a_ri(ri: u8) -> Vec<u8> {
+    vec![0xE6 + ri]
+}
+
+/**
+ * MOV @Ri, A
+ */
+pub fn mov_ri_a(ri: u8) -> Vec<u8> {
+    vec![0xE8 + ri]
 }
 
 /* jmp instructions */
@@ -137,23 +262,189 @@ pub fn add_a_a() -> Vec<u8> {
  * SJMP offset
  */
 pub fn sjmp(offset: u8) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x28);
-    bytes.push(offset as u8);
-    bytes
+    vec![0x28, offset]
 }
 
 /**
  * LJMP addr
  */
 pub fn ljmp(addr: u16) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    bytes.push(0x29);
     /*
        Since 8051 is big endian,
        We store MSB first
     */
-    bytes.push((addr >> 8) as u8);
-    bytes.push((addr & 0xff) as u8);
-    bytes
+    vec![0x29, (addr >> 8) as u8, (addr & 0xff) as u8]
+}
+
+/**
+ * DJNZ Rn, offset
+ */
+pub fn djnz(rn: u8, offset: u8) -> Vec<u8> {
+    vec![0xB8 + rn, offset]
+}
+
+/**
+ * JZ offset
+ * JNZ, JC and JNC follow, `cond` is 0 to 3
+ */
+pub fn jcond(cond: u8, offset: u8) -> Vec<u8> {
+    vec![0xE0 + cond, offset]
+}
+
+/**
+ * CJNE A, #data, offset
+ */
+pub fn cjne_a_data(data: u8, offset: u8) -> Vec<u8> {
+    vec![0xE4, data, offset]
+}
+
+/**
+ * CJNE A, direct, offset
+ */
+pub fn cjne_a_direct(direct: u8, offset: u8) -> Vec<u8> {
+    vec![0xE5, direct, offset]
+}
+
+/* direct addressing */
+
+/**
+ * MOV direct, #data
+ */
+pub fn mov_direct_data(direct: u8, data: u8) -> Vec<u8> {
+    vec![0xC0, direct, data]
+}
+
+/**
+ * MOV direct, A
+ */
+pub fn mov_direct_a(direct: u8) -> Vec<u8> {
+    vec![0xC1, direct]
+}
+
+/**
+ * MOV A, direct
+ */
+pub fn mov_a_direct(direct: u8) -> Vec<u8> {
+    vec![0xC2, direct]
+}
+
+/* bit instructions */
+
+/**
+ * SETB bit
+ */
+pub fn setb(bit: u8) -> Vec<u8> {
+    vec![0xC3, bit]
+}
+
+/**
+ * CLR bit
+ */
+pub fn clr(bit: u8) -> Vec<u8> {
+    vec![0xC4, bit]
+}
+
+/**
+ * CPL bit
+ */
+pub fn cpl(bit: u8) -> Vec<u8> {
+    vec![0xC8, bit]
+}
+
+/**
+ * JB bit, offset
+ */
+pub fn jb(bit: u8, offset: u8) -> Vec<u8> {
+    vec![0xC5, bit, offset]
+}
+
+/**
+ * JNB bit, offset
+ */
+pub fn jnb(bit: u8, offset: u8) -> Vec<u8> {
+    vec![0xC6, bit, offset]
+}
+
+/* interrupts */
+
+/**
+ * INT 21H
+ */
+pub fn int21() -> Vec<u8> {
+    vec![0x21]
+}
+
+/**
+ * RETI
+ */
+pub fn reti() -> Vec<u8> {
+    vec![0xC7]
+}
+
+/* calls and the stack */
+
+/**
+ * AJMP addr11
+ * Bits 8 to 10 of the address go in the opcode
+ */
+pub fn ajmp(addr: u16) -> Vec<u8> {
+    vec![0xD0 | ((addr >> 8) & 0x07) as u8, (addr & 0xff) as u8]
+}
+
+/**
+ * ACALL addr11
+ */
+pub fn acall(addr: u16) -> Vec<u8> {
+    vec![0xD8 | ((addr >> 8) & 0x07) as u8, (addr & 0xff) as u8]
+}
+
+/**
+ * LCALL addr
+ */
+pub fn lcall(addr: u16) -> Vec<u8> {
+    vec![0xC9, (addr >> 8) as u8, (addr & 0xff) as u8]
+}
+
+/**
+ * RET
+ */
+pub fn ret() -> Vec<u8> {
+    vec![0xCA]
+}
+
+/**
+ * PUSH direct
+ */
+pub fn push(direct: u8) -> Vec<u8> {
+    vec![0xCB, direct]
+}
+
+/**
+ * POP direct
+ */
+pub fn pop(direct: u8) -> Vec<u8> {
+    vec![0xCC, direct]
+}
+
+/* external RAM */
+
+/**
+ * MOV DPTR, #data16
+ */
+pub fn mov_dptr_data(data: u16) -> Vec<u8> {
+    vec![0xCD, (data >> 8) as u8, (data & 0xff) as u8]
+}
+
+/**
+ * MOVX A, @DPTR
+ */
+pub fn movx_a_dptr() -> Vec<u8> {
+    vec![0xCE]
+}
+
+/**
+ * MOVX @DPTR, A
+ */
+pub fn movx_dptr_a() -> Vec<u8> {
+    vec![0xCF]
 }
@@ -0,0 +1,320 @@
+use super::engine;
+use super::lines::LineTable;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+
+/* jb, jnb, djnz, jz, jnz, jc, jnc, cjne */
+pub fn is_conditional(opcode: u8) -> bool {
+    matches!(opcode, 0xC5 | 0xC6 | 0xB8..=0xBF | 0xE0..=0xE5)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/**
+ * What a run executed
+ * `hits` counts executions per instruction address, `branches`
+ * the outcomes of every conditional jump that ran.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    pub hits: HashMap<u16, u64>,
+    pub branches: HashMap<u16, Branch>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            hits: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    /**
+     * Counts the instruction at `pc`
+     * `next` is where execution continued, None if it stopped there.
+     */
+    pub fn record(&mut self, pc: u16, opcode: u8, next: Option<u16>) {
+        *self.hits.entry(pc).or_insert(0) += 1;
+
+        if let (true, Some(next)) = (is_conditional(opcode), next) {
+            let fallthrough = pc.wrapping_add(engine::length(opcode).unwrap_or(1));
+            let branch = self.branches.entry(pc).or_default();
+
+            if next != fallthrough {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.hits.clear();
+        self.branches.clear();
+    }
+}
+
+/**
+ * Coverage of one source line
+ * `branches` is None for lines without a conditional jump.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub hits: u64,
+    pub branches: Option<Branch>,
+}
+
+/**
+ * Coverage of one source file, by line
+ */
+#[derive(Debug, Clone, Default)]
+pub struct FileCoverage {
+    pub name: String,
+    pub lines: BTreeMap<usize, LineCoverage>,
+}
+
+impl FileCoverage {
+    pub fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|l| l.hits > 0).count()
+    }
+
+    /* each conditional jump has two outcomes */
+    pub fn branches_total(&self) -> usize {
+        self.lines.values().filter(|l| l.branches.is_some()).count() * 2
+    }
+
+    pub fn branches_hit(&self) -> usize {
+        self.lines
+            .values()
+            .filter_map(|l| l.branches)
+            .map(|b| (b.taken > 0) as usize + (b.not_taken > 0) as usize)
+            .sum()
+    }
+}
+
+fn percent(hit: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        hit as f64 * 100.0 / total as f64
+    }
+}
+
+/**
+ * Coverage mapped back to source lines
+ * Every instruction in the line table counts, executed or not.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub files: Vec<FileCoverage>,
+}
+
+impl Report {
+    /**
+     * `rom` is the assembled code, used to find the conditional jumps
+     */
+    pub fn new(coverage: &Coverage, lines: &LineTable, rom: &[u8]) -> Report {
+        let mut files: Vec<FileCoverage> = lines
+            .files
+            .iter()
+            .map(|name| FileCoverage {
+                name: name.clone(),
+                lines: BTreeMap::new(),
+            })
+            .collect();
+
+        for entry in &lines.entries {
+            let line = files[entry.file].lines.entry(entry.line).or_default();
+            line.hits += coverage.hits.get(&entry.start).copied().unwrap_or(0);
+
+            let opcode = rom.get(entry.start as usize).copied().unwrap_or(0);
+            if is_conditional(opcode) {
+                let seen = coverage
+                    .branches
+                    .get(&entry.start)
+                    .copied()
+                    .unwrap_or_default();
+                let branch = line.branches.get_or_insert_with(Branch::default);
+
+                branch.taken += seen.taken;
+                branch.not_taken += seen.not_taken;
+            }
+        }
+
+        Report { files }
+    }
+
+    /**
+     * Per file percentages, e.g.
+     * test.plasm  lines 12/14 (85.7%)  branches 3/4 (75.0%)
+     */
+    pub fn write_summary(&self, out: &mut dyn Write) -> io::Result<()> {
+        for file in &self.files {
+            writeln!(
+                out,
+                "{}  lines {}/{} ({:.1}%)  branches {}/{} ({:.1}%)",
+                file.name,
+                file.lines_hit(),
+                file.lines.len(),
+                percent(file.lines_hit(), file.lines.len()),
+                file.branches_hit(),
+                file.branches_total(),
+                percent(file.branches_hit(), file.branches_total())
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Source with execution counts, gcov style:
+     *        3:   12:mov A, #1
+     *    #####:   13:lcall error
+     *        -:   14:loop:
+     * Conditional jumps get their outcomes appended.
+     */
+    pub fn write_annotated(&self, out: &mut dyn Write) -> io::Result<()> {
+        for file in &self.files {
+            let source = fs::read_to_string(&file.name).unwrap_or_default();
+            writeln!(out, "        -:    0:Source:{}", file.name)?;
+
+            for (index, text) in source.lines().enumerate() {
+                let count = match file.lines.get(&(index + 1)) {
+                    Some(line) if line.hits > 0 => line.hits.to_string(),
+                    Some(_) => "#####".to_string(),
+                    None => "-".to_string(),
+                };
+
+                let mut row = format!("{:>9}:{:>5}:{}", count, index + 1, text);
+                if let Some(branch) = file.lines.get(&(index + 1)).and_then(|l| l.branches) {
+                    row.push_str(&format!(
+                        "  [taken {}, not taken {}]",
+                        branch.taken, branch.not_taken
+                    ));
+                }
+
+                writeln!(out, "{}", row)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * lcov tracefile, as read by genhtml and most CI tools
+     */
+    pub fn write_lcov(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+
+        for file in &self.files {
+            writeln!(out, "SF:{}", file.name)?;
+
+            for (line, cov) in &file.lines {
+                if let Some(branch) = cov.branches {
+                    /* `-` means the jump never ran */
+                    let count = |n: u64| match cov.hits {
+                        0 => "-".to_string(),
+                        _ => n.to_string(),
+                    };
+
+                    writeln!(out, "BRDA:{},0,0,{}", line, count(branch.taken))?;
+                    writeln!(out, "BRDA:{},0,1,{}", line, count(branch.not_taken))?;
+                }
+            }
+            writeln!(out, "BRF:{}", file.branches_total())?;
+            writeln!(out, "BRH:{}", file.branches_hit())?;
+
+            for (line, cov) in &file.lines {
+                writeln!(out, "DA:{},{}", line, cov.hits)?;
+            }
+            writeln!(out, "LF:{}", file.lines.len())?;
+            writeln!(out, "LH:{}", file.lines_hit())?;
+
+            writeln!(out, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::AsmContext;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+    use crate::emulator::Emulator;
+
+    /* line 4 never runs, the jb on line 3 is taken and the one on line 6 is not */
+    const PROGRAM: &str =
+        "mov A, #1\nsetb 20H.0\njb 20H.0, skip\nmov B, #2\nskip:\njb 20H.1, done\ndone:\nend\n";
+
+    fn report(file: &str) -> Report {
+        let mut lc = LexerContext::new(PROGRAM.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.file = file.to_string();
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx.coverage = Some(Coverage::new());
+        ctx.run();
+
+        Report::new(ctx.coverage.as_ref().unwrap(), &pc.lines, &ctx.em.rom)
+    }
+
+    fn text(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn summary_counts_lines_and_branch_outcomes() {
+        let report = report("test.plasm");
+
+        assert_eq!(
+            text(|out| report.write_summary(out)),
+            "test.plasm  lines 5/6 (83.3%)  branches 2/4 (50.0%)\n"
+        );
+    }
+
+    #[test]
+    fn lcov_lists_every_line_and_branch() {
+        let report = report("test.plasm");
+
+        assert_eq!(
+            text(|out| report.write_lcov(out)),
+            "TN:\nSF:test.plasm\n\
+             BRDA:3,0,0,1\nBRDA:3,0,1,0\nBRDA:6,0,0,0\nBRDA:6,0,1,1\nBRF:4\nBRH:2\n\
+             DA:1,1\nDA:2,1\nDA:3,1\nDA:4,0\nDA:6,1\nDA:8,1\nLF:6\nLH:5\n\
+             end_of_record\n"
+        );
+    }
+
+    #[test]
+    fn annotated_source_marks_missed_lines() {
+        let path =
+            std::env::temp_dir().join(format!("plasm-coverage-{}.plasm", std::process::id()));
+        fs::write(&path, PROGRAM).unwrap();
+        let name = path.to_string_lossy().into_owned();
+
+        let annotated = text(|out| report(&name).write_annotated(out));
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = annotated.lines().collect();
+        assert_eq!(lines[0], format!("        -:    0:Source:{}", name));
+        assert_eq!(
+            lines[3],
+            "        1:    3:jb 20H.0, skip  [taken 1, not taken 0]"
+        );
+        assert_eq!(lines[4], "    #####:    4:mov B, #2");
+        assert_eq!(lines[5], "        -:    5:skip:");
+    }
+}
@@ -0,0 +1,252 @@
+use super::engine::{length, page_target, rel_target};
+use super::symbols::{self, SymbolTable};
+use crate::sfr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+/**
+ * Formats a number the way the assembler reads it back
+ * A leading 0 keeps it from looking like a label
+ */
+pub fn hex8(value: u8) -> String {
+    let res = format!("{:02X}H", value);
+    if res.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", res)
+    } else {
+        res
+    }
+}
+
+pub fn hex16(value: u16) -> String {
+    let res = format!("{:04X}H", value);
+    if res.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", res)
+    } else {
+        res
+    }
+}
+
+/* anl, orl and xrl share an encoding */
+const LOGIC: [&str; 3] = ["anl", "orl", "xrl"];
+
+fn direct(addr: u8) -> String {
+    match sfr::name_of(addr) {
+        Some(name) if addr >= 0x80 => name.to_string(),
+        _ => hex8(addr),
+    }
+}
+
+fn bit(bit: u8) -> String {
+    if let Some(name) = sfr::bit_name_of(bit) {
+        return name.to_string();
+    }
+
+    let (addr, index) = sfr::bit_location(bit);
+    format!("{}.{}", direct(addr), index)
+}
+
+fn code_addr(symbols: &SymbolTable, addr: u16) -> String {
+    match symbols::name_at(symbols, addr) {
+        Some(name) => name.to_string(),
+        None => hex16(addr),
+    }
+}
+
+/**
+ * Disassembles the instruction at `addr`
+ * Undefined opcodes and instructions running off the end
+ * of `rom` come out as `db` bytes.
+ */
+pub fn disassemble(rom: &[u8], addr: u16, symbols: &SymbolTable) -> Disassembly {
+    disassemble_bytes(rom.get(addr as usize..).unwrap_or(&[]), addr, symbols)
+}
+
+/**
+ * Disassembles the instruction at the start of `code`,
+ * which was loaded at `addr`
+ */
+pub fn disassemble_bytes(code: &[u8], addr: u16, symbols: &SymbolTable) -> Disassembly {
+    let opcode = code.first().copied().unwrap_or(0);
+
+    let len = match length(opcode) {
+        Some(len) if len as usize <= code.len() => len as usize,
+        _ => {
+            return Disassembly {
+                addr,
+                bytes: code.first().map(|b| vec![*b]).unwrap_or_default(),
+                text: format!("db {}", hex8(opcode)),
+            };
+        }
+    };
+
+    let bytes = code[..len].to_vec();
+    let op = |i: usize| bytes[i + 1];
+    let op16 = || (bytes[1] as u16) << 8 | bytes[2] as u16;
+    let next = addr.wrapping_add(len as u16);
+
+    let text = match opcode {
+        0x00 => "end".to_string(),
+        0x21 => "int 21H".to_string(),
+        0x28 => format!("sjmp {}", code_addr(symbols, rel_target(next, op(0)))),
+        0x29 => format!("ljmp {}", code_addr(symbols, op16())),
+
+        0x50 => format!("add A, #{}", hex8(op(0))),
+        0x51 => "add A, B".to_string(),
+        0x52..=0x59 => format!("add A, R{}", opcode - 0x52),
+        0x5A => "add A, A".to_string(),
+        0x5B => format!("addc A, #{}", hex8(op(0))),
+        0x5C..=0x63 => format!("addc A, R{}", opcode - 0x5C),
+        0x64 => format!("subb A, #{}", hex8(op(0))),
+        0x65..=0x6C => format!("subb A, R{}", opcode - 0x65),
+
+        0x6D => "inc A".to_string(),
+        0x6E => "dec A".to_string(),
+        0x6F => "inc DPTR".to_string(),
+        0x70 => "mul AB".to_string(),
+        0x71 => "div AB".to_string(),
+        0x72 => "swap A".to_string(),
+        0xA8..=0xAF => format!("inc R{}", opcode - 0xA8),
+        0xB0..=0xB7 => format!("dec R{}", opcode - 0xB0),
+        0xB8..=0xBF => format!(
+            "djnz R{}, {}",
+            opcode - 0xB8,
+            code_addr(symbols, rel_target(next, op(0)))
+        ),
+
+        0x73 => format!("mov R{}, R{}", op(0) >> 4, op(0) & 0x07),
+        0x74..=0x7B => format!("mov R{}, #{}", opcode - 0x74, hex8(op(0))),
+        0x84..=0x8B => format!("mov A, R{}", opcode - 0x84),
+        0x8C => format!("mov A, #{}", hex8(op(0))),
+        0x8D..=0x94 => format!("mov R{}, A", opcode - 0x8D),
+        0x95..=0x9C => format!("mov B, R{}", opcode - 0x95),
+        0x9D => format!("mov B, #{}", hex8(op(0))),
+        0x9E..=0xA5 => format!("mov R{}, B", opcode - 0x9E),
+        0xA6 => "mov A, B".to_string(),
+        0xA7 => "mov B, A".to_string(),
+
+        0xC0 => format!("mov {}, #{}", direct(op(0)), hex8(op(1))),
+        0xC1 => format!("mov {}, A", direct(op(0))),
+        0xC2 => format!("mov A, {}", direct(op(0))),
+        0xC3 => format!("setb {}", bit(op(0))),
+        0xC4 => format!("clr {}", bit(op(0))),
+        0xC5 => format!(
+            "jb {}, {}",
+            bit(op(0)),
+            code_addr(symbols, rel_target(next, op(1)))
+        ),
+        0xC6 => format!(
+            "jnb {}, {}",
+            bit(op(0)),
+            code_addr(symbols, rel_target(next, op(1)))
+        ),
+        0xC7 => "reti".to_string(),
+        0xC8 => format!("cpl {}", bit(op(0))),
+
+        0xC9 => format!("lcall {}", code_addr(symbols, op16())),
+        0xCA => "ret".to_string(),
+        0xCB => format!("push {}", direct(op(0))),
+        0xCC => format!("pop {}", direct(op(0))),
+
+        0xCD => format!("mov DPTR, #{}", hex16(op16())),
+        0xCE => "movx A, @DPTR".to_string(),
+        0xCF => "movx @DPTR, A".to_string(),
+
+        0xD0..=0xD7 => format!(
+            "ajmp {}",
+            code_addr(symbols, page_target(next, opcode, op(0)))
+        ),
+        0xD8..=0xDF => format!(
+            "acall {}",
+            code_addr(symbols, page_target(next, opcode, op(0)))
+        ),
+
+        0xE0..=0xE3 => format!(
+            "{} {}",
+            ["jz", "jnz", "jc", "jnc"][(opcode - 0xE0) as usize],
+            code_addr(symbols, rel_target(next, op(0)))
+        ),
+        0xE4 => format!(
+            "cjne A, #{}, {}",
+            hex8(op(0)),
+            code_addr(symbols, rel_target(next, op(1)))
+        ),
+        0xE5 => format!(
+            "cjne A, {}, {}",
+            direct(op(0)),
+            code_addr(symbols, rel_target(next, op(1)))
+        ),
+        0xE6 | 0xE7 => format!("mov A, @R{}", opcode - 0xE6),
+        0xE8 | 0xE9 => format!("mov @R{}, A", opcode - 0xE8),
+        0xEA..=0xEC => format!("{} A, #{}", LOGIC[(opcode - 0xEA) as usize], hex8(op(0))),
+        0xED..=0xEF => format!("{} A, {}", LOGIC[(opcode - 0xED) as usize], direct(op(0))),
+        0xF0 => "rlc A".to_string(),
+        0xF1 => "rrc A".to_string(),
+        0xF2 => "cpl A".to_string(),
+        0xF3 => "clr A".to_string(),
+        0xF4 => "nop".to_string(),
+
+        _ => format!("db {}", hex8(opcode)),
+    };
+
+    Disassembly { addr, bytes, text }
+}
+
+/**
+ * Disassembles `count` instructions starting at `addr`
+ */
+pub fn disassemble_range(
+    rom: &[u8],
+    addr: u16,
+    count: usize,
+    symbols: &SymbolTable,
+) -> Vec<Disassembly> {
+    let mut res = Vec::new();
+    let mut addr = addr as usize;
+
+    while res.len() < count && addr < rom.len() {
+        let ins = disassemble(rom, addr as u16, symbols);
+        addr += ins.bytes.len().max(1);
+        res.push(ins);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::codegen;
+
+    #[test]
+    fn mov_rn_rm_reads_back_as_written() {
+        let symbols = SymbolTable::new();
+        let rom = codegen::mov_rn_rn(1, 2);
+
+        let dis = disassemble(&rom, 0, &symbols);
+        assert_eq!(dis.text, "mov R1, R2");
+        assert_eq!(dis.bytes.len(), 2);
+    }
+
+    #[test]
+    fn mov_a_rn_is_not_mov_rn_rm() {
+        let symbols = SymbolTable::new();
+        let rom = codegen::mov_a_rn(2);
+
+        assert_eq!(disassemble(&rom, 0, &symbols).text, "mov A, R2");
+    }
+
+    #[test]
+    fn relative_targets_wrap_at_the_top_of_code_memory() {
+        let symbols = SymbolTable::new();
+        let mut rom = vec![0; 0x10000];
+        rom[0xFFFE] = 0x28;
+        rom[0xFFFF] = 0x02;
+
+        assert_eq!(disassemble(&rom, 0xFFFE, &symbols).text, "sjmp 0002H");
+    }
+}
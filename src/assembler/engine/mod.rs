@@ -1,197 +1,1286 @@
+use super::coverage::Coverage;
+use super::history::History;
+use super::lines::LineTable;
+use super::profile::Profiler;
+use super::trace::Tracer;
+use super::watch::{WatchHit, Watchpoint};
+use crate::emulator::*;
+use crate::interrupt;
 use crate::psw::PswFlag;
-use crate::{emulator::*, psw::Psw};
-use crate::{ram, regs};
+use crate::ram;
+use crate::sfr;
+use crate::syscall::{self, Outcome};
+use std::collections::{BTreeMap, HashSet};
 
 pub struct AsmContext {
     pub em: Emulator,
+    pub breakpoints: HashSet<u16>,
+    /* by id, so they can be listed and deleted */
+    pub watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watch: usize,
+    /* records every executed instruction when set */
+    pub tracer: Option<Tracer>,
+    /* undo log for stepping backwards, when set */
+    pub history: Option<History>,
+    /* executed instructions and branch outcomes, when set */
+    pub coverage: Option<Coverage>,
+    /* cycles per instruction and call stack, when set */
+    pub profiler: Option<Profiler>,
+    /* source positions of the loaded program, may be empty */
+    pub lines: LineTable,
 }
 
+/**
+ * Why execution stopped
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /* opcode 0x00, emitted for `end` */
+    Halted,
+    /* int 21H exit service */
+    Exited(u8),
+    Breakpoint(u16),
+    /* stops after the instruction that triggered it */
+    Watchpoint(WatchHit),
+    IllegalOpcode { pc: u16, opcode: u8 },
+    PcOutOfRom(u16),
+    CycleLimit,
+    /* stepping backwards ran out of history */
+    HistoryStart,
+    StackOverflow { pc: u16 },
+    /* @R0 or @R1 pointing past the end of internal RAM */
+    BadIndirect { pc: u16, addr: u8 },
+    UnknownSyscall { pc: u16, service: u8 },
+}
+
+/**
+ * One executed instruction
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub pc: u16,
+    pub opcode: u8,
+    pub operands: Vec<u8>,
+    pub cycles: u32,
+}
+
+/**
+ * Target of a relative jump
+ * `next` is the address of the instruction after the jump
+ */
+pub fn rel_target(next: u16, rel: u8) -> u16 {
+    next.wrapping_add(rel as i8 as u16)
+}
+
+/**
+ * Length in bytes of the instruction starting with `opcode`
+ * None for opcodes that are not defined
+ */
+pub fn length(opcode: u8) -> Option<u16> {
+    match opcode {
+        0x00 | 0x21 => Some(1),
+        /* sjmp, ljmp */
+        0x28 => Some(2),
+        0x29 => Some(3),
+        /* add, addc, subb */
+        0x50 => Some(2),
+        0x51..=0x5A => Some(1),
+        0x5B => Some(2),
+        0x5C..=0x63 => Some(1),
+        0x64 => Some(2),
+        0x65..=0x6C => Some(1),
+        /* inc, dec, mul, div, swap */
+        0x6D..=0x72 => Some(1),
+        /* mov */
+        0x73 => Some(2),
+        0x74..=0x7B => Some(2),
+        0x84..=0x8B => Some(1),
+        0x8C => Some(2),
+        0x8D..=0x9C => Some(1),
+        0x9D => Some(2),
+        0x9E..=0xA7 => Some(1),
+        /* direct addressing and bits */
+        0xC0 => Some(3),
+        0xC1..=0xC4 => Some(2),
+        0xC5 | 0xC6 => Some(3),
+        0xC7 => Some(1),
+        0xC8 => Some(2),
+        /* calls and the stack */
+        0xC9 => Some(3),
+        0xCA => Some(1),
+        0xCB | 0xCC => Some(2),
+        /* external RAM */
+        0xCD => Some(3),
+        0xCE | 0xCF => Some(1),
+        /* ajmp, acall */
+        0xD0..=0xDF => Some(2),
+        /* inc Rn, dec Rn, djnz */
+        0xA8..=0xB7 => Some(1),
+        0xB8..=0xBF => Some(2),
+        /* jz, jnz, jc, jnc, cjne */
+        0xE0..=0xE3 => Some(2),
+        0xE4 | 0xE5 => Some(3),
+        /* mov A, @Ri and mov @Ri, A */
+        0xE6..=0xE9 => Some(1),
+        /* anl, orl, xrl */
+        0xEA..=0xEF => Some(2),
+        /* rlc, rrc, cpl A, clr A, nop */
+        0xF0..=0xF4 => Some(1),
+        _ => None,
+    }
+}
+
+/**
+ * Machine cycles taken by each instruction
+ * These follow the 8051 instruction each opcode corresponds to,
+ * e.g. `mov B, #data` is `mov direct, #data` and takes 2 cycles.
+ */
+pub fn cycles(opcode: u8) -> u32 {
+    match opcode {
+        /* int 21H behaves like an lcall into the host */
+        0x21 => 2,
+        /* sjmp, ljmp */
+        0x28 | 0x29 => 2,
+        /* mov Rn, Rm is mov Rn, direct */
+        0x73 => 2,
+        /* mov B, Rn; mov B, #data; mov Rn, B */
+        0x95..=0xA5 => 2,
+        /* mov direct, #data */
+        0xC0 => 2,
+        /* jb, jnb, reti */
+        0xC5..=0xC7 => 2,
+        /* lcall, ret, push, pop, mov DPTR, movx */
+        0xC9..=0xCF => 2,
+        /* ajmp, acall */
+        0xD0..=0xDF => 2,
+        /* inc DPTR */
+        0x6F => 2,
+        /* mul AB, div AB */
+        0x70 | 0x71 => 4,
+        /* djnz, jz, jnz, jc, jnc, cjne */
+        0xB8..=0xBF | 0xE0..=0xE5 => 2,
+        _ => 1,
+    }
+}
+
+/**
+ * Whether `opcode` is lcall or acall
+ */
+pub fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD8..=0xDF)
+}
+
+/**
+ * Target of an ajmp or acall
+ * The low 11 bits come from the instruction, the rest from `next`.
+ */
+pub fn page_target(next: u16, opcode: u8, low: u8) -> u16 {
+    (next & 0xF800) | ((opcode & 0x07) as u16) << 8 | low as u16
+}
+
+/* machine cycles taken to vector to an interrupt, an lcall */
+pub const INTERRUPT_CYCLES: u32 = 2;
+
 impl AsmContext {
     pub fn new(em: Emulator) -> AsmContext {
-        AsmContext { em }
+        AsmContext {
+            em,
+            breakpoints: HashSet::new(),
+            watchpoints: BTreeMap::new(),
+            next_watch: 1,
+            tracer: None,
+            history: None,
+            coverage: None,
+            profiler: None,
+            lines: LineTable::new(),
+        }
     }
 
-    pub fn run(&mut self) {
-        let mut pc: &mut u16 = &mut self.em.reg.pc.value;
+    /**
+     * `0x0012, test.plasm:14` style description of a code address
+     */
+    pub fn locate(&self, addr: u16) -> String {
+        match self.lines.lookup(addr) {
+            Some(location) => format!("{:#06x}, {}", addr, location),
+            None => format!("{:#06x}", addr),
+        }
+    }
+
+    /**
+     * One line explanation of why execution stopped
+     */
+    pub fn explain(&self, reason: &StopReason) -> String {
+        match reason {
+            StopReason::Halted => "program halted".to_string(),
+            StopReason::Exited(code) => format!("program exited with code {}", code),
+            StopReason::Breakpoint(addr) => format!("breakpoint at {}", self.locate(*addr)),
+            StopReason::Watchpoint(hit) => {
+                format!("watchpoint {} hit at {}", hit.id, self.locate(hit.step.pc))
+            }
+            StopReason::IllegalOpcode { pc, opcode } => {
+                format!("illegal opcode {:#04x} at {}", opcode, self.locate(*pc))
+            }
+            StopReason::PcOutOfRom(pc) => format!("PC ran off the end of ROM at {:#06x}", pc),
+            StopReason::CycleLimit => format!("cycle limit reached at cycle {}", self.em.cycles),
+            StopReason::HistoryStart => "reached the start of the recorded history".to_string(),
+            StopReason::StackOverflow { pc } => format!("stack overflow at {}", self.locate(*pc)),
+            StopReason::BadIndirect { pc, addr } => {
+                format!(
+                    "indirect address {:#04x} is past the end of RAM at {}",
+                    addr,
+                    self.locate(*pc)
+                )
+            }
+            StopReason::UnknownSyscall { pc, service } => {
+                format!(
+                    "unknown int 21H service {:#04x} at {}",
+                    service,
+                    self.locate(*pc)
+                )
+            }
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /**
+     * Returns the id of the new watchpoint
+     */
+    pub fn add_watchpoint(&mut self, watch: Watchpoint) -> usize {
+        let id = self.next_watch;
+
+        self.watchpoints.insert(id, watch);
+        self.next_watch += 1;
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.remove(&id).is_some()
+    }
+
+    /**
+     * Runs until the program stops
+     */
+    pub fn run(&mut self) -> StopReason {
+        self.run_until(u64::MAX)
+    }
+
+    /**
+     * Runs for at least `cycles` machine cycles
+     */
+    pub fn run_for(&mut self, cycles: u64) -> StopReason {
+        let limit = self.em.cycles + cycles;
+        self.run_until(limit)
+    }
+
+    /**
+     * Runs until `us` microseconds after reset
+     */
+    pub fn run_until_us(&mut self, us: u64) -> StopReason {
+        let limit = self.em.us_to_cycles(us);
+        self.run_until(limit)
+    }
+
+    /**
+     * Runs until the cycle counter reaches `limit`
+     * An instruction is never split, so the counter
+     * can end up a few cycles past the limit.
+     * A breakpoint at the current PC is ignored,
+     * so that running again resumes from it.
+     */
+    pub fn run_until(&mut self, limit: u64) -> StopReason {
+        let mut first = true;
 
         loop {
-            let opcode = self.em.rom[*pc as usize];
-            let bank = (self.em.psw.get_flag(PswFlag::RS1) as usize) << 1
-                | (self.em.psw.get_flag(PswFlag::RS0) as usize);
+            if self.em.cycles >= limit {
+                return StopReason::CycleLimit;
+            }
 
-            let bank = ram::BANK_ADDRESSES[bank];
+            let pc = self.em.reg.pc.get();
+            if !first && self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+            first = false;
 
-            // println!("opcode: {:x}", opcode);
+            if let Err(reason) = self.step() {
+                return reason;
+            }
+        }
+    }
 
-            match opcode {
-                0x00 => break,
+    /**
+     * Undoes the last executed instruction
+     * Returns the step that was undone, None without history
+     */
+    pub fn step_back(&mut self) -> Option<Step> {
+        self.history.as_mut()?.undo(&mut self.em)?;
 
-                /* mov instruction */
-                0x74..=0x7B => {
-                    /*
-                       mov Rn, #data
-                    */
-                    let reg = opcode - 0x74;
-                    let data = self.em.rom[(*pc + 1) as usize];
+        let (opcode, operands) = self.fetch().ok()?;
+        Some(Step {
+            pc: self.em.reg.pc.get(),
+            opcode,
+            operands,
+            cycles: cycles(opcode),
+        })
+    }
 
-                    let addr = bank + reg as usize;
-                    // println!("mov R{:x}, #{:x}, {:x}", reg, data, addr);
+    /**
+     * Steps backwards until reaching a breakpoint or undoing
+     * an instruction that triggers a watchpoint
+     * Like `run`, a breakpoint at the current PC is ignored.
+     */
+    pub fn reverse_continue(&mut self) -> StopReason {
+        loop {
+            let after: Vec<u8> = self
+                .watchpoints
+                .values()
+                .map(|w| w.value(&self.em))
+                .collect();
 
-                    self.em.ram.write(addr, data);
-                    *pc += 1;
-                }
+            let frame = match self.history.as_mut().and_then(|h| h.undo(&mut self.em)) {
+                Some(frame) => frame,
+                None => return StopReason::HistoryStart,
+            };
 
-                0x7C..=0x7F => {
-                    /*
-                       mov Rn, Rm
-                    */
-                    let n = (opcode - 0x7C) / 8;
-                    let m = (opcode - 0x7C) % 8;
+            let pc = self.em.reg.pc.get();
 
-                    let addr_n = bank + n as usize;
-                    let addr_m = bank + m as usize;
+            for ((id, watch), now) in self.watchpoints.iter().zip(after) {
+                let before = watch.value(&self.em);
 
-                    let m_data = self.em.ram.read(addr_m);
+                if let Some((old, new)) = watch.hit(&frame.accesses, before, now) {
+                    let (opcode, operands) = self.fetch().unwrap_or((0, Vec::new()));
 
-                    self.em.ram.write(addr_n, m_data);
+                    return StopReason::Watchpoint(WatchHit {
+                        id: *id,
+                        step: Step {
+                            pc,
+                            opcode,
+                            operands,
+                            cycles: cycles(opcode),
+                        },
+                        old,
+                        new,
+                    });
                 }
+            }
 
-                0x84..=0x8B => {
-                    /*
-                       mov A, Rn
-                    */
-                    let n = opcode - 0x84;
-                    let addr_n = bank + n as usize;
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+        }
+    }
 
-                    let n_data = self.em.ram.read(addr_n);
+    /**
+     * Moves to machine cycle `cycle`, backwards or forwards
+     * Backwards, the undo log is used as far as it goes, then the
+     * closest snapshot before `cycle` is restored and run forward.
+     * Breakpoints are ignored, the result is as close to `cycle`
+     * as instruction boundaries allow.
+     */
+    pub fn seek(&mut self, cycle: u64) -> StopReason {
+        if cycle < self.em.cycles {
+            let history = match self.history.as_mut() {
+                Some(history) => history,
+                None => return StopReason::HistoryStart,
+            };
 
-                    self.em.reg.a.set(n_data);
-                }
+            while self.em.cycles > cycle && history.undo(&mut self.em).is_some() {}
 
-                0x8C => {
-                    /*
-                       mov A, #data
-                    */
-                    let data = self.em.rom[(*pc + 1) as usize];
-                    self.em.reg.a.set(data);
+            if self.em.cycles <= cycle {
+                return StopReason::CycleLimit;
+            }
 
-                    *pc += 1;
-                }
+            if !history.rewind_to(&mut self.em, cycle) {
+                return StopReason::HistoryStart;
+            }
+        }
+
+        while self.em.cycles < cycle {
+            if let Err(reason) = self.step() {
+                return reason;
+            }
+        }
 
-                0x8D..=0x94 => {
-                    /*
-                       mov Rn, A
-                    */
-                    let n = opcode - 0x8D;
-                    let addr_n = bank + n as usize;
+        StopReason::CycleLimit
+    }
+
+    /**
+     * Fetches the instruction at PC
+     */
+    pub fn fetch(&self) -> Result<(u8, Vec<u8>), StopReason> {
+        let pc = self.em.reg.pc.get();
+        let rom = &self.em.rom;
 
-                    self.em.ram.write(addr_n, self.em.reg.a.get());
+        let opcode = *rom.get(pc as usize).ok_or(StopReason::PcOutOfRom(pc))?;
+        let len = length(opcode).ok_or(StopReason::IllegalOpcode { pc, opcode })?;
+
+        let end = pc as usize + len as usize;
+        if end > rom.len() {
+            return Err(StopReason::PcOutOfRom(pc));
+        }
+
+        Ok((opcode, rom[pc as usize + 1..end].to_vec()))
+    }
+
+    /**
+     * Executes exactly one instruction
+     * Interrupts raised during it are vectored before returning,
+     * so the next step runs the service routine.
+     */
+    pub fn step(&mut self) -> Result<Step, StopReason> {
+        let pending = self.history.as_mut().map(|h| h.begin(&self.em));
+
+        self.em.accesses.borrow_mut().clear();
+        self.em.recording =
+            pending.is_some() || self.tracer.is_some() || !self.watchpoints.is_empty();
+        let res = self.advance();
+        self.em.recording = false;
+
+        if let (Some(history), Some(pending)) = (&mut self.history, pending) {
+            history.commit(pending, &self.em);
+        }
+
+        res
+    }
+
+    fn advance(&mut self) -> Result<Step, StopReason> {
+        let pc = self.em.reg.pc.get();
+        let (opcode, operands) = self.fetch()?;
+
+        /* values change watchpoints compare against */
+        let before: Vec<u8> = self
+            .watchpoints
+            .values()
+            .map(|w| w.value(&self.em))
+            .collect();
+
+        let traced = match &self.tracer {
+            Some(tracer) if tracer.wants(pc) => Some(Tracer::before(&self.em)),
+            _ => None,
+        };
+
+        let res = self.execute(pc, opcode, &operands);
+
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, opcode, res.as_ref().ok().copied());
+        }
+
+        /* interrupts vectored after the instruction are not part of its entry */
+        if let (Some(tracer), Some(before)) = (&mut self.tracer, traced) {
+            let mut bytes = vec![opcode];
+            bytes.extend_from_slice(&operands);
+            tracer.record(&self.em, before, pc, bytes);
+        }
+
+        let next = res?;
+        self.em.reg.pc.set(next);
+
+        /*
+           The instruction following a reti always executes
+           before another interrupt is serviced.
+        */
+        let taken = cycles(opcode);
+        self.em.tick(taken);
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, opcode, taken, next);
+        }
+
+        if opcode != 0xC7 {
+            match interrupt::poll(&mut self.em) {
+                Ok(Some(_)) => {
+                    self.em.tick(INTERRUPT_CYCLES);
+
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.interrupt(self.em.reg.pc.get(), INTERRUPT_CYCLES);
+                    }
                 }
+                Ok(None) => (),
+                Err(_) => return Err(StopReason::StackOverflow { pc }),
+            }
+        }
+
+        let step = Step {
+            pc,
+            opcode,
+            operands,
+            cycles: taken,
+        };
+
+        for ((id, watch), before) in self.watchpoints.iter().zip(before) {
+            let now = watch.value(&self.em);
+
+            if let Some((old, new)) = watch.hit(&self.em.accesses.borrow(), before, now) {
+                return Err(StopReason::Watchpoint(WatchHit {
+                    id: *id,
+                    step,
+                    old,
+                    new,
+                }));
+            }
+        }
+
+        Ok(step)
+    }
+
+    /**
+     * Adds `data` and the carry to A
+     * CY and AC are the carries out of bits 7 and 3,
+     * OV is set when the signed result doesn't fit.
+     */
+    fn add(&mut self, data: u8, carry: bool) {
+        let a = self.em.reg.a.get();
+        let sum = a as u16 + data as u16 + carry as u16;
+        let res = sum as u8;
+
+        self.em.psw.set_flag(PswFlag::CY, sum > 0xFF);
+        self.em
+            .psw
+            .set_flag(PswFlag::AC, (a & 0x0F) + (data & 0x0F) + carry as u8 > 0x0F);
+        self.em
+            .psw
+            .set_flag(PswFlag::OV, (a ^ res) & (data ^ res) & 0x80 != 0);
+        self.em.reg.a.set(res);
+    }
+
+    /**
+     * Subtracts `data` and the carry from A
+     * CY and AC are the borrows into bits 7 and 3.
+     */
+    fn subb(&mut self, data: u8) {
+        let a = self.em.reg.a.get();
+        let borrow = self.em.psw.get_flag(PswFlag::CY) as u8;
+        let res = a.wrapping_sub(data).wrapping_sub(borrow);
+
+        self.em
+            .psw
+            .set_flag(PswFlag::CY, (a as u16) < data as u16 + borrow as u16);
+        self.em
+            .psw
+            .set_flag(PswFlag::AC, a & 0x0F < (data & 0x0F) + borrow);
+        self.em
+            .psw
+            .set_flag(PswFlag::OV, (a ^ data) & (a ^ res) & 0x80 != 0);
+        self.em.reg.a.set(res);
+    }
+
+    /**
+     * Carries out one fetched instruction
+     * Returns the address of the next instruction
+     */
+    fn execute(&mut self, pc: u16, opcode: u8, operands: &[u8]) -> Result<u16, StopReason> {
+        let bank = (self.em.psw.get_flag(PswFlag::RS1) as usize) << 1
+            | (self.em.psw.get_flag(PswFlag::RS0) as usize);
+
+        let bank = ram::BANK_ADDRESSES[bank];
+
+        /* address of the following instruction, set by jumps */
+        let mut next = pc.wrapping_add(operands.len() as u16 + 1);
+
+        match opcode {
+            0x00 => return Err(StopReason::Halted),
+
+            /* mov instruction */
+            0x74..=0x7B => {
+                /*
+                   mov Rn, #data
+                */
+                let reg = opcode - 0x74;
+                let data = operands[0];
+
+                let addr = bank + reg as usize;
+
+                self.em.write_iram(addr, data);
+            }
+
+            0x73 => {
+                /*
+                   mov Rn, Rm
+                */
+                let n = operands[0] >> 4;
+                let m = operands[0] & 0x07;
+
+                let addr_n = bank + n as usize;
+                let addr_m = bank + m as usize;
+
+                let m_data = self.em.read_iram(addr_m);
+
+                self.em.write_iram(addr_n, m_data);
+            }
+
+            0x84..=0x8B => {
+                /*
+                   mov A, Rn
+                */
+                let n = opcode - 0x84;
+                let addr_n = bank + n as usize;
+
+                let n_data = self.em.read_iram(addr_n);
+
+                self.em.reg.a.set(n_data);
+            }
+
+            0x8C => {
+                /*
+                   mov A, #data
+                */
+                let data = operands[0];
+                self.em.reg.a.set(data);
+            }
+
+            0x8D..=0x94 => {
+                /*
+                   mov Rn, A
+                */
+                let n = opcode - 0x8D;
+                let addr_n = bank + n as usize;
+
+                self.em.write_iram(addr_n, self.em.reg.a.get());
+            }
+
+            0x95..=0x9C => {
+                /*
+                   mov B, Rn
+                */
+                let n = opcode - 0x95;
+                let addr_n = bank + n as usize;
+
+                let data = self.em.read_iram(addr_n);
+                self.em.reg.b.set(data);
+            }
+
+            0x9D => {
+                /*
+                   mov B, #data
+                */
+                let data = operands[0];
+                self.em.reg.b.set(data);
+            }
+
+            0x9E..=0xA5 => {
+                /*
+                   mov Rn, B
+                */
+                let n = opcode - 0x9E;
+                let addr_n = bank + n as usize;
 
-                0x95..=0x9C => {
-                    /*
-                       mov B, Rn
-                    */
-                    let n = opcode - 0x95;
-                    let addr_n = bank + n as usize;
+                self.em.write_iram(addr_n, self.em.reg.b.get());
+            }
+
+            0xA6 => {
+                self.em.reg.a.set(self.em.reg.b.get());
+            }
+
+            0xA7 => {
+                self.em.reg.b.set(self.em.reg.a.get());
+            }
+
+            /* direct addressing */
+            0xC0 => {
+                /*
+                   mov direct, #data
+                */
+                let direct = operands[0];
+                let data = operands[1];
+
+                self.em.write_direct(direct, data);
+            }
+
+            0xC1 => {
+                /*
+                   mov direct, A
+                */
+                let direct = operands[0];
+
+                self.em.write_direct(direct, self.em.reg.a.get());
+            }
+
+            0xC2 => {
+                /*
+                   mov A, direct
+                */
+                let direct = operands[0];
+                let data = self.em.read_direct(direct);
+
+                self.em.reg.a.set(data);
+            }
+
+            /* bit instructions */
+            0xC3 | 0xC4 => {
+                /*
+                   setb bit
+                   clr bit
+                */
+                let bit = operands[0];
+
+                self.em.write_bit(bit, opcode == 0xC3);
+            }
 
-                    let data = self.em.ram.read(addr_n);
-                    self.em.reg.b.set(data);
+            0xC8 => {
+                /*
+                   cpl bit
+                   read-modify-write, so ports toggle their latch
+                */
+                let bit = operands[0];
+                let (address, index) = sfr::bit_location(bit);
+                let value = self.em.read_latch(address) & (1 << index) == 0;
+
+                self.em.write_bit(bit, value);
+            }
+
+            0xC5 | 0xC6 => {
+                /*
+                   jb bit, addr_rel
+                   jnb bit, addr_rel
+                */
+                let bit = operands[0];
+                let addr_rel = operands[1];
+                if self.em.read_bit(bit) == (opcode == 0xC5) {
+                    next = rel_target(next, addr_rel);
                 }
+            }
+
+            0xC7 => {
+                /*
+                   reti
+                */
+                interrupt::reti(&mut self.em);
+                next = self.em.reg.pc.get();
+            }
+
+            /* calls and the stack */
+            0xC9 => {
+                /*
+                   lcall addr
+                   pushes the return address, low byte first
+                */
+                let addr = (operands[0] as u16) << 8 | operands[1] as u16;
+
+                self.em
+                    .push(next as u8)
+                    .and_then(|_| self.em.push((next >> 8) as u8))
+                    .map_err(|_| StopReason::StackOverflow { pc })?;
+                next = addr;
+            }
 
-                0x9D => {
-                    /*
-                       mov B, #data
-                    */
-                    let data = self.em.rom[(*pc + 1) as usize];
-                    self.em.reg.b.set(data);
+            0xCA => {
+                /*
+                   ret
+                */
+                let hi = self.em.pop() as u16;
+                let lo = self.em.pop() as u16;
 
-                    *pc += 1;
+                next = hi << 8 | lo;
+            }
+
+            0xCB => {
+                /*
+                   push direct
+                */
+                let data = self.em.read_direct(operands[0]);
+
+                self.em
+                    .push(data)
+                    .map_err(|_| StopReason::StackOverflow { pc })?;
+            }
+
+            0xCC => {
+                /*
+                   pop direct
+                */
+                let data = self.em.pop();
+                self.em.write_direct(operands[0], data);
+            }
+
+            /* add instruction */
+            0x50 => {
+                /*
+                   add A, #data
+                */
+                self.add(operands[0], false);
+            }
+
+            0x51 => {
+                /*
+                   add A, B
+                */
+                self.add(self.em.reg.b.get(), false);
+            }
+
+            0x52..=0x59 => {
+                /*
+                   add A, Rn
+                */
+                let n = opcode - 0x52;
+                let data = self.em.read_iram(bank + n as usize);
+
+                self.add(data, false);
+            }
+
+            0x5A => {
+                /*
+                   add A, A
+                */
+                self.add(self.em.reg.a.get(), false);
+            }
+
+            0x5B => {
+                /*
+                   addc A, #data
+                */
+                self.add(operands[0], self.em.psw.get_flag(PswFlag::CY));
+            }
+
+            0x5C..=0x63 => {
+                /*
+                   addc A, Rn
+                */
+                let n = opcode - 0x5C;
+                let data = self.em.read_iram(bank + n as usize);
+
+                self.add(data, self.em.psw.get_flag(PswFlag::CY));
+            }
+
+            0x64 => {
+                /*
+                   subb A, #data
+                */
+                self.subb(operands[0]);
+            }
+
+            0x65..=0x6C => {
+                /*
+                   subb A, Rn
+                */
+                let n = opcode - 0x65;
+                let data = self.em.read_iram(bank + n as usize);
+
+                self.subb(data);
+            }
+
+            /* inc and dec, flags are left alone */
+            0x6D | 0x6E => {
+                /*
+                   inc A
+                   dec A
+                */
+                let a = self.em.reg.a.get();
+                let a = if opcode == 0x6D {
+                    a.wrapping_add(1)
+                } else {
+                    a.wrapping_sub(1)
+                };
+
+                self.em.reg.a.set(a);
+            }
+
+            0x6F => {
+                /*
+                   inc DPTR
+                */
+                let dptr = self.em.reg.dptr.get();
+                self.em.reg.dptr.set(dptr.wrapping_add(1));
+            }
+
+            0xA8..=0xB7 => {
+                /*
+                   inc Rn
+                   dec Rn
+                */
+                let n = (opcode - 0xA8) % 8;
+                let addr_n = bank + n as usize;
+                let data = self.em.read_iram(addr_n);
+                let data = if opcode < 0xB0 {
+                    data.wrapping_add(1)
+                } else {
+                    data.wrapping_sub(1)
+                };
+
+                self.em.write_iram(addr_n, data);
+            }
+
+            0xB8..=0xBF => {
+                /*
+                   djnz Rn, addr_rel
+                */
+                let n = opcode - 0xB8;
+                let addr_n = bank + n as usize;
+                let data = self.em.read_iram(addr_n).wrapping_sub(1);
+
+                self.em.write_iram(addr_n, data);
+                if data != 0 {
+                    next = rel_target(next, operands[0]);
                 }
+            }
+
+            /* multiply and divide */
+            0x70 => {
+                /*
+                   mul AB
+                   low byte in A, high byte in B, OV if B is not 0
+                */
+                let res = self.em.reg.a.get() as u16 * self.em.reg.b.get() as u16;
 
-                0x9E..=0xA5 => {
-                    /*
-                       mov Rn, B
-                    */
-                    let n = opcode - 0x9E;
-                    let addr_n = bank + n as usize;
+                self.em.reg.a.set(res as u8);
+                self.em.reg.b.set((res >> 8) as u8);
+                self.em.psw.set_flag(PswFlag::CY, false);
+                self.em.psw.set_flag(PswFlag::OV, res > 0xFF);
+            }
+
+            0x71 => {
+                /*
+                   div AB
+                   quotient in A, remainder in B, OV on division by 0
+                   which leaves A and B as they were
+                */
+                let (a, b) = (self.em.reg.a.get(), self.em.reg.b.get());
 
-                    self.em.ram.write(addr_n, self.em.reg.b.get());
+                if let (Some(quotient), Some(remainder)) = (a.checked_div(b), a.checked_rem(b)) {
+                    self.em.reg.a.set(quotient);
+                    self.em.reg.b.set(remainder);
                 }
+                self.em.psw.set_flag(PswFlag::CY, false);
+                self.em.psw.set_flag(PswFlag::OV, b == 0);
+            }
+
+            /* conditional jumps */
+            0xE0..=0xE3 => {
+                /*
+                   jz addr_rel
+                   jnz addr_rel
+                   jc addr_rel
+                   jnc addr_rel
+                */
+                let taken = match opcode {
+                    0xE0 => self.em.reg.a.get() == 0,
+                    0xE1 => self.em.reg.a.get() != 0,
+                    0xE2 => self.em.psw.get_flag(PswFlag::CY),
+                    _ => !self.em.psw.get_flag(PswFlag::CY),
+                };
 
-                0xA6 => {
-                    self.em.reg.a.set(self.em.reg.b.get());
+                if taken {
+                    next = rel_target(next, operands[0]);
                 }
+            }
+
+            0xE4 | 0xE5 => {
+                /*
+                   cjne A, #data, addr_rel
+                   cjne A, direct, addr_rel
+                   CY is set when A is the smaller
+                */
+                let a = self.em.reg.a.get();
+                let data = match opcode {
+                    0xE4 => operands[0],
+                    _ => self.em.read_direct(operands[0]),
+                };
 
-                0xA7 => {
-                    self.em.reg.b.set(self.em.reg.a.get());
+                self.em.psw.set_flag(PswFlag::CY, a < data);
+                if a != data {
+                    next = rel_target(next, operands[1]);
                 }
+            }
 
-                /* add instruction */
-                0x50 => {
-                    /*
-                       add A, #data
-                    */
-                    let data = self.em.rom[(*pc + 1) as usize];
-                    let d_a = self.em.reg.a.get();
-                    let d_a = d_a + data;
-
-                    self.em.reg.a.set(d_a);
-                    *pc += 1;
+            /* indirect addressing */
+            0xE6 | 0xE7 => {
+                /*
+                   mov A, @Ri
+                */
+                let addr = self.em.read_iram(bank + (opcode - 0xE6) as usize);
+                if addr as usize >= ram::RAM_SIZE {
+                    return Err(StopReason::BadIndirect { pc, addr });
                 }
+                let data = self.em.read_iram(addr as usize);
 
-                0x51 => {
-                    /*
-                       add A, B
-                    */
-                    let d_b = self.em.reg.b.get();
-                    let d_a = self.em.reg.a.get();
+                self.em.reg.a.set(data);
+            }
 
-                    let d_a = d_a + d_b;
-                    self.em.reg.a.set(d_a);
+            0xE8 | 0xE9 => {
+                /*
+                   mov @Ri, A
+                */
+                let addr = self.em.read_iram(bank + (opcode - 0xE8) as usize);
+                if addr as usize >= ram::RAM_SIZE {
+                    return Err(StopReason::BadIndirect { pc, addr });
                 }
 
-                0x52..=0x59 => {
-                    /*
-                       add A, Rn
-                    */
-                    let n = opcode - 0x52;
-                    let addr_n = bank + n as usize;
+                self.em.write_iram(addr as usize, self.em.reg.a.get());
+            }
 
-                    let data = self.em.ram.read(addr_n);
-                    let data = data + self.em.reg.a.get();
+            /* logic */
+            0xEA..=0xEF => {
+                /*
+                   anl A, #data
+                   orl A, #data
+                   xrl A, #data
+                   and the same with a direct address
+                */
+                let data = match opcode {
+                    0xEA..=0xEC => operands[0],
+                    _ => self.em.read_direct(operands[0]),
+                };
+                let a = self.em.reg.a.get();
 
-                    self.em.reg.a.set(data);
-                }
+                let res = match (opcode - 0xEA) % 3 {
+                    0 => a & data,
+                    1 => a | data,
+                    _ => a ^ data,
+                };
+                self.em.reg.a.set(res);
+            }
+
+            0x72 => {
+                /*
+                   swap A
+                */
+                let a = self.em.reg.a.get();
+                self.em.reg.a.set(a.rotate_left(4));
+            }
 
-                0x28 => {
-                    /*
-                       sjmp addr_rel
-                    */
-                    let addr_rel = self.em.rom[(*pc + 1) as usize] as u8;
+            0xF0 | 0xF1 => {
+                /*
+                   rlc A
+                   rrc A
+                   through the carry
+                */
+                let a = self.em.reg.a.get();
+                let carry = self.em.psw.get_flag(PswFlag::CY) as u8;
+
+                let (res, out) = match opcode {
+                    0xF0 => (a << 1 | carry, a & 0x80 != 0),
+                    _ => (a >> 1 | carry << 7, a & 0x01 != 0),
+                };
+                self.em.reg.a.set(res);
+                self.em.psw.set_flag(PswFlag::CY, out);
+            }
+
+            0xF2 => {
+                /*
+                   cpl A
+                */
+                self.em.reg.a.set(!self.em.reg.a.get());
+            }
+
+            0xF3 => {
+                /*
+                   clr A
+                */
+                self.em.reg.a.set(0);
+            }
+
+            0xF4 => {
+                /*
+                   nop
+                */
+            }
 
-                    if addr_rel >> 7 == 1 {
-                        *pc = *pc + 1 - (255 - addr_rel) as u16;
-                    } else {
-                        *pc += addr_rel as u16;
+            0x21 => {
+                /*
+                   int 21H
+                */
+                match syscall::dispatch(&mut self.em) {
+                    Some(Outcome::Continue) => (),
+                    Some(Outcome::Exit(code)) => {
+                        self.em.exit_code = Some(code);
+                        self.em.reg.pc.set(next);
+                        return Err(StopReason::Exited(code));
+                    }
+                    None => {
+                        return Err(StopReason::UnknownSyscall {
+                            pc,
+                            service: self.em.read_rn(0),
+                        });
                     }
-                    continue;
                 }
+            }
 
-                0x29 => {
-                    /*
-                       ljmp addr
-                    */
-                    let addr = (self.em.rom[(*pc + 1) as usize] as u16) << 8
-                        | self.em.rom[(*pc + 2) as usize] as u16;
-                    *pc = addr;
-                    continue;
-                }
+            0x28 => {
+                /*
+                   sjmp addr_rel
+                */
+                let addr_rel = operands[0];
+
+                next = rel_target(next, addr_rel);
+            }
+
+            0x29 => {
+                /*
+                   ljmp addr
+                */
+                let addr = (operands[0] as u16) << 8 | operands[1] as u16;
+                next = addr;
+            }
+
+            0xD0..=0xD7 => {
+                /*
+                   ajmp addr11
+                */
+                next = page_target(next, opcode, operands[0]);
+            }
+
+            0xD8..=0xDF => {
+                /*
+                   acall addr11
+                   pushes the return address like lcall
+                */
+                let addr = page_target(next, opcode, operands[0]);
+
+                self.em
+                    .push(next as u8)
+                    .and_then(|_| self.em.push((next >> 8) as u8))
+                    .map_err(|_| StopReason::StackOverflow { pc })?;
+                next = addr;
+            }
+
+            /* external RAM */
+            0xCD => {
+                /*
+                   mov DPTR, #data16
+                */
+                let data = (operands[0] as u16) << 8 | operands[1] as u16;
+                self.em.reg.dptr.set(data);
+            }
+
+            0xCE => {
+                /*
+                   movx A, @DPTR
+                */
+                let data = self.em.read_xram(self.em.reg.dptr.get());
+                self.em.reg.a.set(data);
+            }
 
-                _ => (),
+            0xCF => {
+                /*
+                   movx @DPTR, A
+                */
+                let addr = self.em.reg.dptr.get();
+                self.em.write_xram(addr, self.em.reg.a.get());
             }
 
-            *pc += 1;
+            _ => return Err(StopReason::IllegalOpcode { pc, opcode }),
         }
+
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::codegen;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+
+    fn context(source: &str) -> AsmContext {
+        let mut lc = LexerContext::new(source.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx
+    }
+
+    #[test]
+    fn instructions_take_their_machine_cycles() {
+        /* 1 + 2 + 2 + 2, halting takes none */
+        let mut ctx = context("mov A, #1\nmov B, #2\nsjmp next\nnext:\nljmp done\ndone:\nend\n");
+
+        ctx.run();
+        assert_eq!(ctx.em.cycles(), 7);
+    }
+
+    #[test]
+    fn run_for_finishes_the_instruction_it_is_in() {
+        let mut ctx = context("loop:\nsjmp loop\nend\n");
+
+        ctx.run_for(5);
+        assert_eq!(ctx.em.cycles(), 6);
+
+        ctx.run_for(4);
+        assert_eq!(ctx.em.cycles(), 10);
+    }
+
+    #[test]
+    fn run_until_us_follows_the_oscillator() {
+        let mut ctx = context("loop:\nsjmp loop\nend\n");
+
+        /* a machine cycle is 1us at 12 MHz */
+        ctx.run_until_us(100);
+        assert_eq!(ctx.em.cycles(), 100);
+        assert_eq!(ctx.em.elapsed_us(), 100.0);
+
+        /* and 2us at 6 MHz */
+        ctx.em.set_oscillator(6_000_000);
+        assert_eq!(ctx.em.us_to_cycles(300), 150);
+        ctx.run_until_us(300);
+        assert_eq!(ctx.em.cycles(), 150);
+        assert_eq!(ctx.em.elapsed_us(), 300.0);
+    }
+
+    #[test]
+    #[cfg(feature = "peripherals")]
+    fn timers_count_the_cycles_instructions_take() {
+        /* timer 0 in mode 1, started by the setb itself */
+        let mut ctx = context("mov TMOD, #01H\nsetb TR0\nmov B, #0\nsjmp next\nnext:\nend\n");
+
+        ctx.run();
+        assert_eq!(ctx.em.cycles(), 7);
+        /* counting starts with the cycle of the setb */
+        assert_eq!(ctx.em.read_direct(crate::sfr::TL0), 5);
+    }
+
+    #[test]
+    #[cfg(feature = "peripherals")]
+    fn vectoring_to_an_interrupt_takes_two_cycles() {
+        /* timer 0 overflows on the cycle of the setb, its handler is the reti at 0BH */
+        let mut ctx = context(
+            "sjmp main\norg 0BH\nreti\nmain:\nmov TMOD, #01H\nmov TL0, #0FFH\nmov TH0, #0FFH\nmov IE, #82H\nsetb TR0\nend\n",
+        );
+
+        ctx.run();
+        /* 2 + 4 * 2 + 1, then 2 to vector and 2 for the reti */
+        assert_eq!(ctx.em.cycles(), 15);
+    }
+
+    #[test]
+    fn mov_rn_rm_copies_between_registers() {
+        let mut ctx = context("mov A, #7\nmov R2, #42\nmov R1, R2\nmov R7, R1\nend\n");
+
+        assert_eq!(ctx.run(), StopReason::Halted);
+        assert_eq!(ctx.em.read_rn(1), 42);
+        assert_eq!(ctx.em.read_rn(7), 42);
+        assert_eq!(ctx.em.read_rn(2), 42);
+        /* it must not decode as mov A, Rn */
+        assert_eq!(ctx.em.reg.a.get(), 7);
+    }
+
+    #[test]
+    fn mov_rn_rm_length_matches_codegen() {
+        for n in 0..8 {
+            for m in 0..8 {
+                let code = codegen::mov_rn_rn(n, m);
+                assert_eq!(length(code[0]), Some(code.len() as u16));
+            }
+        }
+    }
+
+    #[test]
+    fn pc_wraps_at_the_top_of_code_memory() {
+        let mut ctx = context("end\n");
+        ctx.em.rom = vec![0; 0x10000];
+        ctx.em.rom[0xFFFF] = 0xA6;
+        ctx.em.reg.pc.set(0xFFFF);
+
+        let step = ctx.step().unwrap();
+        assert_eq!(step.pc, 0xFFFF);
+        assert_eq!(ctx.em.reg.pc.get(), 0);
     }
 }
@@ -0,0 +1,83 @@
+use std::io::{self, Write};
+
+/* data bytes per record */
+const RECORD_LEN: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+
+fn record(out: &mut dyn Write, kind: u8, addr: u16, data: &[u8]) -> io::Result<()> {
+    let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    bytes.extend_from_slice(data);
+
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+    bytes.push(checksum);
+
+    let text: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    writeln!(out, ":{}", text)
+}
+
+/**
+ * Writes `code`, loaded at address 0, as Intel HEX
+ */
+pub fn write(code: &[u8], out: &mut dyn Write) -> io::Result<()> {
+    for (index, chunk) in code.chunks(RECORD_LEN).enumerate() {
+        record(out, DATA, (index * RECORD_LEN) as u16, chunk)?;
+    }
+
+    record(out, END_OF_FILE, 0, &[])
+}
+
+/**
+ * Reads Intel HEX data records into a code image
+ * Gaps between records are filled with zeros.
+ */
+pub fn parse(text: &str) -> Result<Vec<u8>, String> {
+    let mut code = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let err = |what: &str| format!("line {}: {}", index + 1, what);
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let digits = line.strip_prefix(':').ok_or_else(|| err("missing `:`"))?;
+        if digits.len() % 2 != 0 || !digits.is_ascii() {
+            return Err(err("malformed record"));
+        }
+
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err("malformed record"))?;
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(err("bad record length"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(err("bad checksum"));
+        }
+
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        let data = &bytes[4..bytes.len() - 1];
+
+        match bytes[3] {
+            DATA => {
+                if code.len() < addr + data.len() {
+                    code.resize(addr + data.len(), 0);
+                }
+                code[addr..addr + data.len()].copy_from_slice(data);
+            }
+            END_OF_FILE => break,
+            kind => return Err(err(&format!("unsupported record type {:02X}", kind))),
+        }
+    }
+
+    Ok(code)
+}
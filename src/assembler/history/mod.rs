@@ -0,0 +1,344 @@
+use crate::emulator::{Access, Emulator, Space};
+use crate::snapshot::{CpuState, Snapshot};
+use std::collections::VecDeque;
+
+/* instructions kept in the undo log by default, around 100 bytes each */
+pub const DEFAULT_LIMIT: usize = 100_000;
+
+/* instructions between full snapshots */
+pub const DEFAULT_INTERVAL: u64 = 10_000;
+
+/* full snapshots kept, each holds a copy of external RAM */
+pub const MAX_SNAPSHOTS: usize = 64;
+
+/**
+ * Registers as they were before an instruction
+ * Almost every instruction changes some of them.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub sp: u8,
+    pub psw: u8,
+    pub dptr: u16,
+    pub pc: u16,
+    pub cycles: u64,
+}
+
+impl Registers {
+    fn take(cpu: &CpuState) -> Registers {
+        Registers {
+            a: cpu.a,
+            b: cpu.b,
+            sp: cpu.sp,
+            psw: cpu.psw,
+            dptr: cpu.dptr,
+            pc: cpu.pc,
+            cycles: cpu.cycles,
+        }
+    }
+
+    fn restore(&self, em: &mut Emulator) {
+        em.reg.a.set(self.a);
+        em.reg.b.set(self.b);
+        em.reg.sp.set(self.sp);
+        em.psw.set(self.psw);
+        em.reg.dptr.set(self.dptr);
+        em.reg.pc.set(self.pc);
+        em.cycles = self.cycles;
+
+        #[cfg(feature = "peripherals")]
+        {
+            em.gpio.now = self.cycles;
+            em.gpio.history.retain(|change| change.cycle <= self.cycles);
+        }
+    }
+}
+
+/**
+ * Undo information for one executed instruction, only
+ * what it changed apart from the registers
+ */
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub regs: Registers,
+    /*
+       The whole state before the instruction, kept only when it
+       changed more than the registers: peripherals, interrupt levels
+    */
+    pub cpu: Option<Box<CpuState>>,
+    /* SFR bytes it changed, with their old values */
+    pub sfr: Vec<(u8, u8)>,
+    /* what it read and wrote, in order */
+    pub accesses: Vec<Access>,
+}
+
+/**
+ * State captured before an instruction, turned into a
+ * `Frame` once it has executed
+ */
+pub struct Pending {
+    cpu: CpuState,
+    sfr: Vec<u8>,
+}
+
+/**
+ * Execution history for stepping backwards
+ * Every instruction gets an undo frame, so stepping back is exact
+ * and does not re-execute anything. Every `interval` instructions
+ * a full snapshot is taken as well, letting `AsmContext::seek`
+ * reach points older than the undo log by restoring a snapshot
+ * and running forward from it. Host input is not replayed then,
+ * so programs reading it may take a different path.
+ */
+#[derive(Debug)]
+pub struct History {
+    pub frames: VecDeque<Frame>,
+    pub limit: usize,
+    pub snapshots: VecDeque<Snapshot>,
+    pub interval: u64,
+    since_snapshot: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History::with_limit(DEFAULT_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> History {
+        History {
+            frames: VecDeque::new(),
+            limit,
+            snapshots: VecDeque::new(),
+            interval: DEFAULT_INTERVAL,
+            since_snapshot: 0,
+        }
+    }
+
+    /**
+     * Called before each instruction
+     */
+    pub fn begin(&mut self, em: &Emulator) -> Pending {
+        if self.snapshots.is_empty() || self.since_snapshot >= self.interval {
+            if self.snapshots.len() >= MAX_SNAPSHOTS {
+                self.snapshots.pop_front();
+            }
+
+            self.snapshots.push_back(Snapshot::take(em));
+            self.since_snapshot = 0;
+        }
+
+        Pending {
+            cpu: CpuState::take(em),
+            sfr: em.sfr.memory.clone(),
+        }
+    }
+
+    /**
+     * Called after each instruction, with the accesses it made
+     * Instructions that did not get to run leave no frame.
+     */
+    pub fn commit(&mut self, pending: Pending, em: &Emulator) {
+        let accesses: Vec<Access> = em.accesses.borrow().clone();
+        let wrote = accesses.iter().any(|a| a.write);
+
+        if !wrote && em.cycles == pending.cpu.cycles && em.reg.pc.get() == pending.cpu.pc {
+            return;
+        }
+
+        /* the state after, with the registers from before, is unchanged if equal */
+        let mut after = CpuState::take(em);
+        let regs = Registers::take(&pending.cpu);
+        after.a = regs.a;
+        after.b = regs.b;
+        after.sp = regs.sp;
+        after.psw = regs.psw;
+        after.dptr = regs.dptr;
+        after.pc = regs.pc;
+        after.cycles = regs.cycles;
+
+        let cpu = (after != pending.cpu).then(|| Box::new(pending.cpu));
+
+        let sfr = pending
+            .sfr
+            .iter()
+            .zip(&em.sfr.memory)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (old, _))| (i as u8, *old))
+            .collect();
+
+        if self.frames.len() >= self.limit {
+            self.frames.pop_front();
+        }
+
+        self.frames.push_back(Frame {
+            regs,
+            cpu,
+            sfr,
+            accesses,
+        });
+        self.since_snapshot += 1;
+    }
+
+    /**
+     * Undoes the last instruction, returning its frame
+     */
+    pub fn undo(&mut self, em: &mut Emulator) -> Option<Frame> {
+        let frame = self.frames.pop_back()?;
+
+        /*
+           SFR writes are covered by the SFR bytes
+           and the registers in the CPU state
+        */
+        for access in frame.accesses.iter().rev().filter(|a| a.write) {
+            if access.space != Space::Sfr {
+                em.memory_mut(access.space)
+                    .write(access.addr as usize, access.old);
+            }
+        }
+
+        for (i, old) in &frame.sfr {
+            em.sfr.memory[*i as usize] = *old;
+        }
+
+        match &frame.cpu {
+            Some(cpu) => cpu.restore(em),
+            None => frame.regs.restore(em),
+        }
+        self.since_snapshot = self.since_snapshot.saturating_sub(1);
+
+        /* snapshots from the undone future would be taken for a different past */
+        while matches!(self.snapshots.back(), Some(s) if s.cpu.cycles > em.cycles) {
+            self.snapshots.pop_back();
+        }
+
+        Some(frame)
+    }
+
+    /**
+     * Cycle count of the oldest state that can be reached
+     */
+    pub fn oldest(&self) -> Option<u64> {
+        let frame = self.frames.front().map(|f| f.regs.cycles);
+        let snapshot = self.snapshots.front().map(|s| s.cpu.cycles);
+
+        match (frame, snapshot) {
+            (Some(f), Some(s)) => Some(f.min(s)),
+            (f, s) => f.or(s),
+        }
+    }
+
+    /**
+     * Restores the latest snapshot taken at or before `cycle`
+     * The undo log and later snapshots no longer apply and are dropped.
+     */
+    pub fn rewind_to(&mut self, em: &mut Emulator, cycle: u64) -> bool {
+        let at = match self.snapshots.iter().rposition(|s| s.cpu.cycles <= cycle) {
+            Some(at) => at,
+            None => return false,
+        };
+
+        self.snapshots.truncate(at + 1);
+        self.snapshots[at].restore(em);
+        self.frames.clear();
+        self.since_snapshot = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::{AsmContext, StopReason};
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+
+    /* timer 0 interrupts push onto the stack while the loop runs */
+    const PROGRAM: &str = "ljmp main\norg 0BH\nadd A, #1\nreti\nmain:\nmov TMOD, #02H\nmov TH0, #0F0H\nmov IE, #82H\nsetb TR0\nloop:\nmov 30H, A\npush 30H\npop 31H\nlcall copy\nsjmp loop\ncopy:\nmov B, A\nret\nend\n";
+
+    fn context(history: History) -> AsmContext {
+        let mut lc = LexerContext::new(PROGRAM.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx.history = Some(history);
+        ctx
+    }
+
+    fn state(ctx: &AsmContext) -> (CpuState, Vec<u8>, Vec<u8>) {
+        (
+            CpuState::take(&ctx.em),
+            ctx.em.ram.memory.clone(),
+            ctx.em.sfr.memory.clone(),
+        )
+    }
+
+    #[test]
+    #[cfg(feature = "peripherals")]
+    fn undo_restores_every_step() {
+        let mut ctx = context(History::new());
+
+        let mut states = Vec::new();
+        for _ in 0..500 {
+            states.push(state(&ctx));
+            ctx.step().unwrap();
+        }
+        assert!(ctx.em.reg.a.get() > 0, "the interrupt never ran");
+
+        let frames = &ctx.history.as_ref().unwrap().frames;
+        assert_eq!(frames.len(), 500);
+        /* most instructions only change registers and memory */
+        assert!(frames.iter().filter(|f| f.cpu.is_some()).count() < 100);
+
+        while let Some(expected) = states.pop() {
+            assert!(ctx.step_back().is_some());
+            assert_eq!(state(&ctx), expected);
+        }
+        assert!(ctx.step_back().is_none());
+    }
+
+    #[test]
+    fn seek_falls_back_to_snapshots() {
+        let mut history = History::with_limit(20);
+        history.interval = 50;
+        let mut ctx = context(history);
+
+        let mut states = Vec::new();
+        for _ in 0..300 {
+            states.push(state(&ctx));
+            ctx.step().unwrap();
+        }
+        assert_eq!(ctx.history.as_ref().unwrap().frames.len(), 20);
+
+        let (cpu, ram, sfr) = &states[40];
+        assert_eq!(ctx.seek(cpu.cycles), StopReason::CycleLimit);
+        assert_eq!(&state(&ctx), &(cpu.clone(), ram.clone(), sfr.clone()));
+    }
+
+    #[test]
+    fn reverse_continue_stops_at_breakpoints() {
+        let mut ctx = context(History::new());
+        for _ in 0..100 {
+            ctx.step().unwrap();
+        }
+
+        let copy = ctx.em.rom.iter().rposition(|b| *b == 0xCA).unwrap() as u16 - 1;
+        ctx.add_breakpoint(copy);
+        assert_eq!(ctx.reverse_continue(), StopReason::Breakpoint(copy));
+
+        ctx.remove_breakpoint(copy);
+        assert_eq!(ctx.reverse_continue(), StopReason::HistoryStart);
+        assert_eq!(ctx.em.cycles, 0);
+    }
+}
@@ -0,0 +1,180 @@
+use super::hex;
+use super::lexer::LexerContext;
+use super::lines::LineTable;
+use super::parser::{IPContext, Target};
+use super::preprocess::{Preprocessor, Source};
+use super::segment::Segments;
+use super::symbols::{self, SymbolTable};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/**
+ * How to assemble
+ * `file` names the source in the line table and error messages,
+ * `defines` are `NAME=VALUE` or just `NAME`.
+ */
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub file: String,
+    pub defines: Vec<String>,
+    pub include_paths: Vec<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            file: String::from("<source>"),
+            defines: Vec::new(),
+            include_paths: Vec::new(),
+        }
+    }
+
+    fn preprocessor(&self) -> Preprocessor {
+        let mut res = Preprocessor::new();
+
+        for definition in &self.defines {
+            res.define(definition);
+        }
+        res.include_paths = self.include_paths.clone();
+        res
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /* a file could not be read */
+    Io(String),
+    /* `file:line: message` */
+    Assembly(String),
+    /* undefined or duplicate symbols, overlapping sections */
+    Link(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(message) | Error::Assembly(message) | Error::Link(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+/**
+ * Code to load at address 0, with whatever is known about it
+ * Images read from .hex or binary files have no symbols or lines.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    pub code: Vec<u8>,
+    pub symbols: SymbolTable,
+    pub lines: LineTable,
+    /* data segments, with the labels in them */
+    pub segments: Segments,
+    /* preprocessed input, when assembled from source */
+    pub source: Option<Source>,
+}
+
+impl Image {
+    /**
+     * Reads an Intel .hex file, or any other file as raw code
+     */
+    pub fn load(path: &str) -> Result<Image, Error> {
+        let err = |e: String| Error::Io(format!("{}: {}", path, e));
+
+        let code = if path.ends_with(".hex") {
+            let text = fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+            hex::parse(&text).map_err(err)?
+        } else {
+            fs::read(path).map_err(|e| err(e.to_string()))?
+        };
+
+        Ok(Image {
+            code,
+            ..Image::default()
+        })
+    }
+}
+
+/**
+ * Runs the parser, its errors become `file:line: message`
+ */
+pub fn parse(pc: &mut IPContext) -> Result<(), Error> {
+    pc.run().map_err(|message| {
+        let (file, line) = pc.origin(pc.line);
+        Error::Assembly(format!("{}:{}: {}", file, line, message))
+    })
+}
+
+/**
+ * Runs the lexer and parser on preprocessed source
+ * References to EXTRN labels are left as they are.
+ */
+pub fn context(source: &Source) -> Result<IPContext, Error> {
+    let mut lc = LexerContext::new(source.text.clone());
+    lc.run();
+
+    let mut pc = IPContext::new(lc.dt);
+    pc.lines.files = source.files.clone();
+    pc.origins = source.origins.clone();
+    pc.file = source.files.first().cloned().unwrap_or_default();
+
+    parse(&mut pc)?;
+    Ok(pc)
+}
+
+pub fn assemble_preprocessed(source: Source) -> Result<Image, Error> {
+    let pc = context(&source)?;
+
+    let used = pc.refs.iter().find_map(|r| match &r.target {
+        Target::Label(name) if pc.externs.contains(name) => Some((r.line, name)),
+        _ => None,
+    });
+
+    if let Some((line, name)) = used {
+        let (file, line) = pc.origin(line);
+        return Err(Error::Assembly(format!(
+            "{}:{}: `{}` is EXTRN, assemble to an object and link",
+            file, line, name
+        )));
+    }
+
+    Ok(Image {
+        symbols: symbols::from_labels(&pc.lb),
+        code: pc.cg,
+        lines: pc.lines,
+        segments: pc.segments,
+        source: Some(source),
+    })
+}
+
+/**
+ * Runs the preprocessor, includes are looked up relative to `options.file`
+ */
+pub fn preprocess(source: &str, options: &Options) -> Result<Source, Error> {
+    options
+        .preprocessor()
+        .run(&options.file, source)
+        .map_err(Error::Io)
+}
+
+pub fn assemble(source: &str, options: &Options) -> Result<Image, Error> {
+    assemble_preprocessed(preprocess(source, options)?)
+}
+
+pub fn assemble_file(path: &str, options: &Options) -> Result<Image, Error> {
+    let source = fs::read_to_string(path).map_err(|e| Error::Io(format!("{}: {}", path, e)))?;
+    let options = Options {
+        file: path.to_string(),
+        ..options.clone()
+    };
+
+    assemble(&source, &options)
+}
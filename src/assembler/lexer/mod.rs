@@ -4,22 +4,55 @@ pub enum Instruction {
         name: String,
         op: String,
         line: usize,
+        column: usize,
     },
     TwoArg {
         name: String,
         op1: String,
         op2: String,
         line: usize,
+        column: usize,
+    },
+    NoArg {
+        name: String,
+        line: usize,
+        column: usize,
     },
     Label {
         name: String,
         line: usize,
+        column: usize,
     },
     End {
         line: usize,
+        column: usize,
     },
 }
 
+impl Instruction {
+    /* source line, counting from 1 */
+    pub fn line(&self) -> usize {
+        match self {
+            Instruction::OneArg { line, .. }
+            | Instruction::TwoArg { line, .. }
+            | Instruction::NoArg { line, .. }
+            | Instruction::Label { line, .. }
+            | Instruction::End { line, .. } => *line,
+        }
+    }
+
+    /* column of the first character, counting from 1 */
+    pub fn column(&self) -> usize {
+        match self {
+            Instruction::OneArg { column, .. }
+            | Instruction::TwoArg { column, .. }
+            | Instruction::NoArg { column, .. }
+            | Instruction::Label { column, .. }
+            | Instruction::End { column, .. } => *column,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LexerContext {
     pub code: String,
@@ -35,31 +68,35 @@ impl LexerContext {
     }
 
     pub fn run(&mut self) {
-        let mut lines = self.code.lines();
-        let mut curr_line = 0;
-
-        while let Some(line) = lines.next() {
-            curr_line += 1;
+        for (index, line) in self.code.lines().enumerate() {
+            let curr_line = index + 1;
+            let column = line.len() - line.trim_start().len() + 1;
+            let line = strip_comment(line).trim();
 
+            /* blank lines and comments */
             if line.is_empty() {
                 continue;
             }
 
-            if line[line.len() - 1..].eq(":") {
-                let label = line[..line.len() - 1].to_string();
+            if let Some(label) = line.strip_suffix(':') {
+                let label = label.to_string();
                 self.dt.push(Instruction::Label {
                     name: label,
                     line: curr_line,
+                    column,
                 });
                 continue;
             }
 
             if line == "end" {
-                self.dt.push(Instruction::End { line: curr_line });
+                self.dt.push(Instruction::End {
+                    line: curr_line,
+                    column,
+                });
                 break;
             }
 
-            let mut words = line.split_once(' ');
+            let words = line.split_once(char::is_whitespace);
 
             match words {
                 Some((name, args)) => {
@@ -72,24 +109,46 @@ impl LexerContext {
                             name: name.to_string(),
                             op: op.to_string(),
                             line: curr_line,
+                            column,
                         });
                     } else {
                         let op1 = args.next().unwrap().trim();
-                        let op2 = args.next().unwrap().trim();
+                        /* anything past a second operand, like `PUBLIC a, b, c`, stays in op2 */
+                        let op2 = args.map(str::trim).collect::<Vec<&str>>().join(", ");
 
                         self.dt.push(Instruction::TwoArg {
                             name: name.to_string(),
                             op1: op1.to_string(),
-                            op2: op2.to_string(),
+                            op2,
                             line: curr_line,
+                            column,
                         });
                     }
                 }
 
                 None => {
-                    continue;
+                    self.dt.push(Instruction::NoArg {
+                        name: line.to_string(),
+                        line: curr_line,
+                        column,
+                    });
                 }
             }
         }
     }
 }
+
+/* `line` up to a `;` comment, semicolons in strings are kept */
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+
+    line
+}
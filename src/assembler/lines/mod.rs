@@ -0,0 +1,104 @@
+use std::fmt;
+
+/**
+ * Code bytes `start..end` came from `line`, `column` of `files[file]`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEntry {
+    pub start: u16,
+    pub end: u16,
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/**
+ * Where a code address came from in the source
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/**
+ * Maps code addresses back to source positions
+ * Built by the parser next to `cg`, one entry per instruction
+ * that emitted bytes, in address order.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    pub files: Vec<String>,
+    pub entries: Vec<LineEntry>,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable {
+            files: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    /**
+     * Index of `name` in `files`, adding it if needed
+     */
+    pub fn add_file(&mut self, name: &str) -> usize {
+        match self.files.iter().position(|f| f == name) {
+            Some(index) => index,
+            None => {
+                self.files.push(name.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    pub fn push(&mut self, entry: LineEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entry(&self, addr: u16) -> Option<&LineEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.start <= addr && addr < e.end)
+    }
+
+    /**
+     * Source position of the instruction covering `addr`
+     */
+    pub fn lookup(&self, addr: u16) -> Option<Location<'_>> {
+        self.entry(addr).map(|e| Location {
+            file: &self.files[e.file],
+            line: e.line,
+            column: e.column,
+        })
+    }
+
+    /**
+     * Address of the first instruction at or after `line`
+     * `file` may be empty when there is only one file.
+     */
+    pub fn address_of(&self, file: &str, line: usize) -> Option<(u16, usize)> {
+        self.entries
+            .iter()
+            .filter(|e| {
+                file.is_empty()
+                    || self.files[e.file] == file
+                    || self.files[e.file].ends_with(&format!("/{}", file))
+            })
+            .filter(|e| e.line >= line)
+            .min_by_key(|e| (e.line, e.start))
+            .map(|e| (e.start, e.line))
+    }
+}
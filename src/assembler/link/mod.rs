@@ -0,0 +1,370 @@
+use super::image::{Error, Image};
+use super::lines::{LineEntry, LineTable};
+use super::object::{Object, Symbol};
+use super::parser::{patch, Target};
+use super::segment::{Region, Segment, Segments};
+use super::symbols::SymbolTable;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/**
+ * Where an object's code ended up
+ * Absolute objects get one per stretch of code they emit.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placed {
+    pub name: String,
+    pub base: u16,
+    pub size: usize,
+    pub absolute: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapSymbol {
+    pub name: String,
+    pub segment: Segment,
+    pub value: u16,
+    pub public: bool,
+    /* object it is defined in */
+    pub object: String,
+}
+
+/**
+ * What the linker did, for the map file
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    pub code: Vec<Placed>,
+    pub data: Vec<(String, Region)>,
+    pub symbols: Vec<MapSymbol>,
+}
+
+impl Map {
+    /**
+     * Map file, sections by address then symbols by address:
+     * CODE
+     * 0000H 0012H   19  main.plasm  absolute
+     * DATA
+     * DSEG 30H to 31H   main.plasm
+     * SYMBOLS
+     * 0013H CSEG  print  print.plasm  public
+     */
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "CODE")?;
+        for placed in &self.code {
+            let last = (placed.base as usize + placed.size).max(1) - 1;
+            let kind = if placed.absolute {
+                "absolute"
+            } else {
+                "relocatable"
+            };
+
+            writeln!(
+                out,
+                "{:04X}H {:04X}H {:>5}  {}  {}",
+                placed.base, last, placed.size, placed.name, kind
+            )?;
+        }
+
+        writeln!(out, "DATA")?;
+        for (name, region) in &self.data {
+            writeln!(out, "{:<20} {}", region.to_string(), name)?;
+        }
+
+        writeln!(out, "SYMBOLS")?;
+        for symbol in &self.symbols {
+            let scope = if symbol.public { "public" } else { "local" };
+
+            writeln!(
+                out,
+                "{:04X}H {:<5} {:<20} {}  {}",
+                symbol.value,
+                symbol.segment.name(),
+                symbol.name,
+                symbol.object,
+                scope
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/* code bytes an absolute object emits, in address order */
+fn chunks(object: &Object) -> Vec<(usize, usize)> {
+    let mut res: Vec<(usize, usize)> = Vec::new();
+    let mut entries: Vec<&LineEntry> = object.lines.entries.iter().collect();
+    entries.sort_by_key(|e| e.start);
+
+    for entry in entries {
+        match res.last_mut() {
+            Some(last) if last.1 >= entry.start as usize => last.1 = last.1.max(entry.end as usize),
+            _ => res.push((entry.start as usize, entry.end as usize)),
+        }
+    }
+    res
+}
+
+/**
+ * Links objects into one image
+ * Absolute objects stay where they were assembled, the others
+ * go into the first gap they fit, in the order given. All errors
+ * are reported together, one per line.
+ */
+pub fn link(objects: &[Object]) -> Result<(Image, Map), Error> {
+    let mut errors: Vec<String> = Vec::new();
+    let mut map = Map::default();
+
+    /* (start, end, object index) of code placed so far */
+    let mut used: Vec<(usize, usize, usize)> = Vec::new();
+    let mut bases = vec![0usize; objects.len()];
+
+    for (index, object) in objects.iter().enumerate().filter(|(_, o)| o.absolute) {
+        for (start, end) in chunks(object) {
+            if let Some((s, e, other)) = used.iter().find(|(s, e, _)| start < *e && *s < end) {
+                errors.push(format!(
+                    "{} and {} both place code at {:04X}H",
+                    objects[*other].name,
+                    object.name,
+                    start.max(*s).min(end.min(*e))
+                ));
+            }
+            used.push((start, end, index));
+        }
+    }
+
+    for (index, object) in objects.iter().enumerate().filter(|(_, o)| !o.absolute) {
+        let size = object.code.len();
+        let mut base = 0;
+
+        while let Some((_, end, _)) = used
+            .iter()
+            .find(|(s, e, _)| size > 0 && base < *e && *s < base + size)
+        {
+            base = *end;
+        }
+
+        if base + size > Segment::Code.size() {
+            errors.push(format!("no room for the {} bytes of {}", size, object.name));
+            continue;
+        }
+
+        bases[index] = base;
+        used.push((base, base + size, index));
+    }
+
+    /* relocations can't be applied to code that isn't there */
+    if !errors.is_empty() {
+        return Err(Error::Link(errors.join("\n")));
+    }
+
+    let end = used.iter().map(|(_, e, _)| *e).max().unwrap_or(0);
+    let mut code = vec![0u8; end];
+
+    for (index, object) in objects.iter().enumerate() {
+        let base = bases[index];
+
+        if !object.absolute {
+            code[base..base + object.code.len()].copy_from_slice(&object.code);
+            map.code.push(Placed {
+                name: object.name.clone(),
+                base: base as u16,
+                size: object.code.len(),
+                absolute: false,
+            });
+            continue;
+        }
+
+        /* only what the object emitted, org padding could cover other objects */
+        for (start, end) in chunks(object) {
+            code[start..end].copy_from_slice(&object.code[start..end]);
+            map.code.push(Placed {
+                name: object.name.clone(),
+                base: start as u16,
+                size: end - start,
+                absolute: true,
+            });
+        }
+    }
+    map.code.sort_by_key(|p| p.base);
+
+    /* data reserved with AT stays where it is, the rest goes in the first gap */
+    let mut segments = Segments::new();
+    let mut moved: Vec<(usize, Region, Region)> = Vec::new();
+
+    for object in objects {
+        for region in &object.regions {
+            if let Some((other, r)) = map.data.iter().find(|(_, r)| r.overlaps(region)) {
+                errors.push(format!(
+                    "{} in {} overlaps {} in {}",
+                    region, object.name, r, other
+                ));
+            }
+
+            map.data.push((object.name.clone(), region.clone()));
+            segments.regions.push(region.clone());
+        }
+    }
+
+    for (index, object) in objects.iter().enumerate() {
+        for section in &object.sections {
+            let segment = section.segment;
+            let size = section.end - section.start;
+
+            match segments.find(segment, segment.start(), size, segment.size()) {
+                Ok(region) => {
+                    map.data.push((object.name.clone(), region.clone()));
+                    segments.regions.push(region.clone());
+                    moved.push((index, section.clone(), region));
+                }
+                Err(e) => errors.push(format!("{}: {}", object.name, e)),
+            }
+        }
+    }
+    map.data.sort_by_key(|(_, r)| (r.segment as u8, r.start));
+
+    let value_of = |index: usize, symbol: &Symbol| match symbol.segment {
+        Segment::Code => symbol.value.wrapping_add(bases[index] as u16),
+        segment => relocate(&moved, index, segment, symbol.value),
+    };
+
+    /* public symbols, and where they come from */
+    let mut globals: HashMap<&str, (u16, usize)> = HashMap::new();
+
+    for (index, object) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            let value = value_of(index, symbol);
+
+            map.symbols.push(MapSymbol {
+                name: symbol.name.clone(),
+                segment: symbol.segment,
+                value,
+                public: symbol.public,
+                object: object.name.clone(),
+            });
+
+            if symbol.segment != Segment::Code {
+                segments
+                    .labels
+                    .insert(symbol.name.clone(), (symbol.segment, value));
+            }
+
+            if !symbol.public {
+                continue;
+            }
+
+            match globals.get(symbol.name.as_str()) {
+                Some((_, other)) => errors.push(format!(
+                    "duplicate symbol `{}` in {} and {}",
+                    symbol.name, objects[*other].name, object.name
+                )),
+                None => {
+                    globals.insert(&symbol.name, (value, index));
+                }
+            }
+        }
+    }
+    map.symbols
+        .sort_by(|a, b| a.value.cmp(&b.value).then(a.name.cmp(&b.name)));
+
+    for object in objects {
+        for name in &object.externs {
+            if !globals.contains_key(name.as_str()) {
+                errors.push(format!("undefined symbol `{}` in {}", name, object.name));
+            }
+        }
+    }
+
+    for (index, object) in objects.iter().enumerate() {
+        let base = bases[index];
+
+        for relocation in &object.relocations {
+            let value = match &relocation.target {
+                Target::Start => base as u16,
+                /* the object's own labels first, then public ones */
+                Target::Label(name) => match object.symbols.iter().find(|s| &s.name == name) {
+                    Some(symbol) => value_of(index, symbol),
+                    None => match globals.get(name.as_str()) {
+                        Some((value, _)) => *value,
+                        /* reported above */
+                        None => continue,
+                    },
+                },
+            };
+
+            let at = base + relocation.at as usize;
+            if let Err(e) = patch(
+                &mut code,
+                at,
+                relocation.fixup,
+                value.wrapping_add(relocation.addend),
+            ) {
+                errors.push(format!("{}: {}", object.name, e));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::Link(errors.join("\n")));
+    }
+
+    let mut lines = LineTable::new();
+
+    for (index, object) in objects.iter().enumerate() {
+        let base = bases[index] as u16;
+
+        for entry in &object.lines.entries {
+            let file = lines.add_file(&object.lines.files[entry.file]);
+
+            lines.push(LineEntry {
+                start: entry.start + base,
+                end: entry.end + base,
+                file,
+                ..*entry
+            });
+        }
+    }
+    lines.entries.sort_by_key(|e| e.start);
+
+    /* public names win over local ones that happen to be the same */
+    let mut symbols = SymbolTable::new();
+
+    for symbol in map.symbols.iter().filter(|s| s.segment == Segment::Code) {
+        if symbol.public || !symbols.contains_key(&symbol.name) {
+            symbols.insert(symbol.name.clone(), symbol.value);
+        }
+    }
+
+    let image = Image {
+        code,
+        symbols,
+        lines,
+        segments,
+        source: None,
+    };
+
+    Ok((image, map))
+}
+
+/**
+ * Where a data label of object `index` ended up
+ * Labels in a section move with it, including one just past
+ * its end, anything else was placed with AT.
+ */
+fn relocate(moved: &[(usize, Region, Region)], index: usize, segment: Segment, value: u16) -> u16 {
+    let value = value as usize;
+    let ours = || {
+        moved
+            .iter()
+            .filter(|(i, from, _)| *i == index && from.segment == segment)
+    };
+
+    let section = ours()
+        .find(|(_, from, _)| from.start <= value && value < from.end)
+        .or_else(|| ours().find(|(_, from, _)| from.end == value));
+
+    match section {
+        Some((_, from, to)) => (value - from.start + to.start) as u16,
+        None => value as u16,
+    }
+}
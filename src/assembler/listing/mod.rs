@@ -0,0 +1,60 @@
+use super::lines::LineTable;
+use super::preprocess::Source;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/**
+ * Assembler listing, one row per line of input:
+ * 0003  74 05          7  mov R0, #5
+ * Included files are announced with a `; file` row.
+ */
+pub fn write(
+    out: &mut dyn Write,
+    source: &Source,
+    lines: &LineTable,
+    code: &[u8],
+) -> io::Result<()> {
+    let mut emitted: HashMap<(&str, usize), Vec<(u16, u16)>> = HashMap::new();
+    for entry in &lines.entries {
+        emitted
+            .entry((lines.files[entry.file].as_str(), entry.line))
+            .or_default()
+            .push((entry.start, entry.end));
+    }
+
+    let mut current = None;
+
+    for (text, (file, line)) in source.text.lines().zip(&source.origins) {
+        let name = source.files[*file].as_str();
+
+        if current != Some(*file) {
+            writeln!(out, "; {}", name)?;
+            current = Some(*file);
+        }
+
+        match emitted.get(&(name, *line)) {
+            Some(ranges) => {
+                for (start, end) in ranges {
+                    let bytes: Vec<String> = code
+                        .get(*start as usize..*end as usize)
+                        .unwrap_or(&[])
+                        .iter()
+                        .map(|b| format!("{:02X}", b))
+                        .collect();
+
+                    writeln!(
+                        out,
+                        "{:04X}  {:<9} {:>5}  {}",
+                        start,
+                        bytes.join(" "),
+                        line,
+                        text
+                    )?;
+                }
+            }
+            None => writeln!(out, "      {:<9} {:>5}  {}", "", line, text)?,
+        }
+    }
+
+    Ok(())
+}
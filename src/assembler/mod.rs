@@ -1,4 +1,20 @@
+pub mod archive;
 pub mod codegen;
+pub mod coverage;
+pub mod disasm;
 pub mod engine;
+pub mod hex;
+pub mod history;
+pub mod image;
 pub mod lexer;
+pub mod lines;
+pub mod link;
+pub mod listing;
+pub mod object;
 pub mod parser;
+pub mod preprocess;
+pub mod profile;
+pub mod segment;
+pub mod symbols;
+pub mod trace;
+pub mod watch;
@@ -0,0 +1,404 @@
+use super::image::{self, Error, Options};
+use super::lines::{LineEntry, LineTable};
+use super::parser::{Fixup, IPContext, Target, FIXUPS};
+use super::preprocess::Source;
+use super::segment::{Region, Segment, SEGMENTS};
+use crate::binary::Reader;
+use std::fs;
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"PLOB";
+pub const VERSION: u8 = 2;
+
+/**
+ * A label an object defines
+ * Code labels are offsets into the object's code, data
+ * labels are where the object was assembled to put them.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub segment: Segment,
+    pub value: u16,
+    pub public: bool,
+    /* set with `DOC`, e.g. the registers it takes and clobbers */
+    pub doc: String,
+}
+
+/**
+ * An address the linker fills in at `at`
+ * It is `target` plus `addend`, where `Target::Start` is
+ * wherever the object's own code ends up.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub at: u16,
+    pub fixup: Fixup,
+    pub target: Target,
+    pub addend: u16,
+}
+
+/**
+ * A separately assembled module
+ * Code placed with org or CSEG AT is `absolute` and linked
+ * where it is, anything else can go wherever there is room.
+ * Data reserved with AT is in `regions` and stays where it is,
+ * `sections` were reserved without AT and the linker moves
+ * them, along with the labels in them.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub name: String,
+    pub code: Vec<u8>,
+    pub absolute: bool,
+    pub symbols: Vec<Symbol>,
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    pub regions: Vec<Region>,
+    pub sections: Vec<Region>,
+    pub lines: LineTable,
+}
+
+impl Object {
+    /**
+     * Collects what the linker needs from an assembled module
+     */
+    pub fn from_context(name: &str, pc: &IPContext) -> Object {
+        let mut symbols: Vec<Symbol> = pc
+            .lb
+            .iter()
+            .filter(|(name, _)| name.as_str() != "$")
+            .map(|(name, addr)| Symbol {
+                name: name.clone(),
+                segment: Segment::Code,
+                value: *addr as u16,
+                public: pc.publics.contains(name),
+                doc: pc.docs.get(name).cloned().unwrap_or_default(),
+            })
+            .chain(
+                pc.segments
+                    .labels
+                    .iter()
+                    .map(|(name, (segment, addr))| Symbol {
+                        name: name.clone(),
+                        segment: *segment,
+                        value: *addr,
+                        public: pc.publics.contains(name),
+                        doc: pc.docs.get(name).cloned().unwrap_or_default(),
+                    }),
+            )
+            .collect();
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let relocations = pc
+            .refs
+            .iter()
+            .filter_map(|r| {
+                /* code labels are the object's start plus where they are */
+                let (target, addend) = match &r.target {
+                    Target::Label(name) => match pc.lb.get(name) {
+                        Some(addr) => (Target::Start, *addr as u16 + r.offset),
+                        None => (r.target.clone(), r.offset),
+                    },
+                    Target::Start => (Target::Start, r.offset),
+                };
+
+                /* jumps within the object move with it */
+                if target == Target::Start && r.fixup == Fixup::Rel8 {
+                    return None;
+                }
+
+                Some(Relocation {
+                    at: r.at as u16,
+                    fixup: r.fixup,
+                    target,
+                    addend,
+                })
+            })
+            .collect();
+
+        Object {
+            name: name.to_string(),
+            code: pc.cg.clone(),
+            absolute: pc.placed,
+            symbols,
+            /* declared but unused, e.g. by an include file, needs nothing linked */
+            externs: pc
+                .externs
+                .iter()
+                .filter(|name| {
+                    pc.refs
+                        .iter()
+                        .any(|r| matches!(&r.target, Target::Label(label) if label == *name))
+                })
+                .cloned()
+                .collect(),
+            relocations,
+            regions: pc
+                .segments
+                .regions
+                .iter()
+                .filter(|r| !pc.segments.relocatable.contains(r))
+                .cloned()
+                .collect(),
+            sections: pc.segments.relocatable.clone(),
+            lines: pc.lines.clone(),
+        }
+    }
+
+    /**
+     * Binary object file, big endian like everything else:
+     * magic, version, name, absolute (u8), code length (u32), code,
+     * symbols (u16 count) as name, segment (u8), value (u16), public (u8), doc,
+     * externs (u16 count) as names,
+     * relocations (u16 count) as at (u16), fixup (u8), target, addend (u16),
+     * regions (u16 count) as segment (u8), start (u32), end (u32),
+     * sections (u16 count) the same way,
+     * line table files (u16 count) as names, entries (u32 count)
+     * as start (u16), end (u16), file (u16), line (u32), column (u16).
+     * Strings are a u16 length and UTF-8. A target is 0 (u8) for
+     * the start of the code, or 1 (u8) and a label.
+     */
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        let string = |buf: &mut Vec<u8>, s: &str| {
+            buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+            buf.extend_from_slice(s.as_bytes());
+        };
+
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        string(&mut buf, &self.name);
+        buf.push(self.absolute as u8);
+        buf.extend_from_slice(&(self.code.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.code);
+
+        buf.extend_from_slice(&(self.symbols.len() as u16).to_be_bytes());
+        for symbol in &self.symbols {
+            string(&mut buf, &symbol.name);
+            buf.push(symbol.segment as u8);
+            buf.extend_from_slice(&symbol.value.to_be_bytes());
+            buf.push(symbol.public as u8);
+            string(&mut buf, &symbol.doc);
+        }
+
+        buf.extend_from_slice(&(self.externs.len() as u16).to_be_bytes());
+        for name in &self.externs {
+            string(&mut buf, name);
+        }
+
+        buf.extend_from_slice(&(self.relocations.len() as u16).to_be_bytes());
+        for relocation in &self.relocations {
+            buf.extend_from_slice(&relocation.at.to_be_bytes());
+            buf.push(relocation.fixup as u8);
+            match &relocation.target {
+                Target::Start => buf.push(0),
+                Target::Label(name) => {
+                    buf.push(1);
+                    string(&mut buf, name);
+                }
+            }
+            buf.extend_from_slice(&relocation.addend.to_be_bytes());
+        }
+
+        for regions in [&self.regions, &self.sections] {
+            buf.extend_from_slice(&(regions.len() as u16).to_be_bytes());
+            for region in regions {
+                buf.push(region.segment as u8);
+                buf.extend_from_slice(&(region.start as u32).to_be_bytes());
+                buf.extend_from_slice(&(region.end as u32).to_be_bytes());
+            }
+        }
+
+        buf.extend_from_slice(&(self.lines.files.len() as u16).to_be_bytes());
+        for file in &self.lines.files {
+            string(&mut buf, file);
+        }
+        buf.extend_from_slice(&(self.lines.entries.len() as u32).to_be_bytes());
+        for entry in &self.lines.entries {
+            buf.extend_from_slice(&entry.start.to_be_bytes());
+            buf.extend_from_slice(&entry.end.to_be_bytes());
+            buf.extend_from_slice(&(entry.file as u16).to_be_bytes());
+            buf.extend_from_slice(&(entry.line as u32).to_be_bytes());
+            buf.extend_from_slice(&(entry.column as u16).to_be_bytes());
+        }
+
+        out.write_all(&buf)
+    }
+
+    /**
+     * Reads back an object written by `write`
+     */
+    pub fn read(input: &mut dyn Read) -> Result<Object, String> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+        let mut reader = Reader::new(&data, "object file");
+
+        if reader.take(4)? != MAGIC {
+            return Err("not an object file".to_string());
+        }
+
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported object file version {}", version));
+        }
+
+        let mut res = Object {
+            name: reader.string()?,
+            absolute: reader.u8()? != 0,
+            ..Object::default()
+        };
+
+        let len = reader.u32()? as usize;
+        res.code = reader.take(len)?.to_vec();
+
+        for _ in 0..reader.u16()? {
+            res.symbols.push(Symbol {
+                name: reader.string()?,
+                segment: segment(&mut reader)?,
+                value: reader.u16()?,
+                public: reader.u8()? != 0,
+                doc: reader.string()?,
+            });
+        }
+
+        for _ in 0..reader.u16()? {
+            res.externs.push(reader.string()?);
+        }
+
+        for _ in 0..reader.u16()? {
+            let at = reader.u16()?;
+            let fixup = *FIXUPS
+                .get(reader.u8()? as usize)
+                .ok_or("invalid relocation in object file")?;
+            let target = match reader.u8()? {
+                0 => Target::Start,
+                1 => Target::Label(reader.string()?),
+                _ => return Err("invalid relocation in object file".to_string()),
+            };
+
+            res.relocations.push(Relocation {
+                at,
+                fixup,
+                target,
+                addend: reader.u16()?,
+            });
+        }
+
+        for regions in [&mut res.regions, &mut res.sections] {
+            for _ in 0..reader.u16()? {
+                regions.push(Region {
+                    segment: segment(&mut reader)?,
+                    start: reader.u32()? as usize,
+                    end: reader.u32()? as usize,
+                });
+            }
+        }
+
+        for _ in 0..reader.u16()? {
+            res.lines.files.push(reader.string()?);
+        }
+        for _ in 0..reader.u32()? {
+            res.lines.push(LineEntry {
+                start: reader.u16()?,
+                end: reader.u16()?,
+                file: reader.u16()? as usize,
+                line: reader.u32()? as usize,
+                column: reader.u16()? as usize,
+            });
+        }
+
+        Ok(res)
+    }
+
+    pub fn load(path: &str) -> Result<Object, Error> {
+        let err = |e: String| Error::Io(format!("{}: {}", path, e));
+        let mut file = fs::File::open(path).map_err(|e| err(e.to_string()))?;
+
+        Object::read(&mut file).map_err(err)
+    }
+}
+
+/**
+ * Assembles preprocessed source into an object
+ * Labels declared EXTRN are left for the linker.
+ */
+pub fn assemble_object_preprocessed(source: Source) -> Result<Object, Error> {
+    let name = source.files.first().cloned().unwrap_or_default();
+    let pc = image::context(&source)?;
+
+    Ok(Object::from_context(&name, &pc))
+}
+
+pub fn assemble_object(source: &str, options: &Options) -> Result<Object, Error> {
+    assemble_object_preprocessed(image::preprocess(source, options)?)
+}
+
+pub fn assemble_object_file(path: &str, options: &Options) -> Result<Object, Error> {
+    let source = fs::read_to_string(path).map_err(|e| Error::Io(format!("{}: {}", path, e)))?;
+    let options = Options {
+        file: path.to_string(),
+        ..options.clone()
+    };
+
+    assemble_object(&source, &options)
+}
+
+fn segment(reader: &mut Reader) -> Result<Segment, String> {
+    SEGMENTS
+        .get(reader.u8()? as usize)
+        .copied()
+        .ok_or("invalid segment in object file".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let src = "EXTRN far, unused\nDOC here, \"loops\"\nDSEG\ncount:\nds 1\nCSEG\nhere:\nmov count, #1\nlcall far\nsjmp here\nljmp $\nend\n";
+        let object = assemble_object(src, &Options::new()).unwrap();
+
+        let mut buf = Vec::new();
+        object.write(&mut buf).unwrap();
+        let back = Object::read(&mut &buf[..]).unwrap();
+
+        assert_eq!(back.code, object.code);
+        assert_eq!(back.symbols, object.symbols);
+        assert_eq!(back.symbols[1].doc, "loops");
+        assert_eq!(back.externs, ["far"]);
+        assert_eq!(back.sections, object.sections);
+        assert!(back.regions.is_empty());
+        /* the sjmp stays within the object, so it needs none */
+        assert_eq!(
+            back.relocations,
+            vec![
+                Relocation {
+                    at: 1,
+                    fixup: Fixup::Low,
+                    target: Target::Label("count".to_string()),
+                    addend: 0,
+                },
+                Relocation {
+                    at: 4,
+                    fixup: Fixup::Abs16,
+                    target: Target::Label("far".to_string()),
+                    addend: 0,
+                },
+                Relocation {
+                    at: 9,
+                    fixup: Fixup::Abs16,
+                    target: Target::Start,
+                    addend: 8,
+                },
+            ]
+        );
+
+        assert_eq!(
+            Object::read(&mut &buf[..5]).unwrap_err(),
+            "object file is truncated"
+        );
+    }
+}
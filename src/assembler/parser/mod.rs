@@ -1,10 +1,15 @@
+use super::lines::{LineEntry, LineTable};
+use super::segment::{Region, Segment, Segments};
 use super::{codegen, lexer::Instruction};
+use crate::sfr;
 use std::collections::HashMap;
+use std::mem;
 
 pub enum Destination {
     RegisterR(u8),
     RegisterA,
     RegisterB,
+    Direct(u8),
     Label(String),
 }
 
@@ -12,15 +17,84 @@ pub enum Source {
     RegisterR(u8),
     RegisterA,
     RegisterB,
+    Direct(u8),
     Label(String),
     Immediate(u8),
 }
 
+/**
+ * How an address is filled into the code
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fixup {
+    /* big endian, for ljmp, lcall and mov DPTR */
+    Abs16,
+    /* ajmp and acall, bits 8 to 10 go in the opcode before it */
+    Page11,
+    /* offset from the next byte, for sjmp, jb and jnb */
+    Rel8,
+    /* #HIGH label and #LOW label, and labels in RAM */
+    High,
+    Low,
+}
+
+pub const FIXUPS: [Fixup; 5] = [
+    Fixup::Abs16,
+    Fixup::Page11,
+    Fixup::Rel8,
+    Fixup::High,
+    Fixup::Low,
+];
+
+/**
+ * What a reference is to
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /* the start of the code, for `$` */
+    Start,
+    Label(String),
+}
+
+/**
+ * An address used at `at`, filled in once all labels are known
+ * `offset` is added to the target, so `$` is the start of the
+ * code plus the instruction's address.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub at: usize,
+    pub fixup: Fixup,
+    pub target: Target,
+    pub offset: u16,
+    /* line it is used on, for error messages */
+    pub line: usize,
+}
+
 pub struct IPContext {
     pub cg: Vec<u8>,
     pub raw: Vec<Instruction>,
     pub lb: HashMap<String, usize>,
-    pub future_addrs: Vec<(usize, String, bool /* isAbsoluteAddress? */)>,
+    pub refs: Vec<Reference>,
+    /* labels declared PUBLIC, and EXTRN ones left for the linker */
+    pub publics: Vec<String>,
+    pub externs: Vec<String>,
+    /* `DOC name, text`, what a symbol expects and returns */
+    pub docs: HashMap<String, String>,
+    /* code was placed with org or CSEG AT, so it can't be moved */
+    pub placed: bool,
+    /* source file name, recorded in `lines` */
+    pub file: String,
+    /*
+       (index into `lines.files`, line) for every line of preprocessed
+       input, empty when the input is `file` as is
+    */
+    pub origins: Vec<(usize, usize)>,
+    pub lines: LineTable,
+    /* line being assembled, for error messages */
+    pub line: usize,
+    /* data segments, and RAM reserved with `byte` */
+    pub segments: Segments,
 }
 
 impl IPContext {
@@ -29,98 +103,432 @@ impl IPContext {
             cg: Vec::new(),
             raw,
             lb: HashMap::new(),
-            future_addrs: Vec::new(),
+            refs: Vec::new(),
+            publics: Vec::new(),
+            externs: Vec::new(),
+            docs: HashMap::new(),
+            placed: false,
+            file: String::from("<source>"),
+            origins: Vec::new(),
+            lines: LineTable::new(),
+            line: 0,
+            segments: Segments::new(),
         }
     }
 
-    pub fn parse_address(&self, op: &str, fa: &mut String) -> u8 {
-        if self.lb.contains_key(op) {
-            self.lb[op] as u8
-        } else if op.starts_with("#") {
-            parse_number(&op[1..])
-        } else {
-            // panic!("invalid address");
-            *fa = String::from(op.to_string());
-            0
+    /* address of a label in DSEG or ISEG */
+    fn ram_address(&self, name: &str) -> Option<u8> {
+        match self.segments.labels.get(name) {
+            Some((Segment::Data | Segment::Idata, addr)) => Some(*addr as u8),
+            _ => None,
         }
     }
 
-    pub fn parse_address_16(&self, op: &str, fa: &mut String) -> u16 {
-        if self.lb.contains_key(op) {
-            self.lb[op] as u16
-        } else if op.starts_with("#") {
-            parse_number(&op[1..]) as u16
-        } else {
-            // panic!("invalid address");
-            *fa = String::from(op.to_string());
-            0
+    /**
+     * Address of a label in DSEG or ISEG used as a direct operand
+     * Those always follow the opcode. The use is kept for
+     * the linker, which may move the label.
+     */
+    fn ram_label(&mut self, name: &str) -> Option<u8> {
+        let addr = self.ram_address(name)?;
+
+        self.refer(name, self.cg.len() + 1, Fixup::Low);
+        Some(addr)
+    }
+
+    fn refer(&mut self, name: &str, at: usize, fixup: Fixup) {
+        self.refs.push(Reference {
+            at,
+            fixup,
+            target: Target::Label(name.to_string()),
+            offset: 0,
+            line: self.line,
+        });
+    }
+
+    /**
+     * A direct address, or a label in RAM
+     */
+    pub fn parse_data(&mut self, op: &str) -> Result<Option<u8>, String> {
+        Ok(parse_direct(op)?.or_else(|| self.ram_label(op)))
+    }
+
+    /**
+     * A bit address, or a label in BSEG
+     */
+    pub fn parse_bit(&mut self, op: &str) -> Result<u8, String> {
+        match self.segments.labels.get(op) {
+            Some((Segment::Bit, addr)) => {
+                let addr = *addr as u8;
+
+                self.refer(op, self.cg.len() + 1, Fixup::Low);
+                Ok(addr)
+            }
+            _ => parse_bit(op),
+        }
+    }
+
+    /* labels in RAM are direct addresses */
+    fn data_destination(&mut self, op: &str) -> Result<Destination, String> {
+        match self.ram_label(op) {
+            Some(addr) => Ok(Destination::Direct(addr)),
+            None => parse_destination(op),
+        }
+    }
+
+    /* and `#label` is the address itself, its use is kept once the instruction is emitted */
+    fn data_source(&mut self, op: &str) -> Result<Source, String> {
+        if let Some(addr) = op.strip_prefix('#').and_then(|name| self.ram_address(name)) {
+            return Ok(Source::Immediate(addr));
+        }
+
+        match self.ram_label(op) {
+            Some(addr) => Ok(Source::Direct(addr)),
+            None => parse_source(op),
+        }
+    }
+
+    /**
+     * Fills the address `op` stands for into the code at `at`
+     * Numbers go in right away, labels once they are all known.
+     */
+    fn fill(&mut self, op: &str, at: usize, fixup: Fixup) -> Result<(), String> {
+        let op = op.strip_prefix('#').unwrap_or(op);
+
+        if op.starts_with(|c: char| c.is_ascii_digit()) {
+            return patch(&mut self.cg, at, fixup, parse_number_16(op)?);
+        }
+
+        let (target, offset) = match op {
+            "$" => (Target::Start, self.lb["$"] as u16),
+            _ => (Target::Label(label(op)?), 0),
+        };
+
+        self.refs.push(Reference {
+            at,
+            fixup,
+            target,
+            offset,
+            line: self.line,
+        });
+        Ok(())
+    }
+
+    /* PUBLIC and EXTRN */
+    fn declare(&mut self, directive: &str, names: Vec<&str>) {
+        let list = match directive.to_ascii_uppercase().as_str() {
+            "PUBLIC" => &mut self.publics,
+            _ => &mut self.externs,
+        };
+
+        list.extend(
+            names
+                .iter()
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string()),
+        );
+    }
+
+    /**
+     * File and line `line` of the input came from
+     */
+    pub fn origin(&self, line: usize) -> (&str, usize) {
+        match self.origins.get(line.wrapping_sub(1)) {
+            Some((file, line)) => (&self.lines.files[*file], *line),
+            None => (&self.file, line),
         }
     }
 
-    pub fn run(&mut self) {
-        let mut pc: u16 = 0;
+    /**
+     * Assembles the instructions, stopping at the first error
+     * `line` is left at the line the error is about.
+     */
+    pub fn run(&mut self) -> Result<(), String> {
+        /* taken out so the helpers can borrow `self` */
+        let raw = mem::take(&mut self.raw);
+
+        /* reservations without AT keep clear of the ones with it, wherever they are */
+        self.segments.fixed = fixed_regions(&raw);
+        let res = self.assemble(&raw);
+        self.segments.fixed.clear();
+
+        self.raw = raw;
+        res
+    }
+
+    fn assemble(&mut self, raw: &[Instruction]) -> Result<(), String> {
+        let mut pc: u16;
+        let file = if self.origins.is_empty() {
+            self.lines.add_file(&self.file)
+        } else {
+            0
+        };
 
-        for ins in self.raw.iter() {
+        /* code labels with nothing emitted after them yet, `byte` moves them to RAM */
+        let mut pending: Vec<String> = Vec::new();
+
+        for ins in raw.iter() {
+            /* org pads `cg`, so the location counter is its length */
+            pc = self.cg.len() as u16;
+            self.line = ins.line();
             self.lb.insert(String::from("$"), pc as usize);
 
             match ins {
-                Instruction::OneArg { name, op, line } => match name.as_str() {
+                Instruction::OneArg { name, op, .. } if Segment::parse(name).is_some() => {
+                    let addr = segment_at(name, op)?;
+
+                    self.segments.select(Segment::parse(name).unwrap());
+                    self.placed |= self.segments.current == Segment::Code;
+                    org(&mut self.segments, &mut self.cg, addr)?;
+                }
+
+                Instruction::NoArg { name, .. } if Segment::parse(name).is_some() => {
+                    self.segments.select(Segment::parse(name).unwrap());
+                }
+
+                Instruction::OneArg { name, op, .. } if is_linkage(name) => {
+                    self.declare(name, vec![op.as_str()]);
+                }
+
+                Instruction::TwoArg { name, op1, op2, .. } if is_linkage(name) => {
+                    let mut names = vec![op1.as_str()];
+                    names.extend(op2.split(',').map(str::trim));
+
+                    self.declare(name, names);
+                }
+
+                Instruction::TwoArg { name, op1, op2, .. } if name.eq_ignore_ascii_case("DOC") => {
+                    self.docs
+                        .insert(op1.clone(), op2.trim_matches('"').to_string());
+                }
+
+                Instruction::OneArg { name, op, .. } => match name.as_str() {
                     "org" => {
-                        let addr = parse_number_16(op);
+                        self.placed |= self.segments.current == Segment::Code;
+                        org(&mut self.segments, &mut self.cg, parse_number_16(op)?)?;
+                    }
+                    "ds" | "dbit" => {
+                        let size = parse_number_16(op)? as usize;
 
-                        if addr >= pc {
-                            for _ in 0..(addr - pc) {
-                                self.cg.push(0);
-                                pc += 1;
+                        match (name.as_str(), self.segments.current) {
+                            (_, Segment::Code) => {
+                                return Err(format!(
+                                    "`{}` reserves data, it belongs in DSEG, ISEG, BSEG or XSEG",
+                                    name
+                                ))
                             }
-                        } else {
-                            panic!("Invalid address for `org` directive");
+                            ("ds", Segment::Bit) => {
+                                return Err("BSEG reserves bits with `dbit`".to_string())
+                            }
+                            ("dbit", segment) if segment != Segment::Bit => {
+                                return Err("`dbit` belongs in BSEG".to_string())
+                            }
+                            _ => (),
                         }
+
+                        self.segments.reserve(size)?;
                     }
                     "sjmp" => {
-                        let mut fa: String = String::new();
-                        let mut addr = self.parse_address(op, &mut fa);
+                        let at = self.cg.len() + 1;
 
-                        if fa.len() > 0 {
-                            self.future_addrs
-                                .push((self.cg.len() + 1 as usize, fa, false));
+                        self.cg.append(&mut codegen::sjmp(0));
+                        self.fill(op, at, Fixup::Rel8)?;
+                    }
+                    "ljmp" => {
+                        let at = self.cg.len() + 1;
+
+                        self.cg.append(&mut codegen::ljmp(0));
+                        self.fill(op, at, Fixup::Abs16)?;
+                    }
+                    "ajmp" | "acall" => {
+                        let at = self.cg.len() + 1;
+
+                        if name == "ajmp" {
+                            self.cg.append(&mut codegen::ajmp(0));
+                        } else {
+                            self.cg.append(&mut codegen::acall(0));
+                        }
+                        self.fill(op, at, Fixup::Page11)?;
+                    }
+                    "int" => {
+                        if parse_number(op)? != 0x21 {
+                            return Err("Only `int 21H` is supported".to_string());
                         }
 
-                        if addr < pc as u8 + 2 {
-                            addr = (pc as u8 + 2) - addr;
-                            addr = !addr;
-                            addr += 1;
+                        self.cg.append(&mut codegen::int21());
+                    }
+                    "lcall" => {
+                        let at = self.cg.len() + 1;
+
+                        self.cg.append(&mut codegen::lcall(0));
+                        self.fill(op, at, Fixup::Abs16)?;
+                    }
+                    "push" | "pop" => {
+                        let direct = self
+                            .parse_data(op)?
+                            .ok_or_else(|| format!("invalid direct address `{}`", op))?;
+
+                        if name == "push" {
+                            self.cg.append(&mut codegen::push(direct));
                         } else {
-                            addr = addr - (pc as u8 + 2);
+                            self.cg.append(&mut codegen::pop(direct));
                         }
+                    }
+                    "setb" => {
+                        let bit = self.parse_bit(op)?;
+                        self.cg.append(&mut codegen::setb(bit));
+                    }
+                    "clr" if op.eq_ignore_ascii_case("A") => {
+                        self.cg.append(&mut codegen::clr_a());
+                    }
+                    "cpl" if op.eq_ignore_ascii_case("A") => {
+                        self.cg.append(&mut codegen::cpl_a());
+                    }
+                    "clr" => {
+                        let bit = self.parse_bit(op)?;
+                        self.cg.append(&mut codegen::clr(bit));
+                    }
+                    "cpl" => {
+                        let bit = self.parse_bit(op)?;
+                        self.cg.append(&mut codegen::cpl(bit));
+                    }
+                    "inc" | "dec" => {
+                        let inc = name == "inc";
 
-                        self.cg.append(&mut codegen::sjmp(addr as u8));
-                        pc += 2;
+                        let mut code = match parse_destination(op)? {
+                            _ if op.eq_ignore_ascii_case("DPTR") && inc => codegen::inc_dptr(),
+                            Destination::RegisterA if inc => codegen::inc_a(),
+                            Destination::RegisterA => codegen::dec_a(),
+                            Destination::RegisterR(rn) if inc => codegen::inc_rn(rn),
+                            Destination::RegisterR(rn) => codegen::dec_rn(rn),
+                            _ => {
+                                return Err(format!(
+                                    "`{}` takes A or Rn{}",
+                                    name,
+                                    if inc { " or DPTR" } else { "" }
+                                ))
+                            }
+                        };
+                        self.cg.append(&mut code);
                     }
-                    "ljmp" => {
-                        let mut fa: String = String::new();
-                        let addr = self.parse_address_16(op, &mut fa);
+                    "mul" | "div" => {
+                        if !op.eq_ignore_ascii_case("AB") {
+                            return Err(format!("expected `{} AB`", name));
+                        }
 
-                        if fa.len() > 0 {
-                            self.future_addrs
-                                .push((self.cg.len() + 1 as usize, fa, true));
+                        if name == "mul" {
+                            self.cg.append(&mut codegen::mul_ab());
+                        } else {
+                            self.cg.append(&mut codegen::div_ab());
+                        }
+                    }
+                    "swap" | "rlc" | "rrc" => {
+                        if !op.eq_ignore_ascii_case("A") {
+                            return Err(format!("expected `{} A`", name));
                         }
 
-                        self.cg.append(&mut codegen::ljmp(addr));
-                        pc += 3;
+                        self.cg.append(&mut match name.as_str() {
+                            "swap" => codegen::swap_a(),
+                            "rlc" => codegen::rlc_a(),
+                            _ => codegen::rrc_a(),
+                        });
+                    }
+                    "jz" | "jnz" | "jc" | "jnc" => {
+                        let cond = ["jz", "jnz", "jc", "jnc"]
+                            .iter()
+                            .position(|j| j == name)
+                            .unwrap();
+                        let at = self.cg.len() + 1;
+
+                        self.cg.append(&mut codegen::jcond(cond as u8, 0));
+                        self.fill(op, at, Fixup::Rel8)?;
+                    }
+                    "byte" => {
+                        let count = match op.strip_prefix("times") {
+                            Some(count) => parse_number_16(count.trim())? as usize,
+                            None => return Err("expected `byte` or `byte times n`".to_string()),
+                        };
+
+                        reserve(
+                            &mut self.segments,
+                            &mut self.lb,
+                            &mut self.cg,
+                            count,
+                            &mut pending,
+                        )?;
                     }
-                    _ => (),
+                    _ => return Err(format!("unknown instruction `{}`", name)),
                 },
 
-                Instruction::TwoArg {
-                    name,
-                    op1,
-                    op2,
-                    line,
-                } => match name.as_str() {
+                Instruction::NoArg { name, .. } => match name.as_str() {
+                    "byte" => {
+                        reserve(
+                            &mut self.segments,
+                            &mut self.lb,
+                            &mut self.cg,
+                            1,
+                            &mut pending,
+                        )?;
+                    }
+                    "reti" => {
+                        self.cg.append(&mut codegen::reti());
+                    }
+                    "nop" => {
+                        self.cg.append(&mut codegen::nop());
+                    }
+                    "ret" => {
+                        self.cg.append(&mut codegen::ret());
+                    }
+                    _ => return Err(format!("unknown instruction `{}`", name)),
+                },
+
+                Instruction::TwoArg { name, op1, op2, .. } => match name.as_str() {
+                    "mov" if op1.eq_ignore_ascii_case("DPTR") => {
+                        let at = self.cg.len() + 1;
+
+                        self.cg.append(&mut codegen::mov_dptr_data(0));
+                        self.fill(op2, at, Fixup::Abs16)?;
+                    }
+                    "mov" if op1.starts_with('@') || op2.starts_with('@') => {
+                        let (a, ri) = if op1.starts_with('@') {
+                            (op2, op1)
+                        } else {
+                            (op1, op2)
+                        };
+
+                        let ri = match ri[1..].to_ascii_uppercase().as_str() {
+                            "R0" => 0,
+                            "R1" => 1,
+                            _ => {
+                                return Err("only R0 and R1 can address RAM indirectly".to_string())
+                            }
+                        };
+                        if !a.eq_ignore_ascii_case("A") {
+                            return Err("indirect `mov` goes to or from A".to_string());
+                        }
+
+                        if op1.starts_with('@') {
+                            self.cg.append(&mut codegen::mov_ri_a(ri));
+                        } else {
+                            self.cg.append(&mut codegen::mov_a_ri(ri));
+                        }
+                    }
+                    "movx" => {
+                        if op1.eq_ignore_ascii_case("A") && op2.eq_ignore_ascii_case("@DPTR") {
+                            self.cg.append(&mut codegen::movx_a_dptr());
+                        } else if op1.eq_ignore_ascii_case("@DPTR") && op2.eq_ignore_ascii_case("A")
+                        {
+                            self.cg.append(&mut codegen::movx_dptr_a());
+                        } else {
+                            return Err("Invalid operands for `movx` instruction".to_string());
+                        }
+                    }
                     "mov" => {
-                        let dest = parse_destination(op1);
-                        let src = parse_source(op2);
+                        let dest = self.data_destination(op1)?;
+                        let src = match parse_half(op2) {
+                            Some(_) => Source::Immediate(0),
+                            None => self.data_source(op2)?,
+                        };
 
                         match dest {
                             Destination::RegisterR(rn) => match src {
@@ -137,8 +545,14 @@ impl IPContext {
                                 Source::RegisterR(rm) => {
                                     self.cg.append(&mut codegen::mov_rn_rn(rn, rm));
                                 }
-                                Source::Label(name) => {
-                                    // TODO: implement
+                                Source::Label(label) => {
+                                    return Err(unknown_operand(name, &label));
+                                }
+                                Source::Direct(_) => {
+                                    return Err(
+                                        "Invalid source for `mov` instruction (mov Rn, direct)"
+                                            .to_string(),
+                                    );
                                 }
                             },
                             Destination::RegisterA => match src {
@@ -151,11 +565,15 @@ impl IPContext {
                                 Source::RegisterB => {
                                     self.cg.append(&mut codegen::mov_a_b());
                                 }
-                                Source::Label(_name) => {
-                                    // TODO: implement
+                                Source::Direct(direct) => {
+                                    self.cg.append(&mut codegen::mov_a_direct(direct));
+                                }
+                                Source::Label(label) => {
+                                    return Err(unknown_operand(name, &label));
                                 }
                                 Source::RegisterA => {
-                                    panic!("Invalid source for `mov` instruction (mov A, A)");
+                                    return Err("Invalid source for `mov` instruction (mov A, A)"
+                                        .to_string());
                                 }
                             },
                             Destination::RegisterB => match src {
@@ -166,25 +584,155 @@ impl IPContext {
                                     self.cg.append(&mut codegen::mov_b_rn(rn));
                                 }
                                 Source::RegisterB => {
-                                    panic!("Invalid source for `mov` instruction (mov B, B)");
+                                    return Err("Invalid source for `mov` instruction (mov B, B)"
+                                        .to_string());
                                 }
-                                Source::Label(_name) => {
-                                    // TODO: implement
+                                Source::Label(label) => {
+                                    return Err(unknown_operand(name, &label));
                                 }
                                 Source::RegisterA => {
                                     self.cg.append(&mut codegen::mov_b_a());
                                 }
+                                Source::Direct(_) => {
+                                    return Err(
+                                        "Invalid source for `mov` instruction (mov B, direct)"
+                                            .to_string(),
+                                    );
+                                }
                             },
-                            _ => {}
+                            Destination::Direct(direct) => {
+                                match src {
+                                    Source::Immediate(data) => {
+                                        self.cg.append(&mut codegen::mov_direct_data(direct, data));
+                                    }
+                                    Source::RegisterA => {
+                                        self.cg.append(&mut codegen::mov_direct_a(direct));
+                                    }
+                                    _ => {
+                                        return Err("Invalid source for `mov` instruction (mov direct, ...)".to_string());
+                                    }
+                                }
+                            }
+                            Destination::Label(label) => {
+                                return Err(unknown_operand(name, &label));
+                            }
                         }
+                    }
+                    "set" => {
+                        let bit = self.parse_bit(op1)?;
 
-                        pc += 2;
+                        match parse_number(op2)? {
+                            0 => self.cg.append(&mut codegen::clr(bit)),
+                            1 => self.cg.append(&mut codegen::setb(bit)),
+                            _ => return Err("a bit can only be set to 0 or 1".to_string()),
+                        }
+                    }
+                    "jb" | "jnb" => {
+                        let bit = self.parse_bit(op1)?;
+                        let at = self.cg.len() + 2;
+
+                        if name == "jb" {
+                            self.cg.append(&mut codegen::jb(bit, 0));
+                        } else {
+                            self.cg.append(&mut codegen::jnb(bit, 0));
+                        }
+                        self.fill(op2, at, Fixup::Rel8)?;
+                    }
+                    "addc" | "subb" => {
+                        if !op1.eq_ignore_ascii_case("A") {
+                            return Err(format!("`{}` works on A", name));
+                        }
+                        let src = match parse_half(op2) {
+                            Some(_) => Source::Immediate(0),
+                            None => parse_source(op2)?,
+                        };
+
+                        let mut code = match (name.as_str(), src) {
+                            ("addc", Source::Immediate(data)) => codegen::addc_a_data(data),
+                            ("addc", Source::RegisterR(rn)) => codegen::addc_a_rn(rn),
+                            ("subb", Source::Immediate(data)) => codegen::subb_a_data(data),
+                            ("subb", Source::RegisterR(rn)) => codegen::subb_a_rn(rn),
+                            _ => return Err(format!("`{}` takes A and #data or Rn", name)),
+                        };
+                        self.cg.append(&mut code);
+                    }
+                    "anl" | "orl" | "xrl" => {
+                        let op = ["anl", "orl", "xrl"]
+                            .iter()
+                            .position(|l| l == name)
+                            .unwrap() as u8;
+
+                        if !op1.eq_ignore_ascii_case("A") {
+                            return Err(format!("`{}` works on A", name));
+                        }
+
+                        let mut code = match self.data_source(op2)? {
+                            Source::Immediate(data) => codegen::logic_a_data(op, data),
+                            Source::Direct(direct) => codegen::logic_a_direct(op, direct),
+                            Source::RegisterB => codegen::logic_a_direct(op, sfr::B),
+                            _ => {
+                                return Err(format!(
+                                    "`{}` takes A and #data or a direct address",
+                                    name
+                                ))
+                            }
+                        };
+                        self.cg.append(&mut code);
+                    }
+                    "djnz" => {
+                        let rn = match parse_destination(op1)? {
+                            Destination::RegisterR(rn) => rn,
+                            _ => return Err("`djnz` counts down Rn".to_string()),
+                        };
+                        let at = self.cg.len() + 1;
+
+                        self.cg.append(&mut codegen::djnz(rn, 0));
+                        self.fill(op2, at, Fixup::Rel8)?;
+                    }
+                    "cjne" => {
+                        let (value, target) =
+                            match op2.split_once(',') {
+                                Some((value, target)) if op1.eq_ignore_ascii_case("A") => {
+                                    (value.trim(), target.trim())
+                                }
+                                _ => return Err(
+                                    "expected `cjne A, #data, label` or `cjne A, direct, label`"
+                                        .to_string(),
+                                ),
+                            };
+                        let at = self.cg.len() + 2;
+
+                        /* the immediate follows the opcode here, not the last byte */
+                        if let Some(label) = value
+                            .strip_prefix('#')
+                            .filter(|l| self.ram_address(l).is_some())
+                        {
+                            self.refer(label, at - 1, Fixup::Low);
+                        }
+
+                        let mut code = match self.data_source(value)? {
+                            Source::Immediate(data) => codegen::cjne_a_data(data, 0),
+                            Source::Direct(direct) => codegen::cjne_a_direct(direct, 0),
+                            Source::RegisterB => codegen::cjne_a_direct(sfr::B, 0),
+                            _ => {
+                                return Err(
+                                    "`cjne` compares A with #data or a direct address".to_string()
+                                )
+                            }
+                        };
+                        self.cg.append(&mut code);
+                        self.fill(target, at, Fixup::Rel8)?;
                     }
                     "add" => {
-                        let dest = parse_destination(op1);
-                        let src = parse_source(op2);
+                        let dest = parse_destination(op1)?;
+                        let src = match parse_half(op2) {
+                            Some(_) => Source::Immediate(0),
+                            None => parse_source(op2)?,
+                        };
 
-                        assert!(matches!(dest, Destination::RegisterA));
+                        if !matches!(dest, Destination::RegisterA) {
+                            return Err("`add` works on A".to_string());
+                        }
 
                         match src {
                             Source::RegisterR(rn) => {
@@ -199,142 +747,755 @@ impl IPContext {
                             Source::RegisterB => {
                                 self.cg.append(&mut codegen::add_a_b());
                             }
-                            Source::Label(_name) => {}
+                            Source::Direct(_) | Source::Label(_) => {
+                                return Err("`add` takes A and #data, Rn, A or B".to_string());
+                            }
                         }
-
-                        pc += 2;
                     }
-                    _ => (),
+                    _ => return Err(format!("unknown instruction `{}`", name)),
                 },
 
-                Instruction::Label { name, line } => {
+                Instruction::Label { name, .. }
+                    if self.lb.contains_key(name)
+                        || self.segments.labels.contains_key(name)
+                        || self.segments.pending.contains(name) =>
+                {
+                    return Err(format!("label `{}` is defined twice", name));
+                }
+
+                Instruction::Label { name, .. } if self.segments.current != Segment::Code => {
+                    self.segments.label(name);
+                }
+
+                Instruction::Label { name, .. } => {
                     self.lb.insert(name.clone(), pc as usize);
+                    pending.push(name.clone());
                 }
 
-                Instruction::End { line } => {
+                Instruction::End { .. } => {
+                    self.segments.select(Segment::Code);
                     self.cg.push(0);
                 }
-                _ => (),
             }
+
+            /* #HIGH label, #LOW label and #label in RAM, the immediate is the last byte */
+            if let Instruction::TwoArg { name, op1, op2, .. } = ins {
+                let emitted = self.cg.len() > pc as usize;
+                let at = self.cg.len().wrapping_sub(1);
+
+                let immediate_last = ["mov", "add", "addc", "subb", "anl", "orl", "xrl"];
+
+                if emitted
+                    && immediate_last.contains(&name.as_str())
+                    && !op1.eq_ignore_ascii_case("DPTR")
+                {
+                    if let Some((fixup, label)) = parse_half(op2) {
+                        self.fill(label, at, fixup)?;
+                    } else if let Some(label) = op2
+                        .strip_prefix('#')
+                        .filter(|l| self.ram_address(l).is_some())
+                    {
+                        self.refer(label, at, Fixup::Low);
+                    }
+                }
+            }
+
+            if self.cg.len() > pc as usize {
+                if self.segments.current != Segment::Code {
+                    return Err(format!(
+                        "code in {}, switch back with CSEG",
+                        self.segments.current.name()
+                    ));
+                }
+
+                pending.clear();
+            }
+
+            if self.cg.len() > 0x10000 {
+                return Err("code runs past FFFFH, the end of code memory".to_string());
+            }
+
+            /* org and CSEG AT only pad */
+            let moved = matches!(ins, Instruction::OneArg { name, .. } if name == "org" || Segment::parse(name).is_some());
+
+            if self.cg.len() > pc as usize && !moved {
+                let (file, line) = self
+                    .origins
+                    .get(ins.line() - 1)
+                    .copied()
+                    .unwrap_or((file, ins.line()));
+
+                self.lines.push(LineEntry {
+                    start: pc,
+                    end: self.cg.len() as u16,
+                    file,
+                    line,
+                    column: ins.column(),
+                });
+            }
+        }
+
+        for name in &self.externs {
+            if self.lb.contains_key(name) || self.segments.labels.contains_key(name) {
+                return Err(format!("`{}` is declared EXTRN but defined here", name));
+            }
+        }
+        for name in &self.publics {
+            if !self.lb.contains_key(name) && !self.segments.labels.contains_key(name) {
+                return Err(format!("`{}` is declared PUBLIC but never defined", name));
+            }
+        }
+        for name in self.docs.keys() {
+            if !self.lb.contains_key(name) && !self.segments.labels.contains_key(name) {
+                return Err(format!("`{}` is documented but never defined", name));
+            }
+        }
+
+        for r in &self.refs {
+            self.line = r.line;
+
+            let base = match &r.target {
+                Target::Start => 0,
+                Target::Label(name) => match self.lb.get(name) {
+                    Some(addr) => *addr as u16,
+                    None => match self.segments.labels.get(name) {
+                        Some((_, addr)) => *addr,
+                        /* left for the linker */
+                        None if self.externs.contains(name) => continue,
+                        None => return Err(format!("undefined label `{}`", name)),
+                    },
+                },
+            };
+
+            patch(&mut self.cg, r.at, r.fixup, base.wrapping_add(r.offset))?;
         }
 
-        for (addr, name, isAbs) in self.future_addrs.iter() {
-            let a = self.lb[name] as usize;
+        Ok(())
+    }
+}
+
+fn is_linkage(name: &str) -> bool {
+    name.eq_ignore_ascii_case("PUBLIC") || name.eq_ignore_ascii_case("EXTRN")
+}
+
+/**
+ * `#HIGH label` or `#LOW(label)`, one byte of an address
+ */
+pub fn parse_half(op: &str) -> Option<(Fixup, &str)> {
+    let op = op.strip_prefix('#')?;
+    let (fixup, rest) = match op.get(..4) {
+        Some(high) if high.eq_ignore_ascii_case("HIGH") => (Fixup::High, &op[4..]),
+        _ => match op.get(..3) {
+            Some(low) if low.eq_ignore_ascii_case("LOW") => (Fixup::Low, &op[3..]),
+            _ => return None,
+        },
+    };
 
-            if *isAbs {
-                self.cg[*addr] = (a >> 8) as u8;
-                self.cg[*addr + 1] = a as u8;
-            } else {
-                self.cg[*addr] = (a - *addr) as u8;
+    let label = match rest.trim().strip_prefix('(') {
+        Some(inner) => inner.strip_suffix(')')?.trim(),
+        /* without parentheses there has to be a space, `#LOWER` is a label */
+        None if rest.starts_with(' ') => rest.trim(),
+        None => return None,
+    };
+
+    Some((fixup, label))
+}
+
+/**
+ * Fills `value` into `code` at `at` the way `fixup` says
+ */
+pub fn patch(code: &mut [u8], at: usize, fixup: Fixup, value: u16) -> Result<(), String> {
+    match fixup {
+        Fixup::Abs16 => {
+            code[at] = (value >> 8) as u8;
+            code[at + 1] = value as u8;
+        }
+        Fixup::Page11 => {
+            let next = at as u16 + 1;
+
+            if value & 0xF800 != next & 0xF800 {
+                return Err(format!(
+                    "{:04X}H is outside the 2K page of the ajmp or acall at {:04X}H",
+                    value,
+                    at - 1
+                ));
             }
+
+            code[at - 1] = (code[at - 1] & 0xF8) | ((value >> 8) & 0x07) as u8;
+            code[at] = value as u8;
+        }
+        Fixup::Rel8 => {
+            code[at] = rel_offset(value, at as u16 + 1)?;
         }
+        Fixup::High => code[at] = (value >> 8) as u8,
+        Fixup::Low => code[at] = value as u8,
     }
+
+    Ok(())
 }
 
-pub fn parse_number(s: &str) -> u8 {
-    let mut isneg = false;
-    if s[0..1].eq("-") {
-        isneg = true;
-        panic!("negative numbers are not supported.");
+/**
+ * Parses a number as the assembler writes them (1FH, 101B)
+ * or with a 0x prefix, defaulting to decimal
+ */
+pub fn parse_value(s: &str) -> Result<u32, String> {
+    let err = || format!("invalid number `{}`", s);
+
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).map_err(|_| err())
+    } else if let Some(hex) = s.strip_suffix('H').or_else(|| s.strip_suffix('h')) {
+        u32::from_str_radix(hex, 16).map_err(|_| err())
+    } else if let Some(bin) = s.strip_suffix('B').or_else(|| s.strip_suffix('b')) {
+        u32::from_str_radix(bin, 2).map_err(|_| err())
+    } else {
+        s.parse::<u32>().map_err(|_| err())
     }
+}
+
+/* an operand that is not a register, number or RAM label */
+fn unknown_operand(name: &str, op: &str) -> String {
+    format!(
+        "`{}` cannot take `{}`, it is not a register, number or RAM label",
+        name, op
+    )
+}
 
-    let mut p = s;
-    if isneg {
-        p = &p[1..];
+/* address after `AT` in a segment directive */
+fn segment_at(name: &str, op: &str) -> Result<u16, String> {
+    match op.split_once(char::is_whitespace) {
+        Some((at, addr)) if at.eq_ignore_ascii_case("AT") => parse_number_16(addr.trim()),
+        _ => Err(format!("expected `{} AT address`", name)),
     }
+}
+
+/**
+ * Regions that `ds` and `dbit` take after AT or org
+ * Their addresses are fixed by the source, so they are collected
+ * before assembling for reservations without AT to go around.
+ * Anything malformed is left for the assembler to report.
+ */
+fn fixed_regions(raw: &[Instruction]) -> Vec<Region> {
+    let mut res = Vec::new();
+    let mut segment = Segment::Code;
+    let mut at = None;
+
+    for ins in raw {
+        match ins {
+            Instruction::OneArg { name, op, .. } if Segment::parse(name).is_some() => {
+                segment = Segment::parse(name).unwrap();
+                at = segment_at(name, op).ok().map(|addr| addr as usize);
+            }
+            Instruction::NoArg { name, .. } if Segment::parse(name).is_some() => {
+                segment = Segment::parse(name).unwrap();
+                at = None;
+            }
+            Instruction::OneArg { name, op, .. } if name == "org" => {
+                at = parse_number_16(op).ok().map(|addr| addr as usize);
+            }
+            Instruction::OneArg { name, op, .. } if name == "ds" || name == "dbit" => {
+                if let (Some(start), Ok(size)) = (at, parse_number_16(op)) {
+                    let end = start + size as usize;
 
-    let mut res: u8;
-    if p.ends_with('H') || p.ends_with('h') {
-        res = u8::from_str_radix(&s[..p.len() - 1], 16).unwrap()
-    } else if p.ends_with('B') || p.ends_with('b') {
-        res = u8::from_str_radix(&s[..p.len() - 1], 2).unwrap()
-    } else if p.ends_with('O') || p.ends_with('o') {
-        res = u8::from_str_radix(&s[..p.len() - 1], 8).unwrap()
-    } else if p.ends_with('D') || p.ends_with('d') {
-        res = u8::from_str_radix(&s[..p.len() - 1], 10).unwrap()
+                    if segment != Segment::Code && size > 0 && end <= segment.size() {
+                        res.push(Region {
+                            segment,
+                            start,
+                            end,
+                        });
+                    }
+                    at = Some(end);
+                }
+            }
+            Instruction::End { .. } => {
+                segment = Segment::Code;
+                at = None;
+            }
+            _ => (),
+        }
+    }
+
+    res
+}
+
+/**
+ * Moves the current segment's location counter for `org`
+ * Code can only move forward, padding with zeros,
+ * since what is behind it has been placed already.
+ */
+fn org(segments: &mut Segments, cg: &mut Vec<u8>, addr: u16) -> Result<(), String> {
+    let pc = cg.len();
+
+    if segments.current != Segment::Code {
+        segments.org(addr as usize)
+    } else if addr as usize >= pc {
+        cg.resize(addr as usize, 0);
+        Ok(())
     } else {
-        res = p.parse::<u8>().unwrap()
+        Err(format!(
+            "code at {:04X}H would overlap code placed up to {:04X}H",
+            addr, pc
+        ))
     }
+}
 
-    // if isneg {
-    //     res = res - 1;
-    //     res = !res;
+/**
+ * Reserves `count` bytes of RAM for `byte`
+ * Each reserved byte's address goes into the code, and the
+ * labels just before it resolve to the first one.
+ */
+fn reserve(
+    segments: &mut Segments,
+    lb: &mut HashMap<String, usize>,
+    cg: &mut Vec<u8>,
+    count: usize,
+    pending: &mut Vec<String>,
+) -> Result<(), String> {
+    if count == 0 {
+        return Err("`byte times 0` reserves nothing".to_string());
+    }
 
-    //     if res < 128 {
-    //         panic!("negative number out of 8-bit bounds.");
-    //     }
-    // } else {
-    //     if res > 127 {
-    //         panic!("number out of 8-bit bounds.");
-    //     }
-    // }
+    let addr = segments.allocate(count)?;
 
-    res
+    for name in pending.drain(..) {
+        lb.remove(&name);
+        segments.labels.insert(name, (Segment::Data, addr as u16));
+    }
+
+    cg.extend(addr..addr + count as u8);
+    Ok(())
 }
 
-pub fn parse_number_16(s: &str) -> u16 {
-    let mut isneg = false;
-    if s[0..1].eq("-") {
-        isneg = true;
-        panic!("negative numbers are not supported.");
+/* a jump or address operand that is left for a label */
+fn label(op: &str) -> Result<String, String> {
+    if op.is_empty() {
+        return Err("missing operand".to_string());
     }
 
-    let mut p = s;
-    if isneg {
-        p = &p[1..];
+    Ok(op.to_string())
+}
+
+/**
+ * Parses a byte as the assembler writes them (1FH, 101B, 17O, 10D or 10)
+ */
+pub fn parse_number(s: &str) -> Result<u8, String> {
+    let value = parse_number_16(s)?;
+
+    u8::try_from(value).map_err(|_| format!("`{}` does not fit in a byte", s))
+}
+
+pub fn parse_number_16(s: &str) -> Result<u16, String> {
+    if s.is_empty() {
+        return Err("missing number".to_string());
+    }
+    if s.starts_with('-') {
+        return Err(format!("negative numbers are not supported: `{}`", s));
+    }
+
+    let (digits, radix) = match s.char_indices().last() {
+        Some((i, 'H' | 'h')) => (&s[..i], 16),
+        Some((i, 'B' | 'b')) => (&s[..i], 2),
+        Some((i, 'O' | 'o')) => (&s[..i], 8),
+        Some((i, 'D' | 'd')) => (&s[..i], 10),
+        _ => (s, 10),
+    };
+
+    let value =
+        u32::from_str_radix(digits, radix).map_err(|_| format!("invalid number `{}`", s))?;
+    u16::try_from(value).map_err(|_| format!("`{}` does not fit in 16 bits", s))
+}
+
+/**
+ * Offset of a relative jump from `next` to `target`
+ */
+pub fn rel_offset(target: u16, next: u16) -> Result<u8, String> {
+    let offset = target.wrapping_sub(next) as i16;
+
+    if !(-128..=127).contains(&offset) {
+        return Err(format!("relative jump target out of range: {}", offset));
     }
 
-    let mut res: u16;
-    if p.ends_with('H') || p.ends_with('h') {
-        res = u16::from_str_radix(&s[..p.len() - 1], 16).unwrap()
-    } else if p.ends_with('B') || p.ends_with('b') {
-        res = u16::from_str_radix(&s[..p.len() - 1], 2).unwrap()
-    } else if p.ends_with('O') || p.ends_with('o') {
-        res = u16::from_str_radix(&s[..p.len() - 1], 8).unwrap()
-    } else if p.ends_with('D') || p.ends_with('d') {
-        res = u16::from_str_radix(&s[..p.len() - 1], 10).unwrap()
+    Ok(offset as u8)
+}
+
+/**
+ * Parses a direct address, either an SFR name or a number
+ * None when it is neither, it may be a label then.
+ */
+pub fn parse_direct(s: &str) -> Result<Option<u8>, String> {
+    if let Some(addr) = sfr::address_of(s) {
+        Ok(Some(addr))
+    } else if s.starts_with(|c: char| c.is_ascii_digit()) {
+        parse_number(s).map(Some)
     } else {
-        res = p.parse::<u16>().unwrap()
+        Ok(None)
     }
+}
 
-    // if isneg {
-    //     res = res - 1;
-    //     res = !res;
+/**
+ * Parses a bit address
+ * Either a bit name (TR0), a byte and bit index (TCON.4, 20H.1)
+ * or a plain bit address (7FH)
+ */
+pub fn parse_bit(s: &str) -> Result<u8, String> {
+    if let Some(bit) = sfr::bit_address_of(s) {
+        return Ok(bit);
+    }
 
-    //     if res < 128 {
-    //         panic!("negative number out of 8-bit bounds.");
-    //     }
-    // } else {
-    //     if res > 127 {
-    //         panic!("number out of 8-bit bounds.");
-    //     }
-    // }
+    if let Some((byte, index)) = s.split_once('.') {
+        let addr =
+            parse_direct(byte)?.ok_or_else(|| format!("invalid byte in bit address `{}`", s))?;
+        let index = index
+            .parse::<u8>()
+            .map_err(|_| format!("invalid bit index in `{}`", s))?;
 
-    res
+        if index > 7 {
+            return Err(format!("bit index out of range: {}", s));
+        }
+
+        return match addr {
+            0x20..=0x2F => Ok((addr - 0x20) * 8 + index),
+            _ if addr >= 0x80 && addr.is_multiple_of(8) => Ok(addr + index),
+            _ => Err(format!("byte is not bit addressable: {}", s)),
+        };
+    }
+
+    parse_number(s)
 }
 
-pub fn parse_destination(s: &str) -> Destination {
-    if s.starts_with('R') || s.starts_with('r') {
-        Destination::RegisterR(s[1..].parse::<u8>().unwrap())
+/* R0 to R7, None for anything not shaped like a register */
+fn parse_register(s: &str) -> Result<Option<u8>, String> {
+    match s.strip_prefix(['R', 'r']) {
+        Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+            match n.parse::<u8>() {
+                Ok(rn) if rn < 8 => Ok(Some(rn)),
+                _ => Err(format!("no register `{}`, there are R0 to R7", s)),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn parse_destination(s: &str) -> Result<Destination, String> {
+    Ok(if let Some(rn) = parse_register(s)? {
+        Destination::RegisterR(rn)
     } else if s.eq("A") || s.eq("a") {
         Destination::RegisterA
     } else if s.eq("B") || s.eq("b") {
         Destination::RegisterB
+    } else if let Some(addr) = parse_direct(s)? {
+        Destination::Direct(addr)
     } else {
-        Destination::Label(s.to_string())
-    }
+        Destination::Label(label(s)?)
+    })
 }
 
-pub fn parse_source(s: &str) -> Source {
-    if s.starts_with("#") {
-        Source::Immediate(parse_number(&s[1..]))
-    } else if s.starts_with('R') || s.starts_with('r') {
-        Source::RegisterR(s[1..].parse::<u8>().unwrap())
+pub fn parse_source(s: &str) -> Result<Source, String> {
+    Ok(if let Some(number) = s.strip_prefix('#') {
+        Source::Immediate(parse_number(number)?)
+    } else if let Some(rn) = parse_register(s)? {
+        Source::RegisterR(rn)
     } else if s.eq("A") || s.eq("a") {
         Source::RegisterA
     } else if s.eq("B") || s.eq("b") {
         Source::RegisterB
+    } else if let Some(addr) = parse_direct(s)? {
+        Source::Direct(addr)
     } else {
-        Source::Label(s.to_string())
+        Source::Label(label(s)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::lexer::LexerContext;
+
+    fn assemble(src: &str) -> Result<Vec<u8>, String> {
+        let mut lc = LexerContext::new(src.to_string());
+        lc.run();
+
+        let mut pc = IPContext::new(lc.dt);
+        match pc.run() {
+            Ok(()) => Ok(pc.cg),
+            Err(message) => Err(format!("{}: {}", pc.line, message)),
+        }
+    }
+
+    /* where the labels of the data segments end up */
+    fn place(src: &str) -> Result<HashMap<String, (Segment, u16)>, String> {
+        let mut lc = LexerContext::new(src.to_string());
+        lc.run();
+
+        let mut pc = IPContext::new(lc.dt);
+        match pc.run() {
+            Ok(()) => Ok(pc.segments.labels),
+            Err(message) => Err(format!("{}: {}", pc.line, message)),
+        }
+    }
+
+    #[test]
+    fn any_whitespace_separates_the_operands() {
+        assert_eq!(assemble("mov\tA,\t#1\nend\n"), assemble("mov A, #1\nend\n"));
+        assert_eq!(
+            assemble("mov\tA, #1\nend\n").unwrap(),
+            vec![0x8C, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn comments_end_the_line() {
+        assert_eq!(
+            assemble("mov A, #1 ; one\nend ; done\n").unwrap(),
+            vec![0x8C, 0x01, 0x00]
+        );
+        assert_eq!(
+            assemble("start: ; loop\nsjmp start\nend\n").unwrap(),
+            vec![0x28, 0xFE, 0x00]
+        );
+    }
+
+    #[test]
+    fn labels_are_defined_once() {
+        assert_eq!(
+            assemble("x:\nret\nx:\nret\nend\n").unwrap_err(),
+            "3: label `x` is defined twice"
+        );
+    }
+
+    #[test]
+    fn code_stops_at_the_end_of_memory() {
+        assert_eq!(
+            assemble("org 0FFFFH\nret\nret\nend\n").unwrap_err(),
+            "3: code runs past FFFFH, the end of code memory"
+        );
+        assert_eq!(assemble("org 0FFFFH\nret\n").unwrap().len(), 0x10000);
+    }
+
+    #[test]
+    fn unknown_input_is_reported() {
+        assert_eq!(
+            assemble("ret\nfoo A\nend\n").unwrap_err(),
+            "2: unknown instruction `foo`"
+        );
+        assert_eq!(
+            assemble("halt\nend\n").unwrap_err(),
+            "1: unknown instruction `halt`"
+        );
+        assert_eq!(
+            assemble("mov A, msg\nend\n").unwrap_err(),
+            "1: `mov` cannot take `msg`, it is not a register, number or RAM label"
+        );
+        assert_eq!(
+            assemble("add A, 30H\nend\n").unwrap_err(),
+            "1: `add` takes A and #data, Rn, A or B"
+        );
+        assert_eq!(
+            assemble("add B, #1\nend\n").unwrap_err(),
+            "1: `add` works on A"
+        );
+        assert_eq!(
+            assemble("sjmp nowhere\nend\n").unwrap_err(),
+            "1: undefined label `nowhere`"
+        );
+    }
+
+    #[test]
+    fn bad_numbers_and_registers_are_reported() {
+        assert_eq!(
+            assemble("mov R8, #1\nend\n").unwrap_err(),
+            "1: no register `R8`, there are R0 to R7"
+        );
+        assert_eq!(
+            assemble("mov A, #300\nend\n").unwrap_err(),
+            "1: `300` does not fit in a byte"
+        );
+        assert_eq!(
+            assemble("mov A, #12Z\nend\n").unwrap_err(),
+            "1: invalid number `12Z`"
+        );
+        assert_eq!(
+            assemble("mov A, #\nend\n").unwrap_err(),
+            "1: missing number"
+        );
+        assert_eq!(
+            assemble("org 10000H\nend\n").unwrap_err(),
+            "1: `10000H` does not fit in 16 bits"
+        );
+        assert_eq!(
+            assemble("setb 20H.9\nend\n").unwrap_err(),
+            "1: bit index out of range: 20H.9"
+        );
+        assert_eq!(
+            assemble("setb 30H.1\nend\n").unwrap_err(),
+            "1: byte is not bit addressable: 30H.1"
+        );
+    }
+
+    #[test]
+    fn registers_do_not_swallow_labels() {
+        let src = "mov DPTR, #RESULT\nRESULT:\nret\nend\n";
+
+        assert_eq!(assemble(src).unwrap(), vec![0xCD, 0x00, 0x03, 0xCA, 0x00]);
+        assert_eq!(
+            assemble("mov DPTR, RESULT\nRESULT:\nret\nend\n"),
+            assemble(src)
+        );
+    }
+
+    #[test]
+    fn byte_reserves_ram_for_the_labels_before_it() {
+        let src = "sjmp start\ncount:\nbyte\nbuffer:\nbyte times 3\nstart:\nmov count, #5\nmov A, buffer\npush count\nend\n";
+        let mut lc = LexerContext::new(src.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        assert_eq!(pc.segments.labels["count"], (Segment::Data, 0x20));
+        assert_eq!(pc.segments.labels["buffer"], (Segment::Data, 0x21));
+        assert!(!pc.lb.contains_key("count"));
+        assert_eq!(pc.lb["start"], 6);
+        /* the reserved addresses sit in the code, then direct addressing */
+        assert_eq!(&pc.cg[2..6], &[0x20, 0x21, 0x22, 0x23]);
+        assert_eq!(
+            pc.cg[6..],
+            assemble("mov 20H, #5\nmov A, 21H\npush 20H\nend\n").unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn byte_reports_what_it_cannot_do() {
+        assert_eq!(
+            assemble("byte times 0\nend\n").unwrap_err(),
+            "1: `byte times 0` reserves nothing"
+        );
+        assert_eq!(
+            assemble("byte times 41H\nend\n").unwrap_err(),
+            "1: out of internal RAM: no room for 65 bytes between 20H and 5FH"
+        );
+        assert_eq!(
+            assemble("byte 3\nend\n").unwrap_err(),
+            "1: expected `byte` or `byte times n`"
+        );
+        assert_eq!(
+            assemble("x:\nbyte\nx:\nret\nend\n").unwrap_err(),
+            "3: label `x` is defined twice"
+        );
+    }
+
+    #[test]
+    fn set_writes_a_bit() {
+        assert_eq!(
+            assemble("set PSW.3, 1\nset PSW.4, 0\nend\n"),
+            assemble("setb PSW.3\nclr PSW.4\nend\n")
+        );
+        assert_eq!(
+            assemble("set PSW.3, 2\nend\n").unwrap_err(),
+            "1: a bit can only be set to 0 or 1"
+        );
+    }
+
+    #[test]
+    fn segments_without_at_share_internal_ram() {
+        let labels =
+            place("DSEG\na:\nds 1\nISEG\nb:\nds 1\nDSEG\nc:\nds 1\nCSEG\nret\nend\n").unwrap();
+        assert_eq!(labels["a"], (Segment::Data, 0x20));
+        assert_eq!(labels["b"], (Segment::Idata, 0x21));
+        assert_eq!(labels["c"], (Segment::Data, 0x22));
+
+        let labels = place("x:\nbyte\nDSEG\ny:\nds 2\nCSEG\nret\nend\n").unwrap();
+        assert_eq!(labels["x"], (Segment::Data, 0x20));
+        assert_eq!(labels["y"], (Segment::Data, 0x21));
+
+        let labels = place("DSEG\ny:\nds 2\nCSEG\nx:\nbyte\nend\n").unwrap();
+        assert_eq!(labels["x"], (Segment::Data, 0x22));
+    }
+
+    #[test]
+    fn bits_and_bytes_keep_apart() {
+        /* 20H holds bits 00H to 07H */
+        let labels = place("DSEG\nd:\nds 1\nBSEG\nf:\ndbit 1\nCSEG\nret\nend\n").unwrap();
+        assert_eq!(labels["d"], (Segment::Data, 0x20));
+        assert_eq!(labels["f"], (Segment::Bit, 0x08));
+
+        let labels = place("BSEG\nf:\ndbit 1\nDSEG\nd:\nds 1\nCSEG\nret\nend\n").unwrap();
+        assert_eq!(labels["f"], (Segment::Bit, 0x00));
+        assert_eq!(labels["d"], (Segment::Data, 0x21));
+    }
+
+    #[test]
+    fn only_at_can_clash() {
+        assert_eq!(
+            place("DSEG AT 30H\nds 2\nISEG AT 31H\nds 1\nCSEG\nret\nend\n").unwrap_err(),
+            "4: ISEG 31H to 31H overlaps DSEG 30H to 31H"
+        );
+        assert_eq!(
+            place("BSEG AT 0\ndbit 1\nDSEG AT 20H\nds 1\nCSEG\nret\nend\n").unwrap_err(),
+            "4: DSEG 20H to 20H overlaps BSEG 00H to 00H"
+        );
+
+        /* without AT goes around what AT places later */
+        let labels = place("DSEG\na:\nds 2\nDSEG AT 21H\nb:\nds 1\nCSEG\nx:\nbyte\nend\n").unwrap();
+        assert_eq!(labels["a"], (Segment::Data, 0x22));
+        assert_eq!(labels["b"], (Segment::Data, 0x21));
+        assert_eq!(labels["x"], (Segment::Data, 0x20));
+    }
+
+    #[test]
+    fn segment_labels_are_addresses() {
+        let src = "DSEG\ncount:\nds 1\nBSEG\nready:\ndbit 1\nXSEG AT 100H\nbuf:\nds 10H\n\
+                   CSEG\nmov count, #5\nmov A, #count\nsetb ready\nmov DPTR, #buf\nend\n";
+
+        assert_eq!(
+            assemble(src),
+            assemble("mov 20H, #5\nmov A, #20H\nsetb 8\nmov DPTR, #100H\nend\n")
+        );
+        assert_eq!(
+            assemble("DSEG\nmov A, #1\nend\n").unwrap_err(),
+            "2: code in DSEG, switch back with CSEG"
+        );
+        assert_eq!(
+            assemble("ds 1\nend\n").unwrap_err(),
+            "1: `ds` reserves data, it belongs in DSEG, ISEG, BSEG or XSEG"
+        );
+        assert_eq!(
+            assemble("DSEG 30H\nend\n").unwrap_err(),
+            "1: expected `DSEG AT address`"
+        );
+    }
+
+    #[test]
+    fn empty_operands_are_reported() {
+        assert_eq!(
+            assemble("mov DPTR, #\nend\n").unwrap_err(),
+            "1: missing operand"
+        );
+        assert_eq!(
+            assemble("mov A, #LOW()\nend\n").unwrap_err(),
+            "1: missing operand"
+        );
+        assert_eq!(
+            assemble("here:\ncjne A, #1,\nend\n").unwrap_err(),
+            "2: missing operand"
+        );
+        assert_eq!(
+            assemble("here:\nmov A, #LOW(here)\nsjmp $\nend\n").unwrap(),
+            vec![0x8C, 0x00, 0x28, 0xFE, 0x00]
+        );
+    }
+
+    #[test]
+    fn linkage_is_checked() {
+        assert_eq!(
+            assemble("EXTRN x\nx:\nret\nend\n").unwrap_err(),
+            "4: `x` is declared EXTRN but defined here"
+        );
+        assert_eq!(
+            assemble("PUBLIC x, y\nx:\nret\nend\n").unwrap_err(),
+            "4: `y` is declared PUBLIC but never defined"
+        );
+        assert_eq!(
+            assemble("DOC x, \"takes A\"\nret\nend\n").unwrap_err(),
+            "3: `x` is documented but never defined"
+        );
     }
 }
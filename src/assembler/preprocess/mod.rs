@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/* files may include each other this deep */
+const MAX_DEPTH: usize = 16;
+
+/**
+ * Source text with includes expanded and defines substituted
+ * `origins[n]` is the (index into `files`, line) that line `n + 1`
+ * of `text` came from.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+    pub text: String,
+    pub files: Vec<String>,
+    pub origins: Vec<(usize, usize)>,
+}
+
+/**
+ * Expands `include "file"` lines and replaces defined names
+ * Included files are looked up next to the including file,
+ * then in `include_paths` in order.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessor {
+    pub include_paths: Vec<PathBuf>,
+    pub defines: HashMap<String, String>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor {
+            include_paths: Vec::new(),
+            defines: HashMap::new(),
+        }
+    }
+
+    /**
+     * `NAME=VALUE`, or just `NAME` for 1
+     */
+    pub fn define(&mut self, definition: &str) {
+        let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+        self.defines
+            .insert(name.trim().to_string(), value.trim().to_string());
+    }
+
+    pub fn run_file(&self, path: &str) -> Result<Source, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.run(path, &text)
+    }
+
+    /**
+     * `name` is recorded as the file `text` came from
+     */
+    pub fn run(&self, name: &str, text: &str) -> Result<Source, String> {
+        let mut res = Source::default();
+        let mut stack = Vec::new();
+
+        self.expand(name, text, &mut res, &mut stack)?;
+        Ok(res)
+    }
+
+    fn expand(
+        &self,
+        name: &str,
+        text: &str,
+        res: &mut Source,
+        stack: &mut Vec<String>,
+    ) -> Result<(), String> {
+        if stack.len() >= MAX_DEPTH || stack.iter().any(|f| f == name) {
+            return Err(format!("{}: recursive include", name));
+        }
+        stack.push(name.to_string());
+
+        res.files.push(name.to_string());
+        let file = res.files.len() - 1;
+
+        for (index, line) in text.lines().enumerate() {
+            let included = line
+                .trim()
+                .strip_prefix("include ")
+                .map(|f| f.trim().trim_matches('"'));
+
+            match included {
+                Some(included) => {
+                    let path = self.resolve(name, included).ok_or(format!(
+                        "{}:{}: cannot find included file `{}`",
+                        name,
+                        index + 1,
+                        included
+                    ))?;
+                    let contents =
+                        fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+
+                    self.expand(&path, &contents, res, stack)?;
+                }
+                None => {
+                    res.text.push_str(&self.substitute(line));
+                    res.text.push('\n');
+                    res.origins.push((file, index + 1));
+                }
+            }
+        }
+
+        stack.pop();
+        Ok(())
+    }
+
+    fn resolve(&self, from: &str, name: &str) -> Option<String> {
+        let beside = Path::new(from).parent().map(|dir| dir.join(name));
+
+        beside
+            .into_iter()
+            .chain(self.include_paths.iter().map(|dir| dir.join(name)))
+            .chain(std::iter::once(PathBuf::from(name)))
+            .find(|path| path.is_file())
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+
+    /**
+     * Replaces whole words only, so `COUNT` does not touch `COUNT2`
+     */
+    fn substitute(&self, line: &str) -> String {
+        if self.defines.is_empty() {
+            return line.to_string();
+        }
+
+        let mut res = String::new();
+        let mut word = String::new();
+
+        for c in line.chars().chain(std::iter::once('\n')) {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                word.push(c);
+                continue;
+            }
+
+            match self.defines.get(&word) {
+                Some(value) => res.push_str(value),
+                None => res.push_str(&word),
+            }
+            word.clear();
+
+            if c != '\n' {
+                res.push(c);
+            }
+        }
+
+        res
+    }
+}
@@ -0,0 +1,371 @@
+use super::disasm::{self, hex16};
+use super::engine;
+use super::symbols::{self, SymbolTable};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+/* opcodes that leave subroutines, engine::is_call tells the ones entering them */
+const RET: u8 = 0xCA;
+const RETI: u8 = 0xC7;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cost {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+/**
+ * Attributes machine cycles to instructions and call stacks
+ * Keeps a shadow call stack of subroutine entry addresses, pushed
+ * by lcall and interrupts and popped by ret and reti. The bottom
+ * of the stack is where profiling started.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    pub instructions: HashMap<u16, Cost>,
+    /* cycles spent with exactly this call stack */
+    pub stacks: HashMap<Vec<u16>, u64>,
+    /* (caller, callee) entry addresses */
+    pub calls: HashMap<(u16, u16), u64>,
+    pub stack: Vec<u16>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            instructions: HashMap::new(),
+            stacks: HashMap::new(),
+            calls: HashMap::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    fn charge(&mut self, cycles: u64) {
+        match self.stacks.get_mut(&self.stack) {
+            Some(total) => *total += cycles,
+            None => {
+                self.stacks.insert(self.stack.clone(), cycles);
+            }
+        }
+    }
+
+    fn enter(&mut self, callee: u16) {
+        let caller = *self.stack.last().unwrap();
+
+        *self.calls.entry((caller, callee)).or_insert(0) += 1;
+        self.stack.push(callee);
+    }
+
+    /**
+     * Counts an executed instruction
+     * `next` is the address execution continued at.
+     */
+    pub fn record(&mut self, pc: u16, opcode: u8, cycles: u32, next: u16) {
+        if self.stack.is_empty() {
+            self.stack.push(pc);
+        }
+
+        let cost = self.instructions.entry(pc).or_default();
+        cost.count += 1;
+        cost.cycles += cycles as u64;
+        self.charge(cycles as u64);
+
+        match opcode {
+            _ if engine::is_call(opcode) => self.enter(next),
+            /* never pop the frame profiling started in */
+            RET | RETI if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => (),
+        }
+    }
+
+    /**
+     * An interrupt vectored to `vector`, taking `cycles`
+     */
+    pub fn interrupt(&mut self, vector: u16, cycles: u32) {
+        if self.stack.is_empty() {
+            return;
+        }
+
+        self.enter(vector);
+        self.charge(cycles as u64);
+    }
+
+    pub fn clear(&mut self) {
+        self.instructions.clear();
+        self.stacks.clear();
+        self.calls.clear();
+        self.stack.clear();
+    }
+
+    pub fn total(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /**
+     * Summed over instructions, grouped by the label before them
+     */
+    pub fn by_label(&self, symbols: &SymbolTable) -> Vec<(String, Cost)> {
+        let mut res: HashMap<String, Cost> = HashMap::new();
+
+        for (pc, cost) in &self.instructions {
+            let name = match symbols::nearest(symbols, *pc) {
+                Some((name, _)) => name.to_string(),
+                None => hex16(*pc),
+            };
+
+            let total = res.entry(name).or_default();
+            total.count += cost.count;
+            total.cycles += cost.cycles;
+        }
+
+        let mut res: Vec<(String, Cost)> = res.into_iter().collect();
+        res.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        res
+    }
+
+    /**
+     * (entry, self cycles, total cycles including callees)
+     */
+    pub fn by_subroutine(&self) -> Vec<(u16, u64, u64)> {
+        let mut res: BTreeMap<u16, (u64, u64)> = BTreeMap::new();
+
+        for (stack, cycles) in &self.stacks {
+            res.entry(*stack.last().unwrap()).or_default().0 += cycles;
+
+            /* recursion counts once per stack */
+            let mut seen: Vec<u16> = Vec::new();
+            for entry in stack {
+                if !seen.contains(entry) {
+                    res.entry(*entry).or_default().1 += cycles;
+                    seen.push(*entry);
+                }
+            }
+        }
+
+        let mut res: Vec<(u16, u64, u64)> = res.into_iter().map(|(a, (s, t))| (a, s, t)).collect();
+        res.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        res
+    }
+
+    /**
+     * Sorted flat profile: instructions, labels, subroutines
+     * and the call graph. `rom` is used to disassemble.
+     */
+    pub fn write_flat(
+        &self,
+        out: &mut dyn Write,
+        rom: &[u8],
+        symbols: &SymbolTable,
+    ) -> io::Result<()> {
+        let total = self.total().max(1);
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total as f64;
+
+        writeln!(out, "total {} cycles", self.total())?;
+
+        writeln!(out, "\ninstructions:")?;
+        writeln!(
+            out,
+            "{:>10} {:>6} {:>10}  addr   instruction",
+            "cycles", "%", "count"
+        )?;
+
+        let mut instructions: Vec<(&u16, &Cost)> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+
+        for (pc, cost) in instructions {
+            let ins = disasm::disassemble(rom, *pc, symbols);
+            writeln!(
+                out,
+                "{:>10} {:>6.2} {:>10}  {:04X}   {}",
+                cost.cycles,
+                percent(cost.cycles),
+                cost.count,
+                pc,
+                ins.text
+            )?;
+        }
+
+        writeln!(out, "\nlabels:")?;
+        writeln!(out, "{:>10} {:>6} {:>10}  label", "cycles", "%", "count")?;
+
+        for (name, cost) in self.by_label(symbols) {
+            writeln!(
+                out,
+                "{:>10} {:>6.2} {:>10}  {}",
+                cost.cycles,
+                percent(cost.cycles),
+                cost.count,
+                name
+            )?;
+        }
+
+        writeln!(out, "\nsubroutines:")?;
+        writeln!(
+            out,
+            "{:>10} {:>6} {:>10} {:>6}  subroutine",
+            "self", "%", "total", "%"
+        )?;
+
+        for (entry, own, all) in self.by_subroutine() {
+            writeln!(
+                out,
+                "{:>10} {:>6.2} {:>10} {:>6.2}  {}",
+                own,
+                percent(own),
+                all,
+                percent(all),
+                name_of(symbols, entry)
+            )?;
+        }
+
+        writeln!(out, "\ncall graph:")?;
+
+        let mut calls: Vec<(&(u16, u16), &u64)> = self.calls.iter().collect();
+        calls.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        for ((caller, callee), count) in calls {
+            writeln!(
+                out,
+                "{:>10}  {} -> {}",
+                count,
+                name_of(symbols, *caller),
+                name_of(symbols, *callee)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * One line per call stack, `main;delay;loop 1234`,
+     * the input flamegraph.pl and speedscope expect
+     */
+    pub fn write_folded(&self, out: &mut dyn Write, symbols: &SymbolTable) -> io::Result<()> {
+        let mut lines: Vec<(String, u64)> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names: Vec<String> = stack.iter().map(|a| name_of(symbols, *a)).collect();
+                (names.join(";"), *cycles)
+            })
+            .collect();
+        lines.sort();
+
+        for (stack, cycles) in lines {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Label at exactly `addr`, or its address
+ */
+fn name_of(symbols: &SymbolTable, addr: u16) -> String {
+    match symbols::nearest(symbols, addr) {
+        Some((name, 0)) => name.to_string(),
+        _ => hex16(addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::AsmContext;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+    use crate::emulator::Emulator;
+
+    /* main calls outer once, outer calls inner twice */
+    const PROGRAM: &str =
+        "main:\nlcall outer\nsjmp done\nouter:\nlcall inner\nlcall inner\nret\ninner:\nmov A, #1\nret\ndone:\nend\n";
+
+    fn profile(source: &str) -> (Profiler, Vec<u8>, SymbolTable) {
+        let mut lc = LexerContext::new(source.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx.profiler = Some(Profiler::new());
+        ctx.run();
+
+        let symbols = symbols::from_labels(&pc.lb);
+        (ctx.profiler.unwrap(), ctx.em.rom, symbols)
+    }
+
+    fn text(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn folded_stacks_carry_their_own_cycles() {
+        let (profiler, _, symbols) = profile(PROGRAM);
+
+        /* the lcall is charged to the caller, the halt takes nothing */
+        assert_eq!(
+            text(|out| profiler.write_folded(out, &symbols)),
+            "main 4\nmain;outer 6\nmain;outer;inner 6\n"
+        );
+        assert_eq!(profiler.total(), 16);
+    }
+
+    #[test]
+    fn subroutines_include_their_callees() {
+        let (profiler, _, symbols) = profile(PROGRAM);
+        let outer = symbols["outer"];
+        let inner = symbols["inner"];
+
+        assert_eq!(
+            profiler.by_subroutine(),
+            vec![(0, 4, 16), (outer, 6, 12), (inner, 6, 6)]
+        );
+        assert_eq!(profiler.calls[&(outer, inner)], 2);
+    }
+
+    #[test]
+    fn flat_profile_lists_every_section() {
+        let (profiler, rom, symbols) = profile(PROGRAM);
+        let flat = text(|out| profiler.write_flat(out, &rom, &symbols));
+
+        assert!(flat.starts_with("total 16 cycles\n"), "{}", flat);
+        assert!(
+            flat.contains("         2  12.50          2  000C   mov A, #01H\n"),
+            "{}",
+            flat
+        );
+        assert!(
+            flat.contains("         6  37.50          4  inner\n"),
+            "{}",
+            flat
+        );
+        assert!(
+            flat.contains("         4  25.00         16 100.00  main\n"),
+            "{}",
+            flat
+        );
+        assert!(
+            flat.ends_with("call graph:\n         2  outer -> inner\n         1  main -> outer\n"),
+            "{}",
+            flat
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "peripherals")]
+    fn interrupts_are_their_own_frames() {
+        let (profiler, _, symbols) = profile(
+            "ljmp main\norg 0BH\nisr:\nreti\nmain:\nmov TMOD, #01H\nmov TL0, #0FFH\nmov TH0, #0FFH\nmov IE, #82H\nsetb TR0\nend\n",
+        );
+
+        let folded = text(|out| profiler.write_folded(out, &symbols));
+        /* vectoring and the reti */
+        assert!(folded.ends_with(";isr 4\n"), "{}", folded);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::ram::{RAM_SIZE, XRAM_SIZE};
+use std::collections::HashMap;
+use std::fmt;
+
+/* register banks 0 to 3 take 00H to 1FH */
+pub const DATA_START: usize = 0x20;
+
+/* bytes at the top of RAM left for the stack */
+pub const STACK_SIZE: usize = 0x20;
+
+/* bit addresses 00H to 7FH are the bits of 20H to 2FH */
+const BIT_BASE: usize = 0x20;
+
+/**
+ * What a segment holds
+ * DSEG and ISEG share internal RAM, BSEG is
+ * its bit addressable part.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Code,
+    Data,
+    Idata,
+    Bit,
+    Xdata,
+}
+
+/* in the order object files number them */
+pub const SEGMENTS: [Segment; 5] = [
+    Segment::Code,
+    Segment::Data,
+    Segment::Idata,
+    Segment::Bit,
+    Segment::Xdata,
+];
+
+impl Segment {
+    pub fn parse(name: &str) -> Option<Segment> {
+        match name.to_ascii_uppercase().as_str() {
+            "CSEG" => Some(Segment::Code),
+            "DSEG" => Some(Segment::Data),
+            "ISEG" => Some(Segment::Idata),
+            "BSEG" => Some(Segment::Bit),
+            "XSEG" => Some(Segment::Xdata),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::Code => "CSEG",
+            Segment::Data => "DSEG",
+            Segment::Idata => "ISEG",
+            Segment::Bit => "BSEG",
+            Segment::Xdata => "XSEG",
+        }
+    }
+
+    /* where it starts without AT, internal RAM skips the register banks */
+    pub fn start(&self) -> usize {
+        match self {
+            Segment::Data | Segment::Idata => DATA_START,
+            _ => 0,
+        }
+    }
+
+    /* addresses the segment can use */
+    pub fn size(&self) -> usize {
+        match self {
+            Segment::Code => 0x10000,
+            Segment::Data | Segment::Idata => RAM_SIZE,
+            Segment::Bit => 0x80,
+            Segment::Xdata => XRAM_SIZE,
+        }
+    }
+
+    /* internal RAM bytes that addresses `start..end` take */
+    fn iram(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        match self {
+            Segment::Data | Segment::Idata => Some((start, end)),
+            Segment::Bit => Some((BIT_BASE + start / 8, BIT_BASE + (end - 1) / 8 + 1)),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Addresses `start..end` of a segment, taken by `ds`, `dbit` or `byte`
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub segment: Segment,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Region {
+    pub fn overlaps(&self, other: &Region) -> bool {
+        let intersect = |a: (usize, usize), b: (usize, usize)| a.0 < b.1 && b.0 < a.1;
+
+        if self.segment == other.segment {
+            return intersect((self.start, self.end), (other.start, other.end));
+        }
+
+        match (
+            self.segment.iram(self.start, self.end),
+            other.segment.iram(other.start, other.end),
+        ) {
+            (Some(a), Some(b)) => intersect(a, b),
+            _ => false,
+        }
+    }
+
+    /* first address of `segment` past this region, for one that overlaps it */
+    fn end_in(&self, segment: Segment) -> usize {
+        if self.segment == segment {
+            return self.end;
+        }
+
+        let (_, end) = self.segment.iram(self.start, self.end).unwrap_or((0, 0));
+        match segment {
+            Segment::Bit => end.saturating_sub(BIT_BASE) * 8,
+            _ => end,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:02X}H to {:02X}H",
+            self.segment.name(),
+            self.start,
+            self.end - 1
+        )
+    }
+}
+
+/**
+ * Location counters and reserved regions of the data segments
+ * A segment directive without AT continues the segment after what
+ * it reserved before, skipping anything else already in the way,
+ * so only regions placed with AT or org can clash. CSEG's location
+ * counter is the length of the code. Labels defined in the other
+ * segments are kept in `labels` and resolve to their RAM, bit or
+ * external RAM address.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segments {
+    pub current: Segment,
+    /* where each segment continues without AT */
+    pub counters: HashMap<Segment, usize>,
+    /* location counter after AT or org, until the next segment directive */
+    pub at: Option<usize>,
+    pub regions: Vec<Region>,
+    /* the ones reserved without AT, which the linker may move */
+    pub relocatable: Vec<Region>,
+    /* regions the source places with AT or org, known before assembling */
+    pub fixed: Vec<Region>,
+    pub labels: HashMap<String, (Segment, u16)>,
+    /* labels waiting for the next reservation to place them */
+    pub pending: Vec<String>,
+}
+
+impl Default for Segments {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Segments {
+    pub fn new() -> Segments {
+        Segments {
+            current: Segment::Code,
+            counters: HashMap::new(),
+            at: None,
+            regions: Vec::new(),
+            relocatable: Vec::new(),
+            fixed: Vec::new(),
+            labels: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /* location counter of the current data segment */
+    pub fn counter(&self) -> usize {
+        let counter = self.counters.get(&self.current).copied();
+
+        self.at.or(counter).unwrap_or(self.current.start())
+    }
+
+    /* switches to `segment` without AT */
+    pub fn select(&mut self, segment: Segment) {
+        self.place_pending(self.counter());
+        self.current = segment;
+        self.at = None;
+    }
+
+    pub fn org(&mut self, addr: usize) -> Result<(), String> {
+        if addr >= self.current.size() {
+            return Err(format!("{:04X}H is outside {}", addr, self.current.name()));
+        }
+
+        self.place_pending(self.counter());
+        self.at = Some(addr);
+        Ok(())
+    }
+
+    /**
+     * Defines `name` at the location counter
+     * Without AT that is only known once something is reserved.
+     */
+    pub fn label(&mut self, name: &str) {
+        match self.at {
+            Some(addr) => {
+                self.labels
+                    .insert(name.to_string(), (self.current, addr as u16));
+            }
+            None => self.pending.push(name.to_string()),
+        }
+    }
+
+    fn place_pending(&mut self, addr: usize) {
+        for name in self.pending.drain(..) {
+            self.labels.insert(name, (self.current, addr as u16));
+        }
+    }
+
+    /**
+     * Reserves `size` addresses at the location counter
+     * After AT or org they go exactly there and fail if they
+     * overlap anything reserved before, otherwise they go in the
+     * first gap from there on.
+     */
+    pub fn reserve(&mut self, size: usize) -> Result<usize, String> {
+        let start = self.counter();
+        if size == 0 {
+            return Ok(start);
+        }
+
+        let region = match self.at {
+            Some(_) => Region {
+                segment: self.current,
+                start,
+                end: start + size,
+            },
+            None => self.find(self.current, start, size, self.current.size())?,
+        };
+
+        if region.end > region.segment.size() {
+            return Err(format!(
+                "{} runs past the end of {}",
+                region,
+                region.segment.name()
+            ));
+        }
+        if let Some(other) = self.regions.iter().find(|r| r.overlaps(&region)) {
+            return Err(format!("{} overlaps {}", region, other));
+        }
+
+        let (start, end) = (region.start, region.end);
+        self.place_pending(start);
+        if self.at.is_none() {
+            self.relocatable.push(region.clone());
+        }
+        self.regions.push(region);
+
+        match self.at {
+            Some(_) => self.at = Some(end),
+            None => {
+                self.counters.insert(self.current, end);
+            }
+        }
+        Ok(start)
+    }
+
+    /**
+     * First `size` addresses of `segment` from `start` on that
+     * neither reserved regions nor ones placed with AT take
+     */
+    pub fn find(
+        &self,
+        segment: Segment,
+        start: usize,
+        size: usize,
+        limit: usize,
+    ) -> Result<Region, String> {
+        let mut region = Region {
+            segment,
+            start,
+            end: start + size,
+        };
+
+        while region.end <= limit {
+            let next = self
+                .regions
+                .iter()
+                .chain(&self.fixed)
+                .filter(|r| r.overlaps(&region))
+                .map(|r| r.end_in(segment))
+                .max();
+
+            match next {
+                Some(next) => {
+                    region.start = next;
+                    region.end = next + size;
+                }
+                None => return Ok(region),
+            }
+        }
+
+        Err(format!(
+            "{} is full: no room for {} more from {:02X}H",
+            segment.name(),
+            size,
+            start
+        ))
+    }
+
+    /**
+     * Finds `size` bytes of internal RAM for `byte`
+     * The first gap from 20H up to the stack area
+     * that nothing else takes.
+     */
+    pub fn allocate(&mut self, size: usize) -> Result<u8, String> {
+        let end = RAM_SIZE - STACK_SIZE;
+        let region = self
+            .find(Segment::Data, DATA_START, size, end)
+            .map_err(|_| {
+                format!(
+                    "out of internal RAM: no room for {} bytes between {:02X}H and {:02X}H",
+                    size,
+                    DATA_START,
+                    end - 1
+                )
+            })?;
+
+        let res = region.start as u8;
+        self.relocatable.push(region.clone());
+        self.regions.push(region);
+        Ok(res)
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+pub type SymbolTable = HashMap<String, u16>;
+
+/**
+ * Symbols from the labels `IPContext` collected
+ * `$` is the location counter, not a symbol
+ */
+pub fn from_labels(lb: &HashMap<String, usize>) -> SymbolTable {
+    lb.iter()
+        .filter(|(name, _)| name.as_str() != "$")
+        .map(|(name, addr)| (name.clone(), *addr as u16))
+        .collect()
+}
+
+/**
+ * Symbol file contents, one `address name` pair per line
+ * sorted by address, e.g.
+ * 0000 main
+ * 0030 loop
+ */
+pub fn write(symbols: &SymbolTable) -> String {
+    let mut sorted: Vec<(&String, &u16)> = symbols.iter().collect();
+    sorted.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+
+    let mut res = String::new();
+    for (name, addr) in sorted {
+        res.push_str(&format!("{:04X} {}\n", addr, name));
+    }
+    res
+}
+
+pub fn parse(text: &str) -> Result<SymbolTable, String> {
+    let mut symbols = SymbolTable::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+
+        let (addr, name) = line
+            .split_once(char::is_whitespace)
+            .ok_or(format!("line {}: expected `address name`", index + 1))?;
+        let addr = u16::from_str_radix(addr, 16)
+            .map_err(|_| format!("line {}: invalid address `{}`", index + 1, addr))?;
+
+        symbols.insert(name.trim().to_string(), addr);
+    }
+
+    Ok(symbols)
+}
+
+/**
+ * Name of the symbol at exactly `addr`
+ * If several share the address the alphabetically first one wins
+ */
+pub fn name_at(symbols: &SymbolTable, addr: u16) -> Option<&str> {
+    symbols
+        .iter()
+        .filter(|(_, a)| **a == addr)
+        .map(|(name, _)| name.as_str())
+        .min()
+}
+
+/**
+ * Closest symbol at or before `addr`, with the offset from it
+ */
+pub fn nearest(symbols: &SymbolTable, addr: u16) -> Option<(&str, u16)> {
+    symbols
+        .iter()
+        .filter(|(_, a)| **a <= addr)
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(name, a)| (name.as_str(), addr - a))
+}
@@ -0,0 +1,459 @@
+use super::disasm::{self, hex16, hex8};
+use super::lines::LineTable;
+use super::symbols::SymbolTable;
+use crate::binary::Reader;
+use crate::emulator::{Access, Emulator, Space};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+/* binary log header, followed by the format version */
+pub const MAGIC: &[u8; 4] = b"PLTR";
+pub const VERSION: u8 = 1;
+
+/**
+ * Registers that live outside RAM
+ * R0 to R7 are RAM, so their changes show up as memory writes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    A = 0,
+    B,
+    PSW,
+    SP,
+    DPTR,
+}
+
+const REGS: [Reg; 5] = [Reg::A, Reg::B, Reg::PSW, Reg::SP, Reg::DPTR];
+
+impl Reg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Reg::A => "A",
+            Reg::B => "B",
+            Reg::PSW => "PSW",
+            Reg::SP => "SP",
+            Reg::DPTR => "DPTR",
+        }
+    }
+
+    pub fn get(&self, em: &Emulator) -> u16 {
+        match self {
+            Reg::A => em.reg.a.get() as u16,
+            Reg::B => em.reg.b.get() as u16,
+            Reg::PSW => em.psw.get() as u16,
+            Reg::SP => em.reg.sp.get() as u16,
+            Reg::DPTR => em.reg.dptr.get(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegChange {
+    pub reg: Reg,
+    pub old: u16,
+    pub new: u16,
+}
+
+/**
+ * One traced instruction
+ * `cycle` is the cycle count before it executed.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub pc: u16,
+    pub bytes: Vec<u8>,
+    pub regs: Vec<RegChange>,
+    pub writes: Vec<Access>,
+}
+
+impl TraceEntry {
+    pub fn disassemble(&self, symbols: &SymbolTable) -> String {
+        disasm::disassemble_bytes(&self.bytes, self.pc, symbols).text
+    }
+}
+
+/**
+ * Register values before an instruction, to diff against after it
+ */
+pub struct Before {
+    cycle: u64,
+    regs: [u16; 5],
+}
+
+/**
+ * Records executed instructions
+ * Set `range` to only record instructions inside it, and give
+ * a capacity to keep only the most recent entries on long runs.
+ */
+#[derive(Debug, Default)]
+pub struct Tracer {
+    pub entries: VecDeque<TraceEntry>,
+    pub capacity: Option<usize>,
+    pub range: Option<RangeInclusive<u16>>,
+    /* entries pushed out of the ring buffer */
+    pub dropped: u64,
+}
+
+impl Tracer {
+    pub fn new() -> Tracer {
+        Tracer::default()
+    }
+
+    /**
+     * A ring buffer keeping the last `capacity` entries
+     */
+    pub fn with_capacity(capacity: usize) -> Tracer {
+        Tracer {
+            capacity: Some(capacity),
+            ..Tracer::default()
+        }
+    }
+
+    pub fn wants(&self, pc: u16) -> bool {
+        match &self.range {
+            Some(range) => range.contains(&pc),
+            None => true,
+        }
+    }
+
+    pub fn before(em: &Emulator) -> Before {
+        Before {
+            cycle: em.cycles,
+            regs: REGS.map(|r| r.get(em)),
+        }
+    }
+
+    /**
+     * Adds the instruction at `pc` given the state before it ran
+     * The writes come from the accesses the emulator recorded.
+     */
+    pub fn record(&mut self, em: &Emulator, before: Before, pc: u16, bytes: Vec<u8>) {
+        let regs = REGS
+            .iter()
+            .zip(before.regs)
+            .filter(|(reg, old)| reg.get(em) != *old)
+            .map(|(reg, old)| RegChange {
+                reg: *reg,
+                old,
+                new: reg.get(em),
+            })
+            .collect();
+
+        let writes = em
+            .accesses
+            .borrow()
+            .iter()
+            .filter(|a| a.write)
+            .copied()
+            .collect();
+
+        self.push(TraceEntry {
+            cycle: before.cycle,
+            pc,
+            bytes,
+            regs,
+            writes,
+        });
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if self.capacity == Some(0) {
+            self.dropped += 1;
+            return;
+        }
+
+        if let Some(capacity) = self.capacity {
+            while self.entries.len() >= capacity {
+                self.entries.pop_front();
+                self.dropped += 1;
+            }
+        }
+
+        self.entries.push_back(entry);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.dropped = 0;
+    }
+
+    /**
+     * One line per instruction, e.g.
+     *       12  0004  C1 30     mov 30H, A      [iram 30H] 00 -> 05  ; test.plasm:7
+     */
+    pub fn write_text(
+        &self,
+        out: &mut dyn Write,
+        symbols: &SymbolTable,
+        lines: &LineTable,
+    ) -> io::Result<()> {
+        if self.dropped > 0 {
+            writeln!(out, "; {} earlier instructions dropped", self.dropped)?;
+        }
+
+        for entry in &self.entries {
+            let bytes: Vec<String> = entry.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let mut line = format!(
+                "{:>10}  {:04X}  {:<9} {:<24}",
+                entry.cycle,
+                entry.pc,
+                bytes.join(" "),
+                entry.disassemble(symbols)
+            );
+
+            for change in &entry.regs {
+                let (old, new) = match change.reg {
+                    Reg::DPTR => (hex16(change.old), hex16(change.new)),
+                    _ => (hex8(change.old as u8), hex8(change.new as u8)),
+                };
+                line.push_str(&format!(" {}: {} -> {}", change.reg.name(), old, new));
+            }
+
+            for write in &entry.writes {
+                let location = match write.space {
+                    Space::Iram => format!("iram {}", hex8(write.addr as u8)),
+                    Space::Sfr => format!("sfr {}", hex8(write.addr as u8)),
+                    Space::Xram => format!("xram {}", hex16(write.addr)),
+                };
+                line.push_str(&format!(
+                    " [{}] {} -> {}",
+                    location,
+                    hex8(write.old),
+                    hex8(write.value)
+                ));
+            }
+
+            if let Some(location) = lines.lookup(entry.pc) {
+                line.push_str(&format!("  ; {}", location));
+            }
+
+            writeln!(out, "{}", line.trim_end())?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Binary log, big endian like everything else:
+     * magic, version, entry count (u32), then per entry
+     * cycle (u64), pc (u16), byte count (u8), bytes,
+     * register change count (u8), each as reg (u8) old (u16) new (u16),
+     * write count (u8), each as space (u8) addr (u16) old (u8) value (u8)
+     */
+    pub fn write_binary(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        out.write_all(&(self.entries.len() as u32).to_be_bytes())?;
+
+        for entry in &self.entries {
+            let mut buf = Vec::new();
+
+            buf.extend_from_slice(&entry.cycle.to_be_bytes());
+            buf.extend_from_slice(&entry.pc.to_be_bytes());
+            buf.push(entry.bytes.len() as u8);
+            buf.extend_from_slice(&entry.bytes);
+
+            buf.push(entry.regs.len() as u8);
+            for change in &entry.regs {
+                buf.push(change.reg as u8);
+                buf.extend_from_slice(&change.old.to_be_bytes());
+                buf.extend_from_slice(&change.new.to_be_bytes());
+            }
+
+            /* no instruction writes anywhere near 255 locations */
+            buf.push(entry.writes.len() as u8);
+            for write in &entry.writes {
+                buf.push(write.space as u8);
+                buf.extend_from_slice(&write.addr.to_be_bytes());
+                buf.push(write.old);
+                buf.push(write.value);
+            }
+
+            out.write_all(&buf)?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Reads back a log written by `write_binary`
+     */
+    pub fn read_binary(input: &mut dyn Read) -> Result<Vec<TraceEntry>, String> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+        let mut reader = Reader::new(&data, "trace log");
+
+        if reader.take(4)? != MAGIC {
+            return Err("not a trace log".to_string());
+        }
+
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported trace log version {}", version));
+        }
+
+        let count = reader.u32()?;
+        let mut entries = Vec::new();
+
+        for _ in 0..count {
+            let cycle = reader.u64()?;
+            let pc = reader.u16()?;
+            let len = reader.u8()? as usize;
+            let bytes = reader.take(len)?.to_vec();
+
+            let mut regs = Vec::new();
+            for _ in 0..reader.u8()? {
+                let reg = *REGS
+                    .get(reader.u8()? as usize)
+                    .ok_or("invalid register in trace log")?;
+                let old = reader.u16()?;
+                let new = reader.u16()?;
+
+                regs.push(RegChange { reg, old, new });
+            }
+
+            let mut writes = Vec::new();
+            for _ in 0..reader.u8()? {
+                let space = match reader.u8()? {
+                    0 => Space::Iram,
+                    1 => Space::Sfr,
+                    2 => Space::Xram,
+                    _ => return Err("invalid memory space in trace log".to_string()),
+                };
+                let addr = reader.u16()?;
+                let old = reader.u8()?;
+                let value = reader.u8()?;
+
+                writes.push(Access {
+                    space,
+                    addr,
+                    write: true,
+                    old,
+                    value,
+                });
+            }
+
+            entries.push(TraceEntry {
+                cycle,
+                pc,
+                bytes,
+                regs,
+                writes,
+            });
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::AsmContext;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+
+    fn trace(source: &str, tracer: Tracer) -> Tracer {
+        let mut lc = LexerContext::new(source.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx.tracer = Some(tracer);
+        ctx.run();
+        ctx.tracer.unwrap()
+    }
+
+    const PROGRAM: &str = "mov A, #5\nmov 30H, A\nmov B, #2\nend\n";
+
+    #[test]
+    fn text_shows_registers_and_writes() {
+        let tracer = trace(PROGRAM, Tracer::new());
+        let mut out = Vec::new();
+        tracer
+            .write_text(&mut out, &SymbolTable::new(), &LineTable::new())
+            .unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "         0  0000  8C 05     mov A, #05H              A: 00H -> 05H"
+        );
+        assert!(lines[1].ends_with("[iram 30H] 00H -> 05H"), "{}", lines[1]);
+        assert!(lines[2].contains("B: 00H -> 02H"), "{}", lines[2]);
+        /* the halt is traced too */
+        assert!(lines[3].ends_with("end"), "{}", lines[3]);
+    }
+
+    #[test]
+    fn binary_logs_read_back() {
+        let tracer = trace(PROGRAM, Tracer::new());
+        let mut out = Vec::new();
+        tracer.write_binary(&mut out).unwrap();
+
+        let entries = Tracer::read_binary(&mut out.as_slice()).unwrap();
+        assert_eq!(entries, Vec::from(tracer.entries));
+    }
+
+    #[test]
+    fn bad_logs_are_rejected() {
+        let tracer = trace(PROGRAM, Tracer::new());
+        let mut out = Vec::new();
+        tracer.write_binary(&mut out).unwrap();
+
+        let truncated = &out[..out.len() - 1];
+        assert_eq!(
+            Tracer::read_binary(&mut &truncated[..]),
+            Err("trace log is truncated".to_string())
+        );
+
+        assert_eq!(
+            Tracer::read_binary(&mut &b"PLTX"[..]),
+            Err("not a trace log".to_string())
+        );
+
+        out[4] = VERSION + 1;
+        assert!(Tracer::read_binary(&mut out.as_slice())
+            .unwrap_err()
+            .starts_with("unsupported trace log version"));
+    }
+
+    #[test]
+    fn range_limits_what_is_recorded() {
+        let tracer = trace(
+            PROGRAM,
+            Tracer {
+                range: Some(2..=3),
+                ..Tracer::new()
+            },
+        );
+
+        let pcs: Vec<u16> = tracer.entries.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![2]);
+    }
+
+    #[test]
+    fn ring_buffer_keeps_the_last_entries() {
+        let tracer = trace(PROGRAM, Tracer::with_capacity(2));
+
+        let pcs: Vec<u16> = tracer.entries.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![4, 6]);
+        assert_eq!(tracer.dropped, 2);
+
+        let mut out = Vec::new();
+        tracer
+            .write_text(&mut out, &SymbolTable::new(), &LineTable::new())
+            .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("; 2 earlier instructions dropped\n"));
+    }
+}
@@ -0,0 +1,220 @@
+use super::engine::Step;
+use crate::emulator::{Access, Emulator, Space};
+use crate::sfr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchSpace {
+    Iram,
+    Sfr,
+    Xram,
+    /* a bit address, 0x00 to 0x7F in RAM, the rest in SFRs */
+    Bit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /* any change of value, whoever made it */
+    Change,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Eq(u8),
+    Ne(u8),
+    Lt(u8),
+    Le(u8),
+    Gt(u8),
+    Ge(u8),
+}
+
+impl Condition {
+    pub fn holds(&self, value: u8) -> bool {
+        match *self {
+            Condition::Eq(v) => value == v,
+            Condition::Ne(v) => value != v,
+            Condition::Lt(v) => value < v,
+            Condition::Le(v) => value <= v,
+            Condition::Gt(v) => value > v,
+            Condition::Ge(v) => value >= v,
+        }
+    }
+
+    /**
+     * Parses `== 0xFF` style conditions, already split into
+     * the operator and the value
+     */
+    pub fn parse(op: &str, value: u8) -> Option<Condition> {
+        match op {
+            "==" => Some(Condition::Eq(value)),
+            "!=" => Some(Condition::Ne(value)),
+            "<" => Some(Condition::Lt(value)),
+            "<=" => Some(Condition::Le(value)),
+            ">" => Some(Condition::Gt(value)),
+            ">=" => Some(Condition::Ge(value)),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Stops execution when an instruction touches a location
+ * Read and write watchpoints see the accesses instructions make,
+ * so registers that also live in SFR space (ACC, B, PSW, SP, DPTR)
+ * only trigger when they are addressed directly. Change watchpoints
+ * compare values around every instruction and catch everything,
+ * including flags set by the peripherals.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub space: WatchSpace,
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub condition: Option<Condition>,
+}
+
+/**
+ * A triggered watchpoint and the instruction that triggered it
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub step: Step,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl Watchpoint {
+    /**
+     * Current value of the watched location
+     * Bits read as 0 or 1
+     */
+    pub fn value(&self, em: &Emulator) -> u8 {
+        match self.space {
+            WatchSpace::Iram => em.ram.read(self.addr as usize),
+            WatchSpace::Sfr => em.peek_direct(self.addr as u8),
+            WatchSpace::Xram => em.xram[self.addr as usize],
+            WatchSpace::Bit => {
+                let (addr, index) = sfr::bit_location(self.addr as u8);
+                (em.peek_direct(addr) >> index) & 1
+            }
+        }
+    }
+
+    /**
+     * The old and new value if `access` touches this watchpoint
+     */
+    fn matches(&self, access: &Access) -> Option<(u8, u8)> {
+        if access.write != (self.kind == WatchKind::Write) {
+            return None;
+        }
+
+        match self.space {
+            WatchSpace::Iram if access.space == Space::Iram && access.addr == self.addr => {
+                Some((access.old, access.value))
+            }
+            WatchSpace::Sfr if access.space == Space::Sfr && access.addr == self.addr => {
+                Some((access.old, access.value))
+            }
+            WatchSpace::Xram if access.space == Space::Xram && access.addr == self.addr => {
+                Some((access.old, access.value))
+            }
+            WatchSpace::Bit => {
+                let (addr, index) = sfr::bit_location(self.addr as u8);
+                let space = if addr < 0x80 { Space::Iram } else { Space::Sfr };
+
+                if access.space == space && access.addr == addr as u16 {
+                    Some(((access.old >> index) & 1, (access.value >> index) & 1))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /**
+     * Checks this watchpoint against one executed instruction
+     * given what it accessed and the watched value before and after it
+     */
+    pub fn hit(&self, accesses: &[Access], before: u8, now: u8) -> Option<(u8, u8)> {
+        let (old, new) = match self.kind {
+            WatchKind::Change if now == before => return None,
+            WatchKind::Change => (before, now),
+            _ => accesses.iter().find_map(|access| self.matches(access))?,
+        };
+
+        match self.condition {
+            Some(condition) if !condition.holds(new) => None,
+            _ => Some((old, new)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::{AsmContext, StopReason};
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+
+    fn context(source: &str) -> AsmContext {
+        let mut lc = LexerContext::new(source.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx
+    }
+
+    fn watch(space: WatchSpace, addr: u16, kind: WatchKind) -> Watchpoint {
+        Watchpoint {
+            space,
+            addr,
+            kind,
+            condition: None,
+        }
+    }
+
+    #[test]
+    fn writes_stop_after_the_instruction() {
+        let mut ctx = context("mov 30H, #1\nmov 31H, #2\nmov 30H, #3\nend\n");
+        ctx.add_watchpoint(watch(WatchSpace::Iram, 0x31, WatchKind::Write));
+
+        match ctx.run() {
+            StopReason::Watchpoint(hit) => {
+                assert_eq!(hit.step.pc, 3);
+                assert_eq!((hit.old, hit.new), (0, 2));
+            }
+            other => panic!("expected the write to 31H, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn conditions_filter_hits() {
+        let mut ctx = context("mov 30H, #1\nmov 30H, #2\nmov 30H, #3\nend\n");
+        ctx.add_watchpoint(Watchpoint {
+            condition: Some(Condition::Ge(3)),
+            ..watch(WatchSpace::Iram, 0x30, WatchKind::Write)
+        });
+
+        match ctx.run() {
+            StopReason::Watchpoint(hit) => assert_eq!(hit.step.pc, 6),
+            other => panic!("expected the third write, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interrupt_polling_is_not_a_read() {
+        let mut ctx = context("mov IE, #80H\nmov A, #1\nmov B, #2\nmov A, IE\nend\n");
+        ctx.add_watchpoint(watch(WatchSpace::Sfr, sfr::IE as u16, WatchKind::Read));
+
+        match ctx.run() {
+            StopReason::Watchpoint(hit) => assert_eq!(hit.step.pc, 7),
+            other => panic!("expected the read by `mov A, IE`, got {:?}", other),
+        }
+    }
+}
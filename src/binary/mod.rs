@@ -0,0 +1,84 @@
+/**
+ * Reads the big endian files written by the tracer, snapshots,
+ * objects and libraries
+ * `what` names the file in errors, e.g. "object file is truncated".
+ */
+pub struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+    what: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], what: &'static str) -> Reader<'a> {
+        Reader { data, at: 0, what }
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let res = self
+            .at
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.at..end))
+            .ok_or(format!("{} is truncated", self.what))?;
+
+        self.at += len;
+        Ok(res)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /* a u16 length and UTF-8 */
+    pub fn string(&mut self) -> Result<String, String> {
+        let len = self.u16()? as usize;
+
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| format!("invalid name in {}", self.what))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_big_endian() {
+        let data = [
+            1, 0x12, 0x34, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, b'h', b'i',
+        ];
+        let mut reader = Reader::new(&data, "test file");
+
+        assert_eq!(reader.u8(), Ok(1));
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.u32(), Ok(0x100));
+        assert_eq!(reader.u64(), Ok(2));
+        assert_eq!(reader.string(), Ok("hi".to_string()));
+        assert_eq!(reader.u8(), Err("test file is truncated".to_string()));
+    }
+
+    #[test]
+    fn huge_lengths_are_truncation() {
+        let data = [0xFF, 0xFF, 0xC3];
+        let mut reader = Reader::new(&data, "test file");
+
+        assert_eq!(reader.string(), Err("test file is truncated".to_string()));
+        assert_eq!(
+            reader.take(usize::MAX),
+            Err("test file is truncated".to_string())
+        );
+        assert_eq!(reader.u8(), Ok(0xC3));
+    }
+}
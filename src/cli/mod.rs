@@ -0,0 +1,763 @@
+use prelude_rust::assembler::archive::{self, Archive};
+use prelude_rust::assembler::coverage::{Coverage, Report};
+use prelude_rust::assembler::disasm;
+use prelude_rust::assembler::hex;
+use prelude_rust::assembler::listing;
+use prelude_rust::assembler::object::assemble_object_file;
+use prelude_rust::assembler::parser::parse_value;
+use prelude_rust::assembler::profile::Profiler;
+use prelude_rust::assembler::symbols;
+use prelude_rust::assembler::trace::Tracer;
+#[cfg(feature = "debugger")]
+use prelude_rust::debugger::Debugger;
+#[cfg(all(unix, feature = "peripherals"))]
+use prelude_rust::peripherals::serial::Pty;
+#[cfg(feature = "peripherals")]
+use prelude_rust::peripherals::serial::{ReaderInput, ScriptedInput};
+#[cfg(feature = "debugger")]
+use prelude_rust::repl::Repl;
+use prelude_rust::syscall::StreamIo;
+use prelude_rust::{
+    assemble_file, link, AsmContext, Error, Image, Machine, Object, Options, StopReason,
+};
+#[cfg(feature = "debugger")]
+use prelude_rust::{dap, gdb};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/* process exit codes, a program's own exit code is passed through */
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_USAGE: i32 = 1;
+pub const EXIT_ASSEMBLY: i32 = 2;
+pub const EXIT_FAULT: i32 = 3;
+pub const EXIT_CYCLE_LIMIT: i32 = 4;
+
+const USAGE: &str = "\
+usage: prelude-rust COMMAND [OPTIONS]
+
+commands:
+  asm FILE.plasm       assemble
+    -o FILE              output file (default: input with .bin or .hex)
+    -f bin|hex|obj       output format (default: bin), obj is an
+                         object file for `link`
+    -l FILE              write a listing
+    --sym FILE           write a symbol file
+  link FILE... -o FILE link objects into an image, FILEs may be
+                       .obj objects, .plasm sources or .lib libraries,
+                       only library members that are needed are linked
+    -f bin|hex           output format (default: bin)
+    -m FILE              write a map file
+    --sym FILE           write a symbol file
+  ar LIB [FILE...]     create library LIB from .obj or .plasm FILEs,
+                       or list its members and their public symbols
+                       with what `DOC` says about them
+  run FILE [-- ARG...] assemble or load an image and run it,
+                       passing ARGs as in lib/rsc/argparser.plasm
+    --cycles N           stop after N machine cycles
+    --entry ADDR|LABEL   start executing here
+    --input FILE         console input (default: stdin)
+    --output FILE        console output (default: stdout)
+    --serial-in FILE     serial port input, `-` for stdin
+    --serial-out FILE    serial port output, `-` for stdout
+    --serial-pty         attach the serial port to a pseudo terminal
+  disasm FILE          disassemble an image or source file
+    --sym FILE           read labels from a symbol file
+  debug FILE           interactive debugger
+    --sym FILE           read labels from a symbol file
+  repl [FILE]          assemble and run one line at a time,
+                       after loading FILE if given
+  trace FILE           run, writing every executed instruction
+    -o FILE              output file (default: stdout)
+    -f text|bin          trace format (default: text)
+    --cycles N           stop after N machine cycles
+  coverage FILE [annotate|lcov FILE]
+  profile FILE [flat|folded]
+  gdb FILE [host:port|unix:path]
+  dap
+
+source files also take
+    -D NAME[=VALUE]      replace NAME with VALUE (default 1)
+    -I DIR               look for included files in DIR
+
+FILE may be .plasm source, an Intel .hex image or a raw binary.
+
+exit status: 0 on success or the program's own exit code,
+1 for usage and file errors, 2 for assembly and link errors,
+3 for program faults, 4 when the cycle limit is reached
+";
+
+/**
+ * Failure to report before exiting with `code`
+ */
+#[derive(Debug)]
+pub struct Exit {
+    pub code: i32,
+    pub message: String,
+}
+
+impl Exit {
+    pub fn usage(message: impl Into<String>) -> Exit {
+        Exit {
+            code: EXIT_USAGE,
+            message: message.into(),
+        }
+    }
+}
+
+/**
+ * Command line options, `-o FILE`, `--cycles=N` and `-DNAME` forms
+ * Options listed in `takes_value` consume the next argument.
+ */
+#[derive(Debug, Default)]
+pub struct Args {
+    pub positional: Vec<String>,
+    pub options: HashMap<String, Vec<String>>,
+    /* everything after `--`, if it was given */
+    pub rest: Option<Vec<String>>,
+}
+
+impl Args {
+    pub fn parse(args: &[String], takes_value: &[&str]) -> Result<Args, Exit> {
+        let mut res = Args::default();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                res.rest = Some(iter.cloned().collect());
+                break;
+            }
+
+            if !arg.starts_with('-') || arg == "-" {
+                res.positional.push(arg.clone());
+                continue;
+            }
+
+            /* -DNAME=1, -Ipath, --cycles=10 */
+            let (name, attached) = match arg.split_once('=') {
+                Some((name, value)) if arg.starts_with("--") => {
+                    (name.to_string(), Some(value.to_string()))
+                }
+                _ if !arg.starts_with("--") && arg.len() > 2 => {
+                    (arg[..2].to_string(), Some(arg[2..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+
+            if !takes_value.contains(&name.as_str()) {
+                if attached.is_some() {
+                    return Err(Exit::usage(format!(
+                        "option `{}` does not take a value",
+                        name
+                    )));
+                }
+                res.options.entry(name).or_default();
+                continue;
+            }
+
+            let value = match attached {
+                Some(value) => value,
+                None => iter
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| Exit::usage(format!("option `{}` needs a value", name)))?,
+            };
+            res.options.entry(name).or_default().push(value);
+        }
+
+        Ok(res)
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options
+            .get(name)
+            .and_then(|v| v.last())
+            .map(|s| s.as_str())
+    }
+
+    pub fn values(&self, name: &str) -> &[String] {
+        self.options.get(name).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn input(&self) -> Result<&str, Exit> {
+        match self.positional.first() {
+            Some(file) => Ok(file),
+            None => Err(Exit::usage("missing input file")),
+        }
+    }
+
+    pub fn check(&self, known: &[&str]) -> Result<(), Exit> {
+        match self.options.keys().find(|k| !known.contains(&k.as_str())) {
+            Some(unknown) => Err(Exit::usage(format!("unknown option `{}`", unknown))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_source(path: &str) -> bool {
+    !(path.ends_with(".hex") || path.ends_with(".bin") || path.ends_with(".img"))
+}
+
+fn exit(e: Error) -> Exit {
+    match e {
+        Error::Io(message) => Exit::usage(message),
+        Error::Assembly(message) | Error::Link(message) => Exit {
+            code: EXIT_ASSEMBLY,
+            message,
+        },
+    }
+}
+
+/**
+ * Assembles `.plasm` files, reads images as they are
+ */
+pub fn load(path: &str, args: &Args) -> Result<Image, Exit> {
+    if !is_source(path) {
+        return Image::load(path).map_err(exit);
+    }
+
+    assemble_file(path, &options(args)).map_err(exit)
+}
+
+fn options(args: &Args) -> Options {
+    Options {
+        defines: args.values("-D").to_vec(),
+        include_paths: args.values("-I").iter().map(PathBuf::from).collect(),
+        ..Options::new()
+    }
+}
+
+/**
+ * Loads the input file, adding labels from `--sym`
+ */
+fn load_input(args: &Args) -> Result<Image, Exit> {
+    let mut res = load(args.input()?, args)?;
+
+    if let Some(file) = args.value("--sym") {
+        let err = |e: String| Exit::usage(format!("{}: {}", file, e));
+        let text = fs::read_to_string(file).map_err(|e| err(e.to_string()))?;
+
+        res.symbols.extend(symbols::parse(&text).map_err(err)?);
+    }
+
+    Ok(res)
+}
+
+fn create(path: &str) -> Result<fs::File, Exit> {
+    fs::File::create(path).map_err(|e| Exit::usage(format!("{}: {}", path, e)))
+}
+
+fn write_error(path: &str) -> impl Fn(io::Error) -> Exit + '_ {
+    move |e| Exit::usage(format!("{}: {}", path, e))
+}
+
+/**
+ * Exit code for how a program stopped, explaining faults
+ */
+fn finish(ctx: &AsmContext, reason: &StopReason) -> i32 {
+    match reason {
+        StopReason::Halted => EXIT_OK,
+        StopReason::Exited(code) => *code as i32,
+        StopReason::CycleLimit => {
+            eprintln!("prelude-rust: {}", ctx.explain(reason));
+            EXIT_CYCLE_LIMIT
+        }
+        _ => {
+            eprintln!("prelude-rust: {}", ctx.explain(reason));
+            EXIT_FAULT
+        }
+    }
+}
+
+fn cycle_limit(args: &Args) -> Result<u64, Exit> {
+    match args.value("--cycles") {
+        Some(n) => parse_value(n).map(|n| n as u64).map_err(Exit::usage),
+        None => Ok(u64::MAX),
+    }
+}
+
+fn cmd_asm(args: &Args) -> Result<i32, Exit> {
+    args.check(&["-o", "-f", "-l", "--sym", "-D", "-I"])?;
+    let path = args.input()?;
+
+    let format = args.value("-f").unwrap_or("bin");
+    let stem = path.strip_suffix(".plasm").unwrap_or(path);
+    let output = match args.value("-o") {
+        Some(file) => file.to_string(),
+        None => format!("{}.{}", stem, format),
+    };
+
+    if format == "obj" {
+        if args.has("-l") || args.has("--sym") {
+            return Err(Exit::usage(
+                "listings and symbol files are written when linking",
+            ));
+        }
+
+        let object = assemble_object_file(path, &options(args)).map_err(exit)?;
+        object
+            .write(&mut create(&output)?)
+            .map_err(write_error(&output))?;
+        return Ok(EXIT_OK);
+    }
+
+    let image = load(path, args)?;
+    write_image(&image, format, &output)?;
+
+    if let Some(path) = args.value("-l") {
+        let source = image
+            .source
+            .as_ref()
+            .ok_or(Exit::usage("listings need source input"))?;
+
+        listing::write(&mut create(path)?, source, &image.lines, &image.code)
+            .map_err(write_error(path))?;
+    }
+
+    if let Some(path) = args.value("--sym") {
+        fs::write(path, symbols::write(&image.symbols)).map_err(write_error(path))?;
+    }
+
+    Ok(EXIT_OK)
+}
+
+fn write_image(image: &Image, format: &str, output: &str) -> Result<(), Exit> {
+    let mut file = create(output)?;
+
+    match format {
+        "bin" => file.write_all(&image.code),
+        "hex" => hex::write(&image.code, &mut file),
+        other => return Err(Exit::usage(format!("unknown output format `{}`", other))),
+    }
+    .map_err(write_error(output))
+}
+
+/**
+ * Object file, or `.plasm` source assembled into one
+ */
+fn object(path: &str, args: &Args) -> Result<Object, Exit> {
+    if path.ends_with(".obj") {
+        Object::load(path).map_err(exit)
+    } else {
+        assemble_object_file(path, &options(args)).map_err(exit)
+    }
+}
+
+fn cmd_link(args: &Args) -> Result<i32, Exit> {
+    args.check(&["-o", "-f", "-m", "--sym", "-D", "-I"])?;
+    if args.positional.is_empty() {
+        return Err(Exit::usage("missing object files"));
+    }
+    let output = args
+        .value("-o")
+        .ok_or(Exit::usage("missing output file, use -o"))?;
+
+    let mut objects = Vec::new();
+    let mut archives = Vec::new();
+
+    for path in &args.positional {
+        if path.ends_with(".lib") {
+            archives.push(Archive::load(path).map_err(exit)?);
+        } else {
+            objects.push(object(path, args)?);
+        }
+    }
+
+    let objects = archive::resolve(objects, &archives);
+    let (image, map) = link(&objects).map_err(exit)?;
+
+    write_image(&image, args.value("-f").unwrap_or("bin"), output)?;
+
+    if let Some(path) = args.value("-m") {
+        map.write(&mut create(path)?).map_err(write_error(path))?;
+    }
+
+    if let Some(path) = args.value("--sym") {
+        fs::write(path, symbols::write(&image.symbols)).map_err(write_error(path))?;
+    }
+
+    Ok(EXIT_OK)
+}
+
+fn cmd_ar(args: &Args) -> Result<i32, Exit> {
+    args.check(&["-D", "-I"])?;
+    let path = args.input()?;
+
+    if args.positional.len() == 1 {
+        let archive = Archive::load(path).map_err(exit)?;
+
+        for object in &archive.members {
+            println!("{}  {} bytes", object.name, object.code.len());
+
+            for symbol in object.symbols.iter().filter(|s| s.public) {
+                match symbol.doc.as_str() {
+                    "" => println!("  {}", symbol.name),
+                    doc => println!("  {:<16} {}", symbol.name, doc),
+                }
+            }
+        }
+        return Ok(EXIT_OK);
+    }
+
+    let mut archive = Archive::new();
+    for file in &args.positional[1..] {
+        archive.add(object(file, args)?).map_err(|e| Exit {
+            code: EXIT_ASSEMBLY,
+            message: e,
+        })?;
+    }
+
+    archive
+        .write(&mut create(path)?)
+        .map_err(write_error(path))?;
+    Ok(EXIT_OK)
+}
+
+/**
+ * Emulator for `image`, with the console and serial port
+ * routed and the entry point set as the options say
+ */
+fn machine(image: &Image, args: &Args) -> Result<AsmContext, Exit> {
+    let mut res = Machine::new().image(image);
+
+    let input: Box<dyn io::Read> = match args.value("--input") {
+        Some(path) => Box::new(fs::File::open(path).map_err(write_error(path))?),
+        None => Box::new(io::stdin()),
+    };
+    let output: Box<dyn Write> = match args.value("--output") {
+        Some(path) => Box::new(create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    res = res.io(Box::new(StreamIo::new(input, output)));
+
+    if let Some(entry) = args.value("--entry") {
+        let addr = match image.symbols.get(entry) {
+            Some(addr) => *addr,
+            None => parse_value(entry).map_err(Exit::usage)? as u16,
+        };
+        res = res.entry(addr);
+    }
+
+    #[cfg(feature = "peripherals")]
+    {
+        match args.value("--serial-in") {
+            Some("-") => res = res.serial_input(Box::new(ReaderInput::stdin())),
+            Some(path) => {
+                let data = fs::read_to_string(path).map_err(write_error(path))?;
+                res = res.serial_input(Box::new(ScriptedInput::new(&data)));
+            }
+            None => (),
+        }
+
+        match args.value("--serial-out") {
+            Some("-") => res = res.serial_output(Box::new(io::stdout())),
+            Some(path) => res = res.serial_output(Box::new(create(path)?)),
+            None => (),
+        }
+
+        if args.has("--serial-pty") {
+            #[cfg(unix)]
+            {
+                let pty =
+                    Pty::open().map_err(|e| Exit::usage(format!("cannot open a pty: {}", e)))?;
+                let input = pty
+                    .master
+                    .try_clone()
+                    .map_err(|e| Exit::usage(e.to_string()))?;
+
+                eprintln!("serial port on {}", pty.name);
+                res = res
+                    .serial_input(Box::new(ReaderInput::new(input)))
+                    .serial_output(Box::new(pty.master));
+            }
+            #[cfg(not(unix))]
+            return Err(Exit::usage(
+                "pseudo terminals are not supported on this platform",
+            ));
+        }
+    }
+
+    #[cfg(not(feature = "peripherals"))]
+    if ["--serial-in", "--serial-out", "--serial-pty"]
+        .iter()
+        .any(|o| args.has(o))
+    {
+        return Err(Exit::usage("this build has no serial port"));
+    }
+
+    let mut ctx = res.build();
+
+    /* program arguments, with the input file as argv[0] */
+    if let Some(rest) = &args.rest {
+        let mut argv = vec![args.input()?.to_string()];
+        argv.extend(rest.iter().cloned());
+
+        ctx.em
+            .push_args(&argv, &image.segments)
+            .map_err(Exit::usage)?;
+    }
+
+    Ok(ctx)
+}
+
+const RUN_OPTIONS: [&str; 10] = [
+    "--cycles",
+    "--entry",
+    "--input",
+    "--output",
+    "--serial-in",
+    "--serial-out",
+    "--serial-pty",
+    "--sym",
+    "-D",
+    "-I",
+];
+
+fn cmd_run(args: &Args) -> Result<i32, Exit> {
+    args.check(&RUN_OPTIONS)?;
+    let image = load_input(args)?;
+    let limit = cycle_limit(args)?;
+
+    let mut ctx = machine(&image, args)?;
+
+    let reason = ctx.run_until(limit);
+    Ok(finish(&ctx, &reason))
+}
+
+fn cmd_disasm(args: &Args) -> Result<i32, Exit> {
+    args.check(&["--sym", "-D", "-I"])?;
+    let image = load_input(args)?;
+    let mut out = io::stdout();
+
+    for ins in disasm::disassemble_range(&image.code, 0, usize::MAX, &image.symbols) {
+        if let Some(name) = symbols::name_at(&image.symbols, ins.addr) {
+            let _ = writeln!(out, "{}:", name);
+        }
+
+        let bytes: Vec<String> = ins.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let _ = writeln!(
+            out,
+            "    {:04X}  {:<9} {}",
+            ins.addr,
+            bytes.join(" "),
+            ins.text
+        );
+    }
+
+    Ok(EXIT_OK)
+}
+
+#[cfg(feature = "debugger")]
+fn cmd_debug(args: &Args) -> Result<i32, Exit> {
+    args.check(&["--sym", "-D", "-I"])?;
+    let image = load_input(args)?;
+    let ctx = Machine::new().image(&image).build();
+
+    Debugger::new(ctx, image.symbols).repl();
+    Ok(EXIT_OK)
+}
+
+#[cfg(feature = "debugger")]
+fn cmd_repl(args: &Args) -> Result<i32, Exit> {
+    args.check(&["--sym", "-D", "-I"])?;
+    let mut repl = Repl::new();
+
+    if !args.positional.is_empty() {
+        repl.load(load_input(args)?);
+    }
+
+    repl.run();
+    Ok(EXIT_OK)
+}
+
+fn cmd_trace(args: &Args) -> Result<i32, Exit> {
+    let mut known = RUN_OPTIONS.to_vec();
+    known.extend(["-o", "-f"]);
+    args.check(&known)?;
+
+    let image = load_input(args)?;
+    let limit = cycle_limit(args)?;
+
+    let mut ctx = machine(&image, args)?;
+    ctx.tracer = Some(Tracer::new());
+
+    let reason = ctx.run_until(limit);
+    let tracer = ctx.tracer.as_ref().unwrap();
+
+    let mut out: Box<dyn Write> = match args.value("-o") {
+        Some(path) => Box::new(create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let target = args.value("-o").unwrap_or("stdout");
+
+    match args.value("-f").unwrap_or("text") {
+        "text" => tracer.write_text(&mut out, &image.symbols, &image.lines),
+        "bin" => tracer.write_binary(&mut out),
+        other => return Err(Exit::usage(format!("unknown trace format `{}`", other))),
+    }
+    .map_err(write_error(target))?;
+
+    Ok(finish(&ctx, &reason))
+}
+
+fn cmd_coverage(args: &Args) -> Result<i32, Exit> {
+    args.check(&RUN_OPTIONS)?;
+    let image = load_input(args)?;
+    let limit = cycle_limit(args)?;
+
+    let mut ctx = machine(&image, args)?;
+    ctx.coverage = Some(Coverage::new());
+
+    let reason = ctx.run_until(limit);
+    let code = finish(&ctx, &reason);
+
+    let report = Report::new(ctx.coverage.as_ref().unwrap(), &ctx.lines, &image.code);
+    let mut out = io::stdout();
+
+    match args.positional.get(1).map(|s| s.as_str()) {
+        None => report.write_summary(&mut out),
+        Some("annotate") => report.write_annotated(&mut out),
+        Some("lcov") => {
+            let path = args
+                .positional
+                .get(2)
+                .ok_or(Exit::usage("usage: coverage FILE lcov OUTPUT"))?;
+
+            report
+                .write_lcov(&mut create(path)?)
+                .map_err(write_error(path))?;
+            report.write_summary(&mut out)
+        }
+        Some(other) => return Err(Exit::usage(format!("unknown coverage output `{}`", other))),
+    }
+    .map_err(write_error("stdout"))?;
+
+    Ok(code)
+}
+
+fn cmd_profile(args: &Args) -> Result<i32, Exit> {
+    args.check(&RUN_OPTIONS)?;
+    let image = load_input(args)?;
+    let limit = cycle_limit(args)?;
+
+    let mut ctx = machine(&image, args)?;
+    ctx.profiler = Some(Profiler::new());
+
+    let reason = ctx.run_until(limit);
+    let code = finish(&ctx, &reason);
+
+    let profiler = ctx.profiler.as_ref().unwrap();
+    let mut out = io::stdout();
+
+    match args.positional.get(1).map(|s| s.as_str()) {
+        None | Some("flat") => profiler.write_flat(&mut out, &ctx.em.rom, &image.symbols),
+        Some("folded") => profiler.write_folded(&mut out, &image.symbols),
+        Some(other) => return Err(Exit::usage(format!("unknown profile output `{}`", other))),
+    }
+    .map_err(write_error("stdout"))?;
+
+    Ok(code)
+}
+
+/**
+ * Listens on 127.0.0.1:1234 by default
+ */
+#[cfg(feature = "debugger")]
+fn cmd_gdb(args: &Args) -> Result<i32, Exit> {
+    args.check(&["--sym", "-D", "-I"])?;
+    let image = load_input(args)?;
+    let mut ctx = Machine::new().image(&image).build();
+
+    let listen = args
+        .positional
+        .get(1)
+        .map(|s| s.as_str())
+        .unwrap_or("127.0.0.1:1234");
+    let res = match listen.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(socket) => gdb::serve_unix(&mut ctx, socket),
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(Exit::usage(
+                "unix sockets are not supported on this platform",
+            ))
+        }
+        None => gdb::serve_tcp(&mut ctx, listen),
+    };
+
+    res.map_err(|e| Exit::usage(format!("gdb server: {}", e)))?;
+    Ok(EXIT_OK)
+}
+
+/**
+ * Runs the command line `args`, without the program name,
+ * and returns the process exit code
+ */
+pub fn main(args: &[String]) -> i32 {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => {
+            eprint!("{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let takes_value = [
+        "-o",
+        "-f",
+        "-l",
+        "-m",
+        "-D",
+        "-I",
+        "--sym",
+        "--cycles",
+        "--entry",
+        "--input",
+        "--output",
+        "--serial-in",
+        "--serial-out",
+    ];
+
+    let res = Args::parse(&args[1..], &takes_value).and_then(|args| match command {
+        "asm" => cmd_asm(&args),
+        "link" => cmd_link(&args),
+        "ar" => cmd_ar(&args),
+        "run" => cmd_run(&args),
+        "disasm" => cmd_disasm(&args),
+        #[cfg(feature = "debugger")]
+        "debug" => cmd_debug(&args),
+        #[cfg(feature = "debugger")]
+        "repl" => cmd_repl(&args),
+        "trace" => cmd_trace(&args),
+        "coverage" => cmd_coverage(&args),
+        "profile" => cmd_profile(&args),
+        #[cfg(feature = "debugger")]
+        "gdb" => cmd_gdb(&args),
+        #[cfg(feature = "debugger")]
+        "dap" => {
+            dap::run_stdio();
+            Ok(EXIT_OK)
+        }
+        "help" | "-h" | "--help" => {
+            print!("{}", USAGE);
+            Ok(EXIT_OK)
+        }
+        other => Err(Exit::usage(format!(
+            "unknown command `{}`, try `help`",
+            other
+        ))),
+    });
+
+    match res {
+        Ok(code) => code,
+        Err(exit) => {
+            eprintln!("prelude-rust: {}", exit.message);
+            exit.code
+        }
+    }
+}
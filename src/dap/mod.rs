@@ -0,0 +1,828 @@
+pub mod json;
+
+use crate::assembler::disasm::hex16;
+use crate::assembler::engine::{self, AsmContext, Step, StopReason};
+use crate::assembler::image::{assemble_file, Options};
+use crate::assembler::lines::LineTable;
+use crate::assembler::symbols::{self, SymbolTable};
+use crate::machine::Machine;
+use crate::psw::PswFlag;
+use crate::ram;
+use crate::sfr;
+use crate::syscall::{BufferIo, SharedBuffer};
+use json::Json;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/* opcodes stepping looks at, besides engine::is_call */
+const LCALL: u8 = 0xC9;
+const RET: u8 = 0xCA;
+const RETI: u8 = 0xC7;
+
+/* steps between checks for a pause request */
+const PAUSE_POLL: u32 = 4096;
+
+/* variable references of the scopes every frame has */
+const REGISTERS: u64 = 1;
+const FLAGS: u64 = 2;
+const IRAM: u64 = 3;
+const SFRS: u64 = 4;
+
+const FLAG_NAMES: [(&str, PswFlag); 8] = [
+    ("CY", PswFlag::CY),
+    ("AC", PswFlag::AC),
+    ("F0", PswFlag::F0),
+    ("RS1", PswFlag::RS1),
+    ("RS0", PswFlag::RS0),
+    ("OV", PswFlag::OV),
+    ("F1", PswFlag::F1),
+    ("P", PswFlag::P),
+];
+
+enum Mode {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+}
+
+/**
+ * Debug adapter protocol server
+ * Launches one `.plasm` program. Console output from `int 21H`
+ * and the serial port is sent to the editor as output events,
+ * console input comes from the `input` launch argument.
+ */
+pub struct DapServer {
+    pub ctx: Option<AsmContext>,
+    pub symbols: SymbolTable,
+    pub lines: LineTable,
+    pub path: String,
+    /* requested lines, by the source path the editor gave */
+    breakpoint_lines: BTreeMap<String, Vec<usize>>,
+    stop_on_entry: bool,
+    output: SharedBuffer,
+    out: Box<dyn Write>,
+    seq: u64,
+    requests: Receiver<Json>,
+    pending: VecDeque<Json>,
+}
+
+/**
+ * Reads one `Content-Length` framed message
+ */
+fn read_message(input: &mut dyn BufRead) -> Option<Json> {
+    let mut len = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            len = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0u8; len?];
+    input.read_exact(&mut body).ok()?;
+
+    Json::parse(&String::from_utf8_lossy(&body)).ok()
+}
+
+/**
+ * Serves the protocol on stdin and stdout
+ */
+pub fn run_stdio() {
+    DapServer::new(Box::new(io::stdin()), Box::new(io::stdout())).serve();
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut res = String::new();
+
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+
+    res
+}
+
+fn variable(name: &str, value: String) -> Json {
+    Json::object(vec![
+        ("name", name.into()),
+        ("value", value.into()),
+        ("variablesReference", 0u64.into()),
+    ])
+}
+
+impl DapServer {
+    /**
+     * Requests are read on a separate thread so
+     * a running program can be paused
+     */
+    pub fn new(input: Box<dyn Read + Send>, out: Box<dyn Write>) -> DapServer {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            while let Some(message) = read_message(&mut input) {
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        DapServer {
+            ctx: None,
+            symbols: SymbolTable::new(),
+            lines: LineTable::new(),
+            path: String::new(),
+            breakpoint_lines: BTreeMap::new(),
+            stop_on_entry: false,
+            output: SharedBuffer::new(),
+            out,
+            seq: 1,
+            requests: rx,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn serve(&mut self) {
+        loop {
+            let request = match self.pending.pop_front() {
+                Some(request) => request,
+                None => match self.requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                },
+            };
+
+            if !self.handle(&request) {
+                return;
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) {
+        message.insert(0, ("seq", self.seq.into()));
+        self.seq += 1;
+
+        let body = Json::object(message).to_string();
+        let _ = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.out.flush();
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) {
+        self.send(vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("success", true.into()),
+            ("command", request.get("command").clone()),
+            ("body", body),
+        ]);
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) {
+        self.send(vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("success", false.into()),
+            ("command", request.get("command").clone()),
+            ("message", message.into()),
+        ]);
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(vec![
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ]);
+    }
+
+    /**
+     * Handles one request, false once the session is over
+     */
+    fn handle(&mut self, request: &Json) -> bool {
+        let args = request.get("arguments");
+        let command = request.get("command").as_str().unwrap_or("").to_string();
+
+        if self.ctx.is_none()
+            && !matches!(
+                command.as_str(),
+                "initialize" | "launch" | "setBreakpoints" | "disconnect" | "terminate"
+            )
+        {
+            self.fail(request, "no program has been launched");
+            return true;
+        }
+
+        match command.as_str() {
+            "initialize" => {
+                let capabilities = Json::object(vec![
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsReadMemoryRequest", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                ]);
+                self.respond(request, capabilities);
+            }
+            "launch" => match self.launch(args) {
+                Ok(()) => {
+                    self.respond(request, Json::Null);
+                    self.event("initialized", Json::Null);
+                }
+                Err(e) => self.fail(request, &e),
+            },
+            "setBreakpoints" => {
+                let source = args.get("source");
+                let path = source
+                    .get("path")
+                    .as_str()
+                    .or(source.get("name").as_str())
+                    .unwrap_or("")
+                    .to_string();
+                let lines: Vec<usize> = args
+                    .get("breakpoints")
+                    .as_array()
+                    .iter()
+                    .filter_map(|b| b.get("line").as_u64())
+                    .map(|line| line as usize)
+                    .collect();
+
+                let breakpoints = self.resolve_lines(&path, &lines);
+                self.breakpoint_lines.insert(path, lines);
+                self.apply_breakpoints();
+                self.respond(
+                    request,
+                    Json::object(vec![("breakpoints", breakpoints.into())]),
+                );
+            }
+            "configurationDone" => {
+                self.respond(request, Json::Null);
+
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.resume(Mode::Continue);
+                }
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", 1u64.into()), ("name", "main".into())]);
+                self.respond(
+                    request,
+                    Json::object(vec![("threads", vec![thread].into())]),
+                );
+            }
+            "stackTrace" => {
+                let frames = self.stack_trace();
+                let total = frames.len();
+                self.respond(
+                    request,
+                    Json::object(vec![
+                        ("stackFrames", frames.into()),
+                        ("totalFrames", total.into()),
+                    ]),
+                );
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: u64, expensive: bool| {
+                    Json::object(vec![
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", expensive.into()),
+                    ])
+                };
+
+                let scopes = vec![
+                    scope("Registers", REGISTERS, false),
+                    scope("Flags", FLAGS, false),
+                    scope("Internal RAM", IRAM, true),
+                    scope("SFRs", SFRS, true),
+                ];
+                self.respond(request, Json::object(vec![("scopes", scopes.into())]));
+            }
+            "variables" => {
+                let reference = args.get("variablesReference").as_u64().unwrap_or(0);
+                let variables = self.variables(reference);
+                self.respond(request, Json::object(vec![("variables", variables.into())]));
+            }
+            "readMemory" => match self.read_memory(args) {
+                Some(body) => self.respond(request, body),
+                None => self.fail(request, "invalid memory reference"),
+            },
+            "continue" => {
+                self.respond(
+                    request,
+                    Json::object(vec![("allThreadsContinued", true.into())]),
+                );
+                self.resume(Mode::Continue);
+            }
+            "next" => {
+                self.respond(request, Json::Null);
+                self.resume(Mode::Next);
+            }
+            "stepIn" => {
+                self.respond(request, Json::Null);
+                self.resume(Mode::StepIn);
+            }
+            "stepOut" => {
+                self.respond(request, Json::Null);
+                self.resume(Mode::StepOut);
+            }
+            "pause" => {
+                /* nothing is running between requests */
+                self.respond(request, Json::Null);
+                self.stopped("pause", None);
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Json::Null);
+                return false;
+            }
+            _ => self.fail(request, &format!("unsupported request `{}`", command)),
+        }
+
+        true
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<(), String> {
+        let path = args
+            .get("program")
+            .as_str()
+            .ok_or("missing `program`")?
+            .to_string();
+        let image = assemble_file(&path, &Options::new()).map_err(|e| e.to_string())?;
+
+        let input = args.get("input").as_str().unwrap_or("");
+        let io = BufferIo::new(input);
+        self.output = io.output.clone();
+
+        let machine = Machine::new().image(&image).io(Box::new(io));
+        #[cfg(feature = "peripherals")]
+        let machine = machine.serial_output(Box::new(self.output.clone()));
+
+        self.symbols = image.symbols;
+        self.lines = image.lines;
+        self.path = path;
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        self.ctx = Some(machine.build());
+
+        self.apply_breakpoints();
+        Ok(())
+    }
+
+    /**
+     * Installs the breakpoints requested for the program
+     */
+    fn apply_breakpoints(&mut self) {
+        let mut addrs = Vec::new();
+
+        for (path, lines) in &self.breakpoint_lines {
+            if let Some(file) = self.source_file(path) {
+                addrs.extend(
+                    lines
+                        .iter()
+                        .filter_map(|line| self.lines.address_of(file, *line)),
+                );
+            }
+        }
+
+        if let Some(ctx) = &mut self.ctx {
+            ctx.breakpoints.clear();
+            for (addr, _) in addrs {
+                ctx.add_breakpoint(addr);
+            }
+        }
+    }
+
+    /* the breakpoints to report for `lines` of `path` */
+    fn resolve_lines(&self, path: &str, lines: &[usize]) -> Vec<Json> {
+        let file = self.source_file(path);
+
+        lines
+            .iter()
+            .map(
+                |line| match file.and_then(|file| self.lines.address_of(file, *line)) {
+                    Some((_, actual)) => {
+                        Json::object(vec![("verified", true.into()), ("line", actual.into())])
+                    }
+                    None => {
+                        Json::object(vec![("verified", false.into()), ("line", (*line).into())])
+                    }
+                },
+            )
+            .collect()
+    }
+
+    /**
+     * The line table's name for the source the editor calls `path`
+     * Editors send absolute paths, the table has them as they
+     * were given to the assembler.
+     */
+    fn source_file(&self, path: &str) -> Option<&str> {
+        let canonical = fs::canonicalize(path).ok();
+
+        self.lines
+            .files
+            .iter()
+            .find(|file| {
+                *file == path || (canonical.is_some() && fs::canonicalize(file).ok() == canonical)
+            })
+            .map(|file| file.as_str())
+    }
+
+    /**
+     * Source line of the instruction at `addr`
+     */
+    pub fn line_of(&self, addr: u16) -> Option<usize> {
+        self.lines.lookup(addr).map(|location| location.line)
+    }
+
+    fn flush_output(&mut self) {
+        let data = self.output.take();
+
+        if !data.is_empty() {
+            let text = String::from_utf8_lossy(&data).into_owned();
+            self.event(
+                "output",
+                Json::object(vec![("category", "stdout".into()), ("output", text.into())]),
+            );
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        self.flush_output();
+
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", 1u64.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = description {
+            body.push(("description", text.clone().into()));
+            body.push(("text", text.into()));
+        }
+
+        self.event("stopped", Json::object(body));
+    }
+
+    fn exited(&mut self, code: u8) {
+        self.flush_output();
+        self.event(
+            "exited",
+            Json::object(vec![("exitCode", (code as u64).into())]),
+        );
+        self.event("terminated", Json::Null);
+    }
+
+    /**
+     * Runs until `mode` is done, a breakpoint or a pause request
+     */
+    fn resume(&mut self, mode: Mode) {
+        let ctx = self.ctx.as_mut().unwrap();
+        let sp = ctx.em.reg.sp.get();
+        let (is_call, ret) = match ctx.fetch() {
+            Ok((opcode, operands)) => (
+                engine::is_call(opcode),
+                ctx.em.reg.pc.get().wrapping_add(1 + operands.len() as u16),
+            ),
+            Err(_) => (false, 0),
+        };
+
+        let done = |ctx: &AsmContext, step: &Step| match mode {
+            Mode::Continue => false,
+            Mode::StepIn => true,
+            Mode::Next => !is_call || (ctx.em.reg.pc.get() == ret && ctx.em.reg.sp.get() == sp),
+            Mode::StepOut => {
+                (step.opcode == RET || step.opcode == RETI) && ctx.em.reg.sp.get() < sp
+            }
+        };
+
+        let mut steps = 0u32;
+        loop {
+            let ctx = self.ctx.as_mut().unwrap();
+
+            let step = match ctx.step() {
+                Ok(step) => step,
+                Err(reason) => return self.report(reason),
+            };
+
+            if done(ctx, &step) {
+                return self.stopped("step", None);
+            }
+
+            if ctx.breakpoints.contains(&ctx.em.reg.pc.get()) {
+                return self.stopped("breakpoint", None);
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(PAUSE_POLL) {
+                self.flush_output();
+
+                match self.requests.try_recv() {
+                    Ok(request) if request.get("command").as_str() == Some("pause") => {
+                        self.respond(&request, Json::Null);
+                        return self.stopped("pause", None);
+                    }
+                    Ok(request) => self.pending.push_back(request),
+                    Err(TryRecvError::Empty) => (),
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, reason: StopReason) {
+        match reason {
+            StopReason::Halted => self.exited(0),
+            StopReason::Exited(code) => self.exited(code),
+            StopReason::Breakpoint(_) => self.stopped("breakpoint", None),
+            StopReason::Watchpoint(hit) => self.stopped(
+                "data breakpoint",
+                Some(format!(
+                    "watchpoint {}: {:02X}H -> {:02X}H",
+                    hit.id, hit.old, hit.new
+                )),
+            ),
+            other => {
+                let text = self.ctx.as_ref().unwrap().explain(&other);
+                self.stopped("exception", Some(text));
+            }
+        }
+    }
+
+    fn frame(&self, id: usize, addr: u16) -> Json {
+        let name = match symbols::nearest(&self.symbols, addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => hex16(addr),
+        };
+
+        let file = Path::new(&self.path)
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let source = Json::object(vec![
+            ("name", file.into()),
+            ("path", self.path.as_str().into()),
+        ]);
+
+        Json::object(vec![
+            ("id", id.into()),
+            ("name", name.into()),
+            ("source", source),
+            ("line", self.line_of(addr).unwrap_or(0).into()),
+            (
+                "column",
+                self.lines.lookup(addr).map_or(1, |l| l.column).into(),
+            ),
+            (
+                "instructionPointerReference",
+                format!("0x{:04X}", addr).into(),
+            ),
+        ])
+    }
+
+    /**
+     * The current frame and the callers found on the stack
+     * Return addresses are recognised by the lcall or acall just before them.
+     */
+    fn stack_trace(&self) -> Vec<Json> {
+        let em = &self.ctx.as_ref().unwrap().em;
+        let mut frames = vec![self.frame(0, em.reg.pc.get())];
+
+        /* the stack starts above register bank 0, a return address takes 2 bytes */
+        let mut at = em.reg.sp.get() as usize;
+        while at > 0x08 && at < ram::RAM_SIZE {
+            let addr = (em.ram.read(at) as u16) << 8 | em.ram.read(at - 1) as u16;
+
+            let is_acall = |at: u16| {
+                em.rom
+                    .get(at as usize)
+                    .is_some_and(|op| (0xD8..=0xDF).contains(op))
+            };
+
+            if addr >= 3 && em.rom.get(addr as usize - 3) == Some(&LCALL) {
+                frames.push(self.frame(frames.len(), addr - 3));
+                at -= 2;
+            } else if addr >= 2 && is_acall(addr - 2) {
+                frames.push(self.frame(frames.len(), addr - 2));
+                at -= 2;
+            } else {
+                at -= 1;
+            }
+        }
+
+        frames
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Json> {
+        let em = &self.ctx.as_ref().unwrap().em;
+
+        match reference {
+            REGISTERS => {
+                let mut res = vec![
+                    variable("A", format!("0x{:02X}", em.reg.a.get())),
+                    variable("B", format!("0x{:02X}", em.reg.b.get())),
+                    variable("SP", format!("0x{:02X}", em.reg.sp.get())),
+                    variable("PSW", format!("0x{:02X}", em.psw.get())),
+                    variable("DPTR", format!("0x{:04X}", em.reg.dptr.get())),
+                    variable("PC", format!("0x{:04X}", em.reg.pc.get())),
+                ];
+
+                for n in 0..8 {
+                    res.push(variable(
+                        &format!("R{}", n),
+                        format!("0x{:02X}", em.read_rn(n)),
+                    ));
+                }
+                res.push(variable("cycles", em.cycles.to_string()));
+                res
+            }
+            FLAGS => FLAG_NAMES
+                .iter()
+                .map(|(name, flag)| variable(name, (em.psw.get_flag(*flag) as u8).to_string()))
+                .collect(),
+            IRAM => (0..ram::RAM_SIZE)
+                .step_by(16)
+                .map(|row| {
+                    let bytes: Vec<String> = (row..row + 16)
+                        .map(|a| format!("{:02X}", em.ram.read(a)))
+                        .collect();
+                    variable(&format!("{:02X}", row), bytes.join(" "))
+                })
+                .collect(),
+            SFRS => sfr::NAMES
+                .iter()
+                .map(|(name, addr)| variable(name, format!("0x{:02X}", em.peek_direct(*addr))))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /**
+     * memoryReference is `iram`, `sfr`, `xram` or `code`
+     */
+    fn read_memory(&self, args: &Json) -> Option<Json> {
+        let em = &self.ctx.as_ref().unwrap().em;
+        let reference = args.get("memoryReference").as_str()?;
+        let offset = args.get("offset").as_i64().unwrap_or(0);
+        let count = args.get("count").as_u64()? as usize;
+
+        let memory: Vec<u8> = match reference {
+            "iram" => em.ram.memory.clone(),
+            "sfr" => (0x80..=0xFF).map(|a| em.peek_direct(a)).collect(),
+            "xram" => em.xram.clone(),
+            "code" => em.rom.clone(),
+            _ => return None,
+        };
+
+        let start = (offset.max(0) as usize).min(memory.len());
+        let end = start.checked_add(count)?.min(memory.len());
+        let base = if reference == "sfr" { 0x80 } else { 0 };
+
+        Some(Json::object(vec![
+            ("address", format!("0x{:X}", base + start).into()),
+            ("unreadableBytes", (count - (end - start)).into()),
+            ("data", base64(&memory[start..end]).into()),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn message(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    /* the bodies of everything the server sent */
+    fn session(requests: &[String]) -> Vec<String> {
+        let input: String = requests.iter().map(|r| message(r)).collect();
+        let out = SharedBuffer::new();
+
+        DapServer::new(
+            Box::new(Cursor::new(input.into_bytes())),
+            Box::new(out.clone()),
+        )
+        .serve();
+
+        out.to_string_lossy()
+            .split("Content-Length: ")
+            .filter_map(|m| m.split_once("\r\n\r\n").map(|(_, body)| body.to_string()))
+            .collect()
+    }
+
+    fn reply(replies: &[String], seq: u64) -> String {
+        let needle = format!("\"request_seq\":{},", seq);
+        replies
+            .iter()
+            .find(|r| r.contains(&needle))
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn breakpoints_bind_to_their_own_file() {
+        let dir = std::env::temp_dir().join(format!("plasm-dap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("main.plasm"),
+            "mov A, #1\nmov B, #2\nmov A, B\nend\n",
+        )
+        .unwrap();
+
+        let main = dir.join("main.plasm").to_string_lossy().into_owned();
+        /* the editor sends the same file by another name */
+        let alias = dir
+            .join(".")
+            .join("main.plasm")
+            .to_string_lossy()
+            .into_owned();
+
+        let replies = session(&[
+            r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#.to_string(),
+            format!(r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{}"}}}}"#, main),
+            r#"{"seq":3,"type":"request","command":"setBreakpoints","arguments":{"source":{"path":"/elsewhere/other.plasm"},"breakpoints":[{"line":2}]}}"#.to_string(),
+            format!(
+                r#"{{"seq":4,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{}"}},"breakpoints":[{{"line":3}}]}}}}"#,
+                alias
+            ),
+            r#"{"seq":5,"type":"request","command":"configurationDone","arguments":{}}"#.to_string(),
+            r#"{"seq":6,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#.to_string(),
+            r#"{"seq":7,"type":"request","command":"disconnect","arguments":{}}"#.to_string(),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            reply(&replies, 3).contains(r#""verified":false"#),
+            "{}",
+            reply(&replies, 3)
+        );
+        assert!(
+            reply(&replies, 4).contains(r#""verified":true"#),
+            "{}",
+            reply(&replies, 4)
+        );
+        assert!(replies
+            .iter()
+            .any(|r| r.contains(r#""reason":"breakpoint""#)));
+
+        /* stopped at `mov A, B`, not at line 2 of the other file */
+        let trace = reply(&replies, 6);
+        assert!(trace.contains(r#""line":3"#), "{}", trace);
+    }
+
+    #[test]
+    fn overflowing_memory_reads_fail() {
+        let dir = std::env::temp_dir().join(format!("plasm-dap-read-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.plasm"), "mov A, #1\nend\n").unwrap();
+        let main = dir.join("main.plasm").to_string_lossy().into_owned();
+
+        let replies = session(&[
+            r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#.to_string(),
+            format!(
+                r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{}","stopOnEntry":true}}}}"#,
+                main
+            ),
+            r#"{"seq":3,"type":"request","command":"configurationDone","arguments":{}}"#.to_string(),
+            r#"{"seq":4,"type":"request","command":"readMemory","arguments":{"memoryReference":"iram","offset":16,"count":4}}"#.to_string(),
+            r#"{"seq":5,"type":"request","command":"readMemory","arguments":{"memoryReference":"iram","offset":16,"count":18446744073709551615}}"#.to_string(),
+            r#"{"seq":6,"type":"request","command":"disconnect","arguments":{}}"#.to_string(),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            reply(&replies, 4).contains(r#""success":true"#),
+            "{}",
+            reply(&replies, 4)
+        );
+        assert!(
+            reply(&replies, 5).contains(r#""success":false"#),
+            "{}",
+            reply(&replies, 5)
+        );
+    }
+}
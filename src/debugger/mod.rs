@@ -0,0 +1,946 @@
+use crate::assembler::disasm::{self, hex16, hex8};
+use crate::assembler::engine::{self, AsmContext, StopReason};
+use crate::assembler::history::History;
+use crate::assembler::parser;
+use crate::assembler::symbols::{self, SymbolTable};
+use crate::assembler::trace::Tracer;
+use crate::assembler::watch::{Condition, WatchKind, WatchSpace, Watchpoint};
+use crate::psw::PswFlag;
+use crate::ram;
+use crate::sfr;
+use crate::snapshot;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+pub use crate::assembler::parser::parse_value;
+
+const HELP: &str = "\
+break ADDR|LABEL        (b)   set a breakpoint
+break FILE:LINE               set a breakpoint on a source line
+delete [ADDR|LABEL]     (d)   delete one or all breakpoints
+breakpoints             (bl)  list breakpoints
+watch [read|write|change] iram|sfr|xram|bit ADDR [OP VALUE]
+                        (w)   stop when a location is accessed,
+                              optionally only if e.g. `== 0FFH`
+unwatch ID                    delete a watchpoint
+watches                       list watchpoints
+step [N]                (s)   execute N instructions
+next                    (n)   step over calls
+continue                (c)   run until something stops execution
+finish                        run until the current subroutine returns
+history on [LEN]              record history for stepping backwards,
+                              the last LEN instructions (default 100000)
+history off                   stop recording history
+history                       show how far back history goes
+step-back [N]           (sb)  undo N instructions
+reverse-continue        (rc)  run backwards to a breakpoint or watchpoint
+jump CYCLE                    go to a machine cycle, back or forth
+regs                    (r)   print registers and PSW flags
+x iram|sfr|xram|code ADDR [LEN]
+                              dump memory as hex and ASCII
+set REG|FLAG VALUE            modify a register or PSW flag
+set iram|xram ADDR BYTE...    modify memory
+dis [ADDR] [N]                disassemble, around PC by default
+trace on [LEN]                record executed instructions,
+                              keeping the last LEN if given
+trace off                     stop recording
+trace range FROM TO           only record instructions in a range
+trace [N]                     show the last N recorded instructions
+trace save FILE [text|bin]    write the trace to a file
+symbols FILE                  load a symbol file
+save FILE                     save the machine state to a snapshot file
+load FILE                     restore a snapshot file
+quit                    (q)   leave the debugger";
+
+pub(crate) const FLAGS: [(&str, PswFlag); 8] = [
+    ("CY", PswFlag::CY),
+    ("AC", PswFlag::AC),
+    ("F0", PswFlag::F0),
+    ("RS1", PswFlag::RS1),
+    ("RS0", PswFlag::RS0),
+    ("OV", PswFlag::OV),
+    ("F1", PswFlag::F1),
+    ("P", PswFlag::P),
+];
+
+/* opcodes the call tracking commands look at, besides engine::is_call */
+const RET: u8 = 0xCA;
+const RETI: u8 = 0xC7;
+
+pub struct Debugger {
+    pub ctx: AsmContext,
+    pub symbols: SymbolTable,
+}
+
+impl Debugger {
+    pub fn new(ctx: AsmContext, symbols: SymbolTable) -> Debugger {
+        Debugger { ctx, symbols }
+    }
+
+    /**
+     * Reads commands from stdin until `quit` or end of input
+     */
+    pub fn repl(&mut self) {
+        let stdin = io::stdin();
+        let mut out = io::stdout();
+        let mut lines = stdin.lock().lines();
+
+        self.print_location(&mut out);
+
+        loop {
+            let _ = write!(out, "(pdb) ");
+            let _ = out.flush();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+
+            if !self.execute(&line, &mut out) {
+                break;
+            }
+        }
+    }
+
+    /**
+     * Runs one command, writing its output to `out`
+     * Returns false once the debugger should exit
+     */
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> bool {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            return true;
+        }
+
+        let res = match args[0] {
+            "q" | "quit" => return false,
+            "h" | "help" => writeln!(out, "{}", HELP).map_err(|e| e.to_string()),
+            "b" | "break" => self.cmd_break(&args, out),
+            "d" | "delete" => self.cmd_delete(&args, out),
+            "bl" | "breakpoints" => self.cmd_breakpoints(out),
+            "w" | "watch" => self.cmd_watch(&args, out),
+            "unwatch" => self.cmd_unwatch(&args),
+            "watches" => self.cmd_watches(out),
+            "s" | "step" => self.cmd_step(&args, out),
+            "n" | "next" => self.cmd_next(out),
+            "c" | "continue" => {
+                let reason = self.ctx.run();
+                self.report(&reason, out);
+                Ok(())
+            }
+            "finish" => self.cmd_finish(out),
+            "history" => self.cmd_history(&args, out),
+            "sb" | "step-back" => self.cmd_step_back(&args, out),
+            "rc" | "reverse-continue" => {
+                let reason = self.ctx.reverse_continue();
+                self.report(&reason, out);
+                Ok(())
+            }
+            "jump" => self.cmd_jump(&args, out),
+            "r" | "regs" => {
+                self.print_regs(out);
+                Ok(())
+            }
+            "x" => self.cmd_dump(&args, out),
+            "set" => self.cmd_set(&args),
+            "dis" => self.cmd_dis(&args, out),
+            "trace" => self.cmd_trace(&args, out),
+            "symbols" => self.cmd_symbols(&args, out),
+            "save" => match args.get(1) {
+                Some(path) => snapshot::save(&self.ctx.em, path),
+                None => Err("usage: save FILE".to_string()),
+            },
+            "load" => self.cmd_load(&args, out),
+            _ => Err(format!("unknown command `{}`, try `help`", args[0])),
+        };
+
+        if let Err(e) = res {
+            let _ = writeln!(out, "error: {}", e);
+        }
+
+        true
+    }
+
+    /**
+     * Resolves a label or a number to a code address
+     */
+    pub fn parse_addr(&self, s: &str) -> Result<u16, String> {
+        if let Some(addr) = self.symbols.get(s) {
+            return Ok(*addr);
+        }
+
+        let value = parse_value(s)?;
+        if value > 0xFFFF {
+            return Err(format!("address out of range `{}`", s));
+        }
+        Ok(value as u16)
+    }
+
+    /**
+     * `main+3 (0033H), test.plasm:12` style description of a code address
+     */
+    pub fn describe(&self, addr: u16) -> String {
+        let res = match symbols::nearest(&self.symbols, addr) {
+            Some((name, 0)) => format!("{} ({})", name, hex16(addr)),
+            Some((name, offset)) => format!("{}+{} ({})", name, offset, hex16(addr)),
+            None => hex16(addr),
+        };
+
+        match self.ctx.lines.lookup(addr) {
+            Some(location) => format!("{}, {}", res, location),
+            None => res,
+        }
+    }
+
+    fn print_location(&self, out: &mut dyn Write) {
+        let pc = self.ctx.em.reg.pc.get();
+        let ins = disasm::disassemble(&self.ctx.em.rom, pc, &self.symbols);
+
+        let _ = writeln!(out, "=> {}: {}", self.describe(pc), ins.text);
+    }
+
+    fn report(&self, reason: &StopReason, out: &mut dyn Write) {
+        let _ = writeln!(out, "{}", self.ctx.explain(reason));
+
+        /* the instruction that touched the location, and what it did */
+        if let StopReason::Watchpoint(hit) = reason {
+            let ins = disasm::disassemble(&self.ctx.em.rom, hit.step.pc, &self.symbols);
+            let _ = writeln!(
+                out,
+                "  {}\n  old = {}, new = {}",
+                ins.text,
+                hex8(hit.old),
+                hex8(hit.new)
+            );
+        }
+
+        self.print_location(out);
+    }
+
+    fn print_regs(&self, out: &mut dyn Write) {
+        let em = &self.ctx.em;
+
+        let _ = writeln!(
+            out,
+            "A={:02X}  B={:02X}  SP={:02X}  DPTR={:04X}  PC={:04X}  cycles={}",
+            em.reg.a.get(),
+            em.reg.b.get(),
+            em.reg.sp.get(),
+            em.reg.dptr.get(),
+            em.reg.pc.get(),
+            em.cycles
+        );
+
+        let rn: Vec<String> = (0..8)
+            .map(|n| format!("R{}={:02X}", n, em.read_rn(n)))
+            .collect();
+        let bank = (em.rn_address(0) - ram::BANK_ADDRESSES[0]) / 8;
+        let _ = writeln!(out, "{}  (bank {})", rn.join(" "), bank);
+
+        let flags: Vec<String> = FLAGS
+            .iter()
+            .map(|(name, flag)| {
+                let mask = 1 << *flag as u8;
+                format!("{}={}", name, (em.psw.get() & mask != 0) as u8)
+            })
+            .collect();
+        let _ = writeln!(out, "PSW={:02X}  {}", em.psw.get(), flags.join(" "));
+    }
+
+    fn cmd_break(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let target = args.get(1).ok_or("usage: break ADDR|LABEL|FILE:LINE")?;
+
+        /* FILE:LINE, moved to the first instruction at or after the line */
+        let addr = match target.rsplit_once(':') {
+            Some((file, line)) if line.parse::<usize>().is_ok() => {
+                let line = line.parse::<usize>().unwrap();
+                self.ctx
+                    .lines
+                    .address_of(file, line)
+                    .map(|(addr, _)| addr)
+                    .ok_or(format!("no code at or after {}", target))?
+            }
+            _ => self.parse_addr(target)?,
+        };
+
+        self.ctx.add_breakpoint(addr);
+        writeln!(out, "breakpoint at {}", self.describe(addr)).map_err(|e| e.to_string())
+    }
+
+    fn cmd_delete(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        match args.get(1) {
+            None => {
+                self.ctx.breakpoints.clear();
+                writeln!(out, "deleted all breakpoints").map_err(|e| e.to_string())
+            }
+            Some(target) => {
+                let addr = self.parse_addr(target)?;
+
+                if !self.ctx.remove_breakpoint(addr) {
+                    return Err(format!("no breakpoint at {}", self.describe(addr)));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn cmd_breakpoints(&self, out: &mut dyn Write) -> Result<(), String> {
+        let mut addrs: Vec<&u16> = self.ctx.breakpoints.iter().collect();
+        addrs.sort();
+
+        if addrs.is_empty() {
+            let _ = writeln!(out, "no breakpoints");
+        }
+
+        for addr in addrs {
+            let _ = writeln!(out, "  {}", self.describe(*addr));
+        }
+        Ok(())
+    }
+
+    /**
+     * `iram 30H`, `sfr P1`, `bit TF0`, `bit 20H.3` style locations
+     */
+    fn describe_watch(watch: &Watchpoint) -> String {
+        let kind = match watch.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+        };
+
+        let location = match watch.space {
+            WatchSpace::Iram => format!("iram {}", hex8(watch.addr as u8)),
+            WatchSpace::Xram => format!("xram {}", hex16(watch.addr)),
+            WatchSpace::Sfr => match sfr::name_of(watch.addr as u8) {
+                Some(name) => format!("sfr {}", name),
+                None => format!("sfr {}", hex8(watch.addr as u8)),
+            },
+            WatchSpace::Bit => match sfr::bit_name_of(watch.addr as u8) {
+                Some(name) => format!("bit {}", name),
+                None => {
+                    let (addr, index) = sfr::bit_location(watch.addr as u8);
+                    format!("bit {}.{}", hex8(addr), index)
+                }
+            },
+        };
+
+        let condition = match watch.condition {
+            Some(Condition::Eq(v)) => format!(" == {}", hex8(v)),
+            Some(Condition::Ne(v)) => format!(" != {}", hex8(v)),
+            Some(Condition::Lt(v)) => format!(" < {}", hex8(v)),
+            Some(Condition::Le(v)) => format!(" <= {}", hex8(v)),
+            Some(Condition::Gt(v)) => format!(" > {}", hex8(v)),
+            Some(Condition::Ge(v)) => format!(" >= {}", hex8(v)),
+            None => String::new(),
+        };
+
+        format!("{} {}{}", kind, location, condition)
+    }
+
+    fn cmd_watch(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let usage = "usage: watch [read|write|change] iram|sfr|xram|bit ADDR [OP VALUE]";
+        let mut args = &args[1..];
+
+        let kind = match args.first() {
+            Some(&"read") => WatchKind::Read,
+            Some(&"write") => WatchKind::Write,
+            Some(&"change") => WatchKind::Change,
+            _ => WatchKind::Write,
+        };
+        if matches!(
+            args.first(),
+            Some(&"read") | Some(&"write") | Some(&"change")
+        ) {
+            args = &args[1..];
+        }
+
+        if args.len() != 2 && args.len() != 4 {
+            return Err(usage.to_string());
+        }
+
+        let space = match args[0] {
+            "iram" => WatchSpace::Iram,
+            "sfr" => WatchSpace::Sfr,
+            "xram" => WatchSpace::Xram,
+            "bit" => WatchSpace::Bit,
+            other => return Err(format!("unknown memory space `{}`", other)),
+        };
+
+        let named = match space {
+            WatchSpace::Sfr => sfr::address_of(args[1]),
+            /* names and 20H.3 style, with the assembler's checks */
+            WatchSpace::Bit if !args[1].starts_with("0x") => Some(parser::parse_bit(args[1])?),
+            _ => None,
+        };
+
+        let addr = match named {
+            Some(a) => a as u32,
+            None => parse_value(args[1])?,
+        };
+
+        let limit = match space {
+            WatchSpace::Iram => ram::RAM_SIZE as u32,
+            WatchSpace::Sfr => 0x100,
+            WatchSpace::Xram => ram::XRAM_SIZE as u32,
+            WatchSpace::Bit => 0x100,
+        };
+        if addr >= limit || (space == WatchSpace::Sfr && addr < 0x80) {
+            return Err(format!("address out of range `{}`", args[1]));
+        }
+
+        let condition = if args.len() == 4 {
+            let value = parse_value(args[3])?;
+            if value > 0xFF {
+                return Err(format!("value out of range `{}`", args[3]));
+            }
+
+            Some(
+                Condition::parse(args[2], value as u8)
+                    .ok_or(format!("unknown operator `{}`", args[2]))?,
+            )
+        } else {
+            None
+        };
+
+        let watch = Watchpoint {
+            space,
+            addr: addr as u16,
+            kind,
+            condition,
+        };
+        let text = Self::describe_watch(&watch);
+        let id = self.ctx.add_watchpoint(watch);
+
+        writeln!(out, "watchpoint {}: {}", id, text).map_err(|e| e.to_string())
+    }
+
+    fn cmd_unwatch(&mut self, args: &[&str]) -> Result<(), String> {
+        let id = args.get(1).ok_or("usage: unwatch ID")?;
+        let id = parse_value(id)? as usize;
+
+        if !self.ctx.remove_watchpoint(id) {
+            return Err(format!("no watchpoint {}", id));
+        }
+        Ok(())
+    }
+
+    fn cmd_watches(&self, out: &mut dyn Write) -> Result<(), String> {
+        if self.ctx.watchpoints.is_empty() {
+            let _ = writeln!(out, "no watchpoints");
+        }
+
+        for (id, watch) in &self.ctx.watchpoints {
+            let _ = writeln!(out, "  {}: {}", id, Self::describe_watch(watch));
+        }
+        Ok(())
+    }
+
+    fn cmd_step(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let count = match args.get(1) {
+            Some(n) => parse_value(n)?,
+            None => 1,
+        };
+
+        for _ in 0..count {
+            if let Err(reason) = self.ctx.step() {
+                self.report(&reason, out);
+                return Ok(());
+            }
+        }
+
+        self.print_location(out);
+        Ok(())
+    }
+
+    fn cmd_history(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        match args.get(1).copied() {
+            Some("on") => {
+                let history = match args.get(2) {
+                    Some(n) => History::with_limit(parse_value(n)? as usize),
+                    None => History::new(),
+                };
+
+                self.ctx.history = Some(history);
+                Ok(())
+            }
+            Some("off") => {
+                self.ctx.history = None;
+                Ok(())
+            }
+            Some(other) => Err(format!("unknown history command `{}`", other)),
+            None => {
+                let history = self.ctx.history.as_ref().ok_or("history is off")?;
+
+                let _ = writeln!(
+                    out,
+                    "{} instructions in the undo log, {} snapshots",
+                    history.frames.len(),
+                    history.snapshots.len()
+                );
+                if let Some(oldest) = history.oldest() {
+                    let _ = writeln!(out, "oldest cycle {}", oldest);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn cmd_step_back(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        if self.ctx.history.is_none() {
+            return Err("history is off, see `history on`".to_string());
+        }
+
+        let count = match args.get(1) {
+            Some(n) => parse_value(n)?,
+            None => 1,
+        };
+
+        for _ in 0..count {
+            if self.ctx.step_back().is_none() {
+                self.report(&StopReason::HistoryStart, out);
+                return Ok(());
+            }
+        }
+
+        self.print_location(out);
+        Ok(())
+    }
+
+    fn cmd_jump(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let cycle = args.get(1).ok_or("usage: jump CYCLE")?;
+        let reason = self.ctx.seek(parse_value(cycle)? as u64);
+
+        self.report(&reason, out);
+        Ok(())
+    }
+
+    /**
+     * Steps over an lcall or acall by running until it returns to the
+     * following instruction with the stack back where it was
+     */
+    fn cmd_next(&mut self, out: &mut dyn Write) -> Result<(), String> {
+        let pc = self.ctx.em.reg.pc.get();
+        let sp = self.ctx.em.reg.sp.get();
+
+        let fetched = self.ctx.fetch();
+        let is_call = matches!(fetched, Ok((opcode, _)) if engine::is_call(opcode));
+        let res = self.ctx.step();
+
+        if is_call && res.is_ok() {
+            let len = fetched
+                .map(|(_, operands)| operands.len() as u16)
+                .unwrap_or(0);
+            let ret = pc.wrapping_add(1 + len);
+            let res = self.run_while(|ctx| ctx.em.reg.pc.get() != ret || ctx.em.reg.sp.get() != sp);
+
+            if let Err(reason) = res {
+                self.report(&reason, out);
+                return Ok(());
+            }
+        } else if let Err(reason) = res {
+            self.report(&reason, out);
+            return Ok(());
+        }
+
+        self.print_location(out);
+        Ok(())
+    }
+
+    /**
+     * Runs until a ret (or reti) pops the stack
+     * below where it was when `finish` was issued
+     */
+    fn cmd_finish(&mut self, out: &mut dyn Write) -> Result<(), String> {
+        let sp = self.ctx.em.reg.sp.get();
+
+        loop {
+            match self.ctx.step() {
+                Ok(step) => {
+                    let returned = step.opcode == RET || step.opcode == RETI;
+                    if returned && self.ctx.em.reg.sp.get() < sp {
+                        break;
+                    }
+                }
+                Err(reason) => {
+                    self.report(&reason, out);
+                    return Ok(());
+                }
+            }
+
+            let pc = self.ctx.em.reg.pc.get();
+            if self.ctx.breakpoints.contains(&pc) {
+                self.report(&StopReason::Breakpoint(pc), out);
+                return Ok(());
+            }
+        }
+
+        self.print_location(out);
+        Ok(())
+    }
+
+    /**
+     * Steps while `cond` holds, stopping at breakpoints
+     */
+    fn run_while<F: Fn(&AsmContext) -> bool>(&mut self, cond: F) -> Result<(), StopReason> {
+        while cond(&self.ctx) {
+            let pc = self.ctx.em.reg.pc.get();
+            if self.ctx.breakpoints.contains(&pc) {
+                return Err(StopReason::Breakpoint(pc));
+            }
+
+            self.ctx.step()?;
+        }
+
+        Ok(())
+    }
+
+    fn cmd_dump(&self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let space = args
+            .get(1)
+            .ok_or("usage: x iram|sfr|xram|code ADDR [LEN]")?;
+        let em = &self.ctx.em;
+
+        let (start, default_len): (usize, usize) = match *space {
+            "iram" => (0, ram::RAM_SIZE),
+            "sfr" => (0x80, 0x80),
+            _ => (0, 64),
+        };
+
+        let addr = match args.get(2) {
+            Some(a) => self.parse_addr(a)? as usize,
+            None => start,
+        };
+        let len = match args.get(3) {
+            Some(n) => parse_value(n)? as usize,
+            None => default_len,
+        };
+
+        let bytes: Vec<u8> = match *space {
+            "iram" => (addr..(addr + len).min(ram::RAM_SIZE))
+                .map(|a| em.ram.read(a))
+                .collect(),
+            "sfr" => (addr.max(0x80)..(addr + len).min(0x100))
+                .map(|a| em.read_direct(a as u8))
+                .collect(),
+            "xram" => em.xram[addr.min(ram::XRAM_SIZE)..(addr + len).min(ram::XRAM_SIZE)].to_vec(),
+            "code" => em.rom[addr.min(em.rom.len())..(addr + len).min(em.rom.len())].to_vec(),
+            _ => return Err(format!("unknown memory space `{}`", space)),
+        };
+
+        let base = if *space == "sfr" {
+            addr.max(0x80)
+        } else {
+            addr
+        };
+        for (i, row) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = row
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() || *b == b' ' {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+
+            let _ = writeln!(
+                out,
+                "{:04X}: {:<48} |{}|",
+                base + i * 16,
+                hex.join(" "),
+                ascii
+            );
+        }
+
+        Ok(())
+    }
+
+    fn cmd_set(&mut self, args: &[&str]) -> Result<(), String> {
+        if args.len() < 3 {
+            return Err("usage: set REG|FLAG VALUE or set iram|xram ADDR BYTE...".to_string());
+        }
+
+        let em = &mut self.ctx.em;
+        let target = args[1];
+
+        if target == "iram" || target == "xram" {
+            let addr = parse_value(args[2])? as usize;
+
+            for (i, byte) in args[3..].iter().enumerate() {
+                let byte = parse_value(byte)? as u8;
+                let size = if target == "iram" {
+                    ram::RAM_SIZE
+                } else {
+                    ram::XRAM_SIZE
+                };
+
+                if addr + i >= size {
+                    return Err(format!("{} address out of range", target));
+                }
+
+                if target == "iram" {
+                    em.ram.write(addr + i, byte);
+                } else {
+                    em.xram[addr + i] = byte;
+                }
+            }
+
+            return Ok(());
+        }
+
+        let value = parse_value(args[2])?;
+        let upper = target.to_ascii_uppercase();
+
+        if let Some((_, flag)) = FLAGS.iter().find(|(name, _)| *name == upper) {
+            let mask = 1 << *flag as u8;
+            let psw = em.psw.get();
+            em.psw
+                .set(if value != 0 { psw | mask } else { psw & !mask });
+            return Ok(());
+        }
+
+        match upper.as_str() {
+            "A" => em.reg.a.set(value as u8),
+            "B" => em.reg.b.set(value as u8),
+            "SP" => em.reg.sp.set(value as u8),
+            "PSW" => em.psw.set(value as u8),
+            "DPTR" => em.reg.dptr.set(value as u16),
+            "PC" => em.reg.pc.set(value as u16),
+            r if r.starts_with('R') && r.len() == 2 => {
+                let n = r[1..]
+                    .parse::<u8>()
+                    .map_err(|_| format!("unknown register `{}`", target))?;
+                if n > 7 {
+                    return Err(format!("unknown register `{}`", target));
+                }
+                em.write_rn(n, value as u8);
+            }
+            _ => return Err(format!("unknown register `{}`", target)),
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Disassembles a few instructions either side of PC,
+     * or from an explicit address. Going backwards is ambiguous
+     * with variable length instructions, so the listing starts
+     * at the closest symbol before PC when there is one.
+     */
+    fn cmd_dis(&self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let rom = &self.ctx.em.rom;
+        let pc = self.ctx.em.reg.pc.get();
+
+        let count = match args.get(2) {
+            Some(n) => parse_value(n)? as usize,
+            None => 10,
+        };
+
+        let listing = match args.get(1) {
+            Some(a) => disasm::disassemble_range(rom, self.parse_addr(a)?, count, &self.symbols),
+            None => {
+                let start = match symbols::nearest(&self.symbols, pc) {
+                    Some((_, offset)) if offset <= 32 => pc - offset,
+                    _ => pc,
+                };
+
+                let all = disasm::disassemble_range(rom, start, usize::MAX, &self.symbols);
+                let at = all.iter().position(|i| i.addr >= pc).unwrap_or(0);
+                let first = at.saturating_sub(count / 2);
+
+                all.into_iter().skip(first).take(count).collect()
+            }
+        };
+
+        for ins in listing {
+            if let Some(name) = symbols::name_at(&self.symbols, ins.addr) {
+                let _ = writeln!(out, "{}:", name);
+            }
+
+            let bytes: Vec<String> = ins.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let marker = if ins.addr == pc { "=>" } else { "  " };
+            let _ = writeln!(
+                out,
+                "{} {:04X}  {:<9} {}",
+                marker,
+                ins.addr,
+                bytes.join(" "),
+                ins.text
+            );
+        }
+
+        Ok(())
+    }
+
+    fn cmd_trace(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        match args.get(1).copied() {
+            Some("on") => {
+                let tracer = match args.get(2) {
+                    Some(n) => Tracer::with_capacity(parse_value(n)? as usize),
+                    None => Tracer::new(),
+                };
+
+                self.ctx.tracer = Some(tracer);
+                Ok(())
+            }
+            Some("off") => {
+                self.ctx.tracer = None;
+                Ok(())
+            }
+            Some("range") => {
+                let usage = "usage: trace range FROM TO";
+                let from = self.parse_addr(args.get(2).ok_or(usage)?)?;
+                let to = self.parse_addr(args.get(3).ok_or(usage)?)?;
+                let tracer = self.ctx.tracer.as_mut().ok_or("tracing is off")?;
+
+                tracer.range = Some(from..=to);
+                Ok(())
+            }
+            Some("save") => {
+                let path = args.get(2).ok_or("usage: trace save FILE [text|bin]")?;
+                let tracer = self.ctx.tracer.as_ref().ok_or("tracing is off")?;
+                let mut file = fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+
+                let res = match args.get(3).copied() {
+                    Some("bin") => tracer.write_binary(&mut file),
+                    None | Some("text") => {
+                        tracer.write_text(&mut file, &self.symbols, &self.ctx.lines)
+                    }
+                    Some(other) => return Err(format!("unknown trace format `{}`", other)),
+                };
+
+                res.map_err(|e| format!("{}: {}", path, e))?;
+                writeln!(out, "wrote {} instructions", tracer.entries.len())
+                    .map_err(|e| e.to_string())
+            }
+            count => {
+                let count = match count {
+                    Some(n) => parse_value(n)? as usize,
+                    None => 20,
+                };
+                let tracer = self.ctx.tracer.as_ref().ok_or("tracing is off")?;
+
+                let skip = tracer.entries.len().saturating_sub(count);
+                let last = Tracer {
+                    entries: tracer.entries.iter().skip(skip).cloned().collect(),
+                    ..Tracer::default()
+                };
+                last.write_text(out, &self.symbols, &self.ctx.lines)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /**
+     * Recorded history belongs to the old state, so it starts over
+     */
+    fn cmd_load(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let path = args.get(1).ok_or("usage: load FILE")?;
+        snapshot::load(&mut self.ctx.em, path)?;
+
+        if let Some(history) = &self.ctx.history {
+            self.ctx.history = Some(History::with_limit(history.limit));
+        }
+
+        self.print_location(out);
+        Ok(())
+    }
+
+    fn cmd_symbols(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let path = args.get(1).ok_or("usage: symbols FILE")?;
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let loaded = symbols::parse(&text)?;
+
+        let _ = writeln!(out, "loaded {} symbols", loaded.len());
+        self.symbols.extend(loaded);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+    use crate::emulator::Emulator;
+
+    fn debugger(source: &str) -> Debugger {
+        let mut lc = LexerContext::new(source.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.file = "test.plasm".to_string();
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx.lines = pc.lines;
+        Debugger::new(ctx, symbols::from_labels(&pc.lb))
+    }
+
+    /* what the commands printed */
+    fn execute(debugger: &mut Debugger, commands: &[&str]) -> String {
+        let mut out = Vec::new();
+
+        for command in commands {
+            debugger.execute(command, &mut out);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn watching_a_bit_stops_where_it_changes() {
+        let mut dbg = debugger("mov A, #0\nsetb 20H.3\nmov A, #1\nend\n");
+        let out = execute(&mut dbg, &["watch bit 20H.3", "c"]);
+
+        assert!(
+            out.starts_with("watchpoint 1: write bit 20H.3\n"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("watchpoint 1 hit at 0x0002, test.plasm:2"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("  setb 20H.3\n  old = 00H, new = 01H\n"),
+            "{}",
+            out
+        );
+
+        let out = execute(&mut dbg, &["c"]);
+        assert!(out.starts_with("program halted\n"), "{}", out);
+    }
+
+    #[test]
+    fn stops_name_the_source_line() {
+        let mut dbg = debugger("mov A, #0\nmov B, #1\nend\n");
+        let out = execute(&mut dbg, &["b 2", "c"]);
+
+        assert!(
+            out.contains("breakpoint at 0x0002, test.plasm:2"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn bad_bit_addresses_get_the_assembler_message() {
+        let mut dbg = debugger("mov A, #0\nend\n");
+
+        assert_eq!(
+            execute(&mut dbg, &["watch bit 30H.1"]),
+            "error: byte is not bit addressable: 30H.1\n"
+        );
+        assert_eq!(
+            execute(&mut dbg, &["watch bit 20H.9"]),
+            "error: bit index out of range: 20H.9\n"
+        );
+        assert_eq!(
+            execute(&mut dbg, &["watch bit TR0"]),
+            "watchpoint 1: write bit TR0\n"
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::ram::{self, Ram};
+use crate::sfr::{self, Sfr};
+
+/**
+ * A byte addressed memory
+ * Implemented by internal RAM, the SFRs (addressed 80H to FFH)
+ * and plain byte vectors for external RAM and code.
+ * `Emulator::memory` hands out the one behind an address space.
+ */
+pub trait Memory {
+    /* first valid address */
+    fn base(&self) -> usize {
+        0
+    }
+
+    /* valid addresses, counting from `base` */
+    fn size(&self) -> usize;
+    fn read(&self, address: usize) -> u8;
+    fn write(&mut self, address: usize, data: u8);
+
+    fn contains(&self, address: usize) -> bool {
+        address >= self.base() && address - self.base() < self.size()
+    }
+}
+
+impl Memory for Ram {
+    fn size(&self) -> usize {
+        ram::RAM_SIZE
+    }
+
+    fn read(&self, address: usize) -> u8 {
+        Ram::read(self, address)
+    }
+
+    fn write(&mut self, address: usize, data: u8) {
+        Ram::write(self, address, data)
+    }
+}
+
+impl Memory for Sfr {
+    fn base(&self) -> usize {
+        sfr::SFR_BASE
+    }
+
+    fn size(&self) -> usize {
+        sfr::SFR_SIZE
+    }
+
+    fn read(&self, address: usize) -> u8 {
+        Sfr::read(self, address as u8)
+    }
+
+    fn write(&mut self, address: usize, data: u8) {
+        Sfr::write(self, address as u8, data)
+    }
+}
+
+impl Memory for Vec<u8> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn read(&self, address: usize) -> u8 {
+        self[address]
+    }
+
+    fn write(&mut self, address: usize, data: u8) {
+        self[address] = data;
+    }
+}
+
+/**
+ * A device clocked with the emulator
+ * Hosts add their own hardware to `Emulator::devices`, it
+ * talks to the program through SFRs like the built in ones.
+ */
+pub trait Peripheral {
+    /* advances by `cycles` machine cycles */
+    fn tick(&mut self, sfr: &mut Sfr, cycles: u32);
+}
@@ -1,12 +1,29 @@
+use crate::assembler::segment::{Segments, STACK_SIZE};
+use crate::interrupt;
+#[cfg(feature = "peripherals")]
+use crate::peripherals;
 use crate::psw;
 use crate::ram;
 use crate::regs;
+use crate::sfr;
+use crate::syscall;
 
 use regs::Register16;
 use regs::Register8;
 
-use psw::Psw;
+use crate::device::{Memory, Peripheral};
+use interrupt::InterruptController;
+#[cfg(feature = "peripherals")]
+use peripherals::gpio::Gpio;
+#[cfg(feature = "peripherals")]
+use peripherals::serial::Serial;
+#[cfg(feature = "peripherals")]
+use peripherals::timer::{TimerPins, Timers};
+use psw::{Psw, PswFlag};
 use ram::Ram;
+use sfr::Sfr;
+use std::cell::RefCell;
+use syscall::{HostIo, StdIo, Syscalls};
 
 #[derive(Debug)]
 pub struct AllRegs {
@@ -17,11 +34,82 @@ pub struct AllRegs {
     pub sp: regs::Register8,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Space {
+    Iram,
+    Sfr,
+    Xram,
+}
+
+/**
+ * A memory access made by an instruction
+ * For reads `old` and `value` are the same
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub space: Space,
+    pub addr: u16,
+    pub write: bool,
+    pub old: u8,
+    pub value: u8,
+}
+
+/**
+ * A push past the end of RAM
+ */
+#[derive(Debug)]
+pub struct StackOverflow;
+
 pub struct Emulator {
     pub psw: Psw,
     pub ram: Ram,
+    pub xram: Vec<u8>,
+    pub sfr: Sfr,
     pub reg: AllRegs,
     pub rom: Vec<u8>,
+    #[cfg(feature = "peripherals")]
+    pub timers: Timers,
+    #[cfg(feature = "peripherals")]
+    pub serial: Serial,
+    #[cfg(feature = "peripherals")]
+    pub gpio: Gpio,
+    pub irq: InterruptController,
+
+    /* host supplied devices, ticked after the built in ones */
+    pub devices: Vec<Box<dyn Peripheral>>,
+
+    /* int 21H services and the host side they talk to */
+    pub syscalls: Syscalls,
+    pub io: Box<dyn HostIo>,
+
+    /* set once the program exits through int 21H */
+    pub exit_code: Option<u8>,
+
+    /* machine cycles executed since reset */
+    pub cycles: u64,
+
+    /*
+       Memory accesses of the instruction being executed,
+       only collected while `recording` is set so that
+       the debugger and interrupt polling don't show up
+    */
+    pub recording: bool,
+    pub accesses: RefCell<Vec<Access>>,
+
+    /* oscillator frequency, one machine cycle is 12 periods */
+    pub oscillator_hz: u64,
+}
+
+/* oscillator periods per machine cycle */
+pub const CLOCKS_PER_CYCLE: u64 = 12;
+
+/* 12 MHz, which makes a machine cycle exactly 1us */
+pub const DEFAULT_OSCILLATOR_HZ: u64 = 12_000_000;
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Emulator {
@@ -29,6 +117,8 @@ impl Emulator {
         let mut res = Emulator {
             psw: Psw::new(),
             ram: Ram::new(),
+            xram: vec![0; ram::XRAM_SIZE],
+            sfr: Sfr::new(),
             reg: AllRegs {
                 a: Register8::new(),
                 b: Register8::new(),
@@ -37,6 +127,21 @@ impl Emulator {
                 sp: Register8::new(),
             },
             rom: Vec::new(),
+            #[cfg(feature = "peripherals")]
+            timers: Timers::new(),
+            #[cfg(feature = "peripherals")]
+            serial: Serial::new(),
+            #[cfg(feature = "peripherals")]
+            gpio: Gpio::new(),
+            irq: InterruptController::default(),
+            devices: Vec::new(),
+            syscalls: Syscalls::new(),
+            io: Box::new(StdIo),
+            exit_code: None,
+            cycles: 0,
+            recording: false,
+            accesses: RefCell::new(Vec::new()),
+            oscillator_hz: DEFAULT_OSCILLATOR_HZ,
         };
 
         /*
@@ -53,4 +158,317 @@ impl Emulator {
     pub fn burn(&mut self, bytes: Vec<u8>) {
         self.rom = bytes;
     }
+
+    /**
+     * RAM address of register Rn in the selected bank
+     */
+    pub fn rn_address(&self, n: u8) -> usize {
+        let bank = (self.psw.get_flag(PswFlag::RS1) as usize) << 1
+            | (self.psw.get_flag(PswFlag::RS0) as usize);
+
+        ram::BANK_ADDRESSES[bank] + n as usize
+    }
+
+    pub fn read_rn(&self, n: u8) -> u8 {
+        self.read_iram(self.rn_address(n))
+    }
+
+    pub fn write_rn(&mut self, n: u8, data: u8) {
+        self.write_iram(self.rn_address(n), data);
+    }
+
+    fn record(&self, space: Space, addr: u16, write: bool, old: u8, value: u8) {
+        if self.recording {
+            self.accesses.borrow_mut().push(Access {
+                space,
+                addr,
+                write,
+                old,
+                value,
+            });
+        }
+    }
+
+    /**
+     * The memory behind an address space
+     * SFRs are the plain bytes, without A, B, PSW, SP,
+     * DPTR, SBUF and the ports mapped onto them.
+     */
+    pub fn memory(&self, space: Space) -> &dyn Memory {
+        match space {
+            Space::Iram => &self.ram,
+            Space::Sfr => &self.sfr,
+            Space::Xram => &self.xram,
+        }
+    }
+
+    pub fn memory_mut(&mut self, space: Space) -> &mut dyn Memory {
+        match space {
+            Space::Iram => &mut self.ram,
+            Space::Sfr => &mut self.sfr,
+            Space::Xram => &mut self.xram,
+        }
+    }
+
+    fn load(&self, space: Space, address: usize) -> u8 {
+        let data = self.memory(space).read(address);
+        self.record(space, address as u16, false, data, data);
+        data
+    }
+
+    fn store(&mut self, space: Space, address: usize, data: u8) {
+        let memory = self.memory_mut(space);
+        let old = memory.read(address);
+
+        memory.write(address, data);
+        self.record(space, address as u16, true, old, data);
+    }
+
+    /*
+       Accessors instructions go through,
+       so their accesses can be recorded
+    */
+    pub fn read_iram(&self, address: usize) -> u8 {
+        self.load(Space::Iram, address)
+    }
+
+    pub fn write_iram(&mut self, address: usize, data: u8) {
+        self.store(Space::Iram, address, data);
+    }
+
+    pub fn read_xram(&self, address: u16) -> u8 {
+        self.load(Space::Xram, address as usize)
+    }
+
+    pub fn write_xram(&mut self, address: u16, data: u8) {
+        self.store(Space::Xram, address as usize, data);
+    }
+
+    /**
+     * Reads a directly addressed byte
+     * 0x00 to 0x7F is RAM, the rest are SFRs.
+     * SFRs that the emulator keeps as registers
+     * (A, B, PSW, SP, DPTR) are mapped onto them.
+     * SBUF reads the receive buffer, writes go to the transmitter.
+     * Ports read their pins and write their latches.
+     * Without peripherals these are all plain SFR bytes.
+     */
+    pub fn read_direct(&self, address: u8) -> u8 {
+        if address < 0x80 {
+            return self.read_iram(address as usize);
+        }
+
+        let data = self.peek_direct(address);
+        self.record(Space::Sfr, address as u16, false, data, data);
+        data
+    }
+
+    /**
+     * Reads a directly addressed byte without recording the access
+     */
+    pub fn peek_direct(&self, address: u8) -> u8 {
+        #[cfg(feature = "peripherals")]
+        if let Some(port) = Gpio::port_of(address) {
+            return self.gpio.pins(port);
+        }
+
+        match address {
+            0x00..=0x7F => self.ram.read(address as usize),
+            sfr::ACC => self.reg.a.get(),
+            sfr::B => self.reg.b.get(),
+            sfr::PSW => self.psw.get(),
+            sfr::SP => self.reg.sp.get(),
+            sfr::DPL => self.reg.dptr.get() as u8,
+            sfr::DPH => (self.reg.dptr.get() >> 8) as u8,
+            #[cfg(feature = "peripherals")]
+            sfr::SBUF => self.serial.rx_buffer,
+            _ => self.sfr.read(address),
+        }
+    }
+
+    pub fn write_direct(&mut self, address: u8, data: u8) {
+        if address < 0x80 {
+            self.write_iram(address as usize, data);
+            return;
+        }
+
+        let old = self.peek_direct(address);
+        self.record(Space::Sfr, address as u16, true, old, data);
+
+        #[cfg(feature = "peripherals")]
+        if let Some(port) = Gpio::port_of(address) {
+            self.gpio.write_latch(port, data);
+            return;
+        }
+
+        match address {
+            sfr::ACC => self.reg.a.set(data),
+            sfr::B => self.reg.b.set(data),
+            sfr::PSW => self.psw.set(data),
+            sfr::SP => self.reg.sp.set(data),
+            sfr::DPL => {
+                let dptr = self.reg.dptr.get();
+                self.reg.dptr.set((dptr & 0xFF00) | data as u16);
+            }
+            sfr::DPH => {
+                let dptr = self.reg.dptr.get();
+                self.reg.dptr.set((dptr & 0x00FF) | (data as u16) << 8);
+            }
+            #[cfg(feature = "peripherals")]
+            sfr::SBUF => self.serial.write_sbuf(&self.sfr, data),
+            _ => self.sfr.write(address, data),
+        }
+    }
+
+    pub fn read_bit(&self, bit: u8) -> bool {
+        let (address, index) = sfr::bit_location(bit);
+        self.read_direct(address) & (1 << index) != 0
+    }
+
+    /**
+     * Like read_direct, but ports read their latch
+     * This is what read-modify-write instructions see
+     */
+    pub fn read_latch(&self, address: u8) -> u8 {
+        #[cfg(feature = "peripherals")]
+        if let Some(port) = Gpio::port_of(address) {
+            return self.gpio.latch(port);
+        }
+
+        self.read_direct(address)
+    }
+
+    pub fn write_bit(&mut self, bit: u8, value: bool) {
+        let (address, index) = sfr::bit_location(bit);
+        let data = self.read_latch(address);
+
+        if value {
+            self.write_direct(address, data | (1 << index));
+        } else {
+            self.write_direct(address, data & !(1 << index));
+        }
+    }
+
+    /**
+     * Pushes onto the stack in RAM
+     * Fails, leaving SP alone, if the stack would grow past the end of RAM
+     */
+    pub fn push(&mut self, data: u8) -> Result<(), StackOverflow> {
+        let sp = self.reg.sp.get() as usize + 1;
+        if sp >= ram::RAM_SIZE {
+            return Err(StackOverflow);
+        }
+
+        self.reg.sp.set(sp as u8);
+        self.write_iram(sp, data);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> u8 {
+        let sp = self.reg.sp.get();
+        let data = self.read_iram(sp as usize % ram::RAM_SIZE);
+        self.reg.sp.set(sp.wrapping_sub(1));
+        data
+    }
+
+    /**
+     * Passes command line arguments the way lib/rsc/argparser.plasm expects
+     * The zero terminated strings go in RAM the program's `segments`
+     * leave free, like a `byte times n`. The stack is moved to the area
+     * at the top of RAM the assembler keeps for it, then one byte
+     * pointers to the strings are pushed last to first, then argc, so
+     * the program pops argc, then argv[0], argv[1] and so on.
+     * `args[0]` is the program name.
+     */
+    pub fn push_args(&mut self, args: &[String], segments: &Segments) -> Result<(), String> {
+        let size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+        let pushed = args.len() + 1;
+
+        if pushed > STACK_SIZE {
+            return Err(format!(
+                "{} arguments do not fit on the stack, at most {} do",
+                args.len(),
+                STACK_SIZE - 1
+            ));
+        }
+
+        let mut addr = match size {
+            /* allocate() takes at least a byte */
+            0 => 0,
+            _ => segments.clone().allocate(size).map_err(|_| {
+                format!(
+                    "arguments need {} bytes of RAM the program does not use",
+                    size
+                )
+            })? as usize,
+        };
+        let mut pointers = Vec::new();
+
+        for arg in args {
+            pointers.push(addr as u8);
+            for byte in arg.bytes().chain([0]) {
+                self.ram.write(addr, byte);
+                addr += 1;
+            }
+        }
+
+        self.reg.sp.set((ram::RAM_SIZE - STACK_SIZE - 1) as u8);
+        for pointer in pointers.iter().rev() {
+            self.push(*pointer).map_err(|_| "stack overflow")?;
+        }
+        self.push(args.len() as u8).map_err(|_| "stack overflow")?;
+        Ok(())
+    }
+
+    pub fn set_oscillator(&mut self, hz: u64) {
+        self.oscillator_hz = hz;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /**
+     * Microseconds of emulated time since reset
+     */
+    pub fn elapsed_us(&self) -> f64 {
+        (self.cycles * CLOCKS_PER_CYCLE) as f64 * 1_000_000.0 / self.oscillator_hz as f64
+    }
+
+    /**
+     * Machine cycles in `us` microseconds at the current oscillator frequency
+     */
+    pub fn us_to_cycles(&self, us: u64) -> u64 {
+        (us as u128 * self.oscillator_hz as u128 / (1_000_000 * CLOCKS_PER_CYCLE as u128)) as u64
+    }
+
+    /**
+     * Advances the on-chip peripherals by `cycles` machine cycles
+     * This is the clock every peripheral runs from
+     */
+    pub fn tick(&mut self, cycles: u32) {
+        #[cfg(feature = "peripherals")]
+        {
+            let pins = TimerPins {
+                int0: self.gpio.pin(3, 2),
+                int1: self.gpio.pin(3, 3),
+                t0: self.gpio.pin(3, 4),
+                t1: self.gpio.pin(3, 5),
+            };
+
+            let t1_overflows = self.timers.tick(&mut self.sfr, pins, cycles);
+            self.serial.tick(&mut self.sfr, cycles, t1_overflows);
+        }
+
+        for device in self.devices.iter_mut() {
+            device.tick(&mut self.sfr, cycles);
+        }
+
+        self.cycles += cycles as u64;
+
+        #[cfg(feature = "peripherals")]
+        {
+            self.gpio.now = self.cycles;
+        }
+    }
 }
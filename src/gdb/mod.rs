@@ -0,0 +1,721 @@
+use crate::assembler::engine::{AsmContext, StopReason};
+use crate::assembler::watch::{WatchKind, WatchSpace, Watchpoint};
+use crate::ram;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/*
+   GDB sees one flat address space, split like this:
+   code at 0, external RAM at 10000H and directly addressed
+   data (RAM below 80H, SFRs above) at 20000H
+*/
+pub const CODE_BASE: u32 = 0x0_0000;
+pub const XRAM_BASE: u32 = 0x1_0000;
+pub const DATA_BASE: u32 = 0x2_0000;
+
+const XRAM_END: u32 = XRAM_BASE + ram::XRAM_SIZE as u32;
+const DATA_END: u32 = DATA_BASE + 0x100;
+
+/* stop signals */
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 31;
+
+/* steps between checks for a ^C from the client */
+const INTERRUPT_POLL: u32 = 4096;
+
+/**
+ * Register numbers, in `g` packet order
+ * 8 bit registers are 1 byte, DPTR and PC 2 bytes big endian.
+ */
+pub const REGISTERS: [(&str, usize); 14] = [
+    ("a", 1),
+    ("b", 1),
+    ("psw", 1),
+    ("sp", 1),
+    ("dptr", 2),
+    ("pc", 2),
+    ("r0", 1),
+    ("r1", 1),
+    ("r2", 1),
+    ("r3", 1),
+    ("r4", 1),
+    ("r5", 1),
+    ("r6", 1),
+    ("r7", 1),
+];
+
+/**
+ * A client connection
+ */
+pub trait Connection: Read + Write {
+    /* true if the client sent ^C, must not block */
+    fn interrupted(&mut self) -> bool;
+}
+
+fn poll_interrupt<S: Read>(
+    stream: &mut S,
+    set_nonblocking: impl Fn(&S, bool) -> io::Result<()>,
+) -> bool {
+    let mut byte = [0u8; 1];
+
+    if set_nonblocking(stream, true).is_err() {
+        return false;
+    }
+    let res = matches!(stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+    let _ = set_nonblocking(stream, false);
+
+    res
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> bool {
+        poll_interrupt(self, TcpStream::set_nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn interrupted(&mut self) -> bool {
+        poll_interrupt(self, std::os::unix::net::UnixStream::set_nonblocking)
+    }
+}
+
+/**
+ * GDB remote serial protocol server for one emulator
+ */
+pub struct GdbStub<'a> {
+    pub ctx: &'a mut AsmContext,
+    /* watchpoint ids behind each Z2/Z3/Z4 packet, by type and address */
+    watches: HashMap<(u8, u32), Vec<usize>>,
+    ack: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/**
+ * Listens on a TCP address such as 127.0.0.1:1234
+ * and serves one client
+ */
+pub fn serve_tcp(ctx: &mut AsmContext, addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("waiting for gdb on {}", listener.local_addr()?);
+
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(ctx).serve(stream)
+}
+
+/**
+ * Listens on a Unix socket and serves one client
+ */
+#[cfg(unix)]
+pub fn serve_unix(ctx: &mut AsmContext, path: &str) -> io::Result<()> {
+    let _ = std::fs::remove_file(path);
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    eprintln!("waiting for gdb on {}", path);
+
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(ctx).serve(stream)
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(ctx: &'a mut AsmContext) -> GdbStub<'a> {
+        GdbStub {
+            ctx,
+            watches: HashMap::new(),
+            ack: true,
+        }
+    }
+
+    /**
+     * Answers packets until the client detaches, kills
+     * the target or disconnects
+     */
+    pub fn serve<C: Connection>(&mut self, mut conn: C) -> io::Result<()> {
+        while let Some(packet) = self.read_packet(&mut conn)? {
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.send(&mut conn, "OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(&packet, &mut conn),
+            };
+
+            self.send(&mut conn, &reply)?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Reads the next `$data#checksum` packet, None on disconnect
+     * A ^C outside a packet reads as the packet "\x03".
+     * Packets with a bad checksum are asked for again.
+     */
+    fn read_packet<C: Connection>(&mut self, conn: &mut C) -> io::Result<Option<String>> {
+        let mut byte = [0u8; 1];
+
+        loop {
+            loop {
+                if conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+
+                match byte[0] {
+                    b'$' => break,
+                    0x03 => return Ok(Some("\x03".to_string())),
+                    /* acks and noise */
+                    _ => continue,
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut checksum = [0u8; 2];
+            conn.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+            if self.ack {
+                if expected != Some(actual) {
+                    conn.write_all(b"-")?;
+                    continue;
+                }
+                conn.write_all(b"+")?;
+            }
+
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send<C: Connection>(&mut self, conn: &mut C, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+
+        loop {
+            write!(conn, "${}#{:02x}", data, checksum)?;
+            conn.flush()?;
+
+            if !self.ack {
+                return Ok(());
+            }
+
+            /* the client acks with + or asks again with - */
+            let mut byte = [0u8; 1];
+            conn.read_exact(&mut byte)?;
+            if byte[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    /**
+     * The reply to one packet, empty for unsupported ones
+     */
+    fn handle<C: Connection>(&mut self, packet: &str, conn: &mut C) -> String {
+        if !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (command, args) = packet.split_at(1);
+
+        let res = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "\x03" => Some(format!("S{:02x}", SIGINT)),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.ctx.em.reg.pc.set(addr as u16);
+                }
+                Some(self.resume(conn, command == "s"))
+            }
+            "Z" | "z" => self.breakpoint(args, command == "Z"),
+            "H" => Some("OK".to_string()),
+            "q" | "Q" => self.query(packet),
+            _ => Some(String::new()),
+        };
+
+        res.unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn query(&mut self, packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string());
+        }
+
+        if packet == "QStartNoAckMode" {
+            self.ack = false;
+            return Some("OK".to_string());
+        }
+
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = rest.split_once(',')?;
+            let (offset, len) = (parse_hex(offset)? as usize, parse_hex(len)? as usize);
+            let xml = target_xml();
+
+            let end = offset.saturating_add(len);
+
+            let chunk = xml.get(offset.min(xml.len())..end.min(xml.len()))?;
+            let more = end < xml.len();
+            return Some(format!("{}{}", if more { "m" } else { "l" }, chunk));
+        }
+
+        Some(
+            match packet {
+                "qAttached" => "1",
+                "qC" => "QC1",
+                "qfThreadInfo" => "m1",
+                "qsThreadInfo" => "l",
+                _ => "",
+            }
+            .to_string(),
+        )
+    }
+
+    fn register_bytes(&self, n: usize) -> Option<Vec<u8>> {
+        let em = &self.ctx.em;
+
+        Some(match n {
+            0 => vec![em.reg.a.get()],
+            1 => vec![em.reg.b.get()],
+            2 => vec![em.psw.get()],
+            3 => vec![em.reg.sp.get()],
+            4 => em.reg.dptr.get().to_be_bytes().to_vec(),
+            5 => em.reg.pc.get().to_be_bytes().to_vec(),
+            6..=13 => vec![em.read_rn(n as u8 - 6)],
+            _ => return None,
+        })
+    }
+
+    fn set_register(&mut self, n: usize, bytes: &[u8]) {
+        let em = &mut self.ctx.em;
+        let wide = || (bytes[0] as u16) << 8 | bytes[1] as u16;
+
+        match n {
+            0 => em.reg.a.set(bytes[0]),
+            1 => em.reg.b.set(bytes[0]),
+            2 => em.psw.set(bytes[0]),
+            3 => em.reg.sp.set(bytes[0]),
+            4 => em.reg.dptr.set(wide()),
+            5 => em.reg.pc.set(wide()),
+            _ => em.write_rn(n as u8 - 6, bytes[0]),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTERS.len())
+            .filter_map(|n| self.register_bytes(n))
+            .map(|bytes| hex(&bytes))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let bytes = unhex(args)?;
+        let mut at = 0;
+
+        for (n, (_, size)) in REGISTERS.iter().enumerate() {
+            let value = bytes.get(at..at + size)?;
+            self.set_register(n, value);
+            at += size;
+        }
+
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let n = parse_hex(args)? as usize;
+        Some(hex(&self.register_bytes(n)?))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (n, value) = args.split_once('=')?;
+        let n = parse_hex(n)? as usize;
+        let bytes = unhex(value)?;
+
+        if bytes.len() != REGISTERS.get(n)?.1 {
+            return None;
+        }
+
+        self.set_register(n, &bytes);
+        Some("OK".to_string())
+    }
+
+    fn read_byte(&self, addr: u32) -> Option<u8> {
+        let em = &self.ctx.em;
+
+        match addr {
+            CODE_BASE..XRAM_BASE => em.rom.get(addr as usize).copied(),
+            XRAM_BASE..XRAM_END => Some(em.xram[(addr - XRAM_BASE) as usize]),
+            DATA_BASE..DATA_END => Some(em.peek_direct((addr - DATA_BASE) as u8)),
+            _ => None,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u32, value: u8) -> Option<()> {
+        let em = &mut self.ctx.em;
+
+        match addr {
+            CODE_BASE..XRAM_BASE => *em.rom.get_mut(addr as usize)? = value,
+            XRAM_BASE..XRAM_END => em.xram[(addr - XRAM_BASE) as usize] = value,
+            DATA_BASE..DATA_END => em.write_direct((addr - DATA_BASE) as u8, value),
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+
+        let bytes: Option<Vec<u8>> = (addr..addr.checked_add(len)?)
+            .map(|a| self.read_byte(a))
+            .collect();
+        Some(hex(&bytes?))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = range.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        let bytes = unhex(data)?;
+
+        if bytes.len() != len as usize {
+            return None;
+        }
+
+        /* all or nothing, like the watchpoint ranges */
+        if (addr..addr.checked_add(len)?).any(|a| self.read_byte(a).is_none()) {
+            return None;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write_byte(addr + i as u32, *byte)?;
+        }
+        Some("OK".to_string())
+    }
+
+    /**
+     * Z/z packets: type 0 and 1 are breakpoints,
+     * 2 write, 3 read and 4 access watchpoints
+     */
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut parts = args.split(',');
+        let kind = parts.next()?.parse::<u8>().ok()?;
+        let addr = parse_hex(parts.next()?)?;
+        let len = parse_hex(parts.next()?)?;
+
+        match kind {
+            0 | 1 => {
+                if addr >= XRAM_BASE {
+                    return None;
+                }
+
+                if insert {
+                    self.ctx.add_breakpoint(addr as u16);
+                } else {
+                    self.ctx.remove_breakpoint(addr as u16);
+                }
+            }
+            2..=4 => {
+                if !insert {
+                    for id in self.watches.remove(&(kind, addr)).unwrap_or_default() {
+                        self.ctx.remove_watchpoint(id);
+                    }
+                    return Some("OK".to_string());
+                }
+
+                let kinds: &[WatchKind] = match kind {
+                    2 => &[WatchKind::Write],
+                    3 => &[WatchKind::Read],
+                    _ => &[WatchKind::Read, WatchKind::Write],
+                };
+
+                /* the whole range must be valid before anything is added */
+                let places: Vec<(WatchSpace, u32)> = (addr..addr.checked_add(len.max(1))?)
+                    .map(|a| match a {
+                        XRAM_BASE..XRAM_END => Some((WatchSpace::Xram, a - XRAM_BASE)),
+                        DATA_BASE..DATA_END if a - DATA_BASE < 0x80 => {
+                            Some((WatchSpace::Iram, a - DATA_BASE))
+                        }
+                        DATA_BASE..DATA_END => Some((WatchSpace::Sfr, a - DATA_BASE)),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?;
+
+                let mut ids = Vec::new();
+                for (space, addr) in places {
+                    for kind in kinds {
+                        ids.push(self.ctx.add_watchpoint(Watchpoint {
+                            space,
+                            addr: addr as u16,
+                            kind: *kind,
+                            condition: None,
+                        }));
+                    }
+                }
+
+                self.watches.entry((kind, addr)).or_default().extend(ids);
+            }
+            _ => return Some(String::new()),
+        }
+
+        Some("OK".to_string())
+    }
+
+    /**
+     * Runs or single steps, returning the stop reply
+     */
+    fn resume<C: Connection>(&mut self, conn: &mut C, single: bool) -> String {
+        if single {
+            return match self.ctx.step() {
+                Ok(_) => format!("S{:02x}", SIGTRAP),
+                Err(reason) => self.stop_reply(reason),
+            };
+        }
+
+        let mut steps = 0u32;
+        loop {
+            if let Err(reason) = self.ctx.step() {
+                return self.stop_reply(reason);
+            }
+
+            let pc = self.ctx.em.reg.pc.get();
+            if self.ctx.breakpoints.contains(&pc) {
+                return format!("S{:02x}", SIGTRAP);
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_POLL) && conn.interrupted() {
+                return format!("S{:02x}", SIGINT);
+            }
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Halted => "W00".to_string(),
+            StopReason::Exited(code) => format!("W{:02x}", code),
+            StopReason::Watchpoint(hit) => {
+                let kind = self
+                    .watches
+                    .iter()
+                    .find(|(_, ids)| ids.contains(&hit.id))
+                    .map(|((kind, _), _)| *kind);
+
+                let name = match kind {
+                    Some(3) => "rwatch",
+                    Some(4) => "awatch",
+                    _ => "watch",
+                };
+
+                /* the exact byte that was hit, for ranges */
+                let addr = match self.ctx.watchpoints.get(&hit.id) {
+                    Some(w) if w.space == WatchSpace::Xram => XRAM_BASE + w.addr as u32,
+                    Some(w) => DATA_BASE + w.addr as u32,
+                    None => 0,
+                };
+
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+            }
+            StopReason::IllegalOpcode { .. } => format!("S{:02x}", SIGILL),
+            StopReason::PcOutOfRom(_)
+            | StopReason::StackOverflow { .. }
+            | StopReason::BadIndirect { .. } => {
+                format!("S{:02x}", SIGSEGV)
+            }
+            StopReason::UnknownSyscall { .. } => format!("S{:02x}", SIGSYS),
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+}
+
+/**
+ * Target description, so clients know the register layout
+ */
+pub fn target_xml() -> String {
+    let mut res = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.prelude.plasm.core\">",
+    );
+
+    for (n, (name, size)) in REGISTERS.iter().enumerate() {
+        let kind = match *name {
+            "pc" => "code_ptr",
+            "dptr" => "data_ptr",
+            _ => "uint8",
+        };
+
+        res.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"{}\"/>",
+            name,
+            size * 8,
+            n,
+            kind
+        ));
+    }
+
+    res.push_str("</feature></target>");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+    use crate::emulator::Emulator;
+    use std::thread;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0u8; 1];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send_raw(&mut self, data: &str, checksum: u8) {
+            write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+        }
+
+        /* sends a packet and returns the reply */
+        fn ask(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            self.send_raw(data, checksum);
+            assert_eq!(self.byte(), b'+');
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            assert_eq!(self.byte(), b'$');
+
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            self.byte();
+            self.byte();
+            self.stream.write_all(b"+").unwrap();
+
+            String::from_utf8(data).unwrap()
+        }
+    }
+
+    #[test]
+    fn drives_the_stub_over_tcp() {
+        let mut lc =
+            LexerContext::new("mov A, #5\nmov B, A\nhere:\nmov R0, #30H\nend\n".to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let here = pc.lb["here"] as u16;
+        let code = pc.cg.clone();
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(addr).unwrap(),
+            };
+            client.stream.set_nodelay(true).unwrap();
+            let mut replies = Vec::new();
+
+            replies.push(client.ask("g"));
+            replies.push(client.ask("m0,3"));
+            replies.push(client.ask("mffffffff,10"));
+            replies.push(client.ask("Mffffffff,2:0000"));
+            /* runs past the end of data space, so none of it is installed */
+            replies.push(client.ask("Z2,200f0,20"));
+            replies.push(client.ask(&format!("Z0,{:x},1", here)));
+            replies.push(client.ask("c"));
+            replies.push(client.ask("p5"));
+            replies.push(client.ask("p1"));
+
+            /* a corrupted packet is asked for again */
+            client.send_raw("m0,1", 0);
+            assert_eq!(client.byte(), b'-');
+            replies.push(client.ask("m0,1"));
+
+            replies.push(client.ask("c"));
+            replies.push(client.ask("m20000,1"));
+            client.send_raw("k", b'k');
+            assert_eq!(client.byte(), b'+');
+
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        GdbStub::new(&mut ctx).serve(stream).unwrap();
+
+        let replies = client.join().unwrap();
+        let expected = [
+            /* A, B, PSW, SP, then DPTR and PC, then R0 to R7 after reset */
+            concat!("00000007", "00000000", "0000000000000000"),
+            &code[..3]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+            "E01",
+            "E01",
+            "E01",
+            "OK",
+            "S05",
+            &format!("{:04x}", here),
+            "05",
+            &format!("{:02x}", code[0]),
+            "W00",
+            "30",
+        ];
+
+        assert_eq!(replies, expected);
+        assert!(ctx.watchpoints.is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::emulator::{Emulator, StackOverflow};
+use crate::sfr;
+
+#[derive(Debug)]
+pub struct Source {
+    pub name: &'static str,
+    pub vector: u16,
+    /* bit addresses of the enable, priority and request bits */
+    pub enable: u8,
+    pub priority: u8,
+    pub flags: &'static [u8],
+    /* whether vectoring clears the request flags */
+    pub clear_on_vector: bool,
+}
+
+/**
+ * Interrupt sources in their polling order,
+ * which also decides between requests of the same priority
+ */
+pub const SOURCES: &[Source] = &[
+    Source {
+        name: "timer0",
+        vector: 0x0B,
+        enable: sfr::IE + 1,
+        priority: sfr::IP + 1,
+        flags: &[sfr::TCON + 5],
+        clear_on_vector: true,
+    },
+    Source {
+        name: "timer1",
+        vector: 0x1B,
+        enable: sfr::IE + 3,
+        priority: sfr::IP + 3,
+        flags: &[sfr::TCON + 7],
+        clear_on_vector: true,
+    },
+    Source {
+        name: "serial",
+        vector: 0x23,
+        enable: sfr::IE + 4,
+        priority: sfr::IP + 4,
+        /* RI and TI, left for the service routine to clear */
+        flags: &[sfr::SCON, sfr::SCON + 1],
+        clear_on_vector: false,
+    },
+];
+
+/* EA, the global enable bit */
+const EA: u8 = sfr::IE + 7;
+
+#[derive(Debug, Default)]
+pub struct InterruptController {
+    /*
+       Priority levels of the interrupts being serviced,
+       innermost last. `reti` pops one level.
+    */
+    pub in_service: Vec<bool>,
+}
+
+/* the interrupt to vector to, if any, and whether it is high priority */
+fn pending(em: &Emulator) -> Option<(&'static Source, bool)> {
+    if !em.read_bit(EA) {
+        return None;
+    }
+
+    let current = em.irq.in_service.last().copied();
+    if current == Some(true) {
+        return None;
+    }
+
+    let requested = |high: bool| {
+        SOURCES.iter().find(|s| {
+            em.read_bit(s.enable)
+                && s.flags.iter().any(|f| em.read_bit(*f))
+                && em.read_bit(s.priority) == high
+        })
+    };
+
+    match (requested(true), requested(false)) {
+        (Some(source), _) => Some((source, true)),
+        (None, Some(source)) if current.is_none() => Some((source, false)),
+        _ => None,
+    }
+}
+
+/**
+ * Checks for a pending interrupt that may preempt the current
+ * code and vectors to it, pushing PC like an LCALL would.
+ * A low priority request cannot interrupt any service routine,
+ * a high priority one only interrupts low priority routines.
+ */
+pub fn poll(em: &mut Emulator) -> Result<Option<&'static Source>, StackOverflow> {
+    /*
+       Looking at IE, IP and the flags is not something the program
+       did, vectoring is recorded as usual so it can be undone
+    */
+    let recording = std::mem::replace(&mut em.recording, false);
+    let found = pending(em);
+    em.recording = recording;
+
+    let (source, high) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let pc = em.reg.pc.get();
+    em.push(pc as u8)?;
+    em.push((pc >> 8) as u8)?;
+
+    if source.clear_on_vector {
+        for flag in source.flags {
+            em.write_bit(*flag, false);
+        }
+    }
+
+    em.reg.pc.set(source.vector);
+    em.irq.in_service.push(high);
+
+    Ok(Some(source))
+}
+
+/**
+ * Returns from an interrupt service routine
+ */
+pub fn reti(em: &mut Emulator) {
+    let hi = em.pop() as u16;
+    let lo = em.pop() as u16;
+
+    em.reg.pc.set(hi << 8 | lo);
+    em.irq.in_service.pop();
+}
+
+/* every interrupt source is a peripheral */
+#[cfg(all(test, feature = "peripherals"))]
+mod tests {
+    use super::*;
+    use crate::peripherals::timer::{TF0, TF1, TR0, TR1};
+
+    /* timer 0 in mode 1, one count from overflowing */
+    fn armed(ie: u8) -> Emulator {
+        let mut em = Emulator::new();
+
+        em.write_direct(sfr::TMOD, 0x11);
+        em.write_direct(sfr::TL0, 0xFF);
+        em.write_direct(sfr::TH0, 0xFF);
+        em.write_direct(sfr::TCON, 1 << TR0);
+        em.write_direct(sfr::IE, ie);
+        em.reg.pc.set(0x1234);
+        em
+    }
+
+    #[test]
+    fn timer_overflow_vectors_to_its_interrupt() {
+        let mut em = armed(0x82);
+
+        assert!(poll(&mut em).unwrap().is_none());
+
+        em.tick(1);
+        assert_eq!(poll(&mut em).unwrap().map(|s| s.name), Some("timer0"));
+        assert_eq!(em.reg.pc.get(), 0x0B);
+        assert!(!em.sfr.get_bit(sfr::TCON, TF0));
+        assert_eq!(em.ram.read(0x08), 0x34);
+        assert_eq!(em.ram.read(0x09), 0x12);
+
+        reti(&mut em);
+        assert_eq!(em.reg.pc.get(), 0x1234);
+        assert_eq!(em.reg.sp.get(), 0x07);
+        assert!(em.irq.in_service.is_empty());
+    }
+
+    #[test]
+    fn overflow_waits_for_ea_and_the_timer_enable() {
+        let mut em = armed(0x02);
+        em.tick(1);
+
+        assert!(poll(&mut em).unwrap().is_none());
+        assert!(em.sfr.get_bit(sfr::TCON, TF0));
+
+        em.write_direct(sfr::IE, 0x80);
+        assert!(poll(&mut em).unwrap().is_none());
+
+        em.write_direct(sfr::IE, 0x82);
+        assert_eq!(poll(&mut em).unwrap().map(|s| s.name), Some("timer0"));
+    }
+
+    #[test]
+    fn high_priority_timer1_preempts_timer0() {
+        let mut em = armed(0x8A);
+        em.write_direct(sfr::TL1, 0xFF);
+        em.write_direct(sfr::TH1, 0xFF);
+        em.write_direct(sfr::TCON, (1 << TR0) | (1 << TR1));
+        em.write_direct(sfr::IP, 0x08);
+
+        /* both overflow together, the high priority one goes first */
+        em.tick(1);
+        assert_eq!(poll(&mut em).unwrap().map(|s| s.name), Some("timer1"));
+        assert!(!em.sfr.get_bit(sfr::TCON, TF1));
+
+        /* and timer 0 has to wait for its reti */
+        assert!(poll(&mut em).unwrap().is_none());
+        reti(&mut em);
+        assert_eq!(poll(&mut em).unwrap().map(|s| s.name), Some("timer0"));
+    }
+}
@@ -0,0 +1,36 @@
+/*
+   Assembler and emulator for an 8051 style microcontroller
+   assemble() turns source into an Image, a Machine builds
+   an emulator to run it on.
+*/
+pub mod assembler;
+pub mod binary;
+#[cfg(feature = "debugger")]
+pub mod dap;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+pub mod device;
+pub mod emulator;
+#[cfg(feature = "debugger")]
+pub mod gdb;
+pub mod interrupt;
+pub mod machine;
+#[cfg(feature = "peripherals")]
+pub mod peripherals;
+pub mod psw;
+pub mod ram;
+pub mod regs;
+#[cfg(feature = "debugger")]
+pub mod repl;
+pub mod sfr;
+pub mod snapshot;
+pub mod syscall;
+
+pub use assembler::archive::Archive;
+pub use assembler::engine::{AsmContext, StopReason};
+pub use assembler::image::{assemble, assemble_file, Error, Image, Options};
+pub use assembler::link::link;
+pub use assembler::object::Object;
+pub use device::{Memory, Peripheral};
+pub use emulator::Emulator;
+pub use machine::Machine;
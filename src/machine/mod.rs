@@ -0,0 +1,137 @@
+use crate::assembler::engine::AsmContext;
+use crate::assembler::image::Image;
+use crate::assembler::lines::LineTable;
+use crate::device::Peripheral;
+use crate::emulator::{Emulator, DEFAULT_OSCILLATOR_HZ};
+#[cfg(feature = "peripherals")]
+use crate::peripherals::serial::SerialInput;
+use crate::syscall::{HostIo, Service};
+#[cfg(feature = "peripherals")]
+use std::io::Write;
+
+/**
+ * Builds a ready to run emulator
+ * Machine::new().image(&image).io(Box::new(io)).build()
+ * Anything not set keeps the emulator's default.
+ */
+pub struct Machine {
+    pub code: Vec<u8>,
+    pub lines: LineTable,
+    pub oscillator_hz: u64,
+    pub entry: Option<u16>,
+    pub io: Option<Box<dyn HostIo>>,
+    pub syscalls: Vec<(u8, Service)>,
+    pub devices: Vec<Box<dyn Peripheral>>,
+    #[cfg(feature = "peripherals")]
+    pub serial_input: Option<Box<dyn SerialInput>>,
+    #[cfg(feature = "peripherals")]
+    pub serial_output: Option<Box<dyn Write>>,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Machine {
+        Machine {
+            code: Vec::new(),
+            lines: LineTable::new(),
+            oscillator_hz: DEFAULT_OSCILLATOR_HZ,
+            entry: None,
+            io: None,
+            syscalls: Vec::new(),
+            devices: Vec::new(),
+            #[cfg(feature = "peripherals")]
+            serial_input: None,
+            #[cfg(feature = "peripherals")]
+            serial_output: None,
+        }
+    }
+
+    /* code and line table of an assembled or loaded image */
+    pub fn image(mut self, image: &Image) -> Machine {
+        self.code = image.code.clone();
+        self.lines = image.lines.clone();
+        self
+    }
+
+    pub fn code(mut self, code: Vec<u8>) -> Machine {
+        self.code = code;
+        self
+    }
+
+    pub fn oscillator(mut self, hz: u64) -> Machine {
+        self.oscillator_hz = hz;
+        self
+    }
+
+    /* where execution starts instead of 0000H */
+    pub fn entry(mut self, addr: u16) -> Machine {
+        self.entry = Some(addr);
+        self
+    }
+
+    /* console for int 21H */
+    pub fn io(mut self, io: Box<dyn HostIo>) -> Machine {
+        self.io = Some(io);
+        self
+    }
+
+    /* adds or replaces an int 21H service */
+    pub fn syscall(mut self, number: u8, service: Service) -> Machine {
+        self.syscalls.push((number, service));
+        self
+    }
+
+    pub fn device(mut self, device: Box<dyn Peripheral>) -> Machine {
+        self.devices.push(device);
+        self
+    }
+
+    #[cfg(feature = "peripherals")]
+    pub fn serial_input(mut self, input: Box<dyn SerialInput>) -> Machine {
+        self.serial_input = Some(input);
+        self
+    }
+
+    #[cfg(feature = "peripherals")]
+    pub fn serial_output(mut self, output: Box<dyn Write>) -> Machine {
+        self.serial_output = Some(output);
+        self
+    }
+
+    pub fn build(self) -> AsmContext {
+        let mut em = Emulator::new();
+
+        em.burn(self.code);
+        em.set_oscillator(self.oscillator_hz);
+        em.devices = self.devices;
+
+        if let Some(io) = self.io {
+            em.io = io;
+        }
+        for (number, service) in self.syscalls {
+            em.syscalls.register(number, service);
+        }
+        if let Some(entry) = self.entry {
+            em.reg.pc.set(entry);
+        }
+
+        #[cfg(feature = "peripherals")]
+        {
+            if let Some(input) = self.serial_input {
+                em.serial.set_input(input);
+            }
+            if let Some(output) = self.serial_output {
+                em.serial.set_output(output);
+            }
+        }
+
+        let mut res = AsmContext::new(em);
+        res.lines = self.lines;
+        res
+    }
+}
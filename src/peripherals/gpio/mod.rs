@@ -0,0 +1,250 @@
+use crate::sfr;
+
+pub const PORT_ADDRESSES: [u8; 4] = [sfr::P0, sfr::P1, sfr::P2, sfr::P3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinChange {
+    /* machine cycle the change happened on */
+    pub cycle: u64,
+    pub port: u8,
+    pub bit: u8,
+    pub level: bool,
+}
+
+/**
+ * One quasi-bidirectional port
+ * The pin only reads high if both the latch and whatever
+ * is outside leave it high: either side can pull it low.
+ * P0 has no internal pull-ups on real parts, we assume
+ * external ones so it behaves like the other ports.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port {
+    pub latch: u8,
+    /* levels driven from outside, 1 where released */
+    pub external: u8,
+}
+
+impl Port {
+    pub fn pins(&self) -> u8 {
+        self.latch & self.external
+    }
+}
+
+pub type ChangeCallback = Box<dyn FnMut(&PinChange)>;
+
+/**
+ * Ports P0 to P3
+ * The program sees them through the SFRs, the host drives
+ * input pins with `drive_pin`/`release_pin` and observes output
+ * pins with `pin`, the change `history` or change callbacks.
+ */
+pub struct Gpio {
+    pub ports: [Port; 4],
+
+    /* current machine cycle, used to timestamp changes */
+    pub now: u64,
+
+    /* pin changes, only recorded once `record` is set */
+    pub record: bool,
+    pub history: Vec<PinChange>,
+
+    callbacks: Vec<ChangeCallback>,
+}
+
+impl Default for Gpio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gpio {
+    pub fn new() -> Gpio {
+        Gpio {
+            ports: [Port {
+                latch: 0xFF,
+                external: 0xFF,
+            }; 4],
+            now: 0,
+            record: false,
+            history: Vec::new(),
+            callbacks: Vec::new(),
+        }
+    }
+
+    /**
+     * Port number for a port SFR address
+     */
+    pub fn port_of(address: u8) -> Option<u8> {
+        PORT_ADDRESSES
+            .iter()
+            .position(|a| *a == address)
+            .map(|p| p as u8)
+    }
+
+    pub fn on_change(&mut self, callback: ChangeCallback) {
+        self.callbacks.push(callback);
+    }
+
+    pub fn pins(&self, port: u8) -> u8 {
+        self.ports[port as usize].pins()
+    }
+
+    pub fn pin(&self, port: u8, bit: u8) -> bool {
+        self.pins(port) & (1 << bit) != 0
+    }
+
+    pub fn latch(&self, port: u8) -> u8 {
+        self.ports[port as usize].latch
+    }
+
+    pub fn write_latch(&mut self, port: u8, data: u8) {
+        self.update(port, |p| p.latch = data);
+    }
+
+    /**
+     * Drives pin `port`.`bit` from outside
+     * Driving it high is the same as releasing it,
+     * only the latch can then pull it low.
+     */
+    pub fn drive_pin(&mut self, port: u8, bit: u8, level: bool) {
+        self.update(port, |p| {
+            if level {
+                p.external |= 1 << bit;
+            } else {
+                p.external &= !(1 << bit);
+            }
+        });
+    }
+
+    pub fn release_pin(&mut self, port: u8, bit: u8) {
+        self.drive_pin(port, bit, true);
+    }
+
+    /**
+     * Changes to pin `port`.`bit` recorded in the history
+     */
+    pub fn changes(&self, port: u8, bit: u8) -> Vec<PinChange> {
+        self.history
+            .iter()
+            .filter(|c| c.port == port && c.bit == bit)
+            .copied()
+            .collect()
+    }
+
+    fn update<F: FnOnce(&mut Port)>(&mut self, port: u8, f: F) {
+        let before = self.pins(port);
+        f(&mut self.ports[port as usize]);
+        let after = self.pins(port);
+
+        for bit in 0..8 {
+            if (before ^ after) & (1 << bit) == 0 {
+                continue;
+            }
+
+            let change = PinChange {
+                cycle: self.now,
+                port,
+                bit,
+                level: after & (1 << bit) != 0,
+            };
+
+            if self.record {
+                self.history.push(change);
+            }
+
+            for callback in self.callbacks.iter_mut() {
+                callback(&change);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::engine::AsmContext;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+    use crate::emulator::Emulator;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn run(source: &str, setup: impl FnOnce(&mut Emulator)) -> Emulator {
+        let mut lc = LexerContext::new(source.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        setup(&mut ctx.em);
+        ctx.em.burn(pc.cg);
+        ctx.run();
+        ctx.em
+    }
+
+    #[test]
+    fn latch_and_outside_both_pull_low() {
+        let em = run("mov P1, #0F0H\nmov A, P1\nmov R0, A\nend\n", |em| {
+            em.gpio.drive_pin(1, 7, false);
+        });
+
+        assert_eq!(em.gpio.latch(1), 0xF0);
+        assert_eq!(em.gpio.pins(1), 0x70);
+        assert_eq!(em.ram.read(0), 0x70);
+    }
+
+    #[test]
+    fn program_reads_pins_the_host_drives() {
+        let source = "jb P3.2, high\nclr P1.7\nhigh:\nend\n";
+
+        let em = run(source, |_| ());
+        assert!(em.gpio.pin(1, 7));
+
+        let em = run(source, |em| em.gpio.drive_pin(3, 2, false));
+        assert!(!em.gpio.pin(1, 7));
+    }
+
+    #[test]
+    fn cpl_toggles_the_latch_not_the_pin() {
+        /* the pin reads low while driven, cpl still clears the latch */
+        let mut em = run("cpl P2.0\nend\n", |em| em.gpio.drive_pin(2, 0, false));
+        assert_eq!(em.gpio.latch(2), 0xFE);
+
+        em.gpio.release_pin(2, 0);
+        assert!(!em.gpio.pin(2, 0));
+    }
+
+    #[test]
+    fn changes_are_recorded_with_their_cycle() {
+        let em = run("cpl P1.0\nmov A, #1\ncpl P1.0\ncpl P1.0\nend\n", |em| {
+            em.gpio.record = true
+        });
+
+        let changes = em.gpio.changes(1, 0);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            changes.iter().map(|c| c.level).collect::<Vec<_>>(),
+            [false, true, false]
+        );
+        assert!(changes.windows(2).all(|pair| pair[0].cycle < pair[1].cycle));
+
+        /* nothing else moved */
+        assert_eq!(em.gpio.history.len(), 3);
+        assert_eq!(em.gpio.pins(2), 0xFF);
+    }
+
+    #[test]
+    fn change_callbacks_see_every_edge() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+
+        run("setb P2.1\nclr P2.1\nsetb P2.1\nend\n", |em| {
+            em.gpio.on_change(Box::new(move |c| {
+                log.borrow_mut().push((c.port, c.bit, c.level))
+            }));
+        });
+
+        /* the latch resets high, so setb doesn't change the pin */
+        assert_eq!(*seen.borrow(), vec![(2, 1, false), (2, 1, true)]);
+    }
+}
@@ -0,0 +1,495 @@
+use crate::emulator::CLOCKS_PER_CYCLE;
+use crate::sfr::{self, Sfr};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+/* lives with the host I/O, so it is there without peripherals */
+pub use crate::syscall::SharedBuffer;
+
+/* SCON bits */
+pub const RI: u8 = 0;
+pub const TI: u8 = 1;
+pub const RB8: u8 = 2;
+pub const TB8: u8 = 3;
+pub const REN: u8 = 4;
+pub const SM2: u8 = 5;
+
+/* PCON bits */
+pub const SMOD: u8 = 7;
+
+/**
+ * Where received bytes come from
+ * `poll` must not block, the emulator calls it
+ * whenever the receiver is ready for another frame
+ */
+pub trait SerialInput {
+    fn poll(&mut self) -> Option<u8>;
+}
+
+/**
+ * Input given up front, e.g. by a test
+ */
+#[derive(Debug, Default)]
+pub struct ScriptedInput {
+    pub data: VecDeque<u8>,
+}
+
+impl ScriptedInput {
+    pub fn new(data: &str) -> ScriptedInput {
+        ScriptedInput {
+            data: data.bytes().collect(),
+        }
+    }
+}
+
+impl SerialInput for ScriptedInput {
+    fn poll(&mut self) -> Option<u8> {
+        self.data.pop_front()
+    }
+}
+
+/**
+ * Input read from a blocking reader (stdin, a pty)
+ * on a background thread so the emulator never waits on it
+ */
+pub struct ReaderInput {
+    rx: mpsc::Receiver<u8>,
+}
+
+impl ReaderInput {
+    pub fn new<R: Read + Send + 'static>(mut reader: R) -> ReaderInput {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 256];
+
+            while let Ok(n) = reader.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+
+                for byte in &buf[..n] {
+                    if tx.send(*byte).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        ReaderInput { rx }
+    }
+
+    pub fn stdin() -> ReaderInput {
+        ReaderInput::new(io::stdin())
+    }
+}
+
+impl SerialInput for ReaderInput {
+    fn poll(&mut self) -> Option<u8> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/**
+ * A local pseudo terminal
+ * The emulator owns the master side, a terminal program
+ * (screen, minicom, picocom) can be attached to `name`
+ */
+#[cfg(unix)]
+pub struct Pty {
+    pub master: File,
+    pub name: String,
+}
+
+/* ptsname_r where there is one, ptsname returns a static buffer */
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd"
+))]
+unsafe fn pty_name(fd: i32) -> io::Result<String> {
+    let mut buf = [0 as std::ffi::c_char; 128];
+
+    if libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(std::ffi::CStr::from_ptr(buf.as_ptr())
+        .to_string_lossy()
+        .into_owned())
+}
+
+#[cfg(all(
+    unix,
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd"
+    ))
+))]
+unsafe fn pty_name(fd: i32) -> io::Result<String> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let name = libc::ptsname(fd);
+    if name.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(std::ffi::CStr::from_ptr(name)
+        .to_string_lossy()
+        .into_owned())
+}
+
+#[cfg(unix)]
+impl Pty {
+    pub fn open() -> io::Result<Pty> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        /* so the pty never becomes our controlling terminal */
+        let master = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+
+        let name = unsafe {
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            pty_name(fd)?
+        };
+
+        Ok(Pty { master, name })
+    }
+}
+
+/**
+ * A frame being shifted in or out
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub data: u8,
+    pub ninth: bool,
+    /* time left, in the units of the current mode's clock */
+    pub remaining: u32,
+}
+
+/**
+ * Serial port (UART)
+ * Mode 0: 8-bit shift register, one bit per machine cycle
+ * Mode 1: 10-bit frame, timer 1 baud rate
+ * Mode 2: 11-bit frame, oscillator / 64 (or / 32 with SMOD)
+ * Mode 3: 11-bit frame, timer 1 baud rate
+ */
+pub struct Serial {
+    pub output: Box<dyn Write>,
+    pub input: Box<dyn SerialInput>,
+
+    /* SBUF as seen by reads, the last received byte */
+    pub rx_buffer: u8,
+
+    pub tx_frame: Option<Frame>,
+    pub rx_frame: Option<Frame>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            output: Box::new(io::stdout()),
+            input: Box::new(ScriptedInput::default()),
+            rx_buffer: 0,
+            tx_frame: None,
+            rx_frame: None,
+        }
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn set_input(&mut self, input: Box<dyn SerialInput>) {
+        self.input = input;
+    }
+
+    pub fn output_to_file(&mut self, path: &str) -> io::Result<()> {
+        self.output = Box::new(File::create(path)?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub fn attach_pty(&mut self, pty: Pty) -> io::Result<()> {
+        self.input = Box::new(ReaderInput::new(pty.master.try_clone()?));
+        self.output = Box::new(pty.master);
+        Ok(())
+    }
+
+    /**
+     * Length of one frame in units of the mode's clock
+     * (machine cycles for mode 0, oscillator periods for mode 2
+     * and timer 1 overflows for modes 1 and 3)
+     */
+    fn frame_length(scon: u8, pcon: u8) -> u32 {
+        let smod = pcon & (1 << SMOD) != 0;
+
+        match scon >> 6 {
+            0 => 8,
+            1 => 10 * if smod { 16 } else { 32 },
+            2 => 11 * if smod { 32 } else { 64 },
+            _ => 11 * if smod { 16 } else { 32 },
+        }
+    }
+
+    /**
+     * Writing SBUF starts a transmission
+     */
+    pub fn write_sbuf(&mut self, sfr: &Sfr, data: u8) {
+        let scon = sfr.read(sfr::SCON);
+
+        self.tx_frame = Some(Frame {
+            data,
+            ninth: scon & (1 << TB8) != 0,
+            remaining: Serial::frame_length(scon, sfr.read(sfr::PCON)),
+        });
+    }
+
+    /**
+     * Advances the serial port by `cycles` machine cycles,
+     * during which timer 1 overflowed `t1_overflows` times
+     */
+    pub fn tick(&mut self, sfr: &mut Sfr, cycles: u32, t1_overflows: u32) {
+        let scon = sfr.read(sfr::SCON);
+        let elapsed = match scon >> 6 {
+            0 => cycles,
+            2 => cycles * CLOCKS_PER_CYCLE as u32,
+            _ => t1_overflows,
+        };
+
+        if let Some(frame) = &mut self.tx_frame {
+            frame.remaining = frame.remaining.saturating_sub(elapsed);
+
+            if frame.remaining == 0 {
+                let _ = self.output.write_all(&[frame.data]);
+                let _ = self.output.flush();
+
+                self.tx_frame = None;
+                sfr.set_bit(sfr::SCON, TI, true);
+            }
+        }
+
+        /*
+           The receiver only starts on a new frame once RI
+           has been cleared, so host input is never dropped
+        */
+        let receiving = scon & (1 << REN) != 0 && scon & (1 << RI) == 0;
+
+        if self.rx_frame.is_none() && receiving {
+            if let Some(data) = self.input.poll() {
+                self.rx_frame = Some(Frame {
+                    data,
+                    /* stop bit in mode 1, 9th data bit in modes 2 and 3 */
+                    ninth: true,
+                    remaining: Serial::frame_length(scon, sfr.read(sfr::PCON)),
+                });
+            }
+        }
+
+        if let Some(frame) = &mut self.rx_frame {
+            frame.remaining = frame.remaining.saturating_sub(elapsed);
+
+            if frame.remaining == 0 {
+                let multiprocessor = scon & (1 << SM2) != 0 && scon >> 6 >= 2;
+
+                if !multiprocessor || frame.ninth {
+                    self.rx_buffer = frame.data;
+                    sfr.set_bit(sfr::SCON, RB8, frame.ninth);
+                    sfr.set_bit(sfr::SCON, RI, true);
+                }
+
+                self.rx_frame = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::AsmContext;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+    use crate::emulator::Emulator;
+
+    fn port(scon: u8, input: &str) -> (Serial, Sfr, SharedBuffer) {
+        let output = SharedBuffer::new();
+        let mut serial = Serial::new();
+        let mut sfr = Sfr::new();
+
+        serial.set_output(Box::new(output.clone()));
+        serial.set_input(Box::new(ScriptedInput::new(input)));
+        sfr.write(sfr::SCON, scon);
+        (serial, sfr, output)
+    }
+
+    #[test]
+    fn mode1_sends_after_ten_bit_times() {
+        let (mut serial, mut sfr, output) = port(0x40, "");
+        serial.write_sbuf(&sfr, b'A');
+
+        /* 32 timer 1 overflows per bit without SMOD */
+        serial.tick(&mut sfr, 1, 319);
+        assert!(output.contents().is_empty());
+        assert!(!sfr.get_bit(sfr::SCON, TI));
+
+        serial.tick(&mut sfr, 1, 1);
+        assert_eq!(output.contents(), b"A");
+        assert!(sfr.get_bit(sfr::SCON, TI));
+        assert!(serial.tx_frame.is_none());
+    }
+
+    #[test]
+    fn smod_doubles_the_baud_rate() {
+        let (mut serial, mut sfr, output) = port(0x40, "");
+        sfr.write(sfr::PCON, 1 << SMOD);
+        serial.write_sbuf(&sfr, b'B');
+
+        serial.tick(&mut sfr, 1, 160);
+        assert_eq!(output.contents(), b"B");
+    }
+
+    #[test]
+    fn mode0_shifts_a_bit_per_cycle_and_mode2_counts_clocks() {
+        let (mut serial, mut sfr, output) = port(0x00, "");
+        serial.write_sbuf(&sfr, b'0');
+        serial.tick(&mut sfr, 7, 0);
+        assert!(output.contents().is_empty());
+        serial.tick(&mut sfr, 1, 0);
+        assert_eq!(output.contents(), b"0");
+
+        /* 11 bits of 64 oscillator periods, 58.7 machine cycles */
+        let (mut serial, mut sfr, output) = port(0x80, "");
+        serial.write_sbuf(&sfr, b'2');
+        serial.tick(&mut sfr, 58, 0);
+        assert!(output.contents().is_empty());
+        serial.tick(&mut sfr, 1, 0);
+        assert_eq!(output.contents(), b"2");
+    }
+
+    #[test]
+    fn receives_only_with_ren_and_waits_for_ri() {
+        let (mut serial, mut sfr, _) = port(0x40, "hi");
+        serial.tick(&mut sfr, 1, 320);
+        assert!(!sfr.get_bit(sfr::SCON, RI));
+
+        sfr.set_bit(sfr::SCON, REN, true);
+        serial.tick(&mut sfr, 1, 0);
+        serial.tick(&mut sfr, 1, 320);
+        assert!(sfr.get_bit(sfr::SCON, RI));
+        assert!(sfr.get_bit(sfr::SCON, RB8));
+        assert_eq!(serial.rx_buffer, b'h');
+
+        /* the next frame doesn't start while RI is set */
+        serial.tick(&mut sfr, 1, 320);
+        assert_eq!(serial.rx_buffer, b'h');
+        assert!(serial.rx_frame.is_none());
+
+        sfr.set_bit(sfr::SCON, RI, false);
+        serial.tick(&mut sfr, 1, 0);
+        serial.tick(&mut sfr, 1, 320);
+        assert_eq!(serial.rx_buffer, b'i');
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_carries_bytes_both_ways() {
+        let pty = Pty::open().unwrap();
+        let mut slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&pty.name)
+            .unwrap();
+        let (mut serial, mut sfr, _) = port(0x50, "");
+        serial.attach_pty(pty).unwrap();
+
+        /* the slave side is line buffered */
+        for byte in b"x\n" {
+            serial.write_sbuf(&sfr, *byte);
+            serial.tick(&mut sfr, 1, 320);
+        }
+        let mut buf = [0u8; 2];
+        slave.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"x\n");
+
+        /* what the slave echoes comes first */
+        slave.write_all(b"y").unwrap();
+        for _ in 0..1000 {
+            serial.tick(&mut sfr, 1, 320);
+            if sfr.get_bit(sfr::SCON, RI) {
+                if serial.rx_buffer == b'y' {
+                    break;
+                }
+                sfr.set_bit(sfr::SCON, RI, false);
+            }
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(serial.rx_buffer, b'y');
+    }
+
+    /* timer 1 as the baud rate generator, 9600 baud at 11.0592 MHz */
+    const SETUP: &str = "mov TMOD, #20H\nmov TH1, #0FDH\nmov TL1, #0FDH\nsetb TR1\n";
+
+    fn run(source: &str, input: &str) -> (Emulator, SharedBuffer) {
+        let mut lc = LexerContext::new(format!("{}{}", SETUP, source));
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let output = SharedBuffer::new();
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.serial.set_output(Box::new(output.clone()));
+        ctx.em.serial.set_input(Box::new(ScriptedInput::new(input)));
+        ctx.em.burn(pc.cg);
+        ctx.run();
+
+        (ctx.em, output)
+    }
+
+    #[test]
+    fn program_transmits_through_sbuf() {
+        let (em, output) = run(
+            "mov SCON, #40H\nmov A, #4FH\nmov SBUF, A\nwait1:\njnb TI, wait1\nclr TI\nmov A, #4BH\nmov SBUF, A\nwait2:\njnb TI, wait2\nend\n",
+            "",
+        );
+
+        assert_eq!(output.to_string_lossy(), "OK");
+        /* 3 cycles per timer 1 overflow, 32 overflows per bit, 10 bits per byte */
+        assert!(em.cycles >= 2 * 10 * 32 * 3);
+    }
+
+    #[test]
+    fn program_echoes_scripted_input() {
+        let echo: String = (0..3)
+            .map(|i| {
+                format!(
+                    "next{0}:\njnb RI, next{0}\nclr RI\nmov A, SBUF\nmov SBUF, A\nsent{0}:\njnb TI, sent{0}\nclr TI\n",
+                    i
+                )
+            })
+            .collect();
+        let (_, output) = run(&format!("mov SCON, #50H\n{}end\n", echo), "abc");
+
+        assert_eq!(output.to_string_lossy(), "abc");
+    }
+}
@@ -0,0 +1,351 @@
+use crate::sfr::{self, Sfr};
+
+/* TCON bits */
+pub const IT0: u8 = 0;
+pub const IE0: u8 = 1;
+pub const IT1: u8 = 2;
+pub const IE1: u8 = 3;
+pub const TR0: u8 = 4;
+pub const TF0: u8 = 5;
+pub const TR1: u8 = 6;
+pub const TF1: u8 = 7;
+
+/* TMOD bits, per timer nibble */
+pub const GATE: u8 = 0x08;
+pub const C_T: u8 = 0x04;
+pub const MODE: u8 = 0x03;
+
+/**
+ * Levels of the port 3 pins the timers look at
+ * - int0/int1 gate the timers when GATE is set
+ * - t0/t1 are counted on their falling edge when C/T is set
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct TimerPins {
+    pub int0: bool,
+    pub int1: bool,
+    pub t0: bool,
+    pub t1: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timer {
+    pub tl: u8,
+    pub th: u8,
+    pub tr: u8,
+    pub tf: u8,
+
+    /*
+       Last sampled level of the count pin,
+       a counter increments on a 1 -> 0 transition
+    */
+    pub last_pin: bool,
+}
+
+impl Timer {
+    pub fn new(tl: u8, th: u8, tr: u8, tf: u8) -> Timer {
+        Timer {
+            tl,
+            th,
+            tr,
+            tf,
+            last_pin: true,
+        }
+    }
+
+    /**
+     * Number of counts this timer sees in one machine cycle
+     * `ctrl` is this timer's nibble of TMOD
+     */
+    fn counts(&mut self, ctrl: u8, tcon: u8, gate_pin: bool, count_pin: bool) -> bool {
+        let falling = self.last_pin && !count_pin;
+        self.last_pin = count_pin;
+
+        let running = tcon & (1 << self.tr) != 0 && (ctrl & GATE == 0 || gate_pin);
+        if !running {
+            return false;
+        }
+
+        if ctrl & C_T != 0 {
+            falling
+        } else {
+            true
+        }
+    }
+
+    /**
+     * Increments the timer once in mode 0, 1 or 2
+     * Returns true if the timer overflowed
+     */
+    fn increment(&self, sfr: &mut Sfr, mode: u8) -> bool {
+        let tl = sfr.read(self.tl);
+        let th = sfr.read(self.th);
+
+        match mode {
+            0 => {
+                /*
+                   13-bit timer:
+                   the lower 5 bits of TL act as a prescaler for TH
+                */
+                let value = ((th as u16) << 5 | (tl & 0x1F) as u16) + 1;
+                let overflow = value == 0x2000;
+                let value = value & 0x1FFF;
+
+                sfr.write(self.tl, (tl & 0xE0) | (value & 0x1F) as u8);
+                sfr.write(self.th, (value >> 5) as u8);
+                overflow
+            }
+            1 => {
+                let value = ((th as u16) << 8 | tl as u16).wrapping_add(1);
+
+                sfr.write(self.tl, value as u8);
+                sfr.write(self.th, (value >> 8) as u8);
+                value == 0
+            }
+            2 => {
+                /*
+                   8-bit auto reload:
+                   TL counts, TH holds the reload value
+                */
+                let (value, overflow) = tl.overflowing_add(1);
+
+                sfr.write(self.tl, if overflow { th } else { value });
+                overflow
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timers {
+    pub t0: Timer,
+    pub t1: Timer,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers {
+            t0: Timer::new(sfr::TL0, sfr::TH0, TR0, TF0),
+            t1: Timer::new(sfr::TL1, sfr::TH1, TR1, TF1),
+        }
+    }
+
+    /**
+     * Advances both timers by `cycles` machine cycles
+     * Overflows set TF0/TF1 in TCON, which the interrupt
+     * controller picks up on the next poll.
+     * Returns the number of timer 1 overflows, which is
+     * what the serial port uses as its baud rate clock.
+     */
+    pub fn tick(&mut self, sfr: &mut Sfr, pins: TimerPins, cycles: u32) -> u32 {
+        let mut t1_overflows = 0;
+
+        for _ in 0..cycles {
+            let tmod = sfr.read(sfr::TMOD);
+            let tcon = sfr.read(sfr::TCON);
+
+            let ctrl0 = tmod & 0x0F;
+            let ctrl1 = tmod >> 4;
+            let mode0 = ctrl0 & MODE;
+            let mode1 = ctrl1 & MODE;
+
+            if self.t0.counts(ctrl0, tcon, pins.int0, pins.t0) {
+                if mode0 == 3 {
+                    /*
+                       Mode 3 splits timer 0:
+                       TL0 is an 8-bit timer using timer 0's controls
+                    */
+                    let (tl, overflow) = sfr.read(sfr::TL0).overflowing_add(1);
+                    sfr.write(sfr::TL0, tl);
+                    if overflow {
+                        sfr.set_bit(sfr::TCON, TF0, true);
+                    }
+                } else if self.t0.increment(sfr, mode0) {
+                    sfr.set_bit(sfr::TCON, TF0, true);
+                }
+            }
+
+            if mode0 == 3 {
+                /*
+                   ...and TH0 is an 8-bit timer run by TR1 that
+                   borrows TF1. Timer 1 keeps counting (unless it is
+                   in mode 3 itself) but can no longer raise TF1.
+                */
+                if tcon & (1 << TR1) != 0 {
+                    let (th, overflow) = sfr.read(sfr::TH0).overflowing_add(1);
+                    sfr.write(sfr::TH0, th);
+                    if overflow {
+                        sfr.set_bit(sfr::TCON, TF1, true);
+                    }
+                }
+
+                if mode1 != 3
+                    && self.t1.counts(ctrl1, tcon | (1 << TR1), pins.int1, pins.t1)
+                    && self.t1.increment(sfr, mode1)
+                {
+                    t1_overflows += 1;
+                }
+            } else if mode1 != 3
+                && self.t1.counts(ctrl1, tcon, pins.int1, pins.t1)
+                && self.t1.increment(sfr, mode1)
+            {
+                sfr.set_bit(sfr::TCON, TF1, true);
+                t1_overflows += 1;
+            }
+        }
+
+        t1_overflows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PINS: TimerPins = TimerPins {
+        int0: true,
+        int1: true,
+        t0: true,
+        t1: true,
+    };
+
+    fn timers(tmod: u8, tcon: u8) -> (Timers, Sfr) {
+        let mut sfr = Sfr::new();
+
+        sfr.write(sfr::TMOD, tmod);
+        sfr.write(sfr::TCON, tcon);
+        (Timers::new(), sfr)
+    }
+
+    #[test]
+    fn mode1_counts_cycles_and_overflows_into_tf0() {
+        let (mut timers, mut sfr) = timers(0x01, 1 << TR0);
+        sfr.write(sfr::TL0, 0xFE);
+        sfr.write(sfr::TH0, 0xFF);
+
+        timers.tick(&mut sfr, PINS, 1);
+        assert_eq!((sfr.read(sfr::TH0), sfr.read(sfr::TL0)), (0xFF, 0xFF));
+        assert!(!sfr.get_bit(sfr::TCON, TF0));
+
+        timers.tick(&mut sfr, PINS, 1);
+        assert_eq!((sfr.read(sfr::TH0), sfr.read(sfr::TL0)), (0x00, 0x00));
+        assert!(sfr.get_bit(sfr::TCON, TF0));
+    }
+
+    #[test]
+    fn stopped_timers_do_not_count() {
+        let (mut timers, mut sfr) = timers(0x11, 0);
+
+        timers.tick(&mut sfr, PINS, 100);
+        assert_eq!(sfr.read(sfr::TL0), 0);
+        assert_eq!(sfr.read(sfr::TL1), 0);
+    }
+
+    #[test]
+    fn mode0_uses_the_low_five_bits_of_tl_as_a_prescaler() {
+        let (mut timers, mut sfr) = timers(0x00, 1 << TR0);
+        sfr.write(sfr::TL0, 0xFF);
+
+        /* the upper three bits of TL0 are left alone */
+        timers.tick(&mut sfr, PINS, 1);
+        assert_eq!((sfr.read(sfr::TH0), sfr.read(sfr::TL0)), (0x01, 0xE0));
+
+        sfr.write(sfr::TH0, 0xFF);
+        sfr.write(sfr::TL0, 0x1F);
+        timers.tick(&mut sfr, PINS, 1);
+        assert_eq!((sfr.read(sfr::TH0), sfr.read(sfr::TL0)), (0x00, 0x00));
+        assert!(sfr.get_bit(sfr::TCON, TF0));
+    }
+
+    #[test]
+    fn mode2_reloads_tl_from_th() {
+        let (mut timers, mut sfr) = timers(0x20, 1 << TR1);
+        sfr.write(sfr::TH1, 0xF0);
+        sfr.write(sfr::TL1, 0xFE);
+
+        assert_eq!(timers.tick(&mut sfr, PINS, 1), 0);
+        assert_eq!(timers.tick(&mut sfr, PINS, 1), 1);
+        assert_eq!(sfr.read(sfr::TL1), 0xF0);
+        assert!(sfr.get_bit(sfr::TCON, TF1));
+
+        /* 16 counts from F0H to the next overflow */
+        assert_eq!(timers.tick(&mut sfr, PINS, 16), 1);
+        assert_eq!(sfr.read(sfr::TL1), 0xF0);
+    }
+
+    #[test]
+    fn gate_runs_the_timer_only_while_int_is_high() {
+        let (mut timers, mut sfr) = timers(GATE | 0x01, 1 << TR0);
+        let low = TimerPins {
+            int0: false,
+            ..PINS
+        };
+
+        timers.tick(&mut sfr, low, 5);
+        assert_eq!(sfr.read(sfr::TL0), 0);
+
+        timers.tick(&mut sfr, PINS, 5);
+        assert_eq!(sfr.read(sfr::TL0), 5);
+
+        /* GATE does not start a timer whose TR is clear */
+        sfr.set_bit(sfr::TCON, TR0, false);
+        timers.tick(&mut sfr, PINS, 5);
+        assert_eq!(sfr.read(sfr::TL0), 5);
+    }
+
+    #[test]
+    fn counters_count_falling_edges_of_their_pin() {
+        let (mut timers, mut sfr) = timers((C_T | 0x01) << 4, 1 << TR1);
+        let low = TimerPins { t1: false, ..PINS };
+
+        timers.tick(&mut sfr, PINS, 3);
+        assert_eq!(sfr.read(sfr::TL1), 0);
+
+        /* one edge, however long the pin stays low */
+        timers.tick(&mut sfr, low, 3);
+        assert_eq!(sfr.read(sfr::TL1), 1);
+
+        for _ in 0..4 {
+            timers.tick(&mut sfr, PINS, 1);
+            timers.tick(&mut sfr, low, 1);
+        }
+        assert_eq!(sfr.read(sfr::TL1), 5);
+    }
+
+    #[test]
+    fn mode3_splits_timer0_and_lends_th0_the_timer1_flag() {
+        let (mut timers, mut sfr) = timers(0x13, (1 << TR0) | (1 << TR1));
+        sfr.write(sfr::TL0, 0xFF);
+        sfr.write(sfr::TH0, 0xFE);
+
+        timers.tick(&mut sfr, PINS, 1);
+        assert_eq!(sfr.read(sfr::TL0), 0x00);
+        assert_eq!(sfr.read(sfr::TH0), 0xFF);
+        assert!(sfr.get_bit(sfr::TCON, TF0));
+        assert!(!sfr.get_bit(sfr::TCON, TF1));
+
+        timers.tick(&mut sfr, PINS, 1);
+        assert_eq!(sfr.read(sfr::TH0), 0x00);
+        assert!(sfr.get_bit(sfr::TCON, TF1));
+    }
+
+    #[test]
+    fn timer1_keeps_counting_without_tf1_while_timer0_is_in_mode3() {
+        let (mut timers, mut sfr) = timers(0x13, 0);
+        sfr.write(sfr::TL1, 0xFF);
+        sfr.write(sfr::TH1, 0xFF);
+
+        /* TR1 is TH0's run bit now, timer 1 runs regardless */
+        assert_eq!(timers.tick(&mut sfr, PINS, 1), 1);
+        assert_eq!((sfr.read(sfr::TH1), sfr.read(sfr::TL1)), (0x00, 0x00));
+        assert_eq!(sfr.read(sfr::TH0), 0x00);
+        assert!(!sfr.get_bit(sfr::TCON, TF1));
+    }
+}
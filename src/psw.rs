@@ -1,5 +1,4 @@
-#[derive(Debug)]
-
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PswFlag {
     P = 0,
     F1 = 1,
@@ -21,6 +20,7 @@ pub enum PswFlag {
  * - OV: Overflow flag
  * - F1: User-defined flag 1
  * - P: Parity flag
+ *
  * Bits are 0 indexed starting from bottom
  * The PSW register is 8 bits wide
  * value[0] is P
@@ -32,6 +32,12 @@ pub struct Psw {
     pub value: u8,
 }
 
+impl Default for Psw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Psw {
     pub fn new() -> Psw {
         Psw { value: 0 }
@@ -1,6 +1,9 @@
 // constants for 8051 RAM
 pub const RAM_SIZE: usize = 128;
 
+// external RAM, addressed through DPTR
+pub const XRAM_SIZE: usize = 0x10000;
+
 #[derive(Debug)]
 pub struct Ram {
     pub memory: Vec<u8>,
@@ -8,6 +11,12 @@ pub struct Ram {
 
 pub const BANK_ADDRESSES: [usize; 4] = [0x00, 0x08, 0x10, 0x18];
 
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ram {
     pub fn new() -> Ram {
         Ram {
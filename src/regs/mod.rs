@@ -3,6 +3,12 @@ pub struct Register8 {
     pub value: u8,
 }
 
+impl Default for Register8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Register8 {
     pub fn new() -> Register8 {
         Register8 { value: 0 }
@@ -36,6 +42,12 @@ pub struct Register16 {
     pub value: u16,
 }
 
+impl Default for Register16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Register16 {
     pub fn new() -> Register16 {
         Register16 { value: 0 }
@@ -0,0 +1,345 @@
+use crate::assembler::image::{self, assemble_file, Image, Options};
+use crate::assembler::lexer::LexerContext;
+use crate::assembler::parser::{parse_value, IPContext};
+use crate::assembler::segment::Segments;
+use crate::assembler::symbols::{self, SymbolTable};
+use crate::assembler::trace::{Reg, Tracer};
+use crate::debugger::{Debugger, FLAGS};
+use crate::machine::Machine;
+use std::io::{self, BufRead, Write};
+use std::mem;
+
+const HELP: &str = "\
+Type an instruction to assemble it at the current address
+and run it, or a label followed by `:` to define it there.
+
+:org ADDR               assemble from ADDR on
+:reset                  start over with an empty machine
+:load FILE              reset and load a .plasm file
+:quit                   leave
+:COMMAND                any debugger command, e.g.
+                        :regs, :x iram 30H 16, :dis, :c";
+
+/**
+ * Assembles and runs one line at a time on a persistent machine
+ * Labels carry over from line to line. Lines starting with `:`
+ * are handed to the debugger, so everything it can show is there.
+ */
+pub struct Repl {
+    pub debugger: Debugger,
+    /* where the next line is assembled */
+    pub addr: u16,
+    /* data segments and RAM reserved with `byte` so far */
+    pub segments: Segments,
+    /* label lines waiting for the line they name */
+    pub pending: String,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl {
+            debugger: Debugger::new(Machine::new().build(), SymbolTable::new()),
+            addr: 0,
+            segments: Segments::new(),
+            pending: String::new(),
+        }
+    }
+
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut out = io::stdout();
+        let mut lines = stdin.lock().lines();
+
+        let _ = writeln!(out, "type :help for help");
+
+        loop {
+            let _ = write!(out, "{:04X}> ", self.addr);
+            let _ = out.flush();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+
+            if !self.execute(&line, &mut out) {
+                break;
+            }
+        }
+    }
+
+    /**
+     * Handles one line of input
+     * Returns false once the REPL should exit
+     */
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> bool {
+        let line = line.trim();
+
+        let res = match line.strip_prefix(':') {
+            Some(command) => {
+                let args: Vec<&str> = command.split_whitespace().collect();
+
+                match args.first().copied() {
+                    Some("q") | Some("quit") => return false,
+                    Some("help") => writeln!(out, "{}", HELP).map_err(|e| e.to_string()),
+                    Some("org") => self.cmd_org(&args),
+                    Some("reset") => {
+                        *self = Repl::new();
+                        Ok(())
+                    }
+                    Some("load") => self.cmd_load(&args, out),
+                    _ => return self.debugger.execute(command, out),
+                }
+            }
+            None if line.is_empty() => Ok(()),
+            None if line.starts_with("org ") => {
+                let args: Vec<&str> = line.split_whitespace().collect();
+                self.cmd_org(&args)
+            }
+            None => self.enter(line, out),
+        };
+
+        if let Err(e) = res {
+            let _ = writeln!(out, "error: {}", e);
+        }
+
+        true
+    }
+
+    fn cmd_org(&mut self, args: &[&str]) -> Result<(), String> {
+        let addr = args.get(1).ok_or("usage: org ADDR")?;
+        let addr = parse_value(addr)?;
+
+        if addr > 0xFFFF {
+            return Err(format!("address out of range `{}`", args[1]));
+        }
+        self.addr = addr as u16;
+        Ok(())
+    }
+
+    fn cmd_load(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let path = args.get(1).ok_or("usage: load FILE")?;
+        let image = assemble_file(path, &Options::new()).map_err(|e| e.to_string())?;
+
+        writeln!(out, "loaded {} bytes", image.code.len()).map_err(|e| e.to_string())?;
+        self.load(image);
+        Ok(())
+    }
+
+    /**
+     * Starts over on a fresh machine running `image`
+     * New lines go after the end of its code.
+     */
+    pub fn load(&mut self, image: Image) {
+        self.addr = image.code.len() as u16;
+        self.segments = image.segments.clone();
+        self.debugger = Debugger::new(Machine::new().image(&image).build(), image.symbols);
+    }
+
+    /**
+     * Assembles `line` at the current address, returning its bytes
+     * Existing code in front of it is kept so relative jumps to it
+     * come out right.
+     */
+    fn assemble(&mut self, line: &str) -> Result<Vec<u8>, String> {
+        let start = self.addr as usize;
+        let rom = &self.debugger.ctx.em.rom;
+
+        let mut lc = LexerContext::new(line.to_string());
+        lc.run();
+
+        let mut pc = IPContext::new(lc.dt);
+        pc.file = String::from("<repl>");
+        pc.lb = self
+            .debugger
+            .symbols
+            .iter()
+            .map(|(name, addr)| (name.clone(), *addr as usize))
+            .collect();
+        pc.cg = rom[..start.min(rom.len())].to_vec();
+        pc.cg.resize(start, 0);
+        pc.segments = self.segments.clone();
+
+        image::parse(&mut pc).map_err(|e| e.to_string())?;
+
+        self.debugger.symbols = symbols::from_labels(&pc.lb);
+        self.segments = pc.segments;
+        Ok(pc.cg.split_off(start))
+    }
+
+    /**
+     * Assembles and runs one instruction, showing what it changed
+     */
+    fn enter(&mut self, line: &str, out: &mut dyn Write) -> Result<(), String> {
+        if line.ends_with(':') {
+            self.pending.push_str(line);
+            self.pending.push('\n');
+            return Ok(());
+        }
+
+        let text = mem::take(&mut self.pending) + line;
+        let segments = self.segments.clone();
+        let bytes = self.assemble(&text)?;
+
+        /* `ds`, `byte` and the like only reserve memory, there is nothing to run */
+        if self.segments != segments {
+            for region in &self.segments.regions[segments.regions.len()..] {
+                writeln!(out, "reserved {}", region).map_err(|e| e.to_string())?;
+            }
+        } else if bytes.is_empty() {
+            return Err(format!("nothing to assemble in `{}`", line));
+        }
+
+        let start = self.addr as usize;
+        let ctx = &mut self.debugger.ctx;
+
+        if ctx.em.rom.len() < start + bytes.len() {
+            ctx.em.rom.resize(start + bytes.len(), 0);
+        }
+        ctx.em.rom[start..start + bytes.len()].copy_from_slice(&bytes);
+        self.addr = self.addr.wrapping_add(bytes.len() as u16);
+
+        if self.segments != segments {
+            return Ok(());
+        }
+
+        /* keep whatever trace the user asked for */
+        let tracer = ctx.tracer.replace(Tracer::with_capacity(1));
+        ctx.em.reg.pc.set(start as u16);
+        let res = ctx.step();
+        let step = ctx.tracer.take().and_then(|mut t| t.entries.pop_back());
+        ctx.tracer = tracer;
+
+        if let Some(entry) = step {
+            let mut single = Tracer::new();
+            single.push(entry.clone());
+            single
+                .write_text(out, &self.debugger.symbols, &self.debugger.ctx.lines)
+                .map_err(|e| e.to_string())?;
+
+            for change in entry.regs.iter().filter(|c| c.reg == Reg::PSW) {
+                let flags: Vec<String> = FLAGS
+                    .iter()
+                    .filter(|(_, flag)| (change.old ^ change.new) & (1 << *flag as u8) != 0)
+                    .map(|(name, flag)| format!("{}={}", name, (change.new >> *flag as u8) & 1))
+                    .collect();
+                writeln!(out, "  flags: {}", flags.join(" ")).map_err(|e| e.to_string())?;
+            }
+        }
+
+        if let Err(reason) = res {
+            writeln!(out, "{}", self.debugger.ctx.explain(&reason)).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execute(repl: &mut Repl, lines: &[&str]) -> String {
+        let mut out = Vec::new();
+
+        for line in lines {
+            repl.execute(line, &mut out);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn each_line_runs_as_it_is_entered() {
+        let mut repl = Repl::new();
+        let out = execute(&mut repl, &["mov A, #0FFH", "add A, #1"]);
+
+        assert_eq!(
+            out,
+            "         0  0000  8C FF     mov A, #0FFH             A: 00H -> 0FFH\n\
+             \x20        1  0002  50 01     add A, #01H              A: 0FFH -> 00H PSW: 00H -> 0C0H\n\
+             \x20 flags: CY=1 AC=1\n"
+        );
+        assert_eq!(repl.addr, 4);
+        assert_eq!(repl.debugger.ctx.em.reg.a.get(), 0);
+    }
+
+    #[test]
+    fn flag_changes_are_spelled_out() {
+        let mut repl = Repl::new();
+        let out = execute(&mut repl, &["setb PSW.7", "setb PSW.3", "mov R1, #3"]);
+
+        assert!(out.contains("\n  flags: CY=1\n"), "{}", out);
+        assert!(out.contains("\n  flags: RS0=1\n"), "{}", out);
+        /* bank 1 is in use now */
+        assert!(out.ends_with("[iram 09H] 00H -> 03H\n"), "{}", out);
+    }
+
+    #[test]
+    fn labels_carry_over_between_lines() {
+        let mut repl = Repl::new();
+        let out = execute(&mut repl, &["mov A, #1", "loop:", "sjmp loop"]);
+
+        assert!(out.ends_with("  0002  28 FE     sjmp loop\n"), "{}", out);
+        assert_eq!(repl.debugger.symbols.get("loop"), Some(&2));
+        assert_eq!(repl.debugger.ctx.em.reg.pc.get(), 2);
+    }
+
+    #[test]
+    fn org_and_commands() {
+        let mut repl = Repl::new();
+        let out = execute(&mut repl, &[":org 100H", "mov B, #2", ":regs"]);
+
+        assert!(
+            out.starts_with("         0  0100  9D 02     mov B, #02H"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("A=00  B=02  SP=07  DPTR=0000  PC=0102"),
+            "{}",
+            out
+        );
+
+        assert_eq!(execute(&mut repl, &["org 20H"]), "");
+        assert_eq!(repl.addr, 0x20);
+        assert_eq!(
+            execute(&mut repl, &[":org 10000H"]),
+            "error: address out of range `10000H`\n"
+        );
+
+        execute(&mut repl, &[":reset"]);
+        assert_eq!(repl.addr, 0);
+        assert_eq!(repl.debugger.ctx.em.reg.b.get(), 0);
+        assert!(!repl.execute(":q", &mut Vec::new()));
+    }
+
+    #[test]
+    fn errors_leave_the_machine_alone() {
+        let mut repl = Repl::new();
+        let out = execute(&mut repl, &["mov A, #300", "foo", "sjmp nowhere"]);
+
+        assert_eq!(
+            out,
+            "error: <repl>:1: `300` does not fit in a byte\n\
+             error: <repl>:1: unknown instruction `foo`\n\
+             error: <repl>:1: undefined label `nowhere`\n"
+        );
+        assert_eq!(repl.addr, 0);
+        assert_eq!(repl.debugger.ctx.em.cycles, 0);
+    }
+
+    #[test]
+    fn byte_reserves_ram_instead_of_running() {
+        let mut repl = Repl::new();
+        let out = execute(&mut repl, &["counter:", "byte", "mov counter, #7"]);
+
+        assert!(out.starts_with("reserved DSEG 20H to 20H\n"), "{}", out);
+        assert_eq!(repl.debugger.ctx.em.ram.read(0x20), 7);
+        assert_eq!(repl.debugger.ctx.em.cycles, 2);
+    }
+}
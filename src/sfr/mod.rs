@@ -0,0 +1,186 @@
+// constants for the 8051 special function register space
+pub const SFR_BASE: usize = 0x80;
+pub const SFR_SIZE: usize = 128;
+
+/* SFR addresses */
+pub const P0: u8 = 0x80;
+pub const SP: u8 = 0x81;
+pub const DPL: u8 = 0x82;
+pub const DPH: u8 = 0x83;
+pub const PCON: u8 = 0x87;
+pub const TCON: u8 = 0x88;
+pub const TMOD: u8 = 0x89;
+pub const TL0: u8 = 0x8A;
+pub const TL1: u8 = 0x8B;
+pub const TH0: u8 = 0x8C;
+pub const TH1: u8 = 0x8D;
+pub const P1: u8 = 0x90;
+pub const SCON: u8 = 0x98;
+pub const SBUF: u8 = 0x99;
+pub const P2: u8 = 0xA0;
+pub const IE: u8 = 0xA8;
+pub const P3: u8 = 0xB0;
+pub const IP: u8 = 0xB8;
+pub const PSW: u8 = 0xD0;
+pub const ACC: u8 = 0xE0;
+pub const B: u8 = 0xF0;
+
+/**
+ * Names accepted by the assembler for direct addressing
+ */
+pub const NAMES: &[(&str, u8)] = &[
+    ("P0", P0),
+    ("SP", SP),
+    ("DPL", DPL),
+    ("DPH", DPH),
+    ("PCON", PCON),
+    ("TCON", TCON),
+    ("TMOD", TMOD),
+    ("TL0", TL0),
+    ("TL1", TL1),
+    ("TH0", TH0),
+    ("TH1", TH1),
+    ("P1", P1),
+    ("SCON", SCON),
+    ("SBUF", SBUF),
+    ("P2", P2),
+    ("IE", IE),
+    ("P3", P3),
+    ("IP", IP),
+    ("PSW", PSW),
+    ("ACC", ACC),
+    ("B", B),
+];
+
+/**
+ * Names of individual bits in the bit addressable SFRs
+ * A bit address is the SFR address plus the bit index
+ */
+pub const BIT_NAMES: &[(&str, u8)] = &[
+    /* TCON */
+    ("IT0", TCON),
+    ("IE0", TCON + 1),
+    ("IT1", TCON + 2),
+    ("IE1", TCON + 3),
+    ("TR0", TCON + 4),
+    ("TF0", TCON + 5),
+    ("TR1", TCON + 6),
+    ("TF1", TCON + 7),
+    /* SCON */
+    ("RI", SCON),
+    ("TI", SCON + 1),
+    ("RB8", SCON + 2),
+    ("TB8", SCON + 3),
+    ("REN", SCON + 4),
+    ("SM2", SCON + 5),
+    ("SM1", SCON + 6),
+    ("SM0", SCON + 7),
+    /* IE */
+    ("EX0", IE),
+    ("ET0", IE + 1),
+    ("EX1", IE + 2),
+    ("ET1", IE + 3),
+    ("ES", IE + 4),
+    ("EA", IE + 7),
+    /* IP */
+    ("PX0", IP),
+    ("PT0", IP + 1),
+    ("PX1", IP + 2),
+    ("PT1", IP + 3),
+    ("PS", IP + 4),
+    /* PSW, C is the carry as in `clr C` */
+    ("P", PSW),
+    ("OV", PSW + 2),
+    ("RS0", PSW + 3),
+    ("RS1", PSW + 4),
+    ("F0", PSW + 5),
+    ("AC", PSW + 6),
+    ("C", PSW + 7),
+    ("CY", PSW + 7),
+    /* P3 */
+    ("RXD", P3),
+    ("TXD", P3 + 1),
+    ("INT0", P3 + 2),
+    ("INT1", P3 + 3),
+    ("T0", P3 + 4),
+    ("T1", P3 + 5),
+];
+
+pub fn address_of(name: &str) -> Option<u8> {
+    NAMES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, a)| *a)
+}
+
+pub fn bit_address_of(name: &str) -> Option<u8> {
+    BIT_NAMES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, a)| *a)
+}
+
+pub fn name_of(address: u8) -> Option<&'static str> {
+    NAMES.iter().find(|(_, a)| *a == address).map(|(n, _)| *n)
+}
+
+pub fn bit_name_of(bit: u8) -> Option<&'static str> {
+    BIT_NAMES.iter().find(|(_, a)| *a == bit).map(|(n, _)| *n)
+}
+
+/**
+ * Returns the byte address and bit index of a bit address
+ * Bits 0x00 to 0x7F live in RAM at 0x20 to 0x2F,
+ * bits 0x80 to 0xFF live in the SFRs whose address is divisible by 8
+ */
+pub fn bit_location(bit: u8) -> (u8, u8) {
+    if bit < 0x80 {
+        (0x20 + bit / 8, bit % 8)
+    } else {
+        (bit & 0xF8, bit & 0x07)
+    }
+}
+
+#[derive(Debug)]
+pub struct Sfr {
+    pub memory: Vec<u8>,
+}
+
+impl Default for Sfr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sfr {
+    pub fn new() -> Sfr {
+        Sfr {
+            memory: vec![0; SFR_SIZE],
+        }
+    }
+
+    pub fn write(&mut self, address: u8, data: u8) {
+        if (address as usize) < SFR_BASE {
+            panic!("SFR address out of bounds: {}", address);
+        }
+        self.memory[address as usize - SFR_BASE] = data;
+    }
+
+    pub fn read(&self, address: u8) -> u8 {
+        self.memory[address as usize - SFR_BASE]
+    }
+
+    pub fn set_bit(&mut self, address: u8, bit: u8, value: bool) {
+        let mask = 1 << bit;
+        let data = self.read(address);
+        if value {
+            self.write(address, data | mask);
+        } else {
+            self.write(address, data & !mask);
+        }
+    }
+
+    pub fn get_bit(&self, address: u8, bit: u8) -> bool {
+        self.read(address) & (1 << bit) != 0
+    }
+}
@@ -0,0 +1,434 @@
+use crate::binary::Reader;
+use crate::emulator::Emulator;
+#[cfg(feature = "peripherals")]
+use crate::peripherals::gpio::Port;
+#[cfg(feature = "peripherals")]
+use crate::peripherals::serial::Frame;
+#[cfg(feature = "peripherals")]
+use crate::peripherals::timer::Timers;
+use crate::ram;
+use crate::sfr;
+use std::fs;
+use std::io::{self, Read, Write};
+
+/* snapshot file header, followed by the format version */
+pub const MAGIC: &[u8; 4] = b"PLSN";
+pub const VERSION: u16 = 1;
+
+/**
+ * Everything in the emulator apart from its memories
+ * The history keeps one for instructions that change
+ * more than the registers.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    pub b: u8,
+    pub sp: u8,
+    pub psw: u8,
+    pub dptr: u16,
+    pub pc: u16,
+
+    #[cfg(feature = "peripherals")]
+    pub timers: Timers,
+    #[cfg(feature = "peripherals")]
+    pub rx_buffer: u8,
+    #[cfg(feature = "peripherals")]
+    pub tx_frame: Option<Frame>,
+    #[cfg(feature = "peripherals")]
+    pub rx_frame: Option<Frame>,
+    #[cfg(feature = "peripherals")]
+    pub ports: [Port; 4],
+    pub in_service: Vec<bool>,
+
+    pub exit_code: Option<u8>,
+    pub cycles: u64,
+    pub oscillator_hz: u64,
+}
+
+impl CpuState {
+    pub fn take(em: &Emulator) -> CpuState {
+        CpuState {
+            a: em.reg.a.get(),
+            b: em.reg.b.get(),
+            sp: em.reg.sp.get(),
+            psw: em.psw.get(),
+            dptr: em.reg.dptr.get(),
+            pc: em.reg.pc.get(),
+            #[cfg(feature = "peripherals")]
+            timers: em.timers.clone(),
+            #[cfg(feature = "peripherals")]
+            rx_buffer: em.serial.rx_buffer,
+            #[cfg(feature = "peripherals")]
+            tx_frame: em.serial.tx_frame.clone(),
+            #[cfg(feature = "peripherals")]
+            rx_frame: em.serial.rx_frame.clone(),
+            #[cfg(feature = "peripherals")]
+            ports: em.gpio.ports,
+            in_service: em.irq.in_service.clone(),
+            exit_code: em.exit_code,
+            cycles: em.cycles,
+            oscillator_hz: em.oscillator_hz,
+        }
+    }
+
+    /**
+     * Puts the emulator back in this state
+     * Pin changes recorded after it are forgotten, host input
+     * already consumed and output already written stay as they are.
+     */
+    pub fn restore(&self, em: &mut Emulator) {
+        em.reg.a.set(self.a);
+        em.reg.b.set(self.b);
+        em.reg.sp.set(self.sp);
+        em.psw.set(self.psw);
+        em.reg.dptr.set(self.dptr);
+        em.reg.pc.set(self.pc);
+
+        em.irq.in_service = self.in_service.clone();
+
+        em.exit_code = self.exit_code;
+        em.cycles = self.cycles;
+        em.oscillator_hz = self.oscillator_hz;
+
+        #[cfg(feature = "peripherals")]
+        {
+            em.timers = self.timers.clone();
+            em.serial.rx_buffer = self.rx_buffer;
+            em.serial.tx_frame = self.tx_frame.clone();
+            em.serial.rx_frame = self.rx_frame.clone();
+            em.gpio.ports = self.ports;
+
+            em.gpio.now = self.cycles;
+            em.gpio.history.retain(|change| change.cycle <= self.cycles);
+        }
+    }
+}
+
+/**
+ * The complete state of an emulator
+ * Host side objects (I/O, syscall table, callbacks) are not part of it.
+ */
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub cpu: CpuState,
+    pub ram: Vec<u8>,
+    pub sfr: Vec<u8>,
+    pub xram: Vec<u8>,
+    pub rom: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn take(em: &Emulator) -> Snapshot {
+        Snapshot {
+            cpu: CpuState::take(em),
+            ram: em.ram.memory.clone(),
+            sfr: em.sfr.memory.clone(),
+            xram: em.xram.clone(),
+            rom: em.rom.clone(),
+        }
+    }
+
+    pub fn restore(&self, em: &mut Emulator) {
+        em.ram.memory.clone_from(&self.ram);
+        em.sfr.memory.clone_from(&self.sfr);
+        em.xram.clone_from(&self.xram);
+        em.rom.clone_from(&self.rom);
+
+        self.cpu.restore(em);
+    }
+}
+
+/**
+ * Snapshot file layout, big endian like everything else.
+ * Builds without peripherals write their reset state and
+ * ignore them when reading:
+ * magic, version (u16),
+ * A, B, SP, PSW (u8), DPTR, PC (u16),
+ * exit code (u8 present flag, u8 code), cycles, oscillator (u64),
+ * timer 0 and timer 1 count pin levels (u8),
+ * serial receive buffer (u8), transmit and receive frames
+ * (u8 present flag, data u8, ninth bit u8, remaining u32),
+ * ports P0 to P3 (latch u8, external u8),
+ * interrupt levels in service (u8 count, u8 each),
+ * then RAM and SFRs (u16 length, bytes),
+ * external RAM and code (u32 length, bytes)
+ */
+impl Snapshot {
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let cpu = &self.cpu;
+        let mut buf = Vec::new();
+
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+
+        buf.extend_from_slice(&[cpu.a, cpu.b, cpu.sp, cpu.psw]);
+        buf.extend_from_slice(&cpu.dptr.to_be_bytes());
+        buf.extend_from_slice(&cpu.pc.to_be_bytes());
+        buf.extend_from_slice(&[cpu.exit_code.is_some() as u8, cpu.exit_code.unwrap_or(0)]);
+        buf.extend_from_slice(&cpu.cycles.to_be_bytes());
+        buf.extend_from_slice(&cpu.oscillator_hz.to_be_bytes());
+
+        #[cfg(feature = "peripherals")]
+        {
+            buf.push(cpu.timers.t0.last_pin as u8);
+            buf.push(cpu.timers.t1.last_pin as u8);
+
+            buf.push(cpu.rx_buffer);
+            for frame in [&cpu.tx_frame, &cpu.rx_frame] {
+                match frame {
+                    Some(frame) => {
+                        buf.extend_from_slice(&[1, frame.data, frame.ninth as u8]);
+                        buf.extend_from_slice(&frame.remaining.to_be_bytes());
+                    }
+                    None => buf.extend_from_slice(&[0; 7]),
+                }
+            }
+
+            for port in &cpu.ports {
+                buf.extend_from_slice(&[port.latch, port.external]);
+            }
+        }
+
+        #[cfg(not(feature = "peripherals"))]
+        {
+            buf.extend_from_slice(&[0; 17]);
+            buf.extend_from_slice(&[0xFF; 8]);
+        }
+
+        buf.push(cpu.in_service.len() as u8);
+        buf.extend(cpu.in_service.iter().map(|high| *high as u8));
+
+        for memory in [&self.ram, &self.sfr] {
+            buf.extend_from_slice(&(memory.len() as u16).to_be_bytes());
+            buf.extend_from_slice(memory);
+        }
+
+        for memory in [&self.xram, &self.rom] {
+            buf.extend_from_slice(&(memory.len() as u32).to_be_bytes());
+            buf.extend_from_slice(memory);
+        }
+
+        out.write_all(&buf)
+    }
+
+    pub fn read(input: &mut dyn Read) -> Result<Snapshot, String> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+        let mut reader = Reader::new(&data, "snapshot file");
+
+        if reader.take(4)? != MAGIC {
+            return Err("not a snapshot file".to_string());
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(format!("unsupported snapshot version {}", version));
+        }
+
+        let a = reader.u8()?;
+        let b = reader.u8()?;
+        let sp = reader.u8()?;
+        let psw = reader.u8()?;
+        let dptr = reader.u16()?;
+        let pc = reader.u16()?;
+
+        let has_exit_code = reader.u8()? != 0;
+        let exit_code = reader.u8()?;
+        let cycles = reader.u64()?;
+        let oscillator_hz = reader.u64()?;
+
+        #[cfg(feature = "peripherals")]
+        let (timers, rx_buffer, tx_frame, rx_frame, ports) = {
+            let mut timers = Timers::new();
+            timers.t0.last_pin = reader.u8()? != 0;
+            timers.t1.last_pin = reader.u8()? != 0;
+
+            let rx_buffer = reader.u8()?;
+            let mut frames = Vec::new();
+            for _ in 0..2 {
+                let present = reader.u8()? != 0;
+                let data = reader.u8()?;
+                let ninth = reader.u8()? != 0;
+                let remaining = reader.u32()?;
+
+                frames.push(present.then_some(Frame {
+                    data,
+                    ninth,
+                    remaining,
+                }));
+            }
+            let rx_frame = frames.pop().unwrap();
+            let tx_frame = frames.pop().unwrap();
+
+            let mut ports = [Port {
+                latch: 0xFF,
+                external: 0xFF,
+            }; 4];
+            for port in ports.iter_mut() {
+                port.latch = reader.u8()?;
+                port.external = reader.u8()?;
+            }
+
+            (timers, rx_buffer, tx_frame, rx_frame, ports)
+        };
+
+        /* timer pins, receive buffer, two frames and four ports */
+        #[cfg(not(feature = "peripherals"))]
+        reader.take(2 + 1 + 2 * 7 + 4 * 2)?;
+
+        let count = reader.u8()? as usize;
+        let in_service = reader.take(count)?.iter().map(|high| *high != 0).collect();
+
+        let len = reader.u16()? as usize;
+        let ram = reader.take(len)?.to_vec();
+        let len = reader.u16()? as usize;
+        let sfr = reader.take(len)?.to_vec();
+        let len = reader.u32()? as usize;
+        let xram = reader.take(len)?.to_vec();
+        let len = reader.u32()? as usize;
+        let rom = reader.take(len)?.to_vec();
+
+        if ram.len() != ram::RAM_SIZE || sfr.len() != sfr::SFR_SIZE || xram.len() != ram::XRAM_SIZE
+        {
+            return Err("snapshot memory sizes do not match this emulator".to_string());
+        }
+
+        Ok(Snapshot {
+            cpu: CpuState {
+                a,
+                b,
+                sp,
+                psw,
+                dptr,
+                pc,
+                #[cfg(feature = "peripherals")]
+                timers,
+                #[cfg(feature = "peripherals")]
+                rx_buffer,
+                #[cfg(feature = "peripherals")]
+                tx_frame,
+                #[cfg(feature = "peripherals")]
+                rx_frame,
+                #[cfg(feature = "peripherals")]
+                ports,
+                in_service,
+                exit_code: has_exit_code.then_some(exit_code),
+                cycles,
+                oscillator_hz,
+            },
+            ram,
+            sfr,
+            xram,
+            rom,
+        })
+    }
+}
+
+/**
+ * Writes the state of `em` to a snapshot file
+ */
+pub fn save(em: &Emulator, path: &str) -> Result<(), String> {
+    let mut file = fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    Snapshot::take(em)
+        .write(&mut file)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+/**
+ * Puts `em` in the state saved in a snapshot file
+ * Host side objects (I/O, syscalls, callbacks) are left alone.
+ */
+pub fn load(em: &mut Emulator, path: &str) -> Result<(), String> {
+    let mut file = fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let snapshot = Snapshot::read(&mut file).map_err(|e| format!("{}: {}", path, e))?;
+
+    snapshot.restore(em);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::AsmContext;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+
+    /*
+       Leaves a frame on the serial line, a cleared port latch,
+       timer 0 running and its interrupt in service
+    */
+    const PROGRAM: &str = "ljmp main\norg 0BH\nmov 40H, #55H\nwait:\nsjmp wait\nmain:\nmov SCON, #40H\nmov TMOD, #22H\nmov TH1, #0FDH\nsetb TR1\nmov SBUF, #41H\nclr P1.0\nmov TH0, #0F0H\nmov TL0, #0FEH\nmov IE, #82H\nsetb TR0\nidle:\nsjmp idle\nend\n";
+
+    fn context() -> AsmContext {
+        let mut lc = LexerContext::new(PROGRAM.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.burn(pc.cg);
+        ctx
+    }
+
+    fn written(em: &Emulator) -> Vec<u8> {
+        let mut out = Vec::new();
+        Snapshot::take(em).write(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    #[cfg(feature = "peripherals")]
+    fn round_trip_keeps_the_peripherals() {
+        let mut ctx = context();
+        ctx.em.set_oscillator(11_059_200);
+        ctx.run_for(60);
+
+        let cpu = CpuState::take(&ctx.em);
+        assert!(cpu.tx_frame.is_some());
+        assert_eq!(cpu.ports[1].latch, 0xFE);
+        assert_eq!(cpu.in_service, vec![false]);
+
+        let data = written(&ctx.em);
+        let snapshot = Snapshot::read(&mut data.as_slice()).unwrap();
+        assert_eq!(snapshot.cpu, cpu);
+
+        let mut copy = context();
+        copy.em.rom.clear();
+        snapshot.restore(&mut copy.em);
+        assert_eq!(written(&copy.em), data);
+
+        /* both carry on the same way, the frame finishes sending */
+        ctx.run_for(2000);
+        copy.run_for(2000);
+        assert_eq!(written(&copy.em), written(&ctx.em));
+        assert!(CpuState::take(&copy.em).tx_frame.is_none());
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut data = written(&context().em);
+        data[4..6].copy_from_slice(&(VERSION + 1).to_be_bytes());
+
+        assert_eq!(
+            Snapshot::read(&mut data.as_slice()).unwrap_err(),
+            format!("unsupported snapshot version {}", VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn broken_files_are_rejected() {
+        let data = written(&context().em);
+
+        assert_eq!(
+            Snapshot::read(&mut &data[..data.len() - 1]).unwrap_err(),
+            "snapshot file is truncated"
+        );
+        assert_eq!(
+            Snapshot::read(&mut &b"PLTR\x00\x01"[..]).unwrap_err(),
+            "not a snapshot file"
+        );
+    }
+}
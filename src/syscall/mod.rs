@@ -0,0 +1,407 @@
+use crate::emulator::Emulator;
+use crate::psw::PswFlag;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/* int 21H services, selected by R0 */
+pub const READ_CHAR: u8 = 0x01;
+pub const PRINT_CHAR: u8 = 0x02;
+pub const PRINT_STRING: u8 = 0x09;
+pub const READ_LINE: u8 = 0x0A;
+pub const GET_TICKS: u8 = 0x2C;
+pub const EXIT: u8 = 0x4C;
+
+/**
+ * What the emulated program talks to through `int 21H`
+ */
+pub trait HostIo {
+    fn write(&mut self, data: &[u8]);
+
+    /* blocks until a byte is available, None at end of input */
+    fn read_byte(&mut self) -> Option<u8>;
+}
+
+/**
+ * The terminal
+ */
+#[derive(Debug, Default)]
+pub struct StdIo;
+
+impl HostIo for StdIo {
+    fn write(&mut self, data: &[u8]) {
+        let mut out = io::stdout();
+        let _ = out.write_all(data);
+        let _ = out.flush();
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8; 1];
+
+        match io::stdin().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Output collected in memory
+ * Clones share the same buffer, so a test can keep one
+ * and hand the other to the serial port
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer {
+    pub data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+        SharedBuffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.contents()).into_owned()
+    }
+
+    /* empties the buffer, returning what was in it */
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.data.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/**
+ * In-memory input and output, for tests
+ * `output` can be cloned before handing this to the
+ * emulator to read back what the program printed
+ */
+#[derive(Debug, Default)]
+pub struct BufferIo {
+    pub input: VecDeque<u8>,
+    pub output: SharedBuffer,
+}
+
+impl BufferIo {
+    pub fn new(input: &str) -> BufferIo {
+        BufferIo {
+            input: input.bytes().collect(),
+            output: SharedBuffer::new(),
+        }
+    }
+}
+
+impl HostIo for BufferIo {
+    fn write(&mut self, data: &[u8]) {
+        let _ = self.output.write_all(data);
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+/**
+ * Any reader and writer, e.g. files the command line routed
+ * the console to
+ */
+pub struct StreamIo {
+    pub input: Box<dyn Read>,
+    pub output: Box<dyn Write>,
+}
+
+impl StreamIo {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> StreamIo {
+        StreamIo { input, output }
+    }
+}
+
+impl HostIo for StreamIo {
+    fn write(&mut self, data: &[u8]) {
+        let _ = self.output.write_all(data);
+        let _ = self.output.flush();
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8; 1];
+
+        match self.input.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Continue,
+    Exit(u8),
+}
+
+pub type Service = fn(&mut Emulator) -> Outcome;
+
+/**
+ * The `int 21H` service table
+ * Programs put the service number in R0, arguments in R1, R2...
+ * and get results back in A (or R4 to R7 for wider values).
+ * Hosts can add or replace services with `register`.
+ */
+pub struct Syscalls {
+    pub services: HashMap<u8, Service>,
+}
+
+impl Default for Syscalls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Syscalls {
+    pub fn new() -> Syscalls {
+        let mut res = Syscalls {
+            services: HashMap::new(),
+        };
+
+        res.register(READ_CHAR, read_char);
+        res.register(PRINT_CHAR, print_char);
+        res.register(PRINT_STRING, print_string);
+        res.register(READ_LINE, read_line);
+        res.register(GET_TICKS, get_ticks);
+        res.register(EXIT, exit);
+
+        res
+    }
+
+    pub fn register(&mut self, number: u8, service: Service) {
+        self.services.insert(number, service);
+    }
+}
+
+/**
+ * Runs the service selected by R0
+ * Returns None if no such service is registered
+ */
+pub fn dispatch(em: &mut Emulator) -> Option<Outcome> {
+    let number = em.read_rn(0);
+    let service = *em.syscalls.services.get(&number)?;
+
+    Some(service(em))
+}
+
+/**
+ * R0 = 01H: read a character into A
+ * CY is set at end of input
+ */
+fn read_char(em: &mut Emulator) -> Outcome {
+    let byte = em.io.read_byte();
+
+    em.reg.a.set(byte.unwrap_or(0));
+    em.psw.set_flag(PswFlag::CY, byte.is_none());
+    Outcome::Continue
+}
+
+/**
+ * R0 = 02H: print the character in R1
+ */
+fn print_char(em: &mut Emulator) -> Outcome {
+    let ch = em.read_rn(1);
+
+    em.io.write(&[ch]);
+    Outcome::Continue
+}
+
+/**
+ * R0 = 09H: print the zero terminated string
+ * at the RAM address in R1
+ */
+fn print_string(em: &mut Emulator) -> Outcome {
+    let mut addr = em.read_rn(1);
+    let mut bytes = Vec::new();
+
+    while (addr as usize) < crate::ram::RAM_SIZE {
+        let byte = em.read_iram(addr as usize);
+        if byte == 0 {
+            break;
+        }
+
+        bytes.push(byte);
+        addr += 1;
+    }
+
+    em.io.write(&bytes);
+    Outcome::Continue
+}
+
+/**
+ * R0 = 0AH: read a line into the RAM buffer at R1
+ * R2 is the buffer size, including the terminating zero.
+ * A buffer running past the end of RAM is cut short there.
+ * The newline is not stored, A gets the length of the line.
+ */
+fn read_line(em: &mut Emulator) -> Outcome {
+    let addr = em.read_rn(1) as usize;
+    let size = (em.read_rn(2) as usize).min(crate::ram::RAM_SIZE.saturating_sub(addr));
+    let mut len = 0;
+
+    while let Some(byte) = em.io.read_byte() {
+        if byte == b'\n' {
+            break;
+        }
+
+        if byte != b'\r' && len + 1 < size {
+            em.write_iram(addr + len, byte);
+            len += 1;
+        }
+    }
+
+    if size > 0 {
+        em.write_iram(addr + len, 0);
+    }
+
+    em.reg.a.set(len as u8);
+    Outcome::Continue
+}
+
+/**
+ * R0 = 2CH: machine cycles since reset in R4 to R7
+ * R4 holds the most significant byte (big endian, as always)
+ */
+fn get_ticks(em: &mut Emulator) -> Outcome {
+    let ticks = em.cycles as u32;
+
+    for (i, byte) in ticks.to_be_bytes().iter().enumerate() {
+        em.write_rn(4 + i as u8, *byte);
+    }
+
+    Outcome::Continue
+}
+
+/**
+ * R0 = 4CH: exit with the code in R1
+ */
+fn exit(em: &mut Emulator) -> Outcome {
+    Outcome::Exit(em.read_rn(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::engine::AsmContext;
+    use crate::assembler::lexer::LexerContext;
+    use crate::assembler::parser::IPContext;
+
+    fn call(em: &mut Emulator, service: u8, r1: u8, r2: u8) -> Option<Outcome> {
+        em.write_rn(0, service);
+        em.write_rn(1, r1);
+        em.write_rn(2, r2);
+        dispatch(em)
+    }
+
+    #[test]
+    fn read_line_stops_at_the_end_of_ram() {
+        let mut em = Emulator::new();
+        em.io = Box::new(BufferIo::new("abcdef\nnext"));
+
+        assert_eq!(call(&mut em, READ_LINE, 0x7E, 10), Some(Outcome::Continue));
+        assert_eq!(em.reg.a.get(), 1);
+        assert_eq!(em.ram.read(0x7E), b'a');
+        assert_eq!(em.ram.read(0x7F), 0);
+
+        /* the rest of the line is still consumed */
+        assert_eq!(call(&mut em, READ_CHAR, 0, 0), Some(Outcome::Continue));
+        assert_eq!(em.reg.a.get(), b'n');
+
+        /* no room at all, nothing is written */
+        assert_eq!(call(&mut em, READ_LINE, 0x90, 10), Some(Outcome::Continue));
+        assert_eq!(em.reg.a.get(), 0);
+    }
+
+    #[test]
+    fn read_line_drops_carriage_returns_and_overflow() {
+        let mut em = Emulator::new();
+        em.io = Box::new(BufferIo::new("hello\r\n"));
+
+        call(&mut em, READ_LINE, 0x30, 4);
+        assert_eq!(em.reg.a.get(), 3);
+        assert_eq!(&em.ram.memory[0x30..0x34], b"hel\0");
+    }
+
+    #[test]
+    fn print_string_stops_at_the_end_of_ram() {
+        let mut em = Emulator::new();
+        let io = BufferIo::new("");
+        let output = io.output.clone();
+        em.io = Box::new(io);
+        em.ram.memory[0x7D..].copy_from_slice(b"xyz");
+
+        call(&mut em, PRINT_STRING, 0x7D, 0);
+        assert_eq!(output.to_string_lossy(), "xyz");
+    }
+
+    /* runs `source` against BufferIo with `input`, returns what it printed */
+    fn run(source: &str, input: &str, setup: impl FnOnce(&mut Emulator)) -> (Emulator, String) {
+        let mut lc = LexerContext::new(source.to_string());
+        lc.run();
+        let mut pc = IPContext::new(lc.dt);
+        pc.run().unwrap();
+
+        let io = BufferIo::new(input);
+        let output = io.output.clone();
+        let mut ctx = AsmContext::new(Emulator::new());
+        ctx.em.io = Box::new(io);
+        setup(&mut ctx.em);
+        ctx.em.burn(pc.cg);
+        ctx.run();
+
+        (ctx.em, output.to_string_lossy())
+    }
+
+    #[test]
+    fn program_prints_and_exits() {
+        let source = "mov R0, #2\nmov R1, #48H\nint 21H\nmov R1, #69H\nint 21H\nmov R0, #4CH\nmov R1, #3\nint 21H\nmov R0, #2\nint 21H\nend\n";
+        let (em, output) = run(source, "", |_| ());
+
+        /* nothing after the exit runs */
+        assert_eq!(output, "Hi");
+        assert_eq!(em.exit_code, Some(3));
+    }
+
+    #[test]
+    fn program_reads_input_until_eof() {
+        let source =
+            "mov R0, #1\nint 21H\nmov R1, A\nmov R0, #2\nint 21H\nmov R0, #1\nint 21H\nend\n";
+        let (em, output) = run(source, "x", |_| ());
+
+        assert_eq!(output, "x");
+        assert_eq!(em.reg.a.get(), 0);
+        assert!(em.psw.get_flag(PswFlag::CY));
+        assert_eq!(em.exit_code, None);
+    }
+
+    #[test]
+    fn hosts_can_add_services() {
+        fn answer(em: &mut Emulator) -> Outcome {
+            em.reg.a.set(42);
+            Outcome::Continue
+        }
+
+        let (em, _) = run("mov R0, #80H\nint 21H\nend\n", "", |em| {
+            em.syscalls.register(0x80, answer)
+        });
+        assert_eq!(em.reg.a.get(), 42);
+    }
+}
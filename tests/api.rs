@@ -0,0 +1,204 @@
+/*
+   The library as another crate sees it: assemble,
+   build a Machine, run and read back what happened
+*/
+use prelude_rust::assembler::object::assemble_object;
+use prelude_rust::emulator::Space;
+use prelude_rust::syscall::{BufferIo, Outcome};
+use prelude_rust::{assemble, assemble_file, link, Emulator, Error, Machine, Options, StopReason};
+
+const HELLO: &str = "mov R0, #2\nmov R1, #48H\nint 21H\nmov R1, #69H\nint 21H\nmov R0, #4CH\nmov R1, #3\nint 21H\nend\n";
+
+#[test]
+fn runs_a_program_against_buffer_io() {
+    let image = assemble(HELLO, &Options::new()).unwrap();
+    let io = BufferIo::new("");
+    let output = io.output.clone();
+    let mut ctx = Machine::new().image(&image).io(Box::new(io)).build();
+
+    assert_eq!(ctx.run(), StopReason::Exited(3));
+    assert_eq!(output.to_string_lossy(), "Hi");
+    assert_eq!(ctx.em.exit_code, Some(3));
+}
+
+#[test]
+fn reads_input_from_buffer_io() {
+    let source = "mov R0, #1\nint 21H\nmov R1, A\nmov R0, #2\nint 21H\nmov R0, #1\nint 21H\njc eof\nsjmp done\neof:\nmov R0, #4CH\nmov R1, #7\nint 21H\ndone:\nend\n";
+    let image = assemble(source, &Options::new()).unwrap();
+    let io = BufferIo::new("x");
+    let output = io.output.clone();
+    let mut ctx = Machine::new().image(&image).io(Box::new(io)).build();
+
+    assert_eq!(ctx.run(), StopReason::Exited(7));
+    assert_eq!(output.to_string_lossy(), "x");
+}
+
+#[test]
+fn hosts_can_add_services() {
+    fn answer(em: &mut Emulator) -> Outcome {
+        em.reg.a.set(42);
+        Outcome::Continue
+    }
+
+    let image = assemble("mov R0, #80H\nint 21H\nend\n", &Options::new()).unwrap();
+    let mut ctx = Machine::new().image(&image).syscall(0x80, answer).build();
+
+    assert_eq!(ctx.run(), StopReason::Halted);
+    assert_eq!(ctx.em.reg.a.get(), 42);
+
+    let mut ctx = Machine::new().image(&image).build();
+    assert_eq!(
+        ctx.run(),
+        StopReason::UnknownSyscall {
+            pc: 2,
+            service: 0x80
+        }
+    );
+}
+
+#[test]
+fn entry_point_and_cycle_limit() {
+    let image = assemble("org 10H\nstart:\nsjmp start\nend\n", &Options::new()).unwrap();
+    assert_eq!(image.symbols.get("start"), Some(&0x10));
+
+    let mut ctx = Machine::new().image(&image).entry(0x10).build();
+    assert_eq!(ctx.run_for(100), StopReason::CycleLimit);
+    assert_eq!(ctx.em.reg.pc.get(), 0x10);
+    assert_eq!(ctx.em.cycles, 100);
+}
+
+#[test]
+fn errors_name_the_file_and_line() {
+    let options = Options {
+        file: "bad.plasm".to_string(),
+        ..Options::new()
+    };
+
+    match assemble("mov A, #1\nsjmp nowhere\nend\n", &options) {
+        Err(Error::Assembly(message)) => {
+            assert_eq!(message, "bad.plasm:2: undefined label `nowhere`")
+        }
+        other => panic!(
+            "expected an assembly error, got {:?}",
+            other.map(|i| i.code)
+        ),
+    }
+}
+
+#[test]
+fn memories_cover_their_address_spaces() {
+    let mut em = Emulator::new();
+
+    let ram = em.memory(Space::Iram);
+    assert!(ram.contains(0x00) && ram.contains(0x7F) && !ram.contains(0x80));
+
+    let sfr = em.memory(Space::Sfr);
+    assert!(
+        !sfr.contains(0x7F) && sfr.contains(0x80) && sfr.contains(0xFF) && !sfr.contains(0x100)
+    );
+
+    let xram = em.memory(Space::Xram);
+    assert!(xram.contains(0xFFFF) && !xram.contains(0x10000));
+
+    /* what the program sees */
+    em.memory_mut(Space::Iram).write(0x30, 0x5A);
+    em.memory_mut(Space::Sfr).write(0x8C, 0xA5);
+    assert_eq!(em.read_direct(0x30), 0x5A);
+    assert_eq!(em.read_direct(0x8C), 0xA5);
+
+    em.write_xram(0x1234, 7);
+    assert_eq!(em.memory(Space::Xram).read(0x1234), 7);
+}
+
+#[test]
+fn arguments_are_popped_argc_first() {
+    let image = assemble("pop 30H\npop 31H\npop 32H\nend\n", &Options::new()).unwrap();
+    let mut ctx = Machine::new().image(&image).build();
+    let argv: Vec<String> = ["prog", "-f"].iter().map(|a| a.to_string()).collect();
+
+    ctx.em.push_args(&argv, &image.segments).unwrap();
+    assert_eq!(ctx.run(), StopReason::Halted);
+
+    assert_eq!(ctx.em.ram.read(0x30), 2);
+    let name = ctx.em.ram.read(0x31) as usize;
+    assert_eq!(&ctx.em.ram.memory[name..name + 5], b"prog\0");
+    let flag = ctx.em.ram.read(0x32) as usize;
+    assert_eq!(&ctx.em.ram.memory[flag..flag + 3], b"-f\0");
+
+    let many = vec!["x".repeat(60), "y".repeat(60)];
+    assert!(Machine::new()
+        .build()
+        .em
+        .push_args(&many, &image.segments)
+        .is_err());
+}
+
+#[test]
+fn arguments_stay_clear_of_data_and_stack() {
+    let image = assemble_file("lib/rsc/argparser.plasm", &Options::new()).unwrap();
+    let mut ctx = Machine::new().image(&image).build();
+    let argv: Vec<String> = ["rsc", "-f", "main.rsc"]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+
+    ctx.em.push_args(&argv, &image.segments).unwrap();
+    assert_eq!(ctx.run(), StopReason::Halted);
+
+    /* prog_name, argc and file_name are the `byte`s at 20H to 22H */
+    assert_eq!(ctx.em.ram.read(0x21), 3);
+    let name = ctx.em.ram.read(0x20) as usize;
+    assert!((0x23..0x60).contains(&name), "{:02X}", name);
+    assert_eq!(&ctx.em.ram.memory[name..name + 4], b"rsc\0");
+
+    /* argv[1] and argv[2] are still on the stack above 5FH */
+    assert_eq!(ctx.em.reg.sp.get(), 0x61);
+    let flag = ctx.em.ram.read(0x61) as usize;
+    assert_eq!(&ctx.em.ram.memory[flag..flag + 3], b"-f\0");
+}
+
+#[test]
+fn links_separately_assembled_objects() {
+    let main = assemble_object("EXTRN hello\nlcall hello\nend\n", &Options::new()).unwrap();
+    let hello = assemble_object(
+        "PUBLIC hello\nhello:\nmov R0, #2\nmov R1, #21H\nint 21H\nret\n",
+        &Options::new(),
+    )
+    .unwrap();
+
+    let (image, map) = link(&[main, hello]).unwrap();
+    let io = BufferIo::new("");
+    let output = io.output.clone();
+    let mut ctx = Machine::new().image(&image).io(Box::new(io)).build();
+
+    assert_eq!(ctx.run(), StopReason::Halted);
+    assert_eq!(output.to_string_lossy(), "!");
+    assert_eq!(map.code.len(), 2);
+
+    let main = assemble_object("EXTRN missing\nlcall missing\nend\n", &Options::new()).unwrap();
+    assert!(matches!(link(&[main]), Err(Error::Link(_))));
+}
+
+#[test]
+fn linking_moves_data_reserved_without_at() {
+    /* both reserve 20H when assembled on their own */
+    let main = "EXTRN bump\nDSEG\nflag:\nds 1\nCSEG\nmov flag, #7\nlcall bump\nlcall bump\nend\n";
+    let counter = "PUBLIC bump\nDSEG\ncount:\nds 1\nCSEG\nbump:\nmov A, count\nadd A, #1\nmov count, A\nret\n";
+    let main = assemble_object(main, &Options::new()).unwrap();
+    let counter = assemble_object(counter, &Options::new()).unwrap();
+
+    let (image, map) = link(&[main, counter]).unwrap();
+    let mut ctx = Machine::new().image(&image).build();
+
+    assert_eq!(ctx.run(), StopReason::Halted);
+    assert_eq!(ctx.em.ram.read(0x20), 7);
+    assert_eq!(ctx.em.ram.read(0x21), 2);
+    assert_eq!(image.segments.labels["count"].1, 0x21);
+    assert_eq!(map.data.len(), 2);
+
+    /* with AT they stay put, and clash */
+    let fixed = "DSEG AT 20H\nds 1\nCSEG\nret\n";
+    let a = assemble_object(fixed, &Options::new()).unwrap();
+    let b = assemble_object(fixed, &Options::new()).unwrap();
+    assert!(matches!(link(&[a, b]), Err(Error::Link(_))));
+}
@@ -0,0 +1,211 @@
+/*
+   The arithmetic, logic and branch instructions,
+   assembled, run and disassembled again
+*/
+use prelude_rust::assembler::disasm;
+use prelude_rust::assembler::symbols::SymbolTable;
+use prelude_rust::psw::PswFlag;
+use prelude_rust::{assemble, AsmContext, Machine, Options, StopReason};
+
+fn run(source: &str) -> AsmContext {
+    let image = assemble(source, &Options::new()).unwrap();
+    let mut ctx = Machine::new().image(&image).build();
+
+    assert_eq!(ctx.run_for(10_000), StopReason::Halted);
+    ctx
+}
+
+fn a(ctx: &AsmContext) -> u8 {
+    ctx.em.reg.a.get()
+}
+
+fn flag(ctx: &AsmContext, flag: PswFlag) -> bool {
+    ctx.em.psw.get_flag(flag)
+}
+
+#[test]
+fn add_sets_carry_and_overflow() {
+    let ctx = run("mov A, #0F0H\nadd A, #20H\nend\n");
+    assert_eq!(a(&ctx), 0x10);
+    assert!(flag(&ctx, PswFlag::CY));
+    assert!(!flag(&ctx, PswFlag::OV));
+
+    let ctx = run("mov A, #7FH\nadd A, #1\nend\n");
+    assert_eq!(a(&ctx), 0x80);
+    assert!(!flag(&ctx, PswFlag::CY));
+    assert!(flag(&ctx, PswFlag::AC));
+    assert!(flag(&ctx, PswFlag::OV));
+}
+
+#[test]
+fn add_a_a_doubles() {
+    let ctx = run("mov A, #41H\nadd A, A\nend\n");
+    assert_eq!(a(&ctx), 0x82);
+    assert!(flag(&ctx, PswFlag::OV));
+}
+
+#[test]
+fn addc_and_subb_use_the_carry() {
+    let ctx = run("setb C\nmov A, #1\naddc A, #1\nend\n");
+    assert_eq!(a(&ctx), 3);
+    assert!(!flag(&ctx, PswFlag::CY));
+
+    let ctx = run("clr C\nmov A, #10H\nmov R3, #20H\nsubb A, R3\nend\n");
+    assert_eq!(a(&ctx), 0xF0);
+    assert!(flag(&ctx, PswFlag::CY));
+
+    let ctx = run("setb C\nmov A, #10H\nsubb A, #0FH\nend\n");
+    assert_eq!(a(&ctx), 0);
+    assert!(!flag(&ctx, PswFlag::CY));
+    assert!(flag(&ctx, PswFlag::AC));
+}
+
+#[test]
+fn inc_and_dec() {
+    let ctx = run("mov A, #0FFH\ninc A\nmov R2, #0\ndec R2\nmov R3, #9\ninc R3\nmov DPTR, #00FFH\ninc DPTR\nend\n");
+    assert_eq!(a(&ctx), 0);
+    assert_eq!(ctx.em.ram.read(2), 0xFF);
+    assert_eq!(ctx.em.ram.read(3), 10);
+    assert_eq!(ctx.em.reg.dptr.get(), 0x0100);
+}
+
+#[test]
+fn mul_and_div() {
+    let ctx = run("mov A, #50H\nmov B, #0A0H\nmul AB\nend\n");
+    assert_eq!(a(&ctx), 0x00);
+    assert_eq!(ctx.em.reg.b.get(), 0x32);
+    assert!(flag(&ctx, PswFlag::OV));
+
+    let ctx = run("mov A, #251\nmov B, #18\ndiv AB\nend\n");
+    assert_eq!(a(&ctx), 13);
+    assert_eq!(ctx.em.reg.b.get(), 17);
+    assert!(!flag(&ctx, PswFlag::OV));
+
+    let ctx = run("mov A, #5\nmov B, #0\ndiv AB\nend\n");
+    assert!(flag(&ctx, PswFlag::OV));
+}
+
+#[test]
+fn rotates_and_accumulator_ops() {
+    let ctx = run("mov A, #12H\nswap A\nend\n");
+    assert_eq!(a(&ctx), 0x21);
+
+    let ctx = run("setb C\nmov A, #80H\nrlc A\nend\n");
+    assert_eq!(a(&ctx), 0x01);
+    assert!(flag(&ctx, PswFlag::CY));
+
+    let ctx = run("clr C\nmov A, #01H\nrrc A\nend\n");
+    assert_eq!(a(&ctx), 0x00);
+    assert!(flag(&ctx, PswFlag::CY));
+
+    let ctx = run("mov A, #0F0H\ncpl A\nnop\nend\n");
+    assert_eq!(a(&ctx), 0x0F);
+
+    let ctx = run("mov A, #0F0H\nclr A\nend\n");
+    assert_eq!(a(&ctx), 0);
+}
+
+#[test]
+fn logic_with_data_and_direct() {
+    let ctx = run("mov A, #0F0H\nanl A, #3CH\nend\n");
+    assert_eq!(a(&ctx), 0x30);
+
+    let ctx = run("mov 30H, #0FH\nmov A, #0F0H\norl A, 30H\nend\n");
+    assert_eq!(a(&ctx), 0xFF);
+
+    let ctx = run("mov B, #0FFH\nmov A, #0F0H\nxrl A, B\nend\n");
+    assert_eq!(a(&ctx), 0x0F);
+}
+
+#[test]
+fn djnz_counts_down() {
+    let ctx = run("mov R2, #5\nmov A, #0\nloop:\ninc A\ndjnz R2, loop\nend\n");
+    assert_eq!(a(&ctx), 5);
+    assert_eq!(ctx.em.ram.read(2), 0);
+}
+
+#[test]
+fn conditional_jumps() {
+    /* lexing stops at `end`, so there is only one */
+    let source = "mov R7, #0\nclr A\njnz bad\njz zero\nbad:\nmov R7, #1\nsjmp done\nzero:\nsetb C\njnc bad\njc carry\nsjmp bad\ncarry:\nmov R7, #2\ndone:\nend\n";
+    let ctx = run(source);
+    assert_eq!(ctx.em.ram.read(7), 2);
+}
+
+#[test]
+fn cjne_jumps_and_sets_carry() {
+    let ctx = run("mov R7, #0\nmov A, #3\ncjne A, #5, ne\nmov R7, #1\nne:\nend\n");
+    assert_eq!(ctx.em.ram.read(7), 0);
+    assert!(flag(&ctx, PswFlag::CY));
+
+    let ctx = run("mov R7, #0\nmov 30H, #3\nmov A, #3\ncjne A, 30H, ne\nmov R7, #1\nne:\nend\n");
+    assert_eq!(ctx.em.ram.read(7), 1);
+    assert!(!flag(&ctx, PswFlag::CY));
+}
+
+#[test]
+fn indirect_moves() {
+    let ctx = run("mov R0, #30H\nmov A, #5AH\nmov @R0, A\nclr A\nmov R1, #30H\nmov A, @R1\nend\n");
+    assert_eq!(ctx.em.ram.read(0x30), 0x5A);
+    assert_eq!(a(&ctx), 0x5A);
+}
+
+#[test]
+fn indirect_moves_past_ram_stop() {
+    let image = assemble(
+        "mov R0, #90H\nmov A, #5\nmov A, @R0\nend\n",
+        &Options::new(),
+    )
+    .unwrap();
+    let mut ctx = Machine::new().image(&image).build();
+
+    let reason = ctx.run_for(10_000);
+    assert_eq!(reason, StopReason::BadIndirect { pc: 4, addr: 0x90 });
+    assert_eq!(
+        ctx.explain(&reason),
+        "indirect address 0x90 is past the end of RAM at 0x0004, <source>:3"
+    );
+    assert_eq!(ctx.em.reg.pc.get(), 4);
+    assert_eq!(a(&ctx), 5);
+
+    let image = assemble("mov R1, #80H\nmov @R1, A\nend\n", &Options::new()).unwrap();
+    let mut ctx = Machine::new().image(&image).build();
+    assert_eq!(
+        ctx.run_for(10_000),
+        StopReason::BadIndirect { pc: 2, addr: 0x80 }
+    );
+}
+
+#[test]
+fn disassembles_what_it_assembles() {
+    let lines = [
+        "addc A, #12H",
+        "addc A, R3",
+        "subb A, #12H",
+        "subb A, R7",
+        "inc A",
+        "dec A",
+        "inc DPTR",
+        "mul AB",
+        "div AB",
+        "swap A",
+        "inc R1",
+        "dec R6",
+        "mov A, @R0",
+        "mov @R1, A",
+        "anl A, #0FH",
+        "orl A, 30H",
+        "xrl A, #0FFH",
+        "rlc A",
+        "rrc A",
+        "cpl A",
+        "clr A",
+        "nop",
+    ];
+
+    let image = assemble(&(lines.join("\n") + "\n"), &Options::new()).unwrap();
+    let code = disasm::disassemble_range(&image.code, 0, lines.len(), &SymbolTable::new());
+    let text: Vec<String> = code.iter().map(|d| d.text.clone()).collect();
+
+    assert_eq!(text, lines);
+}
@@ -0,0 +1,52 @@
+/*
+   lib/std/test.plasm linked against lib/std.lib,
+   and the library checked against its sources
+*/
+use prelude_rust::assembler::archive;
+use prelude_rust::assembler::object::assemble_object_file;
+use prelude_rust::syscall::BufferIo;
+use prelude_rust::{link, Archive, Machine, Object, Options, StopReason};
+
+const MEMBERS: [&str; 5] = ["bcd", "delay", "math", "print", "string"];
+
+/* what test.plasm prints, and how it stopped */
+fn run(objects: Vec<Object>) -> (String, StopReason) {
+    let (image, _) = link(&objects).unwrap();
+    let io = BufferIo::new("");
+    let output = io.output.clone();
+    let mut ctx = Machine::new().image(&image).io(Box::new(io)).build();
+
+    let reason = ctx.run_for(10_000_000);
+    (output.to_string_lossy(), reason)
+}
+
+fn test_object() -> Object {
+    assemble_object_file("lib/std/test.plasm", &Options::new()).unwrap()
+}
+
+#[test]
+fn the_library_passes_its_tests() {
+    let library = Archive::load("lib/std.lib").unwrap();
+    let (output, reason) = run(archive::resolve(vec![test_object()], &[library]));
+
+    assert_eq!(output, "0 7 42 255 0 65535 1234 A5\nAAA\nBB\n");
+    assert_eq!(reason, StopReason::Exited(0));
+}
+
+#[test]
+fn the_library_matches_its_sources() {
+    let library = Archive::load("lib/std.lib").unwrap();
+    assert_eq!(library.members.len(), MEMBERS.len());
+
+    for name in MEMBERS {
+        let path = format!("lib/std/{}.plasm", name);
+        let object = assemble_object_file(&path, &Options::new()).unwrap();
+        let member = library
+            .members
+            .iter()
+            .find(|m| m.name.ends_with(&path))
+            .unwrap();
+
+        assert_eq!(member.code, object.code, "{} is out of date", path);
+    }
+}